@@ -0,0 +1,124 @@
+//! Starts the server with a growing number of worker threads and measures
+//! how many commands per second the same number of clients get through.
+//! EXEC stands for a whole transaction of two writes.
+//!
+//! Run it with `cargo bench`; the port and run time can be changed with the
+//! `BENCH_PORT` and `BENCH_SECONDS` environment variables.
+
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::process::{Child, Command};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const THREADS: [usize; 4] = [1, 2, 4, 8];
+const KEYS: usize = 10_000;
+
+fn encode(args: &[&str]) -> Vec<u8> {
+    let mut out = format!("*{}\r\n", args.len()).into_bytes();
+    for arg in args {
+        out.extend(format!("${}\r\n{}\r\n", arg.len(), arg).into_bytes());
+    }
+    out
+}
+
+fn start_server(threads: usize, port: u16) -> Child {
+    let dir = std::env::temp_dir().join(format!("amandadb-bench-{threads}"));
+    std::fs::create_dir_all(&dir).unwrap();
+    let aof_dir = dir.join("appendonlydir");
+    let _ = std::fs::remove_dir_all(&aof_dir);
+
+    let config = dir.join("bench.conf");
+    let contents = format!("dbname=bench.aof\nappenddirname={}\nport={port}\nthreads={threads}", aof_dir.display());
+    std::fs::write(&config, contents).unwrap();
+
+    let mut server = Command::new(env!("CARGO_BIN_EXE_amandadb")).arg(&config).spawn().unwrap();
+    for _ in 0..100 {
+        if TcpStream::connect(("127.0.0.1", port)).is_ok() {
+            return server;
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    server.kill().unwrap();
+    server.wait().unwrap();
+    panic!("server did not start on port {port}");
+}
+
+/// A transaction setting one key and incrementing another, sent in one go.
+/// Its replies take six lines: MULTI's, the two QUEUED and EXEC's array of
+/// two.
+fn transaction(i: usize) -> (Vec<u8>, usize) {
+    let mut request = encode(&["MULTI"]);
+    request.extend(encode(&["SET", &format!("key:{}", i % KEYS), "value"]));
+    request.extend(encode(&["INCR", &format!("counter:{}", i % KEYS)]));
+    request.extend(encode(&["EXEC"]));
+    (request, 6)
+}
+
+/// Sends one command, or one transaction, at a time, waiting for its
+/// replies before the next.
+fn run_client(port: u16, client: usize, command: &str, stop: &AtomicBool, ops: &AtomicU64) {
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    stream.set_nodelay(true).unwrap();
+    let mut buffer = [0; 1024];
+
+    let mut i = client;
+    while !stop.load(Ordering::Relaxed) {
+        let (request, mut lines) = match command {
+            "SET" => (encode(&["SET", &format!("key:{}", i % KEYS), "value"]), 1),
+            "INCR" => (encode(&["INCR", &format!("counter:{}", i % KEYS)]), 1),
+            "EXEC" => transaction(i),
+            _ => (encode(&[command, &format!("key:{}", i % KEYS)]), 1),
+        };
+        stream.write_all(&request).unwrap();
+        // Other replies are read in one go, whatever their number of lines.
+        while lines > 0 {
+            let read = stream.read(&mut buffer).unwrap();
+            if read == 0 {
+                panic!("connection closed by the server");
+            }
+            lines = match command {
+                "EXEC" => lines.saturating_sub(buffer[..read].iter().filter(|&&byte| byte == b'\n').count()),
+                _ => 0,
+            };
+        }
+        ops.fetch_add(1, Ordering::Relaxed);
+        i += 7919;
+    }
+}
+
+fn bench(threads: usize, port: u16, command: &'static str, seconds: u64) -> f64 {
+    let stop = Arc::new(AtomicBool::new(false));
+    let ops = Arc::new(AtomicU64::new(0));
+
+    let clients: Vec<_> = (0..threads).map(|client| {
+        let (stop, ops) = (Arc::clone(&stop), Arc::clone(&ops));
+        thread::spawn(move || run_client(port, client, command, &stop, &ops))
+    }).collect();
+
+    let start = Instant::now();
+    thread::sleep(Duration::from_secs(seconds));
+    stop.store(true, Ordering::Relaxed);
+    for client in clients {
+        client.join().unwrap();
+    }
+    ops.load(Ordering::Relaxed) as f64 / start.elapsed().as_secs_f64()
+}
+
+fn main() {
+    let port = std::env::var("BENCH_PORT").ok().and_then(|p| p.parse().ok()).unwrap_or(6399);
+    let seconds = std::env::var("BENCH_SECONDS").ok().and_then(|s| s.parse().ok()).unwrap_or(3);
+
+    println!("{:>8} {:>8} {:>14}", "threads", "command", "ops/sec");
+    for threads in THREADS {
+        let mut server = start_server(threads, port);
+        for command in ["SET", "GET", "INCR", "EXEC"] {
+            let rate = bench(threads, port, command, seconds);
+            println!("{threads:>8} {command:>8} {rate:>14.0}");
+        }
+        server.kill().unwrap();
+        server.wait().unwrap();
+    }
+}
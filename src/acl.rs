@@ -0,0 +1,534 @@
+use std::collections::{HashMap, VecDeque};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::auth::{constant_time_eq, sha256_hex};
+use crate::config::Config;
+use crate::error::{new_error, Result};
+use crate::glob::glob_match;
+use crate::resp::Value;
+
+const LOG_SIZE: usize = 128;
+
+/// Every command grouped by category; a command not listed in any of them
+/// doesn't exist as far as rules go.
+pub const CATEGORIES: [(&str, &[&str]); 21] = [
+    ("keyspace", &["DEL", "UNLINK", "EXISTS", "TYPE", "KEYS", "SCAN", "RENAME", "RENAMENX", "COPY", "MOVE",
+        "SWAPDB", "FLUSHDB", "FLUSHALL", "RANDOMKEY", "TOUCH", "DBSIZE"]),
+    ("read", &["EXISTS", "TYPE", "KEYS", "SCAN", "RANDOMKEY", "TOUCH", "DBSIZE", "GET", "HGET", "HLEN", "HEXISTS",
+        "PFCOUNT", "BF.EXISTS", "BF.MEXISTS", "BF.INFO", "CF.EXISTS", "CF.MEXISTS", "CF.COUNT", "CF.INFO",
+        "JSON.GET", "JSON.MGET", "JSON.TYPE", "JSON.OBJKEYS", "TS.RANGE", "TS.REVRANGE", "TS.GET", "TS.INFO",
+        "XLEN", "XRANGE", "XREVRANGE", "XREAD", "XPENDING", "XINFO", "ZCARD", "ZSCORE", "ZRANGE", "GEOPOS",
+        "GEOHASH", "GEODIST", "GEOSEARCH", "LLEN", "LRANGE", "SCARD", "SISMEMBER", "SMEMBERS"]),
+    ("write", &["SET", "HSET", "DEL", "UNLINK", "RENAME", "RENAMENX", "COPY", "MOVE", "SWAPDB", "FLUSHDB",
+        "FLUSHALL", "HDEL", "INCR", "INCRBY", "DECR", "DECRBY", "PFADD", "PFMERGE", "BF.RESERVE", "BF.ADD",
+        "BF.MADD", "CF.RESERVE", "CF.ADD", "CF.ADDNX", "CF.INSERT", "CF.INSERTNX", "CF.DEL", "JSON.SET",
+        "JSON.DEL", "JSON.NUMINCRBY", "JSON.ARRAPPEND", "JSON.STRAPPEND", "TS.CREATE", "TS.ADD", "TS.MADD",
+        "TS.INCRBY", "XADD", "XDEL", "XTRIM", "XGROUP", "XREADGROUP", "XACK", "XCLAIM", "XAUTOCLAIM",
+        "ZADD", "ZREM", "GEOADD", "GEOSEARCHSTORE", "LPUSH", "RPUSH", "LPOP", "RPOP", "SADD", "SREM"]),
+    ("string", &["SET", "GET", "INCR", "INCRBY", "DECR", "DECRBY"]),
+    ("hash", &["HSET", "HGET", "HDEL", "HLEN", "HEXISTS"]),
+    ("hyperloglog", &["PFADD", "PFCOUNT", "PFMERGE"]),
+    ("bloom", &["BF.RESERVE", "BF.ADD", "BF.MADD", "BF.EXISTS", "BF.MEXISTS", "BF.INFO"]),
+    ("cuckoo", &["CF.RESERVE", "CF.ADD", "CF.ADDNX", "CF.INSERT", "CF.INSERTNX", "CF.EXISTS", "CF.MEXISTS",
+        "CF.DEL", "CF.COUNT", "CF.INFO"]),
+    ("json", &["JSON.SET", "JSON.GET", "JSON.DEL", "JSON.MGET", "JSON.NUMINCRBY", "JSON.ARRAPPEND",
+        "JSON.STRAPPEND", "JSON.TYPE", "JSON.OBJKEYS"]),
+    ("timeseries", &["TS.CREATE", "TS.ADD", "TS.MADD", "TS.INCRBY", "TS.RANGE", "TS.REVRANGE", "TS.GET",
+        "TS.INFO"]),
+    ("stream", &["XADD", "XLEN", "XRANGE", "XREVRANGE", "XDEL", "XTRIM", "XREAD", "XGROUP", "XREADGROUP",
+        "XACK", "XPENDING", "XCLAIM", "XAUTOCLAIM", "XINFO"]),
+    ("blocking", &["XREAD", "XREADGROUP"]),
+    ("sortedset", &["ZADD", "ZCARD", "ZSCORE", "ZREM", "ZRANGE"]),
+    ("geo", &["GEOADD", "GEOPOS", "GEOHASH", "GEODIST", "GEOSEARCH", "GEOSEARCHSTORE"]),
+    ("list", &["LPUSH", "RPUSH", "LPOP", "RPOP", "LLEN", "LRANGE"]),
+    ("set", &["SADD", "SREM", "SCARD", "SISMEMBER", "SMEMBERS"]),
+    ("connection", &["PING", "ECHO", "SELECT", "AUTH", "COMMAND"]),
+    ("transaction", &["MULTI", "EXEC", "DISCARD"]),
+    ("admin", &["ACL", "BGREWRITEAOF", "SAVE", "BGSAVE", "LASTSAVE"]),
+    ("dangerous", &["FLUSHDB", "FLUSHALL", "SWAPDB", "KEYS", "ACL", "INFO", "BGREWRITEAOF", "SAVE", "BGSAVE",
+        "LASTSAVE"]),
+    ("all", &[]),
+];
+
+fn category(name: &str) -> Option<&'static [&'static str]> {
+    CATEGORIES.iter().find(|(category, _)| category.eq_ignore_ascii_case(name)).map(|(_, commands)| *commands)
+}
+
+fn is_command(name: &str) -> bool {
+    CATEGORIES.iter().any(|(_, commands)| commands.iter().any(|c| c.eq_ignore_ascii_case(name)))
+}
+
+/// The arguments of `cmd` that are keys, picked as Redis key specs do: from
+/// `first` to `last` in steps, a negative `last` counting from the end.
+///
+/// The handlers of commands over several keys lock their shards with this
+/// too, so a key can't be reached without being checked here first.
+pub fn keys<'a>(cmd: &str, args: &'a [Value]) -> Vec<&'a str> {
+    let (first, last, step): (usize, isize, usize) = match cmd {
+        "DEL" | "UNLINK" | "EXISTS" | "TOUCH" | "PFCOUNT" | "PFMERGE" => (0, -1, 1),
+        "HDEL" | "HEXISTS" => (0, -1, 2),
+        "RENAME" | "RENAMENX" | "COPY" | "GEOSEARCHSTORE" => (0, 1, 1),
+        "JSON.MGET" => (0, -2, 1),
+        "TS.MADD" => (0, -1, 3),
+        "XGROUP" | "XINFO" => (1, 1, 1),
+        "XREAD" | "XREADGROUP" => return stream_keys(args),
+        "KEYS" | "SCAN" | "RANDOMKEY" | "DBSIZE" | "FLUSHDB" | "FLUSHALL" | "SWAPDB" | "PING" | "ECHO"
+            | "SELECT" | "AUTH" | "COMMAND" | "MULTI" | "EXEC" | "DISCARD" | "ACL" | "INFO"
+            | "BGREWRITEAOF" | "SAVE" | "BGSAVE" | "LASTSAVE" => return Vec::new(),
+        _ => (0, 0, 1),
+    };
+    let last = match last {
+        last if last < 0 => args.len() as isize + last,
+        last => last,
+    };
+    if last < 0 {
+        return Vec::new();
+    }
+
+    args.iter().enumerate()
+        .skip(first)
+        .take_while(|(i, _)| *i as isize <= last)
+        .step_by(step)
+        .filter_map(|(_, arg)| match arg {
+            Value::BulkStr(key) => Some(key.as_str()),
+            _ => None,
+        })
+        .collect()
+}
+
+/// The keys of XREAD and XREADGROUP, which are the first half of what
+/// follows STREAMS, the rest being their IDs.
+fn stream_keys(args: &[Value]) -> Vec<&str> {
+    let mut position = 0;
+    while let Some(Value::BulkStr(option)) = args.get(position) {
+        position += match option.to_uppercase().as_str() {
+            "STREAMS" => break,
+            "GROUP" => 3,
+            "COUNT" | "BLOCK" => 2,
+            _ => 1,
+        };
+    }
+    let streams = args.get(position + 1..).unwrap_or_default();
+    streams[..streams.len() / 2].iter()
+        .filter_map(|arg| match arg {
+            Value::BulkStr(key) => Some(key.as_str()),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Why a command was refused, which ACL LOG groups entries by.
+pub enum Denial {
+    Command,
+    Key(String),
+    Auth,
+}
+
+struct LogEntry {
+    count: usize,
+    reason: &'static str,
+    object: String,
+    username: String,
+    created: u128,
+    updated: u128,
+}
+
+fn now() -> u128 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis()
+}
+
+#[derive(Clone)]
+pub struct User {
+    enabled: bool,
+    nopass: bool,
+    passwords: Vec<String>,
+    patterns: Vec<String>,
+    commands: Vec<String>,
+}
+
+impl Default for User {
+    /// A new user is off and can't run anything until rules say otherwise.
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            nopass: false,
+            passwords: Vec::new(),
+            patterns: Vec::new(),
+            commands: vec!["-@all".into()],
+        }
+    }
+}
+
+impl User {
+    fn superuser() -> Self {
+        Self {
+            enabled: true,
+            nopass: true,
+            passwords: Vec::new(),
+            patterns: vec!["*".into()],
+            commands: vec!["+@all".into()],
+        }
+    }
+
+    /// Applies one rule of ACL SETUSER, such as `on`, `>password`, `~key:*`
+    /// or `+@read`.
+    pub fn apply(&mut self, rule: &str) -> std::result::Result<(), &'static str> {
+        match rule.to_lowercase().as_str() {
+            "on" => self.enabled = true,
+            "off" => self.enabled = false,
+            "nopass" => {
+                self.nopass = true;
+                self.passwords.clear();
+            },
+            "resetpass" => {
+                self.nopass = false;
+                self.passwords.clear();
+            },
+            "allkeys" => self.patterns = vec!["*".into()],
+            "resetkeys" => self.patterns.clear(),
+            "allcommands" => self.commands = vec!["+@all".into()],
+            "nocommands" => self.commands = vec!["-@all".into()],
+            "reset" => *self = User::default(),
+            _ => match rule.split_at(rule.chars().next().map_or(0, char::len_utf8)) {
+                (">", password) => {
+                    let hash = sha256_hex(password.as_bytes());
+                    if !self.passwords.contains(&hash) {
+                        self.passwords.push(hash);
+                    }
+                    self.nopass = false;
+                },
+                ("<", password) => {
+                    let hash = sha256_hex(password.as_bytes());
+                    if !self.passwords.contains(&hash) {
+                        return Err("ERR: The password to remove is not set for the user");
+                    }
+                    self.passwords.retain(|p| p != &hash);
+                },
+                ("#", hash) => {
+                    if hash.len() != 64 || !hash.bytes().all(|b| b.is_ascii_hexdigit()) {
+                        return Err("ERR: The password hash must be exactly 64 hexadecimal characters");
+                    }
+                    let hash = hash.to_lowercase();
+                    if !self.passwords.contains(&hash) {
+                        self.passwords.push(hash);
+                    }
+                    self.nopass = false;
+                },
+                ("!", hash) => self.passwords.retain(|p| !p.eq_ignore_ascii_case(hash)),
+                ("~", pattern) => match self.patterns.iter().any(|p| p == "*") {
+                    true => (),
+                    false => self.patterns.push(pattern.into()),
+                },
+                ("+" | "-", name) => {
+                    let valid = match name.strip_prefix('@') {
+                        Some(name) => category(name).is_some(),
+                        None => is_command(name),
+                    };
+                    if !valid {
+                        return Err("ERR: Unknown command or category name in ACL rule");
+                    }
+                    if name.eq_ignore_ascii_case("@all") {
+                        self.commands.clear();
+                    }
+                    self.commands.push(rule.to_lowercase());
+                },
+                _ => return Err("ERR: Syntax error in ACL rule"),
+            },
+        };
+        Ok(())
+    }
+
+    /// Rules are looked at in order, so the last one naming the command
+    /// decides.
+    pub fn can_run(&self, cmd: &str) -> bool {
+        let mut allowed = false;
+        for rule in &self.commands {
+            let (sign, name) = rule.split_at(1);
+            let matches = match name.strip_prefix('@') {
+                Some("all") => true,
+                Some(name) => category(name).is_some_and(|commands| commands.contains(&cmd)),
+                None => name.eq_ignore_ascii_case(cmd),
+            };
+            if matches {
+                allowed = sign == "+";
+            }
+        }
+        allowed
+    }
+
+    pub fn can_access(&self, key: &str) -> bool {
+        self.patterns.iter().any(|pattern| glob_match(pattern.as_bytes(), key.as_bytes()))
+    }
+
+    fn check_password(&self, password: &str) -> bool {
+        if self.nopass {
+            return true;
+        }
+        let hash = sha256_hex(password.as_bytes());
+        self.passwords.iter().fold(false, |found, p| constant_time_eq(p.as_bytes(), hash.as_bytes()) | found)
+    }
+
+    /// The rules that recreate this user, as ACL LIST and the aclfile have
+    /// them.
+    fn describe(&self) -> String {
+        let mut rules = vec![match self.enabled {
+            true => "on".to_string(),
+            false => "off".to_string(),
+        }];
+        if self.nopass {
+            rules.push("nopass".into());
+        }
+        rules.extend(self.passwords.iter().map(|hash| format!("#{hash}")));
+        rules.extend(self.patterns.iter().map(|pattern| format!("~{pattern}")));
+        rules.extend(self.commands.iter().cloned());
+        rules.join(" ")
+    }
+
+    /// The reply of ACL GETUSER, as pairs of field names and values.
+    fn info(&self) -> Value {
+        let mut flags = vec![Value::BulkStr(match self.enabled {
+            true => "on".into(),
+            false => "off".into(),
+        })];
+        if self.nopass {
+            flags.push(Value::BulkStr("nopass".into()));
+        }
+        if self.patterns.iter().any(|p| p == "*") {
+            flags.push(Value::BulkStr("allkeys".into()));
+        }
+        let passwords = self.passwords.iter().map(|hash| Value::BulkStr(hash.clone())).collect();
+        let keys = self.patterns.iter().map(|pattern| format!("~{pattern}")).collect::<Vec<_>>();
+
+        Value::Array(vec![
+            Value::BulkStr("flags".into()), Value::Array(flags),
+            Value::BulkStr("passwords".into()), Value::Array(passwords),
+            Value::BulkStr("commands".into()), Value::BulkStr(self.commands.join(" ")),
+            Value::BulkStr("keys".into()), Value::BulkStr(keys.join(" ")),
+        ])
+    }
+}
+
+/// The users every connection authenticates against, shared by the whole
+/// server.
+pub struct Acl {
+    users: HashMap<String, User>,
+    default: User,
+    log: VecDeque<LogEntry>,
+    file: Option<String>,
+}
+
+impl Acl {
+    /// Starts with a `default` user that can run everything, guarded by
+    /// `requirepass` when set, and then loads the aclfile if there is one.
+    /// That user is kept when the aclfile has no `default` of its own.
+    pub fn new(config: &Config) -> Result<Self> {
+        let mut default = User::superuser();
+        if let Some(password) = config.requirepass() {
+            default.apply("resetpass").unwrap();
+            default.apply(&format!(">{password}")).unwrap();
+        }
+
+        let mut acl = Self {
+            users: HashMap::from([("default".to_string(), default.clone())]),
+            default,
+            log: VecDeque::new(),
+            file: config.aclfile().map(String::from),
+        };
+        if config.aclfile().is_some_and(|path| std::path::Path::new(path).exists()) {
+            acl.load()?;
+        }
+        Ok(acl)
+    }
+
+    /// Reads every user from the aclfile, keeping the current ones when any
+    /// line is wrong.
+    pub fn load(&mut self) -> Result<()> {
+        let Some(path) = &self.file else {
+            return Err(new_error("This server is not configured with an ACL file"));
+        };
+
+        let mut users = HashMap::new();
+        for line in std::fs::read_to_string(path)?.lines() {
+            let mut words = line.split_whitespace();
+            match (words.next(), words.next()) {
+                (None, _) => continue,
+                (Some("user"), Some(name)) => {
+                    let mut user = User::default();
+                    for rule in words {
+                        user.apply(rule).map_err(new_error)?;
+                    }
+                    users.insert(name.to_string(), user);
+                },
+                _ => return Err(new_error("ACL file lines must look like 'user <name> <rules>'")),
+            };
+        }
+        users.entry("default".into()).or_insert_with(|| self.default.clone());
+
+        self.users = users;
+        Ok(())
+    }
+
+    pub fn save(&self) -> Result<()> {
+        let Some(path) = &self.file else {
+            return Err(new_error("This server is not configured with an ACL file"));
+        };
+        std::fs::write(path, self.list().join("\n") + "\n")?;
+        Ok(())
+    }
+
+    /// Applies every rule to a copy of the user, so nothing changes when one
+    /// of them is wrong.
+    pub fn set_user(&mut self, name: &str, rules: &[String]) -> std::result::Result<(), &'static str> {
+        let mut user = self.users.get(name).cloned().unwrap_or_default();
+        for rule in rules {
+            user.apply(rule)?;
+        }
+        self.users.insert(name.into(), user);
+        Ok(())
+    }
+
+    pub fn delete_user(&mut self, name: &str) -> bool {
+        self.users.remove(name).is_some()
+    }
+
+    pub fn usernames(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.users.keys().cloned().collect();
+        names.sort();
+        names
+    }
+
+    pub fn list(&self) -> Vec<String> {
+        self.usernames().into_iter().map(|name| format!("user {name} {}", self.users[&name].describe())).collect()
+    }
+
+    pub fn user_info(&self, name: &str) -> Value {
+        self.users.get(name).map_or(Value::Null, User::info)
+    }
+
+    /// The user a new connection is logged in as, which is only `default`
+    /// when it needs no password.
+    pub fn initial_user(&self) -> Option<String> {
+        self.users.get("default").filter(|user| user.enabled && user.nopass).map(|_| "default".into())
+    }
+
+    pub fn authenticate(&self, name: &str, password: &str) -> bool {
+        self.users.get(name).is_some_and(|user| user.check_password(password) && user.enabled)
+    }
+
+    /// Checks whether `name` may run `cmd` over the keys in `args`.
+    pub fn check(&self, name: &str, cmd: &str, args: &[Value]) -> std::result::Result<(), Denial> {
+        let Some(user) = self.users.get(name) else {
+            return Err(Denial::Auth);
+        };
+        if !user.can_run(cmd) {
+            return Err(Denial::Command);
+        }
+        match keys(cmd, args).into_iter().find(|key| !user.can_access(key)) {
+            Some(key) => Err(Denial::Key(key.into())),
+            None => Ok(()),
+        }
+    }
+
+    /// Records a refused command, counting repeats of the same one.
+    pub fn log(&mut self, denial: &Denial, username: &str, cmd: &str) {
+        let (reason, object) = match denial {
+            Denial::Command => ("command", cmd.to_lowercase()),
+            Denial::Key(key) => ("key", key.clone()),
+            Denial::Auth => ("auth", "AUTH".into()),
+        };
+
+        let time = now();
+        let existing = self.log.iter_mut()
+            .find(|e| e.reason == reason && e.object == object && e.username == username);
+        if let Some(entry) = existing {
+            entry.count += 1;
+            entry.updated = time;
+            return;
+        }
+        self.log.push_front(LogEntry {
+            count: 1,
+            reason,
+            object,
+            username: username.into(),
+            created: time,
+            updated: time,
+        });
+        self.log.truncate(LOG_SIZE);
+    }
+
+    pub fn log_entries(&self, count: usize) -> Value {
+        let time = now();
+        let entries = self.log.iter().take(count).map(|entry| Value::Array(vec![
+            Value::BulkStr("count".into()), Value::Num(entry.count as i64),
+            Value::BulkStr("reason".into()), Value::BulkStr(entry.reason.into()),
+            Value::BulkStr("context".into()), Value::BulkStr("toplevel".into()),
+            Value::BulkStr("object".into()), Value::BulkStr(entry.object.clone()),
+            Value::BulkStr("username".into()), Value::BulkStr(entry.username.clone()),
+            Value::BulkStr("age-seconds".into()),
+            Value::BulkStr(format!("{:.3}", (time - entry.created) as f64 / 1000.0)),
+            Value::BulkStr("timestamp-created".into()), Value::Num(entry.created as i64),
+            Value::BulkStr("timestamp-last-updated".into()), Value::Num(entry.updated as i64),
+        ]));
+        Value::Array(entries.collect())
+    }
+
+    pub fn reset_log(&mut self) {
+        self.log.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<Value> {
+        args.iter().map(|arg| Value::BulkStr(arg.to_string())).collect()
+    }
+
+    #[test]
+    fn picks_keys_by_command() {
+        assert_eq!(keys("GET", &args(&["k"])), ["k"]);
+        assert_eq!(keys("HSET", &args(&["h", "f", "v"])), ["h"]);
+        assert_eq!(keys("DEL", &args(&["a", "b", "c"])), ["a", "b", "c"]);
+        assert_eq!(keys("RENAME", &args(&["a", "b"])), ["a", "b"]);
+        assert_eq!(keys("HDEL", &args(&["cache:x", "f", "secret:y", "f"])), ["cache:x", "secret:y"]);
+        assert_eq!(keys("HEXISTS", &args(&["a", "f", "b", "g"])), ["a", "b"]);
+        assert_eq!(keys("JSON.MGET", &args(&["a", "b", "$"])), ["a", "b"]);
+        assert_eq!(keys("TS.MADD", &args(&["a", "1", "1", "b", "2", "2"])), ["a", "b"]);
+        assert_eq!(keys("XGROUP", &args(&["CREATE", "s", "g", "$"])), ["s"]);
+        assert_eq!(keys("XREAD", &args(&["COUNT", "2", "STREAMS", "a", "b", "0", "$"])), ["a", "b"]);
+        assert_eq!(keys("XREADGROUP", &args(&["GROUP", "STREAMS", "c", "STREAMS", "a", ">"])), ["a"]);
+        assert!(keys("PING", &args(&["hello"])).is_empty());
+    }
+
+    #[test]
+    fn checks_every_key_of_a_command() {
+        let mut acl = Acl::new(&Config::default()).unwrap();
+        let rules: Vec<String> = ["on", "nopass", "+@all", "~cache:*"].map(String::from).to_vec();
+        acl.set_user("app", &rules).unwrap();
+
+        assert!(acl.check("app", "HDEL", &args(&["cache:x", "f", "cache:y", "f"])).is_ok());
+        let denial = acl.check("app", "HDEL", &args(&["cache:x", "f", "secret:y", "f"]));
+        assert!(matches!(denial, Err(Denial::Key(key)) if key == "secret:y"));
+        let denial = acl.check("app", "HEXISTS", &args(&["cache:x", "f", "secret:y", "f"]));
+        assert!(matches!(denial, Err(Denial::Key(key)) if key == "secret:y"));
+    }
+
+    #[test]
+    fn keeps_requirepass_without_a_default_user_in_the_aclfile() {
+        let dir = std::env::temp_dir().join(format!("amandadb-acl-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (conf, aclfile) = (dir.join("amandadb.conf"), dir.join("users.acl"));
+        std::fs::write(&aclfile, "user app on nopass +@all ~*\n").unwrap();
+        std::fs::write(&conf, format!("requirepass=secret\naclfile={}\n", aclfile.display())).unwrap();
+        let config = Config::read_from_file(conf.to_str().unwrap()).unwrap();
+        let acl = Acl::new(&config);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let acl = acl.unwrap();
+        assert_eq!(acl.initial_user(), None);
+        assert!(acl.authenticate("default", "secret"));
+        assert!(!acl.authenticate("default", ""));
+        assert!(acl.authenticate("app", "anything"));
+    }
+}
@@ -1,65 +1,1057 @@
+use std::collections::HashMap;
+use std::fmt;
 use std::fs::File;
-use std::io::prelude::*;
-use std::sync::{Arc, RwLock};
+use std::io::{prelude::*, BufWriter};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::thread;
+use std::time::{Duration, Instant};
 
-use crate::config::Config;
-use crate::error::Result;
-use crate::database::Database;
-use crate::resp::{RESP, Value};
+use crate::config::{AppendFsync, Config};
+use crate::database::{Data, Database};
+use crate::dump;
+use crate::error::{new_error, Result};
+use crate::handlers::{Databases, Handlers};
+use crate::json::Format;
+use crate::manifest::Manifest;
+use crate::rdb::crc64;
+use crate::resp::{frame_len, RESP, Value};
+use crate::snapshot;
 
-type DB = Arc<RwLock<Database>>;
+/// How long `everysec` lets written commands wait for an fsync before it
+/// counts as delayed.
+const FSYNC_DELAY: Duration = Duration::from_secs(2);
 
+/// How often a long replay reports how far it got.
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Puts back a value a rewrite without the preamble serialized, for the
+/// types no plain command recreates. Only replays run it: it's no command
+/// of clients, so they can't send it and ACL rules never see it.
+pub const RESTORE_OPCODE: &str = "AMANDADB.RESTORE";
+
+/// How many bytes of commands go by before a checksum record covers them.
+const SEGMENT_SIZE: u64 = 64 * 1024;
+
+/// Held for reading by a write command from when it's logged until it's
+/// applied, so a rewrite can copy the databases at a point where they agree
+/// with the log.
+static WRITES: RwLock<()> = RwLock::new(());
+
+pub fn lock_writes() -> RwLockReadGuard<'static, ()> {
+    WRITES.read().unwrap()
+}
+
+/// Waits for the write commands being applied and holds new ones back, for
+/// copies of the databases that must not see half of a command.
+pub fn pause_writes() -> RwLockWriteGuard<'static, ()> {
+    WRITES.write().unwrap()
+}
+
+/// Moves a fully written file over `path`, syncing the directory so the
+/// rename survives a crash.
+pub fn replace_file(temp: impl AsRef<Path>, path: impl AsRef<Path>) -> Result<()> {
+    std::fs::rename(temp, &path)?;
+    let dir = path.as_ref().parent().filter(|dir| !dir.as_os_str().is_empty());
+    File::open(dir.unwrap_or(Path::new(".")))?.sync_all()?;
+    Ok(())
+}
+
+fn open_append(path: &Path) -> Result<File> {
+    Ok(File::options().read(true).append(true).create(true).open(path)?)
+}
+
+/// Opens the manifest in `appenddirname`, starting one when there's none.
+/// An AOF from before the directory existed becomes its base, linked in
+/// before the old name is removed so that a crash loses neither, once a
+/// command a crash left incomplete at its end is cut off. Also tells whether
+/// there was an AOF at all.
+fn open_manifest(config: &Config) -> Result<(Manifest, bool)> {
+    let dir = Path::new(config.appenddirname());
+    let legacy = Path::new(config.dbname());
+    let name = legacy.file_name().and_then(|name| name.to_str()).unwrap_or("database.aof");
+    std::fs::create_dir_all(dir)?;
+
+    let mut migrated = false;
+    let loaded = Manifest::load(dir, name)?;
+    let existed = loaded.is_some() || legacy.is_file();
+    let mut manifest = match loaded {
+        Some(manifest) => manifest,
+        None => {
+            let mut manifest = Manifest::new(dir, name);
+            if legacy.is_file() {
+                cut_incomplete_tail(legacy, config.aof_load_truncated())?;
+                let base = manifest.next_base();
+                let _ = std::fs::remove_file(manifest.path_of(&base));
+                std::fs::hard_link(legacy, manifest.path_of(&base))?;
+                manifest.base = Some(base);
+                migrated = true;
+            }
+            manifest
+        },
+    };
+    if manifest.incrs.is_empty() {
+        let part = manifest.next_incr();
+        let mut file = open_append(&manifest.path_of(&part))?;
+        file.write_all(&sealed_select(0))?;
+        file.sync_all()?;
+    }
+    manifest.save()?;
+    manifest.remove_history()?;
+    if migrated {
+        std::fs::remove_file(legacy)?;
+    }
+    Ok((manifest, existed))
+}
+
+/// Repairs an AOF about to become a base, which can't be cut once other files
+/// follow it, as `aof-load-truncated` only allows for the last one.
+fn cut_incomplete_tail(path: &Path, load_truncated: bool) -> Result<()> {
+    let data = std::fs::read(path)?;
+    let start = match data.starts_with(snapshot::MAGIC) {
+        true => snapshot::check_amandadb(&data)?,
+        false => 0,
+    };
+    match commands(&data, start).find_map(|command| command.err()) {
+        None => Ok(()),
+        Some(Damage::Truncated(offset)) if load_truncated => {
+            eprintln!("{} {}, discarding the last {} bytes", path.display(), Damage::Truncated(offset), data.len() - offset);
+            let file = File::options().write(true).open(path)?;
+            file.set_len(offset as u64)?;
+            file.sync_all()?;
+            Ok(())
+        },
+        Some(damage) => Err(new_error(&format!(
+            "{} {damage}, amandadb-check-aof can check and repair it before it's moved into the AOF directory",
+            path.display(),
+        ))),
+    }
+}
+
+fn select_command(index: usize) -> Vec<u8> {
+    Value::Array(vec![Value::BulkStr("SELECT".into()), Value::BulkStr(index.to_string())]).marshal()
+}
+
+/// The `SELECT` a new incremental file starts with, with its checksum.
+fn sealed_select(index: usize) -> Vec<u8> {
+    let mut select = select_command(index);
+    let mut segment = Segment::default();
+    segment.update(&select);
+    select.extend(segment.close());
+    select
+}
+
+/// The commands written since the last checksum record of a file, which
+/// the next one covers with a CRC64.
+#[derive(Default, Clone, Copy)]
+struct Segment {
+    crc: u64,
+    len: u64,
+}
+
+impl Segment {
+    fn update(&mut self, bytes: &[u8]) {
+        self.crc = crc64(self.crc, bytes);
+        self.len += bytes.len() as u64;
+    }
+
+    /// The checksum record that ends the segment, starting the next one.
+    fn close(&mut self) -> Vec<u8> {
+        let crc = std::mem::take(self).crc;
+        Value::Array(vec![Value::BulkStr("CHECKSUM".into()), Value::BulkStr(format!("{crc:016x}"))]).marshal()
+    }
+}
+
+/// The multi-part AOF: a base written by the last rewrite and incremental
+/// files with the writes that came after it, listed by a manifest.
+#[allow(clippy::upper_case_acronyms)]
 pub struct AOF {
-    file: File,
-    insert_queue: Vec<Value>,
+    /// The incremental file being appended to, `None` with `appendonly=no`
+    /// when nothing gets logged.
+    file: Option<File>,
+    manifest: Option<Manifest>,
+    /// Whether there was an AOF to load when the server started.
+    existed: bool,
+    insert_queue: Vec<(usize, Value)>,
+    selected: usize,
+    fsync: AppendFsync,
+    buffer: Vec<u8>,
+    pending_since: Option<Instant>,
+    delayed_fsync: u64,
+    size: u64,
+    base_size: u64,
+    auto_rewrite_percentage: u64,
+    auto_rewrite_min_size: u64,
+    use_preamble: bool,
+    compress: bool,
+    load_truncated: bool,
+    segment: Segment,
+    rewriting: bool,
+    rewrites: u64,
+    last_rewrite_ok: bool,
 }
 
 impl AOF {
     pub fn new(config: Config) -> Result<Self> {
-        let file = File::options()
-            .read(true)
-            .write(true)
-            .create(true)
-            .open(config.dbname())?;
+        let (manifest, existed) = match config.appendonly() {
+            true => {
+                let (manifest, existed) = open_manifest(&config)?;
+                (Some(manifest), existed)
+            },
+            false => (None, false),
+        };
+
+        let (mut size, mut base_size) = (0, 0);
+        let mut file = None;
+        if let Some(manifest) = &manifest {
+            for part in manifest.parts() {
+                size += std::fs::metadata(manifest.path_of(part))?.len();
+            }
+            if let Some(base) = &manifest.base {
+                base_size = std::fs::metadata(manifest.path_of(base))?.len();
+            }
+            file = manifest.incrs.last().map(|part| open_append(&manifest.path_of(part))).transpose()?;
+        }
 
-        Ok(Self { file, insert_queue: Vec::new() })
+        Ok(Self {
+            file,
+            manifest,
+            existed,
+            insert_queue: Vec::new(),
+            selected: 0,
+            fsync: config.appendfsync(),
+            buffer: Vec::new(),
+            pending_since: None,
+            delayed_fsync: 0,
+            size,
+            base_size,
+            auto_rewrite_percentage: config.auto_aof_rewrite_percentage(),
+            auto_rewrite_min_size: config.auto_aof_rewrite_min_size(),
+            use_preamble: config.aof_use_rdb_preamble(),
+            compress: config.rdbcompression(),
+            load_truncated: config.aof_load_truncated(),
+            segment: Segment::default(),
+            rewriting: false,
+            rewrites: 0,
+            last_rewrite_ok: true,
+        })
     }
 
-    pub fn read(&mut self, func: fn(Value, DB), db: DB) -> Result<()> {
-        let len = self.file.metadata()?.len();
-        if len == 0 {
-            return Ok(());
+    /// Starts rewriting the log in the background from a copy of every
+    /// database. Writes go to a new incremental file from the point of the
+    /// copy, so the files being replaced are never touched and the manifest
+    /// lists a complete AOF at any time.
+    pub fn rewrite(aof: &Arc<RwLock<AOF>>, dbs: &[Arc<Database>]) -> std::result::Result<(), &'static str> {
+        let snapshot: Vec<_> = {
+            let _writes = pause_writes();
+            let mut aof = aof.write().unwrap();
+            if !aof.is_enabled() {
+                return Err("ERR: The AOF is disabled, set appendonly=yes to use it");
+            }
+            if aof.rewriting {
+                return Err("ERR: Background append only file rewriting already in progress");
+            }
+            if let Err(e) = aof.open_incr() {
+                eprintln!("Failed to open a new AOF file: {e}");
+                aof.last_rewrite_ok = false;
+                return Err("ERR: Failed to open a new AOF file");
+            }
+            aof.rewriting = true;
+            dbs.iter().map(|db| db.create_database_copy()).collect()
+        };
+
+        let aof = Arc::clone(aof);
+        thread::spawn(move || {
+            let (temp, preamble, compress) = {
+                let aof = aof.read().unwrap();
+                (aof.manifest.as_ref().unwrap().temp_path(), aof.use_preamble, aof.compress)
+            };
+            let result = write_snapshot(&temp, &snapshot, preamble, compress).and_then(|file| aof.write().unwrap().finish_rewrite(file, &temp));
+            if let Err(e) = result {
+                eprintln!("Failed to rewrite the AOF: {e}");
+                let _ = std::fs::remove_file(&temp);
+                let mut aof = aof.write().unwrap();
+                aof.rewriting = false;
+                aof.last_rewrite_ok = false;
+            }
+        });
+        Ok(())
+    }
+
+    /// Moves the writes to a new incremental file that starts by selecting
+    /// the current database, after the current one is on disk with a
+    /// checksum for its last commands.
+    fn open_incr(&mut self) -> Result<()> {
+        self.write_buffer()?;
+        if let Some(file) = &self.file {
+            file.sync_data()?;
         }
 
-        let mut data = Vec::new();
-        self.file.read_to_end(&mut data)?;
+        let manifest = self.manifest.as_mut().unwrap();
+        let part = manifest.next_incr();
+        let select = sealed_select(self.selected);
+        let file = open_append(&manifest.path_of(&part)).and_then(|mut file| {
+            file.write_all(&select)?;
+            manifest.save()?;
+            Ok(file)
+        });
+        let file = match file {
+            Ok(file) => file,
+            Err(e) => {
+                manifest.incrs.pop();
+                let _ = std::fs::remove_file(manifest.path_of(&part));
+                return Err(e);
+            },
+        };
+        self.file = Some(file);
+        self.size += select.len() as u64;
+        Ok(())
+    }
 
-        let input = std::str::from_utf8(&data)?;
-        let mut reader = RESP::new(&input);
-        loop {
-            let value = reader.read()?;
-            match value {
-                Value::Null => break,
-                _ => func(value, Arc::clone(&db)),
+    fn finish_rewrite(&mut self, file: File, temp: &Path) -> Result<()> {
+        let manifest = self.manifest.as_mut().unwrap();
+        let base = manifest.next_base();
+        replace_file(temp, manifest.path_of(&base))?;
+        manifest.set_base(base);
+        manifest.save()?;
+        if let Err(e) = manifest.remove_history() {
+            eprintln!("Failed to remove the old AOF files: {e}");
+        }
+
+        let incr = self.file.as_ref().map(File::metadata).transpose()?.map_or(0, |metadata| metadata.len());
+        self.base_size = file.metadata()?.len();
+        self.size = self.base_size + incr + self.buffer.len() as u64;
+        self.rewriting = false;
+        self.rewrites += 1;
+        self.last_rewrite_ok = true;
+        Ok(())
+    }
+
+    /// Whether the log grew enough since the last rewrite, as set by the
+    /// `auto-aof-rewrite-*` configs.
+    pub fn should_rewrite(&self) -> bool {
+        let growth = self.base_size.max(1) * (100 + self.auto_rewrite_percentage) / 100;
+        self.auto_rewrite_percentage > 0
+            && self.is_enabled()
+            && !self.rewriting
+            && self.size >= self.auto_rewrite_min_size
+            && self.size >= growth
+    }
+
+    /// With `everysec`, starts the thread that writes the buffered commands
+    /// and fsyncs them once a second, outside of the lock so that writers
+    /// don't wait on the disk.
+    pub fn spawn_flusher(aof: Arc<RwLock<AOF>>) {
+        if !aof.read().unwrap().is_enabled() || aof.read().unwrap().fsync != AppendFsync::Everysec {
+            return;
+        }
+
+        thread::spawn(move || loop {
+            thread::sleep(Duration::from_secs(1));
+            let file = {
+                let mut aof = aof.write().unwrap();
+                if aof.pending_since.is_some_and(|since| since.elapsed() > FSYNC_DELAY) {
+                    aof.delayed_fsync += 1;
+                }
+                match aof.write_buffer().and_then(|_| Ok(aof.file.as_ref().map(File::try_clone).transpose()?)) {
+                    Ok(Some(file)) => file,
+                    Ok(None) => continue,
+                    Err(e) => {
+                        eprintln!("Failed to write the AOF: {e}");
+                        continue;
+                    },
+                }
             };
+            if let Err(e) = file.sync_data() {
+                eprintln!("Failed to fsync the AOF: {e}");
+            }
+        });
+    }
+
+    /// Loads every file of the manifest in order: the snapshot a base starts
+    /// with after a rewrite, then every command through `handlers` in the
+    /// database it targets, following the `SELECT` commands recorded along
+    /// them. Commands cut short or without their checksum at the end of the
+    /// last file, as left by a crash mid-write, are cut off the file with
+    /// `aof-load-truncated`, while anything else that can't be replayed,
+    /// down to a command that panics, stops the load at its offset.
+    pub fn replay(&mut self, handlers: &mut Handlers, dbs: &Databases) -> Result<()> {
+        let Some(manifest) = &self.manifest else {
+            return Ok(());
+        };
+
+        let started = Instant::now();
+        let mut reported = started;
+        let mut replayed: u64 = 0;
+        let count = manifest.parts().count();
+        for (i, part) in manifest.parts().enumerate() {
+            let data = match std::fs::read(manifest.path_of(part)) {
+                Ok(data) => data,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                    return Err(new_error(&format!("{} is in the AOF manifest but doesn't exist", part.file)));
+                },
+                Err(e) => return Err(e.into()),
+            };
+            eprintln!("Loading {}, {} bytes", part.file, data.len());
+
+            let start = match data.starts_with(snapshot::MAGIC) {
+                true => snapshot::read_amandadb(&data, dbs)?,
+                false => 0,
+            };
+            let mut commands = commands(&data, start);
+            for command in &mut commands {
+                let (offset, value) = match command {
+                    Ok(command) => command,
+                    Err(Damage::Truncated(offset)) if i + 1 == count && self.load_truncated => {
+                        eprintln!("{} {}, discarding the last {} bytes", part.file, Damage::Truncated(offset), data.len() - offset);
+                        if let Some(file) = &self.file {
+                            file.set_len(offset as u64)?;
+                        }
+                        self.size -= (data.len() - offset) as u64;
+                        break;
+                    },
+                    Err(damage) => {
+                        return Err(new_error(&format!("{} {damage}, amandadb-check-aof can check and repair the AOF", part.file)));
+                    },
+                };
+
+                match select_index(&value) {
+                    Some(index) => self.selected = index,
+                    None => {
+                        let replay = AssertUnwindSafe(|| handlers.replay(self.selected, value, dbs));
+                        panic::catch_unwind(replay).unwrap_or(Err("ERR: The command panicked")).map_err(|err| {
+                            new_error(&format!("{} has a command that can't be replayed at offset {offset}: {err}", part.file))
+                        })?
+                    },
+                };
+                replayed += 1;
+                if reported.elapsed() >= PROGRESS_INTERVAL {
+                    eprintln!("Replayed {replayed} commands, at offset {offset} of {}", part.file);
+                    reported = Instant::now();
+                }
+            }
+            // New commands carry on from the last segment of the last file.
+            self.segment = commands.segment;
+        }
+        eprintln!("Loaded the AOF in {:.3} seconds, {replayed} commands replayed", started.elapsed().as_secs_f64());
+        Ok(())
+    }
+
+    pub fn write(&mut self, db: usize, value: Value) -> Result<()> {
+        self.append(db, value);
+        self.sync()
+    }
+
+    fn append(&mut self, db: usize, value: Value) {
+        if self.file.is_none() {
+            return;
+        }
+        if db != self.selected {
+            self.push(&select_command(db));
+            self.selected = db;
         }
+        self.push(&value.marshal());
+        if self.segment.len >= SEGMENT_SIZE {
+            self.close_segment();
+        }
+        self.pending_since.get_or_insert_with(Instant::now);
+    }
+
+    fn push(&mut self, bytes: &[u8]) {
+        self.buffer.extend(bytes);
+        self.size += bytes.len() as u64;
+        self.segment.update(bytes);
+    }
+
+    fn close_segment(&mut self) {
+        let record = self.segment.close();
+        self.buffer.extend(&record);
+        self.size += record.len() as u64;
+    }
+
+    /// Hands the buffer to the file as `appendfsync` says, leaving it to the
+    /// flusher with `everysec`.
+    fn sync(&mut self) -> Result<()> {
+        match self.fsync {
+            AppendFsync::Always => {
+                self.write_buffer()?;
+                if let Some(file) = &self.file {
+                    file.sync_data()?;
+                }
+            },
+            AppendFsync::No => self.write_buffer()?,
+            AppendFsync::Everysec => (),
+        };
         Ok(())
     }
 
-    pub fn write(&mut self, value: Value) -> Result<()> {
-        self.file.write_all(&value.marshal())?;
-        self.file.sync_all()?;
+    /// Writes the buffered commands out, ended by the checksum of their
+    /// segment so that no command on disk goes without one.
+    fn write_buffer(&mut self) -> Result<()> {
+        if self.file.is_some() && self.segment.len > 0 {
+            self.close_segment();
+        }
+        if let (Some(file), false) = (&mut self.file, self.buffer.is_empty()) {
+            file.write_all(&self.buffer)?;
+            self.buffer.clear();
+        }
+        self.pending_since = None;
         Ok(())
     }
 
-    pub fn enqueue(&mut self, value: Value) {
-        self.insert_queue.push(value);
+    pub fn enqueue(&mut self, db: usize, value: Value) {
+        self.insert_queue.push((db, value));
+    }
+    /// Drops the commands of a transaction that was reverted.
+    pub fn discard_queued(&mut self) {
+        self.insert_queue.clear();
     }
     pub fn write_queued(&mut self) -> Result<()> {
-        for value in self.insert_queue.clone() {
-            self.write(value)?;
+        for (db, value) in std::mem::take(&mut self.insert_queue) {
+            self.append(db, value);
         }
-        self.insert_queue.clear();
-        Ok(())
+        self.sync()
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.file.is_some()
+    }
+
+    pub fn existed(&self) -> bool {
+        self.existed
+    }
+
+    /// The lines of the persistence section of INFO.
+    pub fn info(&self) -> Result<String> {
+        Ok([
+            format!("aof_enabled:{}", self.is_enabled() as u8),
+            format!("aof_rewrite_in_progress:{}", self.rewriting as u8),
+            format!("aof_rewrites:{}", self.rewrites),
+            format!("aof_last_bgrewrite_status:{}", if self.last_rewrite_ok { "ok" } else { "err" }),
+            format!("aof_current_size:{}", self.size),
+            format!("aof_base_size:{}", self.base_size),
+            format!("aof_buffer_length:{}", self.buffer.len()),
+            format!("aof_delayed_fsync:{}", self.delayed_fsync),
+        ].join("\r\n"))
+    }
+}
+
+/// Closing the AOF writes out what's still buffered with its checksum.
+impl Drop for AOF {
+    fn drop(&mut self) {
+        let result = self.write_buffer().and_then(|_| Ok(self.file.as_ref().map(File::sync_data).transpose()?));
+        if let Err(e) = result {
+            eprintln!("Failed to write the AOF: {e}");
+        }
+    }
+}
+
+/// Writes every database as a binary snapshot with `aof-use-rdb-preamble`,
+/// otherwise as the shortest commands that rebuild them, falling back to
+/// [`RESTORE_OPCODE`] for the values no plain command recreates.
+fn write_snapshot(path: &Path, snapshot: &[Vec<HashMap<String, Data>>], preamble: bool, compress: bool) -> Result<File> {
+    let file = File::options().read(true).write(true).create(true).truncate(true).open(path)?;
+    let mut writer = BufWriter::new(file);
+    match preamble {
+        true => snapshot::write_amandadb(&mut writer, snapshot, compress)?,
+        false => write_commands(&mut writer, snapshot)?,
+    };
+
+    let file = writer.into_inner().map_err(|e| e.into_error())?;
+    file.sync_data()?;
+    Ok(file)
+}
+
+fn write_commands(writer: &mut impl Write, snapshot: &[Vec<HashMap<String, Data>>]) -> Result<()> {
+    let mut segment = Segment::default();
+    for (index, shards) in snapshot.iter().enumerate() {
+        if shards.iter().all(HashMap::is_empty) {
+            continue;
+        }
+        write_segmented(writer, &mut segment, &select_command(index))?;
+        for (key, data) in shards.iter().flatten() {
+            for command in rebuild_commands(key, data) {
+                let command = Value::Array(command.into_iter().map(Value::BulkBytes).collect()).marshal();
+                write_segmented(writer, &mut segment, &command)?;
+            }
+        }
+    }
+    if segment.len > 0 {
+        writer.write_all(&segment.close())?;
+    }
+    Ok(())
+}
+
+/// Writes a command, ending the segment with its checksum once it's long
+/// enough.
+fn write_segmented(writer: &mut impl Write, segment: &mut Segment, command: &[u8]) -> Result<()> {
+    writer.write_all(command)?;
+    segment.update(command);
+    if segment.len >= SEGMENT_SIZE {
+        writer.write_all(&segment.close())?;
+    }
+    Ok(())
+}
+
+/// Elements of a list or set per RPUSH or SADD of a rewrite, as in Redis, so
+/// a big one doesn't make a command too long to read back in one go.
+const REBUILD_BATCH: usize = 64;
+
+/// HSET only takes a single field, so hashes need one command per field.
+fn rebuild_commands(key: &str, data: &Data) -> Vec<Vec<Vec<u8>>> {
+    match data {
+        Data::Str(value) => vec![vec!["SET".into(), key.into(), value.clone()]],
+        Data::Hash(hash) => hash.iter()
+            .map(|(field, value)| vec!["HSET".into(), key.into(), field.clone().into(), value.clone().into()])
+            .collect(),
+        Data::Json(json) => vec![vec!["JSON.SET".into(), key.into(), "$".into(), json.serialize(&Format::default()).into()]],
+        Data::SortedSet(set) => set.iter()
+            .map(|(member, score)| vec!["ZADD".into(), key.into(), score.to_string().into(), member.into()])
+            .collect(),
+        Data::List(list) => batches("RPUSH", key, list.iter()),
+        Data::Set(set) => batches("SADD", key, set.iter()),
+        _ => {
+            let mut payload = Vec::new();
+            dump::dump(data, &mut payload);
+            vec![vec![RESTORE_OPCODE.into(), key.into(), payload]]
+        },
+    }
+}
+
+fn batches<'a>(command: &str, key: &str, elements: impl Iterator<Item = &'a String>) -> Vec<Vec<Vec<u8>>> {
+    let elements: Vec<&String> = elements.collect();
+    elements.chunks(REBUILD_BATCH)
+        .map(|chunk| [command.into(), key.into()].into_iter().chain(chunk.iter().map(|element| element.as_bytes().to_vec())).collect())
+        .collect()
+}
+
+/// Where the commands of an AOF file stop being readable.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Damage {
+    /// The last commands were cut short or miss the checksum that ends
+    /// them, as when the server dies mid-write.
+    Truncated(usize),
+    Corrupted(usize),
+    /// The segment starting there was changed after its checksum was
+    /// written.
+    Checksum(usize),
+}
+
+impl Damage {
+    pub fn offset(&self) -> usize {
+        match self {
+            Damage::Truncated(offset) | Damage::Corrupted(offset) | Damage::Checksum(offset) => *offset,
+        }
+    }
+}
+
+impl fmt::Display for Damage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Damage::Truncated(offset) => write!(f, "ends with an incomplete command at offset {offset}"),
+            Damage::Corrupted(offset) => write!(f, "has an invalid command at offset {offset}"),
+            Damage::Checksum(offset) => write!(f, "has commands that don't match their checksum from offset {offset}"),
+        }
+    }
+}
+
+/// The commands of an AOF file from `start` on, with the offset of each,
+/// ending at the first one that isn't whole or isn't a command. The
+/// checksum records along them are checked and left out, and no command is
+/// given before the checksum of its segment matched. Once a file has one,
+/// commands after its last checksum are a damaged segment too; only files
+/// with none at all, written before checksums were, are read without.
+pub struct Commands<'a> {
+    data: &'a [u8],
+    offset: usize,
+    /// Where the commands that were checked end.
+    checked: usize,
+    checksummed: bool,
+    /// The commands given since the last checksum, in a file without any.
+    segment: Segment,
+    damage: Option<Damage>,
+    damaged: bool,
+}
+
+pub fn commands(data: &[u8], start: usize) -> Commands<'_> {
+    Commands {
+        data,
+        offset: start,
+        checked: start,
+        checksummed: false,
+        segment: Segment::default(),
+        damage: None,
+        damaged: false,
+    }
+}
+
+impl Commands<'_> {
+    /// The command at `offset` and its length.
+    fn read(&self, offset: usize) -> std::result::Result<(usize, Value), Damage> {
+        let len = match frame_len(&self.data[offset..]) {
+            Ok(Some(len)) => len,
+            Ok(None) => return Err(Damage::Truncated(offset)),
+            Err(_) => return Err(Damage::Corrupted(offset)),
+        };
+        let frame = &self.data[offset..offset + len];
+        let value = match frame[0] == b'*' {
+            true => RESP::new(frame).read().ok(),
+            false => None,
+        };
+        match value {
+            Some(value) if is_command(&value) => Ok((len, value)),
+            _ => Err(Damage::Corrupted(offset)),
+        }
+    }
+
+    /// Reads ahead through the segment at `offset` up to its checksum,
+    /// moving `checked` past it when it matches.
+    fn check_segment(&mut self) {
+        let start = self.offset;
+        let mut segment = Segment::default();
+        let mut offset = start;
+        loop {
+            if offset >= self.data.len() {
+                if self.checksummed && segment.len > 0 {
+                    self.damage = Some(Damage::Truncated(start));
+                } else {
+                    self.checked = offset;
+                    self.segment = segment;
+                }
+                return;
+            }
+
+            let (len, value) = match self.read(offset) {
+                Ok(command) => command,
+                // Nothing of a segment that can't be checked is given.
+                Err(damage) if self.checksummed => {
+                    self.damage = Some(match damage {
+                        Damage::Truncated(_) => Damage::Truncated(start),
+                        _ => Damage::Checksum(start),
+                    });
+                    return;
+                },
+                Err(damage) => {
+                    self.checked = offset;
+                    self.segment = segment;
+                    self.damage = Some(damage);
+                    return;
+                },
+            };
+            match checksum(&value) {
+                Some(crc) if crc == segment.crc => {
+                    self.checked = offset + len;
+                    self.checksummed = true;
+                    self.segment = Segment::default();
+                    return;
+                },
+                Some(_) => {
+                    self.damage = Some(Damage::Checksum(start));
+                    return;
+                },
+                None => segment.update(&self.data[offset..offset + len]),
+            }
+            offset += len;
+        }
+    }
+}
+
+impl Iterator for Commands<'_> {
+    type Item = std::result::Result<(usize, Value), Damage>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if self.damaged {
+                return None;
+            }
+            let offset = self.offset;
+            if offset < self.checked {
+                let (len, value) = self.read(offset).expect("checked commands are whole");
+                self.offset += len;
+                match checksum(&value) {
+                    Some(_) => continue,
+                    None => return Some(Ok((offset, value))),
+                }
+            }
+            if let Some(damage) = self.damage {
+                self.damaged = true;
+                return Some(Err(damage));
+            }
+            if offset >= self.data.len() {
+                return None;
+            }
+            self.check_segment();
+        }
+    }
+}
+
+fn is_command(value: &Value) -> bool {
+    matches!(value, Value::Array(arr) if !arr.is_empty() && arr.iter().all(|arg| arg.bytes().is_some()))
+}
+
+fn checksum(value: &Value) -> Option<u64> {
+    match value {
+        Value::Array(arr) => match &arr[..] {
+            [Value::BulkStr(cmd), Value::BulkStr(crc)] if cmd == "CHECKSUM" => u64::from_str_radix(crc, 16).ok(),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+fn select_index(value: &Value) -> Option<usize> {
+    match value {
+        Value::Array(arr) => match &arr[..] {
+            [Value::BulkStr(cmd), Value::BulkStr(index)] if cmd.eq_ignore_ascii_case("SELECT") => index.parse().ok(),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn command(args: &[&str]) -> Vec<u8> {
+        Value::Array(args.iter().map(|arg| Value::BulkStr(arg.to_string())).collect()).marshal()
+    }
+
+    /// Two commands closed by their checksum, then one more with its own.
+    fn file() -> Vec<u8> {
+        let mut data = Vec::new();
+        let mut segment = Segment::default();
+        for args in [&["SET", "a", "1"][..], &["SELECT", "1"]] {
+            let command = command(args);
+            segment.update(&command);
+            data.extend(command);
+        }
+        data.extend(segment.close());
+        let command = command(&["HSET", "h", "f", "v"]);
+        segment.update(&command);
+        data.extend(command);
+        data.extend(segment.close());
+        data
+    }
+
+    /// Where the last command of [`file`] starts.
+    fn last(data: &[u8]) -> usize {
+        data.len() - command(&["HSET", "h", "f", "v"]).len() - Segment::default().close().len()
+    }
+
+    fn offsets(data: &[u8]) -> Vec<std::result::Result<usize, Damage>> {
+        commands(data, 0).map(|command| command.map(|(offset, _)| offset)).collect()
+    }
+
+    #[test]
+    fn reads_commands_between_checksums() {
+        let data = file();
+        let second = command(&["SET", "a", "1"]).len();
+        assert_eq!(offsets(&data), [Ok(0), Ok(second), Ok(last(&data))]);
+    }
+
+    #[test]
+    fn reads_a_file_without_checksums() {
+        let data = [command(&["SET", "a", "1"]), command(&["SET", "b", "2"])].concat();
+        assert_eq!(offsets(&data), [Ok(0), Ok(command(&["SET", "a", "1"]).len())]);
+    }
+
+    #[test]
+    fn stops_at_a_truncated_command() {
+        // Cutting off just the checksum leaves the last command unchecked,
+        // which is no better.
+        let data = file();
+        let second = command(&["SET", "a", "1"]).len();
+        let last = last(&data);
+        for len in last + 1..data.len() {
+            assert_eq!(offsets(&data[..len]), [Ok(0), Ok(second), Err(Damage::Truncated(last))], "cut at {len}");
+        }
+    }
+
+    #[test]
+    fn stops_at_a_corrupted_command() {
+        for garbage in [&b"+OK\r\n"[..], b"*1\r\n:1\r\n", b"*0\r\n", b"*x\r\n", b"$1\r\na\r\n"] {
+            let mut data = command(&["SET", "a", "1"]);
+            let second = data.len();
+            data.extend(garbage);
+            data.extend(command(&["SET", "b", "2"]));
+            assert_eq!(offsets(&data), [Ok(0), Err(Damage::Corrupted(second))], "{}", String::from_utf8_lossy(garbage));
+        }
+    }
+
+    #[test]
+    fn stops_at_a_segment_that_doesnt_match_its_checksum() {
+        let mut data = file();
+        let second = command(&["SET", "a", "1"]).len();
+        // Still a valid command, but not the one the checksum covers.
+        data[second - 3] = b'2';
+        assert_eq!(offsets(&data), [Err(Damage::Checksum(0))]);
+    }
+
+    #[test]
+    fn checks_the_last_segment_of_a_file() {
+        let mut data = file();
+        let second = command(&["SET", "a", "1"]).len();
+        let last = last(&data);
+        data[last + command(&["HSET", "h", "f", "v"]).len() - 3] = b'w';
+        assert_eq!(offsets(&data), [Ok(0), Ok(second), Err(Damage::Checksum(last))]);
+
+        // A command that breaks after a checksum can't be checked either.
+        let mut data = file();
+        data[last] = b'+';
+        assert_eq!(offsets(&data), [Ok(0), Ok(second), Err(Damage::Checksum(last))]);
+    }
+
+    /// A config keeping the AOF in a fresh directory, with `lines` added.
+    fn temp_config(name: &str, lines: &str) -> (Config, std::path::PathBuf) {
+        let dir = std::env::temp_dir().join(format!("amandadb-aof-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let conf = dir.join("amandadb.conf");
+        std::fs::write(&conf, format!(
+            "dbname={}\nappenddirname={}\n{lines}",
+            dir.join("legacy.aof").display(),
+            dir.join("appendonlydir").display(),
+        )).unwrap();
+        (Config::read_from_file(conf.to_str().unwrap()).unwrap(), dir)
+    }
+
+    /// A config with the AOF of an older version at `legacy.aof` in a fresh
+    /// directory.
+    fn legacy_config(name: &str, load_truncated: bool, legacy: &[u8]) -> (Config, std::path::PathBuf) {
+        let load_truncated = if load_truncated { "yes" } else { "no" };
+        let (config, dir) = temp_config(name, &format!("aof-load-truncated={load_truncated}\n"));
+        std::fs::write(dir.join("legacy.aof"), legacy).unwrap();
+        (config, dir)
+    }
+
+    fn value(args: &[&str]) -> Value {
+        Value::Array(args.iter().map(|arg| Value::BulkStr(arg.to_string())).collect())
+    }
+
+    #[test]
+    fn fsyncs_as_appendfsync_says() {
+        for (policy, written) in [("always", true), ("no", true), ("everysec", false)] {
+            let (config, dir) = temp_config(&format!("fsync-{policy}"), &format!("appendfsync={policy}\n"));
+            let mut aof = AOF::new(config).unwrap();
+            let path = aof.manifest.as_ref().map(|manifest| manifest.path_of(manifest.incrs.last().unwrap())).unwrap();
+            let before = std::fs::metadata(&path).unwrap().len();
+
+            aof.write(0, value(&["SET", "a", "1"])).unwrap();
+            let grown = std::fs::metadata(&path).unwrap().len() > before;
+            assert_eq!((grown, aof.buffer.is_empty()), (written, written), "{policy}");
+            // Only `everysec` has the flusher write what's left.
+            assert_eq!(aof.pending_since.is_some(), !written, "{policy}");
+            drop(aof);
+            assert!(std::fs::metadata(&path).unwrap().len() > before, "{policy}");
+            std::fs::remove_dir_all(dir).unwrap();
+        }
+
+        let (_, dir) = temp_config("fsync-wrong", "");
+        let conf = dir.join("wrong.conf");
+        std::fs::write(&conf, "appendfsync=sometimes\n").unwrap();
+        assert!(Config::read_from_file(conf.to_str().unwrap()).is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn cuts_a_truncated_legacy_aof_before_it_becomes_the_base() {
+        let set = command(&["SET", "a", "1"]);
+        let cut = [set.clone(), set[..set.len() - 2].to_vec()].concat();
+
+        let (config, dir) = legacy_config("legacy-kept", false, &cut);
+        let e = open_manifest(&config).err().unwrap().to_string();
+        assert!(e.contains("amandadb-check-aof"), "{e}");
+        assert_eq!(std::fs::read(dir.join("legacy.aof")).unwrap(), cut);
+        std::fs::remove_dir_all(dir).unwrap();
+
+        let (config, dir) = legacy_config("legacy-cut", true, &cut);
+        let (manifest, existed) = open_manifest(&config).unwrap();
+        assert!(existed && !dir.join("legacy.aof").exists());
+        let base = manifest.base.as_ref().unwrap();
+        assert_eq!(std::fs::read(manifest.path_of(base)).unwrap(), set);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    fn databases() -> Databases {
+        Arc::new((0..2).map(|_| Arc::new(Database::new())).collect())
+    }
+
+    /// Loads the AOF of `config` into new databases the way the server does
+    /// on startup.
+    fn load(config: Config) -> (AOF, Databases) {
+        let mut aof = AOF::new(config).unwrap();
+        assert!(aof.existed());
+        let dbs = databases();
+        let mut handlers = Handlers::new();
+        handlers.init();
+        aof.replay(&mut handlers, &dbs).unwrap();
+        (aof, dbs)
+    }
+
+    fn get(dbs: &Databases, index: usize, key: &str) -> String {
+        String::from_utf8(dbs[index].read([key]).set_get(key).marshal()).unwrap()
+    }
+
+    #[test]
+    fn loads_a_preamble_base_and_the_commands_after_it() {
+        let (config, dir) = temp_config("preamble", "aof-use-rdb-preamble=yes\n");
+        let dbs = databases();
+        dbs[0].write(["a"]).set_push("a".into(), b"1".to_vec());
+        dbs[1].write(["h"]).hset_push("h".into(), "f".into(), "v".into());
+        let aof = Arc::new(RwLock::new(AOF::new(config.clone()).unwrap()));
+        AOF::rewrite(&aof, &dbs).unwrap();
+        while aof.read().unwrap().rewriting {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(aof.read().unwrap().last_rewrite_ok);
+        {
+            let mut aof = aof.write().unwrap();
+            aof.write(1, value(&["HSET", "h", "g", "w"])).unwrap();
+            aof.write(0, value(&["SET", "a", "2"])).unwrap();
+        }
+        drop(aof);
+
+        let (aof, dbs) = load(config);
+        let manifest = aof.manifest.as_ref().unwrap();
+        let base = std::fs::read(manifest.path_of(manifest.base.as_ref().unwrap())).unwrap();
+        assert!(base.starts_with(snapshot::MAGIC));
+        assert_eq!(manifest.incrs.len(), 1);
+        assert_eq!(get(&dbs, 0, "a"), "$1\r\n2\r\n");
+        assert_eq!(String::from_utf8(dbs[1].read(["h"]).hset_len("h").marshal()).unwrap(), ":2\r\n");
+        assert!(!dbs[1].read_all().contains("a") && !dbs[0].read_all().contains("h"));
+        drop(aof);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn replays_each_command_in_the_database_selected_before_it() {
+        let (config, dir) = temp_config("replay", "appendfsync=always\n");
+        let mut aof = AOF::new(config.clone()).unwrap();
+        for (index, args) in [(0, &["SET", "a", "0"][..]), (1, &["SET", "a", "1"]), (1, &["RPUSH", "l", "x"]),
+            (0, &["INCR", "a"]), (1, &["SET", "b", "1"])] {
+            aof.write(index, value(args)).unwrap();
+        }
+        drop(aof);
+
+        let (aof, dbs) = load(config);
+        assert_eq!((get(&dbs, 0, "a"), get(&dbs, 1, "a"), get(&dbs, 1, "b")),
+            ("$1\r\n1\r\n".to_string(), "$1\r\n1\r\n".to_string(), "$1\r\n1\r\n".to_string()));
+        assert!(dbs[1].read_all().contains("l") && !dbs[0].read_all().contains("l"));
+        // Replayed commands aren't logged again, and new ones follow on from
+        // the last database selected.
+        let manifest = aof.manifest.as_ref().unwrap();
+        let path = manifest.path_of(manifest.incrs.last().unwrap());
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), aof.size);
+        assert_eq!(aof.selected, 1);
+        drop(aof);
+
+        let mut handlers = Handlers::new();
+        handlers.init();
+        assert_eq!(handlers.replay(0, value(&["GET", "a"]), &dbs), Err("ERR: Only write commands can be replayed"));
+        assert_eq!(handlers.replay(2, value(&["SET", "a", "2"]), &dbs), Err("ERR: DB index is out of range"));
+        assert_eq!(get(&dbs, 0, "a"), "$1\r\n1\r\n");
+        std::fs::remove_dir_all(dir).unwrap();
     }
 }
@@ -0,0 +1,100 @@
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+
+use amandadb::aof;
+use amandadb::error::{new_error, Result};
+use amandadb::manifest::Manifest;
+use amandadb::snapshot;
+
+const USAGE: &str = "Usage: amandadb-check-aof [--fix] <file.manifest|file.aof>";
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let (path, fix) = match &args[..] {
+        [path] if path != "--fix" => (path, false),
+        [flag, path] | [path, flag] if flag == "--fix" && path != "--fix" => (path, true),
+        _ => {
+            eprintln!("{USAGE}");
+            return ExitCode::FAILURE;
+        },
+    };
+
+    match check(Path::new(path), fix) {
+        Ok(true) => ExitCode::SUCCESS,
+        Ok(false) => ExitCode::FAILURE,
+        Err(e) => {
+            eprintln!("Failed to check {path}: {e}");
+            ExitCode::FAILURE
+        },
+    }
+}
+
+/// Checks every file listed by a manifest in order, or a single file for an
+/// AOF from before the manifests.
+fn check(path: &Path, fix: bool) -> Result<bool> {
+    let name = path.file_name().and_then(|name| name.to_str()).and_then(|name| name.strip_suffix(".manifest"));
+    let files: Vec<PathBuf> = match name {
+        Some(name) => {
+            let dir = path.parent().unwrap_or(Path::new(""));
+            let Some(manifest) = Manifest::load(dir, name)? else {
+                return Err(new_error("The manifest doesn't exist"));
+            };
+            manifest.parts().map(|part| manifest.path_of(part)).collect()
+        },
+        None => vec![path.into()],
+    };
+
+    for (i, file) in files.iter().enumerate() {
+        if !check_file(file, i + 1 == files.len(), fix)? {
+            return Ok(false);
+        }
+    }
+    println!("The AOF is valid");
+    Ok(true)
+}
+
+/// Only the last file can be cut short: the files after any other one
+/// carry on from its last command.
+fn check_file(path: &Path, last: bool, fix: bool) -> Result<bool> {
+    let data = std::fs::read(path)?;
+    let start = match data.starts_with(snapshot::MAGIC) {
+        true => match snapshot::check_amandadb(&data) {
+            Ok(start) => start,
+            Err(e) => {
+                println!("{}: the snapshot it starts with is invalid: {e}", path.display());
+                return Ok(false);
+            },
+        },
+        false => 0,
+    };
+
+    let (mut commands, mut damage) = (0, None);
+    for command in aof::commands(&data, start) {
+        match command {
+            Ok(_) => commands += 1,
+            Err(e) => damage = Some(e),
+        };
+    }
+    let Some(damage) = damage else {
+        println!("{}: {} bytes, {commands} commands{}", path.display(), data.len(), if start > 0 { " after a snapshot" } else { "" });
+        return Ok(true);
+    };
+
+    let offset = damage.offset();
+    println!("{}: {damage}, {offset} of {} bytes are valid", path.display(), data.len());
+    if !last {
+        println!("Only the last file of the AOF can be fixed");
+        return Ok(false);
+    }
+    if !fix {
+        println!("Run with --fix to cut the file at offset {offset}, discarding the {} bytes after it", data.len() - offset);
+        return Ok(false);
+    }
+
+    let file = File::options().write(true).open(path)?;
+    file.set_len(offset as u64)?;
+    file.sync_all()?;
+    println!("Cut {} bytes off {}", data.len() - offset, path.display());
+    Ok(true)
+}
@@ -0,0 +1,228 @@
+use crate::dump::{put_u64, Reader};
+use crate::hash::murmurhash64a;
+
+pub const DEFAULT_ERROR_RATE: f64 = 0.01;
+pub const DEFAULT_CAPACITY: u64 = 100;
+pub const DEFAULT_EXPANSION: u64 = 2;
+pub const MAX_CAPACITY: u64 = 1 << 30;
+pub const MAX_EXPANSION: u64 = 32768;
+
+/// Largest layer in bytes, the same as Redis' limit on a single string.
+const MAX_LAYER_SIZE: u64 = 512 * 1024 * 1024;
+
+/// Hashes a layer takes for the smallest error rate an f64 holds, 2^-1074.
+const MAX_HASHES: u32 = 1075;
+
+const TIGHTENING_RATIO: f64 = 0.5;
+
+const INVALID: &str = "ERR: Invalid serialized bloom filter";
+
+#[derive(Clone)]
+struct Layer {
+    bits: Vec<u64>,
+    nbits: u64,
+    hashes: u32,
+    capacity: u64,
+    items: u64,
+}
+
+impl Layer {
+    /// Returns `None` when the layer would take more than `MAX_LAYER_SIZE`.
+    fn new(capacity: u64, error_rate: f64) -> Option<Self> {
+        let ln2 = std::f64::consts::LN_2;
+        let bits_per_entry = -error_rate.ln() / (ln2 * ln2);
+        let nbits = (capacity as f64 * bits_per_entry).ceil().max(64.0);
+        if nbits > (MAX_LAYER_SIZE * 8) as f64 {
+            return None;
+        }
+        let nbits = nbits as u64;
+        let hashes = (ln2 * bits_per_entry).ceil() as u32;
+
+        Some(Self {
+            bits: vec![0; nbits.div_ceil(64) as usize],
+            nbits,
+            hashes,
+            capacity,
+            items: 0,
+        })
+    }
+
+    fn positions(&self, hash: (u64, u64)) -> impl Iterator<Item = u64> + '_ {
+        let (h1, h2) = hash;
+        (0..self.hashes as u64).map(move |i| h1.wrapping_add(i.wrapping_mul(h2)) % self.nbits)
+    }
+
+    fn contains(&self, hash: (u64, u64)) -> bool {
+        self.positions(hash).all(|bit| self.bits[(bit / 64) as usize] & (1 << (bit % 64)) != 0)
+    }
+
+    fn insert(&mut self, hash: (u64, u64)) {
+        let positions: Vec<u64> = self.positions(hash).collect();
+        for bit in positions {
+            self.bits[(bit / 64) as usize] |= 1 << (bit % 64);
+        }
+        self.items += 1;
+    }
+}
+
+/// Scalable bloom filter: once the newest layer reaches its capacity another
+/// one is stacked on top, bigger by `expansion` and with a tighter error rate.
+#[derive(Clone)]
+pub struct BloomFilter {
+    layers: Vec<Layer>,
+    error_rate: f64,
+    expansion: u64,
+}
+
+impl BloomFilter {
+    /// An `expansion` of zero makes the filter non scaling.
+    pub fn new(error_rate: f64, capacity: u64, expansion: u64) -> Result<Self, &'static str> {
+        let Some(layer) = Layer::new(capacity, error_rate) else {
+            return Err("ERR: Capacity is too large for the error rate");
+        };
+        Ok(Self {
+            layers: vec![layer],
+            error_rate,
+            expansion,
+        })
+    }
+
+    fn hash(item: &[u8]) -> (u64, u64) {
+        let h1 = murmurhash64a(item, 0xc6a4_a793_5bd1_e995);
+        let h2 = murmurhash64a(item, h1);
+        (h1, h2 | 1)
+    }
+
+    pub fn exists(&self, item: &[u8]) -> bool {
+        let hash = Self::hash(item);
+        self.layers.iter().any(|layer| layer.contains(hash))
+    }
+
+    /// Returns `Ok(false)` when the item may already be in the filter.
+    pub fn add(&mut self, item: &[u8]) -> Result<bool, &'static str> {
+        let hash = Self::hash(item);
+        if self.layers.iter().any(|layer| layer.contains(hash)) {
+            return Ok(false);
+        }
+
+        let last = self.layers.last().unwrap();
+        if last.items >= last.capacity {
+            if self.expansion == 0 {
+                return Err("ERR: Non scaling filter is full");
+            }
+            let error_rate = self.error_rate * TIGHTENING_RATIO.powi(self.layers.len() as i32);
+            let layer = last.capacity.checked_mul(self.expansion)
+                .and_then(|capacity| Layer::new(capacity, error_rate));
+            let Some(layer) = layer else {
+                return Err("ERR: Filter is full and can't grow any larger");
+            };
+            self.layers.push(layer);
+        }
+
+        self.layers.last_mut().unwrap().insert(hash);
+        Ok(true)
+    }
+
+    pub fn capacity(&self) -> u64 {
+        self.layers.iter().map(|layer| layer.capacity).sum()
+    }
+
+    pub fn size(&self) -> u64 {
+        self.layers.iter().map(|layer| layer.bits.len() as u64 * 8).sum()
+    }
+
+    pub fn filters(&self) -> u64 {
+        self.layers.len() as u64
+    }
+
+    pub fn items(&self) -> u64 {
+        self.layers.iter().map(|layer| layer.items).sum()
+    }
+
+    pub fn expansion(&self) -> u64 {
+        self.expansion
+    }
+
+    pub fn dump(&self, out: &mut Vec<u8>) {
+        out.extend(self.error_rate.to_bits().to_le_bytes());
+        put_u64(out, self.expansion);
+        put_u64(out, self.layers.len() as u64);
+        for layer in &self.layers {
+            put_u64(out, layer.nbits);
+            out.extend(layer.hashes.to_le_bytes());
+            put_u64(out, layer.capacity);
+            put_u64(out, layer.items);
+            for word in &layer.bits {
+                put_u64(out, *word);
+            }
+        }
+    }
+
+    /// Checks every field against what `new` and `add` can build, as
+    /// lookups loop over the hashes and the totals are summed up.
+    pub fn restore(reader: &mut Reader) -> Result<Self, &'static str> {
+        let error_rate = reader.f64()?;
+        let expansion = reader.u64()?;
+        if !(error_rate > 0.0 && error_rate < 1.0) || expansion > MAX_EXPANSION {
+            return Err(INVALID);
+        }
+
+        let mut layers = Vec::new();
+        let mut total: u64 = 0;
+        for _ in 0..reader.u64()? {
+            let nbits = reader.u64()?;
+            let hashes = reader.u32()?;
+            let capacity = reader.u64()?;
+            let items = reader.u64()?;
+            total = total.checked_add(capacity).ok_or(INVALID)?;
+            if nbits == 0 || nbits > MAX_LAYER_SIZE * 8 || hashes == 0 || hashes > MAX_HASHES || items > capacity {
+                return Err(INVALID);
+            }
+            let bits = (0..nbits.div_ceil(64)).map(|_| reader.u64()).collect::<Result<_, _>>()?;
+            layers.push(Layer { bits, nbits, hashes, capacity, items });
+        }
+        if layers.is_empty() {
+            return Err(INVALID);
+        }
+        Ok(Self { layers, error_rate, expansion })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn refuses_layers_over_the_size_limit() {
+        assert!(BloomFilter::new(0.01, MAX_CAPACITY, DEFAULT_EXPANSION).is_err());
+        assert!(BloomFilter::new(1e-300, 1 << 24, DEFAULT_EXPANSION).is_err());
+    }
+
+    #[test]
+    fn stops_growing_at_the_size_limit() {
+        let mut filter = BloomFilter::new(0.01, 1 << 20, MAX_EXPANSION).unwrap();
+        filter.layers[0].items = filter.layers[0].capacity;
+        assert!(filter.add(b"item").is_err());
+        assert_eq!(filter.filters(), 1);
+    }
+
+    #[test]
+    fn restores_only_what_it_could_have_built() {
+        let mut filter = BloomFilter::new(0.01, 100, DEFAULT_EXPANSION).unwrap();
+        filter.add(b"item").unwrap();
+        let mut payload = Vec::new();
+        filter.dump(&mut payload);
+        let restored = BloomFilter::restore(&mut Reader::new(&payload)).unwrap();
+        assert!(restored.exists(b"item"));
+
+        // The hashes of the first layer follow the error rate, the expansion,
+        // the layer count and its number of bits.
+        let mut hashes = payload.clone();
+        hashes[32..36].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(BloomFilter::restore(&mut Reader::new(&hashes)).is_err());
+
+        let mut rate = payload.clone();
+        rate[..8].copy_from_slice(&f64::NAN.to_bits().to_le_bytes());
+        assert!(BloomFilter::restore(&mut Reader::new(&rate)).is_err());
+    }
+}
@@ -1,10 +1,118 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
 use crate::error::{new_error, Result};
 
+/// An address to listen on, which may be skipped when it can't be bound.
+#[derive(Clone)]
+pub struct BindAddress {
+    pub ip: IpAddr,
+    pub optional: bool,
+}
+
+impl BindAddress {
+    /// Parses the `bind` syntax of Redis, where `*` and `::*` stand for every
+    /// IPv4 and IPv6 interface and a leading `-` marks the address optional.
+    fn parse(address: &str) -> Result<Self> {
+        let (optional, address) = match address.strip_prefix('-') {
+            Some(address) => (true, address),
+            None => (false, address),
+        };
+        let ip = match address {
+            "*" => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            "::*" => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+            _ => address.parse().map_err(|_| new_error("Invalid bind address"))?,
+        };
+        Ok(Self { ip, optional })
+    }
+}
+
+fn parse_bool(value: &str) -> Result<bool> {
+    match value {
+        "yes" => Ok(true),
+        "no" => Ok(false),
+        _ => Err(new_error("Value must be either yes or no")),
+    }
+}
+
+/// Whether TLS clients must present a certificate signed by the CA.
+#[derive(Clone, Copy, PartialEq)]
+pub enum TlsAuthClients {
+    Yes,
+    No,
+    Optional,
+}
+
+/// Parses sizes like `64mb`, with the units of Redis config files.
+fn parse_size(value: &str) -> Result<u64> {
+    let value = value.to_lowercase();
+    let digits = value.find(|c: char| !c.is_ascii_digit()).unwrap_or(value.len());
+    let multiplier = match &value[digits..] {
+        "" | "b" => 1,
+        "k" => 1000,
+        "kb" => 1024,
+        "m" => 1000 * 1000,
+        "mb" => 1024 * 1024,
+        "g" => 1000 * 1000 * 1000,
+        "gb" => 1024 * 1024 * 1024,
+        _ => return Err(new_error("Invalid size unit")),
+    };
+    Ok(value[..digits].parse::<u64>()? * multiplier)
+}
+
+/// Parses `save` rules, pairs of seconds and changes like `3600 1 300 100`.
+fn parse_save(value: &str) -> Result<Vec<(u64, u64)>> {
+    let numbers = value.split_whitespace().map(str::parse).collect::<std::result::Result<Vec<u64>, _>>()?;
+    if numbers.len() % 2 != 0 {
+        return Err(new_error("save rules must be pairs of seconds and changes"));
+    }
+    Ok(numbers.chunks(2).map(|pair| (pair[0], pair[1])).collect())
+}
+
+/// What `SAVE` and `BGSAVE` write, the format of AmandaDB or one Redis
+/// can load.
+#[derive(Clone, Copy, PartialEq)]
+pub enum SnapshotFormat {
+    AmandaDb,
+    Rdb,
+}
+
+/// When the AOF gets flushed to disk.
+#[derive(Clone, Copy, PartialEq)]
+pub enum AppendFsync {
+    Always,
+    Everysec,
+    No,
+}
+
 #[derive(Clone)]
 pub struct Config {
     dbname: String,
     port: u16,
     threads: usize,
+    databases: usize,
+    bind: Vec<BindAddress>,
+    protected_mode: bool,
+    unixsocket: Option<String>,
+    unixsocketperm: Option<u32>,
+    tls_port: Option<u16>,
+    tls_cert_file: Option<String>,
+    tls_key_file: Option<String>,
+    tls_ca_cert_file: Option<String>,
+    tls_auth_clients: TlsAuthClients,
+    requirepass: Option<String>,
+    aclfile: Option<String>,
+    appendfsync: AppendFsync,
+    auto_aof_rewrite_percentage: u64,
+    auto_aof_rewrite_min_size: u64,
+    appendonly: bool,
+    dbfilename: String,
+    save: Vec<(u64, u64)>,
+    snapshot_format: SnapshotFormat,
+    aof_use_rdb_preamble: bool,
+    appenddirname: String,
+    aof_load_truncated: bool,
+    rdbcompression: bool,
+    rdb_skip_unsupported: bool,
 }
 
 impl Default for Config {
@@ -13,6 +121,33 @@ impl Default for Config {
             dbname: "database.aof".into(),
             port: 6379,
             threads: 4,
+            databases: 16,
+            bind: vec![
+                BindAddress { ip: IpAddr::V4(Ipv4Addr::LOCALHOST), optional: false },
+                BindAddress { ip: IpAddr::V6(Ipv6Addr::LOCALHOST), optional: true },
+            ],
+            protected_mode: true,
+            unixsocket: None,
+            unixsocketperm: None,
+            tls_port: None,
+            tls_cert_file: None,
+            tls_key_file: None,
+            tls_ca_cert_file: None,
+            tls_auth_clients: TlsAuthClients::Yes,
+            requirepass: None,
+            aclfile: None,
+            appendfsync: AppendFsync::Everysec,
+            auto_aof_rewrite_percentage: 100,
+            auto_aof_rewrite_min_size: 64 * 1024 * 1024,
+            appendonly: true,
+            dbfilename: "dump.adb".into(),
+            save: Vec::new(),
+            snapshot_format: SnapshotFormat::AmandaDb,
+            aof_use_rdb_preamble: true,
+            appenddirname: "appendonlydir".into(),
+            aof_load_truncated: true,
+            rdbcompression: true,
+            rdb_skip_unsupported: false,
         }
     }
 }
@@ -23,15 +158,72 @@ impl Config {
 
         let contents = std::fs::read_to_string(path)?;
         for line in contents.lines() {
-            let kv: Vec<&str> = line.split('=').collect();
+            let kv: Vec<&str> = line.splitn(2, '=').collect();
             match (kv[0].trim(), kv[1].trim()) {
                 ("dbname", v) => config.dbname = v.into(),
                 ("port", v) => config.port = v.parse()?,
                 ("threads", v) => config.threads = v.parse()?,
+                ("databases", v) => match v.parse()? {
+                    0 => return Err(new_error("There must be at least one database")),
+                    n => config.databases = n,
+                },
+                ("bind", v) => {
+                    config.bind = v.split_whitespace().map(BindAddress::parse).collect::<Result<_>>()?;
+                    if config.bind.is_empty() {
+                        return Err(new_error("At least one bind address is needed"));
+                    }
+                },
+                ("protected-mode", v) => config.protected_mode = parse_bool(v)?,
+                ("unixsocket", v) => config.unixsocket = Some(v.into()),
+                ("unixsocketperm", v) => config.unixsocketperm = Some(u32::from_str_radix(v, 8)?),
+                ("tls-port", v) => config.tls_port = Some(v.parse()?).filter(|&port| port != 0),
+                ("tls-cert-file", v) => config.tls_cert_file = Some(v.into()),
+                ("tls-key-file", v) => config.tls_key_file = Some(v.into()),
+                ("tls-ca-cert-file", v) => config.tls_ca_cert_file = Some(v.into()),
+                ("tls-auth-clients", v) => config.tls_auth_clients = match v {
+                    "optional" => TlsAuthClients::Optional,
+                    _ => match parse_bool(v)? {
+                        true => TlsAuthClients::Yes,
+                        false => TlsAuthClients::No,
+                    },
+                },
+                ("requirepass", "") => config.requirepass = None,
+                ("requirepass", v) => config.requirepass = Some(v.into()),
+                ("aclfile", v) => config.aclfile = Some(v.into()),
+                ("appendfsync", v) => config.appendfsync = match v {
+                    "always" => AppendFsync::Always,
+                    "everysec" => AppendFsync::Everysec,
+                    "no" => AppendFsync::No,
+                    _ => return Err(new_error("appendfsync must be always, everysec or no")),
+                },
+                ("auto-aof-rewrite-percentage", v) => config.auto_aof_rewrite_percentage = v.parse()?,
+                ("auto-aof-rewrite-min-size", v) => config.auto_aof_rewrite_min_size = parse_size(v)?,
+                ("aof-use-rdb-preamble", v) => config.aof_use_rdb_preamble = parse_bool(v)?,
+                ("appenddirname", v) => config.appenddirname = v.into(),
+                ("aof-load-truncated", v) => config.aof_load_truncated = parse_bool(v)?,
+                ("rdbcompression", v) => config.rdbcompression = parse_bool(v)?,
+                ("rdb-skip-unsupported", v) => config.rdb_skip_unsupported = parse_bool(v)?,
+                ("appendonly", v) => config.appendonly = parse_bool(v)?,
+                ("dbfilename", v) => config.dbfilename = v.into(),
+                ("save", v) => config.save = parse_save(v.trim_matches('"'))?,
+                ("snapshot-format", v) => config.snapshot_format = match v {
+                    "amandadb" => SnapshotFormat::AmandaDb,
+                    "rdb" => SnapshotFormat::Rdb,
+                    _ => return Err(new_error("snapshot-format must be amandadb or rdb")),
+                },
                 _ => return Err(new_error("Field does not exist for config")),
             };
         }
 
+        if config.tls_port.is_some() {
+            if config.tls_cert_file.is_none() || config.tls_key_file.is_none() {
+                return Err(new_error("tls-port needs both tls-cert-file and tls-key-file"));
+            }
+            if config.tls_auth_clients != TlsAuthClients::No && config.tls_ca_cert_file.is_none() {
+                return Err(new_error("tls-ca-cert-file is needed to verify clients, or set tls-auth-clients=no"));
+            }
+        }
+
         Ok(config)
     }
     pub fn dbname(&self) -> &str {
@@ -45,4 +237,104 @@ impl Config {
     pub fn threads(&self) -> usize {
         self.threads
     }
+
+    pub fn databases(&self) -> usize {
+        self.databases
+    }
+
+    pub fn bind(&self) -> &[BindAddress] {
+        &self.bind
+    }
+
+    pub fn protected_mode(&self) -> bool {
+        self.protected_mode
+    }
+
+    pub fn unixsocket(&self) -> Option<&str> {
+        self.unixsocket.as_deref()
+    }
+
+    pub fn unixsocketperm(&self) -> Option<u32> {
+        self.unixsocketperm
+    }
+
+    pub fn tls_port(&self) -> Option<u16> {
+        self.tls_port
+    }
+
+    #[cfg(feature = "tls")]
+    pub fn tls_cert_file(&self) -> Option<&str> {
+        self.tls_cert_file.as_deref()
+    }
+
+    #[cfg(feature = "tls")]
+    pub fn tls_key_file(&self) -> Option<&str> {
+        self.tls_key_file.as_deref()
+    }
+
+    #[cfg(feature = "tls")]
+    pub fn tls_ca_cert_file(&self) -> Option<&str> {
+        self.tls_ca_cert_file.as_deref()
+    }
+
+    #[cfg(feature = "tls")]
+    pub fn tls_auth_clients(&self) -> TlsAuthClients {
+        self.tls_auth_clients
+    }
+
+    pub fn requirepass(&self) -> Option<&str> {
+        self.requirepass.as_deref()
+    }
+
+    pub fn aclfile(&self) -> Option<&str> {
+        self.aclfile.as_deref()
+    }
+
+    pub fn appendfsync(&self) -> AppendFsync {
+        self.appendfsync
+    }
+
+    pub fn auto_aof_rewrite_percentage(&self) -> u64 {
+        self.auto_aof_rewrite_percentage
+    }
+
+    pub fn auto_aof_rewrite_min_size(&self) -> u64 {
+        self.auto_aof_rewrite_min_size
+    }
+
+    pub fn appendonly(&self) -> bool {
+        self.appendonly
+    }
+
+    pub fn dbfilename(&self) -> &str {
+        &self.dbfilename
+    }
+
+    pub fn save(&self) -> &[(u64, u64)] {
+        &self.save
+    }
+
+    pub fn snapshot_format(&self) -> SnapshotFormat {
+        self.snapshot_format
+    }
+
+    pub fn aof_use_rdb_preamble(&self) -> bool {
+        self.aof_use_rdb_preamble
+    }
+
+    pub fn appenddirname(&self) -> &str {
+        &self.appenddirname
+    }
+
+    pub fn aof_load_truncated(&self) -> bool {
+        self.aof_load_truncated
+    }
+
+    pub fn rdbcompression(&self) -> bool {
+        self.rdbcompression
+    }
+
+    pub fn rdb_skip_unsupported(&self) -> bool {
+        self.rdb_skip_unsupported
+    }
 }
@@ -0,0 +1,312 @@
+use crate::dump::{put_bytes, put_u64, Reader};
+use crate::hash::murmurhash64a;
+
+pub const DEFAULT_CAPACITY: u64 = 1080;
+pub const DEFAULT_BUCKET_SIZE: u64 = 2;
+pub const DEFAULT_MAX_ITERATIONS: u64 = 20;
+pub const DEFAULT_EXPANSION: u64 = 1;
+pub const MAX_CAPACITY: u64 = 1 << 32;
+pub const MAX_BUCKET_SIZE: u64 = 255;
+pub const MAX_ITERATIONS: u64 = 65535;
+pub const MAX_EXPANSION: u64 = 32768;
+
+/// Largest layer in bytes, the same as Redis' limit on a single string.
+const MAX_LAYER_SIZE: u64 = 512 * 1024 * 1024;
+
+const EMPTY: u8 = 0;
+
+const INVALID: &str = "ERR: Invalid serialized cuckoo filter";
+
+#[derive(Clone)]
+struct Layer {
+    slots: Vec<u8>,
+    buckets: u64,
+}
+
+impl Layer {
+    /// Returns `None` when the layer would take more than `MAX_LAYER_SIZE`.
+    fn new(buckets: u64, bucket_size: u64) -> Option<Self> {
+        let size = buckets.checked_mul(bucket_size).filter(|size| *size <= MAX_LAYER_SIZE)?;
+        Some(Self {
+            slots: vec![EMPTY; size as usize],
+            buckets,
+        })
+    }
+
+    fn indexes(&self, hash: u64, fp: u8) -> (u64, u64) {
+        let i1 = hash & (self.buckets - 1);
+        (i1, self.alt_index(i1, fp))
+    }
+
+    fn alt_index(&self, index: u64, fp: u8) -> u64 {
+        (index ^ (fp as u64).wrapping_mul(0x5bd1_e995)) & (self.buckets - 1)
+    }
+
+    fn bucket(&self, index: u64, bucket_size: u64) -> &[u8] {
+        let start = (index * bucket_size) as usize;
+        &self.slots[start..start + bucket_size as usize]
+    }
+
+    fn bucket_mut(&mut self, index: u64, bucket_size: u64) -> &mut [u8] {
+        let start = (index * bucket_size) as usize;
+        &mut self.slots[start..start + bucket_size as usize]
+    }
+
+    fn count(&self, hash: u64, fp: u8, bucket_size: u64) -> u64 {
+        let (i1, i2) = self.indexes(hash, fp);
+        let mut count = self.bucket(i1, bucket_size).iter().filter(|s| **s == fp).count();
+        if i2 != i1 {
+            count += self.bucket(i2, bucket_size).iter().filter(|s| **s == fp).count();
+        }
+        count as u64
+    }
+
+    fn put(&mut self, index: u64, fp: u8, bucket_size: u64) -> bool {
+        match self.bucket_mut(index, bucket_size).iter_mut().find(|s| **s == EMPTY) {
+            Some(slot) => {
+                *slot = fp;
+                true
+            },
+            None => false,
+        }
+    }
+
+    fn remove(&mut self, hash: u64, fp: u8, bucket_size: u64) -> bool {
+        let (i1, i2) = self.indexes(hash, fp);
+        for index in [i1, i2] {
+            if let Some(slot) = self.bucket_mut(index, bucket_size).iter_mut().find(|s| **s == fp) {
+                *slot = EMPTY;
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Relocates fingerprints between their alternate buckets to make room,
+    /// undoing every move if no free slot shows up in time.
+    fn kick_insert(&mut self, hash: u64, fp: u8, bucket_size: u64, max_iterations: u64) -> bool {
+        let (i1, i2) = self.indexes(hash, fp);
+        if self.put(i1, fp, bucket_size) || self.put(i2, fp, bucket_size) {
+            return true;
+        }
+
+        let mut victim = fp;
+        let mut index = i1;
+        let mut path: Vec<(u64, usize)> = Vec::new();
+        for n in 0..max_iterations {
+            let slot = (n % bucket_size) as usize;
+            std::mem::swap(&mut victim, &mut self.bucket_mut(index, bucket_size)[slot]);
+            path.push((index, slot));
+
+            index = self.alt_index(index, victim);
+            if self.put(index, victim, bucket_size) {
+                return true;
+            }
+        }
+
+        for (index, slot) in path.into_iter().rev() {
+            std::mem::swap(&mut victim, &mut self.bucket_mut(index, bucket_size)[slot]);
+        }
+        false
+    }
+}
+
+/// Cuckoo filter storing 8 bit fingerprints, growing by stacking new layers
+/// when the newest one can't fit an item anymore.
+#[derive(Clone)]
+pub struct CuckooFilter {
+    layers: Vec<Layer>,
+    bucket_size: u64,
+    max_iterations: u64,
+    expansion: u64,
+    items: u64,
+    deleted: u64,
+}
+
+impl CuckooFilter {
+    /// An `expansion` of zero makes the filter non scaling.
+    pub fn new(capacity: u64, bucket_size: u64, max_iterations: u64, expansion: u64) -> Result<Self, &'static str> {
+        let layer = capacity.div_ceil(bucket_size).max(1).checked_next_power_of_two()
+            .and_then(|buckets| Layer::new(buckets, bucket_size));
+        let Some(layer) = layer else {
+            return Err("ERR: Capacity is too large");
+        };
+        Ok(Self {
+            layers: vec![layer],
+            bucket_size,
+            max_iterations,
+            expansion,
+            items: 0,
+            deleted: 0,
+        })
+    }
+
+    fn hash(item: &[u8]) -> (u64, u8) {
+        let hash = murmurhash64a(item, 0);
+        (hash, (hash % 255 + 1) as u8)
+    }
+
+    pub fn add(&mut self, item: &[u8]) -> Result<(), &'static str> {
+        let (hash, fp) = Self::hash(item);
+        let (bucket_size, max_iterations) = (self.bucket_size, self.max_iterations);
+
+        for layer in self.layers.iter_mut().rev() {
+            let (i1, i2) = layer.indexes(hash, fp);
+            if layer.put(i1, fp, bucket_size) || layer.put(i2, fp, bucket_size) {
+                self.items += 1;
+                return Ok(());
+            }
+        }
+
+        let last = self.layers.last_mut().unwrap();
+        if !last.kick_insert(hash, fp, bucket_size, max_iterations) {
+            if self.expansion == 0 {
+                return Err("ERR: Filter is full");
+            }
+
+            let layer = last.buckets.checked_mul(self.expansion)
+                .and_then(u64::checked_next_power_of_two)
+                .and_then(|buckets| Layer::new(buckets, bucket_size));
+            let Some(mut layer) = layer else {
+                return Err("ERR: Filter is full and can't grow any larger");
+            };
+            layer.kick_insert(hash, fp, bucket_size, max_iterations);
+            self.layers.push(layer);
+        }
+        self.items += 1;
+        Ok(())
+    }
+
+    pub fn count(&self, item: &[u8]) -> u64 {
+        let (hash, fp) = Self::hash(item);
+        let bucket_size = self.bucket_size;
+        self.layers.iter().map(|layer| layer.count(hash, fp, bucket_size)).sum()
+    }
+
+    pub fn exists(&self, item: &[u8]) -> bool {
+        self.count(item) > 0
+    }
+
+    pub fn delete(&mut self, item: &[u8]) -> bool {
+        let (hash, fp) = Self::hash(item);
+        let bucket_size = self.bucket_size;
+
+        for layer in self.layers.iter_mut().rev() {
+            if layer.remove(hash, fp, bucket_size) {
+                self.items -= 1;
+                self.deleted += 1;
+                return true;
+            }
+        }
+        false
+    }
+
+    pub fn size(&self) -> u64 {
+        self.layers.iter().map(|layer| layer.slots.len() as u64).sum()
+    }
+
+    pub fn buckets(&self) -> u64 {
+        self.layers.iter().map(|layer| layer.buckets).sum()
+    }
+
+    pub fn filters(&self) -> u64 {
+        self.layers.len() as u64
+    }
+
+    pub fn items(&self) -> u64 {
+        self.items
+    }
+
+    pub fn deleted(&self) -> u64 {
+        self.deleted
+    }
+
+    pub fn bucket_size(&self) -> u64 {
+        self.bucket_size
+    }
+
+    pub fn expansion(&self) -> u64 {
+        self.expansion
+    }
+
+    pub fn max_iterations(&self) -> u64 {
+        self.max_iterations
+    }
+
+    pub fn dump(&self, out: &mut Vec<u8>) {
+        for n in [self.bucket_size, self.max_iterations, self.expansion, self.items, self.deleted] {
+            put_u64(out, n);
+        }
+        put_u64(out, self.layers.len() as u64);
+        for layer in &self.layers {
+            put_u64(out, layer.buckets);
+            put_bytes(out, &layer.slots);
+        }
+    }
+
+    /// Checks every field against what `new` and `add` can build, as
+    /// inserts loop up to `max_iterations` and deletes count `items` down.
+    pub fn restore(reader: &mut Reader) -> Result<Self, &'static str> {
+        let [bucket_size, max_iterations, expansion, items, deleted] = [
+            reader.u64()?, reader.u64()?, reader.u64()?, reader.u64()?, reader.u64()?,
+        ];
+        if !(1..=MAX_BUCKET_SIZE).contains(&bucket_size) || !(1..=MAX_ITERATIONS).contains(&max_iterations)
+            || expansion > MAX_EXPANSION {
+            return Err(INVALID);
+        }
+
+        let mut layers = Vec::new();
+        for _ in 0..reader.u64()? {
+            let buckets = reader.u64()?;
+            let slots = reader.bytes()?.to_vec();
+            if !buckets.is_power_of_two() || Some(slots.len() as u64) != buckets.checked_mul(bucket_size)
+                || slots.len() as u64 > MAX_LAYER_SIZE {
+                return Err(INVALID);
+            }
+            layers.push(Layer { slots, buckets });
+        }
+        let stored = layers.iter().flat_map(|layer| &layer.slots).filter(|slot| **slot != EMPTY).count();
+        if layers.is_empty() || stored as u64 != items {
+            return Err(INVALID);
+        }
+        Ok(Self { layers, bucket_size, max_iterations, expansion, items, deleted })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn refuses_layers_over_the_size_limit() {
+        assert!(CuckooFilter::new(MAX_CAPACITY, 1, DEFAULT_MAX_ITERATIONS, DEFAULT_EXPANSION).is_err());
+        assert!(CuckooFilter::new(u64::MAX, 1, DEFAULT_MAX_ITERATIONS, DEFAULT_EXPANSION).is_err());
+    }
+
+    #[test]
+    fn stops_growing_at_the_size_limit() {
+        let mut filter = CuckooFilter::new(1, 1, DEFAULT_MAX_ITERATIONS, u64::MAX).unwrap();
+        let errors = (0..64).filter(|i| filter.add(format!("item:{i}").as_bytes()).is_err()).count();
+        assert!(errors > 0);
+        assert_eq!(filter.filters(), 1);
+    }
+
+    #[test]
+    fn restores_only_what_it_could_have_built() {
+        let mut filter = CuckooFilter::new(DEFAULT_CAPACITY, DEFAULT_BUCKET_SIZE, DEFAULT_MAX_ITERATIONS, DEFAULT_EXPANSION).unwrap();
+        filter.add(b"item").unwrap();
+        let mut payload = Vec::new();
+        filter.dump(&mut payload);
+        let restored = CuckooFilter::restore(&mut Reader::new(&payload)).unwrap();
+        assert!(restored.exists(b"item"));
+
+        let mut iterations = payload.clone();
+        iterations[8..16].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert!(CuckooFilter::restore(&mut Reader::new(&iterations)).is_err());
+
+        // Deleting the item would count the items below zero.
+        let mut items = payload.clone();
+        items[24..32].copy_from_slice(&0u64.to_le_bytes());
+        assert!(CuckooFilter::restore(&mut Reader::new(&items)).is_err());
+    }
+}
@@ -1,176 +1,1030 @@
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
+use std::hash::{BuildHasher, Hasher, RandomState};
+use std::ops::Deref;
+use std::sync::atomic::{self, AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::thread::{self, ThreadId};
 
-use crate::config::Config;
+use crate::bloom::{self, BloomFilter};
+use crate::cuckoo::{self, CuckooFilter};
+use crate::dump::{self, Reader};
+use crate::glob::glob_match;
+use crate::hash::murmurhash64a;
+use crate::hyperloglog::HyperLogLog;
+use crate::json::Json;
+use crate::poll::Waker;
 use crate::resp::Value;
+use crate::stream::Stream;
+use crate::timeseries::TimeSeries;
+use crate::zset::SortedSet;
 
+pub const WRONGTYPE: &str = "WRONGTYPE: Operation against a key holding the wrong kind of value";
+
+#[derive(Clone)]
+pub enum Data {
+    Str(Vec<u8>),
+    Hash(HashMap<String, String>),
+    Bloom(BloomFilter),
+    Cuckoo(CuckooFilter),
+    Json(Json),
+    TimeSeries(TimeSeries),
+    Stream(Stream),
+    SortedSet(SortedSet),
+    List(VecDeque<String>),
+    Set(HashSet<String>),
+}
+
+impl Data {
+    /// Names reported by `TYPE`, using the module type names for the kinds
+    /// Redis only provides through modules.
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Data::Str(_) => "string",
+            Data::Hash(_) => "hash",
+            Data::Bloom(_) => "MBbloom--",
+            Data::Cuckoo(_) => "MBbloomCF",
+            Data::Json(_) => "ReJSON-RL",
+            Data::TimeSeries(_) => "TSDB-TYPE",
+            Data::Stream(_) => "stream",
+            Data::SortedSet(_) => "zset",
+            Data::List(_) => "list",
+            Data::Set(_) => "set",
+        }
+    }
+}
+
+/// Number of lock-striped shards each database's keyspace is split into, a
+/// key going to the one picked by the low bits of its hash.
+const SHARD_BITS: u32 = 6;
+const SHARDS: usize = 1 << SHARD_BITS;
+
+/// The keys of one shard, also ordered by [`scan_position`] so `SCAN` can
+/// resume right where its cursor points.
+#[derive(Default)]
+struct Shard {
+    keys: HashMap<String, Data>,
+    order: BTreeSet<(u64, String)>,
+    /// The thread of the transaction that reserved the shard, if any.
+    reserved_by: Option<ThreadId>,
+}
+
+impl Shard {
+    fn get(&self, key: &str) -> Option<&Data> {
+        self.keys.get(key)
+    }
+    fn get_mut(&mut self, key: &str) -> Option<&mut Data> {
+        self.keys.get_mut(key)
+    }
+    fn insert(&mut self, key: String, data: Data) -> Option<Data> {
+        if !self.keys.contains_key(&key) {
+            self.order.insert((scan_position(&key), key.clone()));
+        }
+        self.keys.insert(key, data)
+    }
+    fn get_or_insert_with(&mut self, key: String, default: impl FnOnce() -> Data) -> &mut Data {
+        if !self.keys.contains_key(&key) {
+            self.order.insert((scan_position(&key), key.clone()));
+        }
+        self.keys.entry(key).or_insert_with(default)
+    }
+    fn remove(&mut self, key: &str) -> Option<Data> {
+        let data = self.keys.remove(key)?;
+        self.order.remove(&(scan_position(key), key.to_string()));
+        Some(data)
+    }
+    fn keys(&self) -> impl Iterator<Item = &String> {
+        self.keys.keys()
+    }
+    fn len(&self) -> usize {
+        self.keys.len()
+    }
+    fn clear(&mut self) {
+        self.keys.clear();
+        self.order.clear();
+    }
+    /// Whether a transaction of another thread has the shard to itself.
+    fn is_reserved(&self) -> bool {
+        self.reserved_by.is_some_and(|thread| thread != thread::current().id())
+    }
+}
+
+fn shard_index(key: &str) -> usize {
+    (murmurhash64a(key.as_bytes(), 0) % SHARDS as u64) as usize
+}
+
+/// Where `SCAN` visits a key: its hash with the bits of its shard moved on
+/// top, so the keys of each shard come one after the other.
+fn scan_position(key: &str) -> u64 {
+    murmurhash64a(key.as_bytes(), 0).rotate_right(SHARD_BITS)
+}
+
+enum Lock<'a> {
+    Read(RwLockReadGuard<'a, Shard>),
+    Write(RwLockWriteGuard<'a, Shard>),
+}
+
+impl Deref for Lock<'_> {
+    type Target = Shard;
+
+    fn deref(&self) -> &Shard {
+        match self {
+            Lock::Read(guard) => guard,
+            Lock::Write(guard) => guard,
+        }
+    }
+}
+
+impl Lock<'_> {
+    fn get_mut(&mut self) -> &mut Shard {
+        match self {
+            Lock::Write(guard) => guard,
+            Lock::Read(_) => panic!("shard is locked for reading only"),
+        }
+    }
+}
+
+/// The locked shards of a command, behaving like a single map over them.
+struct Shards<'a> {
+    locks: Vec<(usize, Lock<'a>)>,
+}
+
+impl Shards<'_> {
+    fn position(&self, key: &str) -> usize {
+        let index = shard_index(key);
+        self.locks.binary_search_by_key(&index, |(i, _)| *i).expect("shard of the key is not locked")
+    }
+
+    fn get(&self, key: &str) -> Option<&Data> {
+        self.locks[self.position(key)].1.get(key)
+    }
+    fn get_mut(&mut self, key: &str) -> Option<&mut Data> {
+        let position = self.position(key);
+        self.locks[position].1.get_mut().get_mut(key)
+    }
+    fn contains_key(&self, key: &str) -> bool {
+        self.get(key).is_some()
+    }
+    fn insert(&mut self, key: String, data: Data) -> Option<Data> {
+        let position = self.position(&key);
+        self.locks[position].1.get_mut().insert(key, data)
+    }
+    fn remove(&mut self, key: &str) -> Option<Data> {
+        let position = self.position(key);
+        self.locks[position].1.get_mut().remove(key)
+    }
+    fn get_or_insert_with(&mut self, key: String, default: impl FnOnce() -> Data) -> &mut Data {
+        let position = self.position(&key);
+        self.locks[position].1.get_mut().get_or_insert_with(key, default)
+    }
+
+    fn keys(&self) -> impl Iterator<Item = &String> {
+        self.locks.iter().flat_map(|(_, lock)| lock.keys())
+    }
+    fn len(&self) -> usize {
+        self.locks.iter().map(|(_, lock)| lock.len()).sum()
+    }
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    fn clear(&mut self) {
+        for (_, lock) in self.locks.iter_mut() {
+            lock.get_mut().clear();
+        }
+    }
+}
+
+/// A logical database, with its keyspace split into shards that commands
+/// lock as a whole before running, so each command is atomic while commands
+/// over different shards run in parallel.
 pub struct Database {
-    config: Config,
-    set: HashMap<String, String>,
-    hset: HashMap<String, HashMap<String, String>>,
-    multi: Vec<(Value, Vec<Value>)>,
-    transaction_mode: bool,
-    execution_mode: bool,
+    shards: Vec<RwLock<Shard>>,
+    /// Event loops with clients blocked on this database, woken after its
+    /// next write.
+    waiting: Mutex<Vec<Arc<Waker>>>,
+    has_waiting: AtomicBool,
+    /// Counts the reservations given up, which commands of other threads
+    /// wait on.
+    released: Mutex<u64>,
+    on_release: Condvar,
+}
+
+impl Default for Database {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Database {
-    pub fn new(config: Config) -> Self {
+    pub fn new() -> Self {
         Self {
-            config,
-            set: HashMap::new(),
-            hset: HashMap::new(),
-            multi: Vec::new(),
-            transaction_mode: false,
-            execution_mode: false,
+            shards: (0..SHARDS).map(|_| RwLock::new(Shard::default())).collect(),
+            waiting: Mutex::new(Vec::new()),
+            has_waiting: AtomicBool::new(false),
+            released: Mutex::new(0),
+            on_release: Condvar::new(),
         }
     }
 
-    pub fn config(&self) -> Config {
-        self.config.clone()
+    /// Has `waker` woken after the next write. Registering before retrying
+    /// a blocked command means a write in between isn't missed.
+    pub fn wait(&self, waker: &Arc<Waker>) {
+        let mut waiting = self.waiting.lock().unwrap();
+        if !waiting.iter().any(|w| Arc::ptr_eq(w, waker)) {
+            waiting.push(waker.clone());
+        }
+        self.has_waiting.store(true, Ordering::SeqCst);
+        atomic::fence(Ordering::SeqCst);
+    }
+
+    /// Called after every write, so it's a single load when nobody waits.
+    pub fn wake_waiting(&self) {
+        atomic::fence(Ordering::SeqCst);
+        if !self.has_waiting.load(Ordering::SeqCst) {
+            return;
+        }
+        let waiting = {
+            let mut waiting = self.waiting.lock().unwrap();
+            self.has_waiting.store(false, Ordering::SeqCst);
+            std::mem::take(&mut *waiting)
+        };
+        for waker in waiting {
+            let _ = waker.wake();
+        }
+    }
+
+    /// Locks the shards of `keys` always in index order, so commands
+    /// touching the same shards can't deadlock each other.
+    fn lock<K: AsRef<str>>(&self, keys: impl IntoIterator<Item = K>, write: bool) -> Keyspace<'_> {
+        let mut indexes: Vec<usize> = keys.into_iter().map(|key| shard_index(key.as_ref())).collect();
+        indexes.sort_unstable();
+        indexes.dedup();
+        self.lock_shards(indexes, write)
+    }
+    /// Shards reserved by a transaction of another thread are waited for,
+    /// letting go of those already locked so the transaction can go on.
+    fn lock_shards(&self, indexes: Vec<usize>, write: bool) -> Keyspace<'_> {
+        'retry: loop {
+            let mut locks = Vec::with_capacity(indexes.len());
+            for &i in &indexes {
+                let lock = match write {
+                    true => Lock::Write(self.shards[i].write().unwrap()),
+                    false => Lock::Read(self.shards[i].read().unwrap()),
+                };
+                if lock.is_reserved() {
+                    // Read while the shard is locked, so the reservation
+                    // can't be given up before.
+                    let released = *self.released.lock().unwrap();
+                    drop((locks, lock));
+                    self.wait_for_release(released);
+                    continue 'retry;
+                }
+                locks.push((i, lock));
+            }
+            return Keyspace { keyspace: Shards { locks } };
+        }
+    }
+
+    fn wait_for_release(&self, seen: u64) {
+        let released = self.released.lock().unwrap();
+        drop(self.on_release.wait_while(released, |released| *released == seen).unwrap());
+    }
+
+    /// Keeps the shards of `keys` to a transaction until the reservation is
+    /// dropped: commands of other threads wait for them, while those of the
+    /// transaction lock them as usual between each other. Reservations must
+    /// be taken in the order of the databases, the shards of each being
+    /// taken in index order, so transactions can't deadlock each other.
+    pub fn reserve<K: AsRef<str>>(&self, keys: impl IntoIterator<Item = K>) -> Reservation<'_> {
+        let mut indexes: Vec<usize> = keys.into_iter().map(|key| shard_index(key.as_ref())).collect();
+        indexes.sort_unstable();
+        indexes.dedup();
+        self.reserve_shards(indexes)
+    }
+    pub fn reserve_all(&self) -> Reservation<'_> {
+        self.reserve_shards((0..SHARDS).collect())
+    }
+    fn reserve_shards(&self, indexes: Vec<usize>) -> Reservation<'_> {
+        for &i in &indexes {
+            loop {
+                let mut shard = self.shards[i].write().unwrap();
+                if !shard.is_reserved() {
+                    shard.reserved_by = Some(thread::current().id());
+                    break;
+                }
+                let released = *self.released.lock().unwrap();
+                drop(shard);
+                self.wait_for_release(released);
+            }
+        }
+        Reservation { db: self, indexes }
+    }
+
+    pub fn read<K: AsRef<str>>(&self, keys: impl IntoIterator<Item = K>) -> Keyspace<'_> {
+        self.lock(keys, false)
+    }
+    pub fn write<K: AsRef<str>>(&self, keys: impl IntoIterator<Item = K>) -> Keyspace<'_> {
+        self.lock(keys, true)
+    }
+    pub fn read_all(&self) -> Keyspace<'_> {
+        self.lock_shards((0..SHARDS).collect(), false)
+    }
+    pub fn write_all(&self) -> Keyspace<'_> {
+        self.lock_shards((0..SHARDS).collect(), true)
+    }
+
+    /// Walks the keyspace one shard at a time, each ordered by key hash. The
+    /// cursor is the [`scan_position`] of the next key to visit, so keys
+    /// present during the whole iteration are always returned no matter how
+    /// the shards are rehashed between calls, while only the shard being
+    /// walked is locked. Each call only reads the keys it returns.
+    pub fn scan(&self, cursor: u64, count: usize, pattern: Option<&str>, kind: Option<&str>) -> (u64, Vec<String>) {
+        let mut keys = Vec::new();
+        let mut left = count;
+        for index in (cursor >> (u64::BITS - SHARD_BITS)) as usize..SHARDS {
+            let shard = self.read_shard(index);
+            let mut order = shard.order.range((cursor, String::new())..).peekable();
+            let mut page: Vec<&(u64, String)> = order.by_ref().take(left).collect();
+
+            // Keys with the same hash are returned together.
+            let last = page.last().map(|(position, _)| *position);
+            while let Some(entry) = order.next_if(|(position, _)| Some(*position) == last) {
+                page.push(entry);
+            }
+            let next = order.peek().map(|(position, _)| *position);
+            left = left.saturating_sub(page.len());
+
+            keys.extend(page.into_iter()
+                .filter(|(_, key)| pattern.is_none_or(|p| glob_match(p.as_bytes(), key.as_bytes())))
+                .filter(|(_, key)| kind.is_none_or(|k| shard.keys[key].type_name().eq_ignore_ascii_case(k)))
+                .map(|(_, key)| key.clone()));
+            match next {
+                Some(next) => return (next, keys),
+                None if left == 0 && index + 1 < SHARDS => return (((index + 1) as u64) << (u64::BITS - SHARD_BITS), keys),
+                None => (),
+            };
+        }
+        (0, keys)
+    }
+
+    pub fn create_database_copy(&self) -> Vec<HashMap<String, Data>> {
+        (0..SHARDS).map(|index| self.read_shard(index).keys.clone()).collect()
     }
 
-    pub fn is_transaction_mode(&self) -> bool {
-        self.transaction_mode
+    fn read_shard(&self, index: usize) -> Lock<'_> {
+        let Keyspace { keyspace: Shards { mut locks } } = self.lock_shards(vec![index], false);
+        locks.pop().unwrap().1
     }
-    pub fn set_transaction_mode(&mut self, state: bool) {
-        self.transaction_mode = state
+}
+
+/// Shards a transaction keeps to itself, given up when dropped.
+pub struct Reservation<'a> {
+    db: &'a Database,
+    indexes: Vec<usize>,
+}
+
+impl Drop for Reservation<'_> {
+    fn drop(&mut self) {
+        for &i in &self.indexes {
+            self.db.shards[i].write().unwrap().reserved_by = None;
+        }
+        *self.db.released.lock().unwrap() += 1;
+        self.db.on_release.notify_all();
     }
+}
 
-    pub fn is_execution_mode(&self) -> bool {
-        self.execution_mode
+/// The keys a command can work on, borrowed from a [`Database`] through
+/// [`Database::read`], [`Database::write`] or their `_all` versions.
+pub struct Keyspace<'a> {
+    keyspace: Shards<'a>,
+}
+
+impl Keyspace<'_> {
+    pub fn contains(&self, key: &str) -> bool {
+        self.keyspace.contains_key(key)
+    }
+    pub fn remove(&mut self, key: &str) -> bool {
+        self.keyspace.remove(key).is_some()
+    }
+    pub fn type_name(&self, key: &str) -> &'static str {
+        match self.keyspace.get(key) {
+            Some(data) => data.type_name(),
+            None => "none",
+        }
     }
-    pub fn set_execution_mode(&mut self, state: bool) {
-        self.execution_mode = state
+    pub fn len(&self) -> usize {
+        self.keyspace.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.keyspace.is_empty()
+    }
+    pub fn clear(&mut self) {
+        self.keyspace.clear()
     }
 
-    pub fn set_push(&mut self, key: String, value: String) {
-        self.set.insert(key, value);
+    pub fn keys(&self, pattern: &str) -> Vec<String> {
+        self.keyspace.keys()
+            .filter(|key| glob_match(pattern.as_bytes(), key.as_bytes()))
+            .cloned()
+            .collect()
     }
-    pub fn set_get(&self, key: &String) -> Value {
-        match self.set.get(key) {
-            Some(value) => Value::BulkStr(value.into()),
-            None => Value::Null,
+    pub fn random_key(&self) -> Option<String> {
+        if self.keyspace.is_empty() {
+            return None;
         }
+        let random = RandomState::new().build_hasher().finish() as usize;
+        self.keyspace.keys().nth(random % self.keyspace.len()).cloned()
     }
-    pub fn set_remove(&mut self, key: &String) -> bool {
-        match self.set.remove(key) {
-            Some(_) => true,
-            None => false,
+
+    pub fn rename(&mut self, key: &str, new_key: String, nx: bool) -> Value {
+        if !self.keyspace.contains_key(key) {
+            return Value::Error("ERR: No such key");
+        }
+        if nx && self.keyspace.contains_key(&new_key) {
+            return Value::Num(0);
+        }
+
+        let data = self.keyspace.remove(key).unwrap();
+        self.keyspace.insert(new_key, data);
+        match nx {
+            true => Value::Num(1),
+            false => Value::Str("OK"),
         }
     }
-    pub fn set_clear(&mut self) {
-        self.set.clear()
+    pub fn copy(&mut self, key: &str, new_key: String, replace: bool) -> bool {
+        let Some(data) = self.keyspace.get(key) else {
+            return false;
+        };
+        if !replace && self.keyspace.contains_key(&new_key) {
+            return false;
+        }
+        self.keyspace.insert(new_key, data.clone());
+        true
     }
-    pub fn set_len(&self) -> usize {
-        self.set.len()
+    pub fn copy_into(&self, key: &str, destination: &mut Keyspace, new_key: String, replace: bool) -> bool {
+        let Some(data) = self.keyspace.get(key) else {
+            return false;
+        };
+        if !replace && destination.keyspace.contains_key(&new_key) {
+            return false;
+        }
+        destination.keyspace.insert(new_key, data.clone());
+        true
+    }
+    pub fn move_into(&mut self, key: &str, destination: &mut Keyspace) -> bool {
+        if !self.keyspace.contains_key(key) || destination.keyspace.contains_key(key) {
+            return false;
+        }
+        let data = self.keyspace.remove(key).unwrap();
+        destination.keyspace.insert(key.into(), data);
+        true
+    }
+    /// Replaces the key with a value serialized by [`dump::dump`].
+    pub fn restore(&mut self, key: String, payload: &[u8]) -> Value {
+        let mut reader = Reader::new(payload);
+        match dump::restore(&mut reader) {
+            Ok(_) if !reader.is_empty() => Value::Error("ERR: Invalid serialized value"),
+            Ok(data) => {
+                self.keyspace.insert(key, data);
+                Value::Str("OK")
+            },
+            Err(err) => Value::Error(err),
+        }
+    }
+    /// A copy of the value of the key, to put back with [`Keyspace::insert`].
+    pub fn cloned(&self, key: &str) -> Option<Data> {
+        self.keyspace.get(key).cloned()
+    }
+    /// Replaces the key, for values read from a snapshot.
+    pub fn insert(&mut self, key: String, data: Data) {
+        self.keyspace.insert(key, data);
+    }
+
+    /// Both keyspaces must have every shard locked. Reservations stay with
+    /// the shards of each database.
+    pub fn swap(&mut self, other: &mut Keyspace) {
+        for ((_, a), (_, b)) in self.keyspace.locks.iter_mut().zip(other.keyspace.locks.iter_mut()) {
+            let (a, b) = (a.get_mut(), b.get_mut());
+            std::mem::swap(&mut a.keys, &mut b.keys);
+            std::mem::swap(&mut a.order, &mut b.order);
+        }
     }
-    pub fn set_incr(&mut self, key: String, num: i64) -> Value {
-        let mut value = 0i64;
-        let mut err = "";
-
-        self.set.entry(key)
-            .and_modify(|val| {
-                let v = match val.parse::<i64>() {
-                    Ok(n) => n,
-                    _ => {
-                        err = "ERR: Value is not an integer or out of range";
-                        return;
-                    },
-                };
-                value = v + num;
-                *val = value.to_string()
-            })
-            .or_insert_with(|| {
-                value += num;
-                value.to_string()
-            });
 
-        if err.len() != 0 {
-            return Value::Error(err);
+    fn set_bytes(&self, key: &str) -> Result<Option<&Vec<u8>>, Value> {
+        match self.keyspace.get(key) {
+            Some(Data::Str(value)) => Ok(Some(value)),
+            Some(_) => Err(Value::Error(WRONGTYPE)),
+            None => Ok(None),
         }
+    }
+    pub fn set_push(&mut self, key: String, value: Vec<u8>) {
+        self.keyspace.insert(key, Data::Str(value));
+    }
+    pub fn set_get(&self, key: &str) -> Value {
+        match self.set_bytes(key) {
+            Ok(Some(value)) => Value::BulkBytes(value.clone()),
+            Ok(None) => Value::Null,
+            Err(err) => err,
+        }
+    }
+    pub fn set_incr(&mut self, key: String, num: i64) -> Value {
+        let current = match self.set_bytes(&key) {
+            Ok(Some(value)) => match std::str::from_utf8(value).map(|s| s.parse::<i64>()) {
+                Ok(Ok(n)) => n,
+                _ => return Value::Error("ERR: Value is not an integer or out of range"),
+            },
+            Ok(None) => 0,
+            Err(err) => return err,
+        };
+
+        let Some(value) = current.checked_add(num) else {
+            return Value::Error("ERR: Increment or decrement would overflow");
+        };
+        self.keyspace.insert(key, Data::Str(value.to_string().into_bytes()));
         Value::Num(value)
     }
-    pub fn set_contains(&self, key: &String) -> bool {
-        self.set.contains_key(key)
+
+    fn pf_get(&self, key: &str) -> Result<Option<HyperLogLog>, Value> {
+        match self.set_bytes(key)? {
+            Some(bytes) => match HyperLogLog::from_bytes(bytes) {
+                Some(hll) => Ok(Some(hll)),
+                None => Err(Value::Error("WRONGTYPE: Key is not a valid HyperLogLog string value")),
+            },
+            None => Ok(None),
+        }
     }
+    pub fn pf_add(&mut self, key: String, elements: &[&[u8]]) -> Value {
+        let (mut hll, mut updated) = match self.pf_get(&key) {
+            Ok(Some(hll)) => (hll, false),
+            Ok(None) => (HyperLogLog::new(), true),
+            Err(err) => return err,
+        };
 
-    pub fn hset_push(&mut self, hash: String, key: String, value: String) {
-        let map: HashMap<String, String> = HashMap::from([(key, value)]);
-        self.hset.insert(hash, map);
+        for element in elements {
+            updated |= hll.add(element);
+        }
+        if updated {
+            self.keyspace.insert(key, Data::Str(hll.to_bytes()));
+        }
+        Value::Num(updated as i64)
     }
-    pub fn hset_get(&self, hash: &String, key: &String) -> Value {
-        let map = match self.hset.get(hash) {
-            Some(m) => m.clone(),
-            _ => return Value::Null,
+    pub fn pf_count(&mut self, keys: &[String]) -> Value {
+        if let [key] = keys {
+            return match self.pf_get(key) {
+                Ok(Some(mut hll)) => {
+                    let card = hll.count();
+                    self.keyspace.insert(key.into(), Data::Str(hll.to_bytes()));
+                    Value::Num(card as i64)
+                },
+                Ok(None) => Value::Num(0),
+                Err(err) => err,
+            };
+        }
+
+        let mut merged = HyperLogLog::new();
+        for key in keys {
+            match self.pf_get(key) {
+                Ok(Some(hll)) => merged.merge(&hll),
+                Ok(None) => (),
+                Err(err) => return err,
+            };
+        }
+        Value::Num(merged.count() as i64)
+    }
+    pub fn pf_merge(&mut self, dest: String, sources: &[String]) -> Value {
+        let mut merged = match self.pf_get(&dest) {
+            Ok(Some(hll)) => hll,
+            Ok(None) => HyperLogLog::new(),
+            Err(err) => return err,
         };
 
-        match map.get(key) {
-            Some(value) => Value::BulkStr(value.into()),
-            None => Value::Null,
+        for key in sources {
+            match self.pf_get(key) {
+                Ok(Some(hll)) => merged.merge(&hll),
+                Ok(None) => (),
+                Err(err) => return err,
+            };
         }
+        self.keyspace.insert(dest, Data::Str(merged.to_bytes()));
+        Value::Str("OK")
     }
-    pub fn hset_remove(&mut self, hash: &String, key: &String) -> bool {
-        let mut hmap = match self.hset.get(hash) {
-            Some(m) => m.clone(),
-            None => return false,
+
+    fn hset_map(&self, hash: &str) -> Result<Option<&HashMap<String, String>>, Value> {
+        match self.keyspace.get(hash) {
+            Some(Data::Hash(map)) => Ok(Some(map)),
+            Some(_) => Err(Value::Error(WRONGTYPE)),
+            None => Ok(None),
+        }
+    }
+    pub fn hset_push(&mut self, hash: String, key: String, value: String) -> Value {
+        let data = self.keyspace.get_or_insert_with(hash, || Data::Hash(HashMap::new()));
+        let Data::Hash(map) = data else {
+            return Value::Error(WRONGTYPE);
         };
-        match hmap.remove(key) {
-            Some(_) => (),
-            None => return false,
+        map.insert(key, value);
+        Value::Str("OK")
+    }
+    pub fn hset_get(&self, hash: &str, key: &String) -> Value {
+        match self.hset_map(hash) {
+            Ok(Some(map)) => match map.get(key) {
+                Some(value) => Value::BulkStr(value.into()),
+                None => Value::Null,
+            },
+            Ok(None) => Value::Null,
+            Err(err) => err,
+        }
+    }
+    pub fn hset_remove(&mut self, hash: &str, key: &String) -> Result<bool, Value> {
+        let Some(data) = self.keyspace.get_mut(hash) else {
+            return Ok(false);
+        };
+        let Data::Hash(map) = data else {
+            return Err(Value::Error(WRONGTYPE));
         };
 
-        self.hset.insert(hash.into(), hmap);
-        true
+        let removed = map.remove(key).is_some();
+        if map.is_empty() {
+            self.keyspace.remove(hash);
+        }
+        Ok(removed)
     }
-    pub fn hset_total_len(&self) -> usize {
-        let mut len = 0usize;
-        for map in self.hset.values() {
-            len += map.len();
+    pub fn hset_len(&self, hash: &str) -> Value {
+        match self.hset_map(hash) {
+            Ok(map) => Value::Num(map.map_or(0, |m| m.len()) as i64),
+            Err(err) => err,
         }
-        len
     }
-    pub fn hset_len(&self, hash: &String) -> usize {
-        match self.hset.get(hash) {
-            Some(m) => m.len(),
-            None => 0,
+    pub fn hset_contains(&self, hash: &str, key: &String) -> Result<bool, Value> {
+        Ok(self.hset_map(hash)?.is_some_and(|map| map.contains_key(key)))
+    }
+
+    fn bf_get(&self, key: &str) -> Result<&BloomFilter, Value> {
+        match self.keyspace.get(key) {
+            Some(Data::Bloom(filter)) => Ok(filter),
+            Some(_) => Err(Value::Error(WRONGTYPE)),
+            None => Err(Value::Error("ERR: Not found")),
         }
     }
-    pub fn hset_clear(&mut self) {
-        self.hset.clear()
+    pub fn bf_reserve(&mut self, key: String, error_rate: f64, capacity: u64, expansion: u64) -> Value {
+        if self.keyspace.contains_key(&key) {
+            return Value::Error("ERR: Item exists");
+        }
+        match BloomFilter::new(error_rate, capacity, expansion) {
+            Ok(filter) => self.keyspace.insert(key, Data::Bloom(filter)),
+            Err(err) => return Value::Error(err),
+        };
+        Value::Str("OK")
     }
-    pub fn hset_contains(&self, hash: &String, key: &String) -> bool {
-        let map = match self.hset.get(hash) {
-            Some(m) => m.clone(),
-            _ => return false,
+    pub fn bf_add(&mut self, key: &String, item: &[u8]) -> Value {
+        if !self.keyspace.contains_key(key) {
+            match BloomFilter::new(bloom::DEFAULT_ERROR_RATE, bloom::DEFAULT_CAPACITY, bloom::DEFAULT_EXPANSION) {
+                Ok(filter) => self.keyspace.insert(key.into(), Data::Bloom(filter)),
+                Err(err) => return Value::Error(err),
+            };
+        }
+        let Some(Data::Bloom(filter)) = self.keyspace.get_mut(key) else {
+            return Value::Error(WRONGTYPE);
         };
-        map.contains_key(key)
+        match filter.add(item) {
+            Ok(added) => Value::Num(added as i64),
+            Err(err) => Value::Error(err),
+        }
+    }
+    pub fn bf_exists(&self, key: &str, item: &[u8]) -> Value {
+        match self.bf_get(key) {
+            Ok(filter) => Value::Num(filter.exists(item) as i64),
+            Err(Value::Error(WRONGTYPE)) => Value::Error(WRONGTYPE),
+            Err(_) => Value::Num(0),
+        }
+    }
+    pub fn bf_info(&self, key: &str) -> Result<Vec<(&'static str, i64)>, Value> {
+        let filter = self.bf_get(key)?;
+        Ok(vec![
+            ("Capacity", filter.capacity() as i64),
+            ("Size", filter.size() as i64),
+            ("Number of filters", filter.filters() as i64),
+            ("Number of items inserted", filter.items() as i64),
+            ("Expansion rate", filter.expansion() as i64),
+        ])
     }
 
-    pub fn multi_push(&mut self, cmd: Value, args: Vec<Value>) {
-        self.multi.push((cmd, args))
+    fn cf_get(&self, key: &str) -> Result<&CuckooFilter, Value> {
+        match self.keyspace.get(key) {
+            Some(Data::Cuckoo(filter)) => Ok(filter),
+            Some(_) => Err(Value::Error(WRONGTYPE)),
+            None => Err(Value::Error("ERR: Not found")),
+        }
     }
-    pub fn multi_get(&self) -> Vec<(Value, Vec<Value>)> {
-        self.multi.clone()
+    pub fn cf_reserve(&mut self, key: String, capacity: u64, bucket_size: u64, max_iterations: u64, expansion: u64) -> Value {
+        if self.keyspace.contains_key(&key) {
+            return Value::Error("ERR: Item exists");
+        }
+        match CuckooFilter::new(capacity, bucket_size, max_iterations, expansion) {
+            Ok(filter) => self.keyspace.insert(key, Data::Cuckoo(filter)),
+            Err(err) => return Value::Error(err),
+        };
+        Value::Str("OK")
+    }
+    /// Creates the filter with `capacity` when missing, or fails when no
+    /// capacity is given.
+    pub fn cf_insert(&mut self, key: &String, item: &[u8], nx: bool, capacity: Option<u64>) -> Value {
+        if !self.keyspace.contains_key(key) {
+            let Some(capacity) = capacity else {
+                return Value::Error("ERR: Not found");
+            };
+            let filter = CuckooFilter::new(
+                capacity,
+                cuckoo::DEFAULT_BUCKET_SIZE,
+                cuckoo::DEFAULT_MAX_ITERATIONS,
+                cuckoo::DEFAULT_EXPANSION,
+            );
+            match filter {
+                Ok(filter) => self.keyspace.insert(key.into(), Data::Cuckoo(filter)),
+                Err(err) => return Value::Error(err),
+            };
+        }
+
+        let Some(Data::Cuckoo(filter)) = self.keyspace.get_mut(key) else {
+            return Value::Error(WRONGTYPE);
+        };
+        if nx && filter.exists(item) {
+            return Value::Num(0);
+        }
+        match filter.add(item) {
+            Ok(_) => Value::Num(1),
+            Err(err) => Value::Error(err),
+        }
     }
-    pub fn multi_clear(&mut self) {
-        self.multi.clear()
+    pub fn cf_exists(&self, key: &str, item: &[u8]) -> Value {
+        match self.cf_get(key) {
+            Ok(filter) => Value::Num(filter.exists(item) as i64),
+            Err(Value::Error(WRONGTYPE)) => Value::Error(WRONGTYPE),
+            Err(_) => Value::Num(0),
+        }
+    }
+    pub fn cf_count(&self, key: &str, item: &[u8]) -> Value {
+        match self.cf_get(key) {
+            Ok(filter) => Value::Num(filter.count(item) as i64),
+            Err(Value::Error(WRONGTYPE)) => Value::Error(WRONGTYPE),
+            Err(_) => Value::Num(0),
+        }
+    }
+    pub fn cf_del(&mut self, key: &str, item: &[u8]) -> Value {
+        match self.keyspace.get_mut(key) {
+            Some(Data::Cuckoo(filter)) => Value::Num(filter.delete(item) as i64),
+            Some(_) => Value::Error(WRONGTYPE),
+            None => Value::Error("ERR: Not found"),
+        }
+    }
+    pub fn cf_info(&self, key: &str) -> Result<Vec<(&'static str, i64)>, Value> {
+        let filter = self.cf_get(key)?;
+        Ok(vec![
+            ("Size", filter.size() as i64),
+            ("Number of buckets", filter.buckets() as i64),
+            ("Number of filters", filter.filters() as i64),
+            ("Number of items inserted", filter.items() as i64),
+            ("Number of items deleted", filter.deleted() as i64),
+            ("Bucket size", filter.bucket_size() as i64),
+            ("Expansion rate", filter.expansion() as i64),
+            ("Max iterations", filter.max_iterations() as i64),
+        ])
     }
 
-    pub fn create_database_copy(&self) -> Self {
-        Self {
-            config: self.config.clone(),
-            set: self.set.clone(),
-            hset: self.hset.clone(),
-            multi: self.multi.clone(),
-            transaction_mode: self.transaction_mode,
-            execution_mode: self.execution_mode,
-        }
-    }
-    pub fn database_revert(&mut self, copy: Database) {
-        self.config = copy.config;
-        self.set = copy.set;
-        self.hset = copy.hset;
-        self.multi = copy.multi;
-        self.transaction_mode = copy.transaction_mode;
-        self.execution_mode = copy.execution_mode;
+    pub fn json_push(&mut self, key: String, value: Json) {
+        self.keyspace.insert(key, Data::Json(value));
+    }
+    pub fn json_get(&self, key: &str) -> Result<Option<&Json>, Value> {
+        match self.keyspace.get(key) {
+            Some(Data::Json(doc)) => Ok(Some(doc)),
+            Some(_) => Err(Value::Error(WRONGTYPE)),
+            None => Ok(None),
+        }
+    }
+    pub fn json_get_mut(&mut self, key: &str) -> Result<Option<&mut Json>, Value> {
+        match self.keyspace.get_mut(key) {
+            Some(Data::Json(doc)) => Ok(Some(doc)),
+            Some(_) => Err(Value::Error(WRONGTYPE)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn ts_push(&mut self, key: String, series: TimeSeries) {
+        self.keyspace.insert(key, Data::TimeSeries(series));
+    }
+    pub fn ts_get(&self, key: &str) -> Result<Option<&TimeSeries>, Value> {
+        match self.keyspace.get(key) {
+            Some(Data::TimeSeries(series)) => Ok(Some(series)),
+            Some(_) => Err(Value::Error(WRONGTYPE)),
+            None => Ok(None),
+        }
+    }
+    pub fn ts_get_mut(&mut self, key: &str) -> Result<Option<&mut TimeSeries>, Value> {
+        match self.keyspace.get_mut(key) {
+            Some(Data::TimeSeries(series)) => Ok(Some(series)),
+            Some(_) => Err(Value::Error(WRONGTYPE)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn stream_push(&mut self, key: String, stream: Stream) {
+        self.keyspace.insert(key, Data::Stream(stream));
+    }
+    pub fn stream_get(&self, key: &str) -> Result<Option<&Stream>, Value> {
+        match self.keyspace.get(key) {
+            Some(Data::Stream(stream)) => Ok(Some(stream)),
+            Some(_) => Err(Value::Error(WRONGTYPE)),
+            None => Ok(None),
+        }
+    }
+    pub fn stream_get_mut(&mut self, key: &str) -> Result<Option<&mut Stream>, Value> {
+        match self.keyspace.get_mut(key) {
+            Some(Data::Stream(stream)) => Ok(Some(stream)),
+            Some(_) => Err(Value::Error(WRONGTYPE)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn zset_push(&mut self, key: String, set: SortedSet) {
+        self.keyspace.insert(key, Data::SortedSet(set));
+    }
+    pub fn zset_get(&self, key: &str) -> Result<Option<&SortedSet>, Value> {
+        match self.keyspace.get(key) {
+            Some(Data::SortedSet(set)) => Ok(Some(set)),
+            Some(_) => Err(Value::Error(WRONGTYPE)),
+            None => Ok(None),
+        }
+    }
+    pub fn zset_get_mut(&mut self, key: &str) -> Result<Option<&mut SortedSet>, Value> {
+        match self.keyspace.get_mut(key) {
+            Some(Data::SortedSet(set)) => Ok(Some(set)),
+            Some(_) => Err(Value::Error(WRONGTYPE)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn list_push(&mut self, key: String, list: VecDeque<String>) {
+        self.keyspace.insert(key, Data::List(list));
+    }
+    pub fn list_get(&self, key: &str) -> Result<Option<&VecDeque<String>>, Value> {
+        match self.keyspace.get(key) {
+            Some(Data::List(list)) => Ok(Some(list)),
+            Some(_) => Err(Value::Error(WRONGTYPE)),
+            None => Ok(None),
+        }
+    }
+    pub fn list_get_mut(&mut self, key: &str) -> Result<Option<&mut VecDeque<String>>, Value> {
+        match self.keyspace.get_mut(key) {
+            Some(Data::List(list)) => Ok(Some(list)),
+            Some(_) => Err(Value::Error(WRONGTYPE)),
+            None => Ok(None),
+        }
+    }
+
+    // The `set_*` methods being taken by strings, those of sets are named
+    // after what they hold.
+    pub fn members_push(&mut self, key: String, set: HashSet<String>) {
+        self.keyspace.insert(key, Data::Set(set));
+    }
+    pub fn members_get(&self, key: &str) -> Result<Option<&HashSet<String>>, Value> {
+        match self.keyspace.get(key) {
+            Some(Data::Set(set)) => Ok(Some(set)),
+            Some(_) => Err(Value::Error(WRONGTYPE)),
+            None => Ok(None),
+        }
+    }
+    pub fn members_get_mut(&mut self, key: &str) -> Result<Option<&mut HashSet<String>>, Value> {
+        match self.keyspace.get_mut(key) {
+            Some(Data::Set(set)) => Ok(Some(set)),
+            Some(_) => Err(Value::Error(WRONGTYPE)),
+            None => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scan_returns_every_key_once() {
+        let db = Database::new();
+        for i in 0..1000 {
+            db.write([format!("key:{i}")]).set_push(format!("key:{i}"), Vec::new());
+        }
+
+        for count in [1, 7, 100, 5000] {
+            let (mut cursor, mut seen) = (0, Vec::new());
+            loop {
+                let (next, keys) = db.scan(cursor, count, None, None);
+                assert!(keys.len() <= count, "page of {} for a count of {count}", keys.len());
+                seen.extend(keys);
+                // Keys added halfway through don't make any key come back.
+                if cursor == 0 {
+                    db.write(["added"]).set_push("added".into(), Vec::new());
+                }
+                cursor = next;
+                if cursor == 0 {
+                    break;
+                }
+            }
+            db.write(["added"]).remove("added");
+            seen.retain(|key| key != "added");
+            let len = seen.len();
+            seen.sort();
+            seen.dedup();
+            assert_eq!((len, seen.len()), (1000, 1000), "count of {count}");
+        }
+    }
+
+    #[test]
+    fn scan_follows_keys_as_they_change() {
+        let scan_all = |db: &Database| {
+            let (mut cursor, mut seen) = (0, Vec::new());
+            loop {
+                let (next, keys) = db.scan(cursor, 3, None, None);
+                seen.extend(keys);
+                cursor = next;
+                if cursor == 0 {
+                    seen.sort();
+                    return seen;
+                }
+            }
+        };
+        let (db, other) = (Database::new(), Database::new());
+        for key in ["a", "b", "c", "d"] {
+            db.write([key]).set_push(key.into(), Vec::new());
+        }
+        db.write(["a"]).remove("a");
+        db.write(["b", "e"]).rename("b", "e".into(), false);
+        db.write(["h"]).hset_push("h".into(), "f".into(), "v".into());
+        assert_eq!(scan_all(&db), ["c", "d", "e", "h"]);
+
+        db.write_all().swap(&mut other.write_all());
+        assert!(scan_all(&db).is_empty());
+        other.write_all().clear();
+        assert!(scan_all(&other).is_empty());
+    }
+
+    #[test]
+    fn scan_filters_by_pattern_and_type() {
+        let db = Database::new();
+        db.write(["a:1"]).set_push("a:1".into(), Vec::new());
+        db.write(["b:1"]).set_push("b:1".into(), Vec::new());
+        db.write(["a:2"]).hset_push("a:2".into(), "f".into(), "v".into());
+
+        let (cursor, mut keys) = db.scan(0, 10, Some("a:*"), None);
+        keys.sort();
+        assert_eq!((cursor, keys), (0, vec!["a:1".to_string(), "a:2".to_string()]));
+        assert_eq!(db.scan(0, 10, Some("a:*"), Some("hash")), (0, vec!["a:2".to_string()]));
+    }
+
+    #[test]
+    fn reserved_shards_wait_for_the_reservation_to_be_given_up() {
+        let db = Arc::new(Database::new());
+        let other = (0..).map(|i| format!("other:{i}")).find(|key| shard_index(key) != shard_index("reserved")).unwrap();
+        let reservation = db.reserve(["reserved"]);
+        // The thread holding the reservation still gets at its keys.
+        db.write(["reserved"]).set_push("reserved".into(), b"mine".to_vec());
+
+        let (done, finished) = std::sync::mpsc::channel();
+        let writer = {
+            let (db, other) = (Arc::clone(&db), other.clone());
+            thread::spawn(move || {
+                db.write([&other]).set_push(other.clone(), Vec::new());
+                done.send("other").unwrap();
+                db.write(["reserved"]).set_push("reserved".into(), b"theirs".to_vec());
+                done.send("reserved").unwrap();
+            })
+        };
+        assert_eq!(finished.recv().unwrap(), "other");
+        assert!(finished.recv_timeout(std::time::Duration::from_millis(100)).is_err());
+        assert_eq!(db.read(["reserved"]).set_bytes("reserved").ok().flatten(), Some(&b"mine".to_vec()));
+        drop(reservation);
+        assert_eq!(finished.recv().unwrap(), "reserved");
+        writer.join().unwrap();
+        assert!(db.read_all().contains(&other));
+    }
+
+    #[test]
+    fn reservations_stay_with_their_database_on_swap() {
+        let (a, b) = (Arc::new(Database::new()), Arc::new(Database::new()));
+        a.write(["key"]).set_push("key".into(), Vec::new());
+        let reservation = a.reserve_all();
+        let (mut first, mut second) = (a.write_all(), b.write_all());
+        first.swap(&mut second);
+        drop((first, second));
+
+        // Another thread gets at the swapped keys, but not at the reserved
+        // database.
+        let b = Arc::clone(&b);
+        assert!(thread::spawn(move || b.read_all().contains("key")).join().unwrap());
+        let (done, finished) = std::sync::mpsc::channel();
+        let reader = {
+            let a = Arc::clone(&a);
+            thread::spawn(move || done.send(a.read_all().is_empty()).unwrap())
+        };
+        assert!(finished.recv_timeout(std::time::Duration::from_millis(100)).is_err());
+        drop(reservation);
+        assert!(finished.recv().unwrap());
+        reader.join().unwrap();
     }
 }
@@ -0,0 +1,178 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use crate::bloom::BloomFilter;
+use crate::cuckoo::CuckooFilter;
+use crate::database::Data;
+use crate::json::{Format, Json};
+use crate::stream::Stream;
+use crate::timeseries::TimeSeries;
+use crate::zset::SortedSet;
+
+const STR: u8 = 0;
+const HASH: u8 = 1;
+const BLOOM: u8 = 2;
+const CUCKOO: u8 = 3;
+const JSON: u8 = 4;
+const TIMESERIES: u8 = 5;
+const STREAM: u8 = 6;
+const ZSET: u8 = 7;
+const LIST: u8 = 8;
+const SET: u8 = 9;
+
+const INVALID: &str = "ERR: Invalid serialized value";
+
+/// Reads back what the `put_*` functions wrote, little endian and with
+/// lengths in front of byte strings.
+pub struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes, pos: 0 }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pos == self.bytes.len()
+    }
+
+    pub fn position(&self) -> usize {
+        self.pos
+    }
+
+    fn take(&mut self, n: usize) -> Result<&'a [u8], &'static str> {
+        let end = self.pos.checked_add(n).filter(|end| *end <= self.bytes.len()).ok_or(INVALID)?;
+        let bytes = &self.bytes[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    pub fn u8(&mut self) -> Result<u8, &'static str> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn u32(&mut self) -> Result<u32, &'static str> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    pub fn u64(&mut self) -> Result<u64, &'static str> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    pub fn f64(&mut self) -> Result<f64, &'static str> {
+        Ok(f64::from_bits(self.u64()?))
+    }
+
+    pub fn bytes(&mut self) -> Result<&'a [u8], &'static str> {
+        let len = self.u64()?;
+        self.take(usize::try_from(len).map_err(|_| INVALID)?)
+    }
+
+    pub fn string(&mut self) -> Result<String, &'static str> {
+        String::from_utf8(self.bytes()?.to_vec()).map_err(|_| INVALID)
+    }
+}
+
+pub fn put_u64(out: &mut Vec<u8>, n: u64) {
+    out.extend(n.to_le_bytes());
+}
+
+pub fn put_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    put_u64(out, bytes.len() as u64);
+    out.extend(bytes);
+}
+
+/// Serializes any value of the keyspace, the inverse of [`restore`].
+pub fn dump(data: &Data, out: &mut Vec<u8>) {
+    match data {
+        Data::Str(value) => {
+            out.push(STR);
+            put_bytes(out, value);
+        },
+        Data::Hash(hash) => {
+            out.push(HASH);
+            put_u64(out, hash.len() as u64);
+            for (field, value) in hash {
+                put_bytes(out, field.as_bytes());
+                put_bytes(out, value.as_bytes());
+            }
+        },
+        Data::Bloom(filter) => {
+            out.push(BLOOM);
+            filter.dump(out);
+        },
+        Data::Cuckoo(filter) => {
+            out.push(CUCKOO);
+            filter.dump(out);
+        },
+        Data::Json(json) => {
+            out.push(JSON);
+            put_bytes(out, json.serialize(&Format::default()).as_bytes());
+        },
+        Data::TimeSeries(series) => {
+            out.push(TIMESERIES);
+            series.dump(out);
+        },
+        Data::Stream(stream) => {
+            out.push(STREAM);
+            stream.dump(out);
+        },
+        Data::SortedSet(set) => {
+            out.push(ZSET);
+            set.dump(out);
+        },
+        Data::List(list) => {
+            out.push(LIST);
+            put_u64(out, list.len() as u64);
+            for element in list {
+                put_bytes(out, element.as_bytes());
+            }
+        },
+        Data::Set(set) => {
+            out.push(SET);
+            put_u64(out, set.len() as u64);
+            for member in set {
+                put_bytes(out, member.as_bytes());
+            }
+        },
+    };
+}
+
+pub fn restore(reader: &mut Reader) -> Result<Data, &'static str> {
+    let data = match reader.u8()? {
+        STR => Data::Str(reader.bytes()?.to_vec()),
+        HASH => {
+            let len = reader.u64()?;
+            let mut hash = HashMap::new();
+            for _ in 0..len {
+                hash.insert(reader.string()?, reader.string()?);
+            }
+            Data::Hash(hash)
+        },
+        BLOOM => Data::Bloom(BloomFilter::restore(reader)?),
+        CUCKOO => Data::Cuckoo(CuckooFilter::restore(reader)?),
+        JSON => Data::Json(Json::parse(&reader.string()?)?),
+        TIMESERIES => Data::TimeSeries(TimeSeries::restore(reader)?),
+        STREAM => Data::Stream(Stream::restore(reader)?),
+        ZSET => Data::SortedSet(SortedSet::restore(reader)?),
+        LIST => {
+            let len = reader.u64()?;
+            let mut list = VecDeque::new();
+            for _ in 0..len {
+                list.push_back(reader.string()?);
+            }
+            Data::List(list)
+        },
+        SET => {
+            let len = reader.u64()?;
+            let mut set = HashSet::new();
+            for _ in 0..len {
+                set.insert(reader.string()?);
+            }
+            Data::Set(set)
+        },
+        _ => return Err(INVALID),
+    };
+    Ok(data)
+}
@@ -1,7 +1,7 @@
-use std::io::{Error, ErrorKind};
+use std::io::Error;
 
 pub type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
 
 pub fn new_error(kind: &str) -> Box<Error> {
-    Box::new(Error::new(ErrorKind::Other, kind))
+    Box::new(Error::other(kind))
 }
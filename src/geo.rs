@@ -0,0 +1,303 @@
+// Geohashes as Redis stores them in sorted sets: 26 bits of longitude and
+// 26 of latitude interleaved into a 52 bit score, over the latitudes Web
+// Mercator covers.
+
+pub const LON_MIN: f64 = -180.0;
+pub const LON_MAX: f64 = 180.0;
+pub const LAT_MIN: f64 = -85.05112878;
+pub const LAT_MAX: f64 = 85.05112878;
+
+const STEPS: u32 = 26;
+const EARTH_RADIUS: f64 = 6372797.560856;
+const MERCATOR_MAX: f64 = 20037726.37;
+const BASE32: &[u8] = b"0123456789bcdefghjkmnpqrstuvwxyz";
+
+/// Meters per each unit distances are given in.
+pub fn unit(name: &str) -> Option<f64> {
+    match name.to_lowercase().as_str() {
+        "m" => Some(1.0),
+        "km" => Some(1000.0),
+        "mi" => Some(1609.34),
+        "ft" => Some(0.3048),
+        _ => None,
+    }
+}
+
+pub fn is_valid(lon: f64, lat: f64) -> bool {
+    (LON_MIN..=LON_MAX).contains(&lon) && (LAT_MIN..=LAT_MAX).contains(&lat)
+}
+
+/// Spreads the low 32 bits of `x` over the even bits.
+fn spread(x: u32) -> u64 {
+    let mut x = x as u64;
+    x = (x | (x << 16)) & 0x0000ffff0000ffff;
+    x = (x | (x << 8)) & 0x00ff00ff00ff00ff;
+    x = (x | (x << 4)) & 0x0f0f0f0f0f0f0f0f;
+    x = (x | (x << 2)) & 0x3333333333333333;
+    (x | (x << 1)) & 0x5555555555555555
+}
+
+/// Gathers the even bits of `x`, the inverse of [`spread`].
+fn squash(x: u64) -> u32 {
+    let mut x = x & 0x5555555555555555;
+    x = (x | (x >> 1)) & 0x3333333333333333;
+    x = (x | (x >> 2)) & 0x0f0f0f0f0f0f0f0f;
+    x = (x | (x >> 4)) & 0x00ff00ff00ff00ff;
+    x = (x | (x >> 8)) & 0x0000ffff0000ffff;
+    ((x | (x >> 16)) & 0x00000000ffffffff) as u32
+}
+
+/// A cell of the grid that splits each axis in `1 << step` parts, with
+/// latitude on the even bits and longitude on the odd ones.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Cell {
+    pub bits: u64,
+    pub step: u32,
+}
+
+impl Cell {
+    fn encode(lon: f64, lat: f64, step: u32, lat_range: (f64, f64)) -> Self {
+        let scale = (1u64 << step) as f64;
+        let offset = |value: f64, (min, max): (f64, f64)| (((value - min) / (max - min)) * scale).min(scale - 1.0) as u32;
+        let (lat, lon) = (offset(lat, lat_range), offset(lon, (LON_MIN, LON_MAX)));
+        Self { bits: spread(lat) | (spread(lon) << 1), step }
+    }
+
+    pub fn new(lon: f64, lat: f64, step: u32) -> Self {
+        Self::encode(lon, lat, step, (LAT_MIN, LAT_MAX))
+    }
+
+    /// The longitudes and latitudes the cell spans.
+    fn bounds(&self) -> ((f64, f64), (f64, f64)) {
+        let scale = (1u64 << self.step) as f64;
+        let (lat, lon) = (squash(self.bits) as f64, squash(self.bits >> 1) as f64);
+        let lon_width = (LON_MAX - LON_MIN) / scale;
+        let lat_width = (LAT_MAX - LAT_MIN) / scale;
+        (
+            (LON_MIN + lon * lon_width, LON_MIN + (lon + 1.0) * lon_width),
+            (LAT_MIN + lat * lat_width, LAT_MIN + (lat + 1.0) * lat_width),
+        )
+    }
+
+    /// The cell `dx` columns east and `dy` rows north, wrapping around.
+    fn neighbor(&self, dx: i64, dy: i64) -> Self {
+        let mask = (1u64 << self.step) - 1;
+        let shift = |bits: u32, d: i64| (bits as i64 + d) as u64 & mask;
+        let lat = shift(squash(self.bits), dy);
+        let lon = shift(squash(self.bits >> 1), dx);
+        Self { bits: spread(lat as u32) | (spread(lon as u32) << 1), step: self.step }
+    }
+
+    /// The scores of the members inside the cell, from the first included
+    /// up to the last excluded.
+    pub fn scores(&self) -> (u64, u64) {
+        let shift = 2 * (STEPS - self.step);
+        (self.bits << shift, (self.bits + 1) << shift)
+    }
+}
+
+/// The score a member at `lon`, `lat` is stored with.
+pub fn encode(lon: f64, lat: f64) -> f64 {
+    Cell::new(lon, lat, STEPS).bits as f64
+}
+
+/// The center of the cell a score stands for.
+pub fn decode(score: f64) -> (f64, f64) {
+    let ((lon_min, lon_max), (lat_min, lat_max)) = Cell { bits: score as u64, step: STEPS }.bounds();
+    let lon = ((lon_min + lon_max) / 2.0).clamp(LON_MIN, LON_MAX);
+    let lat = ((lat_min + lat_max) / 2.0).clamp(LAT_MIN, LAT_MAX);
+    (lon, lat)
+}
+
+/// The standard 11 character geohash of a score, which unlike the score
+/// spans latitudes from -90 to 90.
+pub fn geohash(score: f64) -> String {
+    let (lon, lat) = decode(score);
+    let bits = Cell::encode(lon, lat, STEPS, (-90.0, 90.0)).bits;
+    (0..11)
+        .map(|i| {
+            // The last character only has two bits left, padded with zeros.
+            let index = match i {
+                10 => 0,
+                i => (bits >> (52 - (i + 1) * 5)) & 0x1f,
+            };
+            BASE32[index as usize] as char
+        })
+        .collect()
+}
+
+/// The great circle distance in meters, with the haversine formula.
+pub fn distance((lon1, lat1): (f64, f64), (lon2, lat2): (f64, f64)) -> f64 {
+    let (lat1, lat2) = (lat1.to_radians(), lat2.to_radians());
+    let u = ((lat2 - lat1) / 2.0).sin();
+    let v = ((lon2.to_radians() - lon1.to_radians()) / 2.0).sin();
+    2.0 * EARTH_RADIUS * (u * u + lat1.cos() * lat2.cos() * v * v).sqrt().asin()
+}
+
+/// What a search covers around its center, in meters.
+#[derive(Clone, Copy, Debug)]
+pub enum Shape {
+    Radius(f64),
+    Box(f64, f64),
+}
+
+impl Shape {
+    /// The distance from `center` to `point` if the point lies within the
+    /// shape. A box is measured along its meridian and its parallels.
+    pub fn distance(&self, center: (f64, f64), point: (f64, f64)) -> Option<f64> {
+        let distance = distance(center, point);
+        match *self {
+            Shape::Radius(radius) => (distance <= radius).then_some(distance),
+            Shape::Box(width, height) => {
+                let lat_distance = EARTH_RADIUS * (point.1.to_radians() - center.1.to_radians()).abs();
+                let lon_distance = self::distance(point, (center.0, point.1));
+                (lat_distance <= height / 2.0 && lon_distance <= width / 2.0).then_some(distance)
+            },
+        }
+    }
+
+    /// The longitudes and latitudes around `center` that hold the shape.
+    fn bounds(&self, (lon, lat): (f64, f64)) -> ((f64, f64), (f64, f64)) {
+        let (width, height) = match *self {
+            Shape::Radius(radius) => (radius, radius),
+            Shape::Box(width, height) => (width / 2.0, height / 2.0),
+        };
+        let lat_delta = (height / EARTH_RADIUS).to_degrees();
+        // The shape is widest on the side nearer to a pole.
+        let lon_delta = |lat: f64| (width / EARTH_RADIUS / lat.to_radians().cos()).to_degrees();
+        let lon_delta = lon_delta(lat + lat_delta).max(lon_delta(lat - lat_delta));
+        ((lon - lon_delta, lon + lon_delta), (lat - lat_delta, lat + lat_delta))
+    }
+
+    fn radius(&self) -> f64 {
+        match *self {
+            Shape::Radius(radius) => radius,
+            Shape::Box(width, height) => (width / 2.0).hypot(height / 2.0),
+        }
+    }
+}
+
+/// The coarsest grid whose cells are about as big as `radius` at `lat`.
+fn steps(radius: f64, lat: f64) -> u32 {
+    if radius == 0.0 {
+        return STEPS;
+    }
+    let (mut range, mut step) = (radius, 1i32);
+    while range < MERCATOR_MAX {
+        range *= 2.0;
+        step += 1;
+    }
+    // Make sure the range is included in most of the base cases.
+    step -= 2;
+    // Cells narrow towards the poles.
+    if lat.abs() > 66.0 {
+        step -= 1;
+        if lat.abs() > 80.0 {
+            step -= 1;
+        }
+    }
+    step.clamp(1, STEPS as i32) as u32
+}
+
+/// The cells a search of `shape` around `center` has to look in: the one
+/// of the center and its eight neighbors, on a grid coarse enough for them
+/// to cover the whole shape.
+pub fn areas(center: (f64, f64), shape: &Shape) -> Vec<Cell> {
+    let ((lon_min, lon_max), (lat_min, lat_max)) = shape.bounds(center);
+    let mut step = steps(shape.radius(), center.1);
+    let cells = loop {
+        let cell = Cell::new(center.0, center.1, step);
+        let cells: Vec<Cell> = [(0, 0), (0, 1), (0, -1), (1, 0), (-1, 0), (1, 1), (-1, 1), (1, -1), (-1, -1)]
+            .into_iter()
+            .map(|(dx, dy)| cell.neighbor(dx, dy))
+            .collect();
+        let ((_, north), (_, east)) = (cells[1].bounds().1, cells[3].bounds().0);
+        let ((south, _), (west, _)) = (cells[2].bounds().1, cells[4].bounds().0);
+        // Near the edge of a cell its neighbors may not reach far enough.
+        let covered = north >= lat_max && south <= lat_min && east >= lon_max && west <= lon_min;
+        if covered || step == 1 {
+            break cells;
+        }
+        step -= 1;
+    };
+
+    let mut areas: Vec<Cell> = Vec::new();
+    for cell in cells {
+        if !areas.contains(&cell) {
+            areas.push(cell);
+        }
+    }
+    areas
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PALERMO: (f64, f64) = (13.361389, 38.115556);
+    const CATANIA: (f64, f64) = (15.087269, 37.502669);
+
+    #[test]
+    fn encodes_as_redis_does() {
+        assert_eq!(encode(PALERMO.0, PALERMO.1), 3479099956230698.0);
+        assert_eq!(encode(CATANIA.0, CATANIA.1), 3479447370796909.0);
+        let (lon, lat) = decode(3479099956230698.0);
+        assert!((lon - 13.361389338970184).abs() < 1e-12 && (lat - 38.1155563954963).abs() < 1e-12);
+        assert_eq!(geohash(encode(PALERMO.0, PALERMO.1)), "sqc8b49rny0");
+        assert_eq!(geohash(encode(CATANIA.0, CATANIA.1)), "sqdtr74hyu0");
+    }
+
+    #[test]
+    fn measures_distances() {
+        // Members are measured from the center of their cells.
+        let position = |(lon, lat)| decode(encode(lon, lat));
+        let meters = distance(position(PALERMO), position(CATANIA));
+        assert_eq!(format!("{meters:.4}"), "166274.1516");
+        assert_eq!(Shape::Radius(200_000.0).distance(position(PALERMO), position(CATANIA)), Some(meters));
+        assert_eq!(Shape::Radius(100_000.0).distance(PALERMO, CATANIA), None);
+        assert!(Shape::Box(400_000.0, 400_000.0).distance(PALERMO, CATANIA).is_some());
+        assert!(Shape::Box(400_000.0, 100_000.0).distance(PALERMO, CATANIA).is_none());
+    }
+
+    #[test]
+    fn search_areas_cover_the_shape() {
+        let shape = Shape::Radius(200_000.0);
+        let areas = areas((15.0, 37.0), &shape);
+        assert_eq!(areas.len(), 9);
+        for point in [PALERMO, CATANIA] {
+            let score = encode(point.0, point.1) as u64;
+            assert!(areas.iter().any(|cell| (cell.scores().0..cell.scores().1).contains(&score)));
+        }
+    }
+
+    #[test]
+    fn search_areas_hold_every_point_in_the_shape() {
+        let mut seed = 42u64;
+        let mut random = |min: f64, max: f64| {
+            seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            min + (seed >> 11) as f64 / (1u64 << 53) as f64 * (max - min)
+        };
+        for _ in 0..2000 {
+            let center = (random(LON_MIN, LON_MAX), random(LAT_MIN, LAT_MAX));
+            let size = 10f64.powf(random(0.0, 6.5));
+            let shape = match random(0.0, 1.0) < 0.5 {
+                true => Shape::Radius(size),
+                false => Shape::Box(size, size * random(0.2, 2.0)),
+            };
+            let areas = areas(center, &shape);
+            for _ in 0..20 {
+                let angle = random(0.0, 360.0_f64).to_radians();
+                let lat_step = (random(0.0, size) / EARTH_RADIUS).to_degrees();
+                let point = (center.0 + lat_step * angle.cos() / center.1.to_radians().cos(), center.1 + lat_step * angle.sin());
+                if !is_valid(point.0, point.1) {
+                    continue;
+                }
+                let score = encode(point.0, point.1);
+                if shape.distance(center, decode(score)).is_some() {
+                    let inside = areas.iter().any(|cell| (cell.scores().0..cell.scores().1).contains(&(score as u64)));
+                    assert!(inside, "{point:?} around {center:?} in {shape:?}");
+                }
+            }
+        }
+    }
+}
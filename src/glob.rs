@@ -0,0 +1,86 @@
+/// Glob style matching with the same rules as Redis: `*`, `?`, `[...]`
+/// classes with ranges and `^` negation, and `\` to escape.
+pub fn glob_match(pattern: &[u8], string: &[u8]) -> bool {
+    let (mut p, mut s) = (0, 0);
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while s < string.len() {
+        let matched = match pattern.get(p) {
+            Some(b'*') => {
+                while pattern.get(p) == Some(&b'*') {
+                    p += 1;
+                }
+                if p == pattern.len() {
+                    return true;
+                }
+                backtrack = Some((p, s));
+                continue;
+            },
+            Some(b'?') => {
+                p += 1;
+                true
+            },
+            Some(b'[') => match class_match(&pattern[p + 1..], string[s]) {
+                Some((matched, len)) => {
+                    p += len + 1;
+                    matched
+                },
+                None => false,
+            },
+            Some(b'\\') if p + 1 < pattern.len() => {
+                p += 2;
+                pattern[p - 1] == string[s]
+            },
+            Some(c) => {
+                p += 1;
+                *c == string[s]
+            },
+            None => false,
+        };
+
+        if matched {
+            s += 1;
+            continue;
+        }
+        match backtrack {
+            Some((bp, bs)) => {
+                p = bp;
+                s = bs + 1;
+                backtrack = Some((bp, bs + 1));
+            },
+            None => return false,
+        }
+    }
+
+    pattern[p..].iter().all(|c| *c == b'*')
+}
+
+/// Matches `c` against a class body (right after the `[`), returning whether
+/// it matched and how many pattern bytes the class used, closing `]` included.
+fn class_match(class: &[u8], c: u8) -> Option<(bool, usize)> {
+    let mut i = 0;
+    let negate = class.first() == Some(&b'^');
+    if negate {
+        i += 1;
+    }
+
+    let mut matched = false;
+    while i < class.len() && class[i] != b']' {
+        if class[i] == b'\\' && i + 1 < class.len() {
+            matched |= class[i + 1] == c;
+            i += 2;
+        } else if i + 2 < class.len() && class[i + 1] == b'-' && class[i + 2] != b']' {
+            let (start, end) = (class[i].min(class[i + 2]), class[i].max(class[i + 2]));
+            matched |= (start..=end).contains(&c);
+            i += 3;
+        } else {
+            matched |= class[i] == c;
+            i += 1;
+        }
+    }
+
+    if i == class.len() {
+        return None;
+    }
+    Some((matched != negate, i + 1))
+}
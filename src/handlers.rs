@@ -1,76 +1,653 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::ops::Bound;
 use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
 
-use crate::aof::AOF;
-use crate::database::Database;
+use crate::acl::{self, Acl, Denial};
+use crate::aof::{self, AOF};
+use crate::bloom;
+use crate::cuckoo;
+use crate::database::{Data, Database, Keyspace, Reservation};
+use crate::geo::{self, Shape};
+use crate::json::{self, Json, Path};
 use crate::resp::Value;
+use crate::snapshot::Snapshot;
+use crate::stream::{self, Claim, Fields, NewId, Stream, StreamId, Trim};
+use crate::timeseries::{self, Aggregation, DuplicatePolicy, TimeSeries};
+use crate::zset::SortedSet;
 
 type Aof = Arc<RwLock<AOF>>;
-type DB = Arc<RwLock<Database>>;
+type DB = Arc<Database>;
+pub type Databases = Arc<Vec<DB>>;
 
 type Handler = fn(Vec<Value>, DB) -> Value;
 
+/// Handlers of the write commands whose effects depend on the time or on
+/// what the database held. Besides the reply they give the commands the AOF
+/// logs in their place, which have the same effects when replayed.
+type Propagated = fn(Vec<Value>, DB) -> (Value, Vec<Value>);
+
+/// Commands that work on the connection, on more than one database or
+/// on persistence, which is why they can't be plain handlers over the
+/// selected one.
+const CONNECTION_COMMANDS: [&str; 15] = ["SELECT", "MOVE", "SWAPDB", "FLUSHALL", "COPY", "MULTI", "EXEC", "DISCARD",
+    "AUTH", "ACL", "INFO", "BGREWRITEAOF", "SAVE", "BGSAVE", "LASTSAVE"];
+
+/// Commands that change the databases, which are the ones the AOF logs.
+const WRITE_COMMANDS: [&str; 54] = ["SET", "HSET", "DEL", "UNLINK", "RENAME", "RENAMENX", "COPY", "MOVE", "SWAPDB",
+    "FLUSHDB", "FLUSHALL", "HDEL", "INCR", "INCRBY", "DECR", "DECRBY", "PFADD", "PFMERGE",
+    "BF.RESERVE", "BF.ADD", "BF.MADD", "CF.RESERVE", "CF.ADD", "CF.ADDNX", "CF.INSERT", "CF.INSERTNX", "CF.DEL",
+    "JSON.SET", "JSON.DEL", "JSON.NUMINCRBY", "JSON.ARRAPPEND", "JSON.STRAPPEND",
+    "TS.CREATE", "TS.ADD", "TS.MADD", "TS.INCRBY",
+    "XADD", "XDEL", "XTRIM", "XGROUP", "XREADGROUP", "XACK", "XCLAIM", "XAUTOCLAIM",
+    "ZADD", "ZREM", "GEOADD", "GEOSEARCHSTORE", "LPUSH", "RPUSH", "LPOP", "RPOP", "SADD", "SREM"];
+
+/// A blocking XREAD or XREADGROUP waiting for entries, without its BLOCK
+/// option so it runs as a plain read each time it's tried again.
+struct Blocked {
+    command: Value,
+    deadline: Option<Instant>,
+}
+
 pub struct Handlers<'a> {
     handlers: HashMap<&'a str, Handler>,
+    propagated: HashMap<&'a str, Propagated>,
+    selected: usize,
+    multi: Vec<(Value, Vec<Value>)>,
+    transaction_mode: bool,
+    execution_mode: bool,
+    acl: Option<Arc<RwLock<Acl>>>,
+    user: Option<String>,
+    snapshot: Option<Arc<Snapshot>>,
+    blocked: Option<Blocked>,
+}
+
+impl Default for Handlers<'_> {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl<'a> Handlers<'a> {
     pub fn new() -> Self {
         Handlers{
             handlers: HashMap::new(),
+            propagated: HashMap::new(),
+            selected: 0,
+            multi: Vec::new(),
+            transaction_mode: false,
+            execution_mode: false,
+            acl: None,
+            user: None,
+            snapshot: None,
+            blocked: None,
         }
     }
 
-    pub fn match_handler(&mut self, input: Value, aof: Aof, db: DB) -> Value {
-        let Value::Array(arr) = input.clone() else {
-            return Value::Error("ERR: Only arrays should be used");
+    pub fn match_handler(&mut self, input: Value, aof: Aof, dbs: &Databases) -> Value {
+        let (cmd, arr) = match parse_command(&input) {
+            Ok(command) => command,
+            Err(err) => return Value::Error(err),
         };
-        if arr.len() == 0 {
-            return Value::Error("ERR: An empty array was provided");
+        if self.acl.is_some() && self.user.is_none() && cmd != "AUTH" {
+            return Value::Error("NOAUTH: Authentication required");
+        }
+        if !self.exists(&cmd) && !CONNECTION_COMMANDS.contains(&cmd.as_str()) {
+            return Value::Error("ERR: Command does not exist");
+        }
+        if let Err(err) = self.check_permissions(&cmd, &arr[1..]) {
+            return err;
+        }
+
+        if &cmd == "EXEC" || &cmd == "DISCARD" {
+            self.transaction_mode = false;
+        }
+
+        let args = &arr[1..];
+        if self.transaction_mode {
+            if &cmd == "MULTI" {
+                return Value::Error("ERR: MULTI calls can not be nested");
+            }
+            self.multi.push((arr[0].clone(), args.to_vec()));
+            return Value::Str("QUEUED");
+        }
+        if &cmd == "MULTI" || &cmd == "DISCARD" {
+            return self.multi(&cmd, args);
+        }
+        if &cmd == "EXEC" {
+            // No other client gets at the keys of the transaction until it is
+            // applied or reverted, so reverting can't undo their writes.
+            let writes = aof::lock_writes();
+            let queued = self.multi.iter().filter_map(|(cmd, args)| match cmd {
+                Value::BulkStr(name) => Some((name.to_uppercase(), args.as_slice())),
+                _ => None,
+            });
+            let reserved = reserve(queued, self.selected, dbs);
+            let result = self.exec(args.to_vec(), Arc::clone(&aof), dbs);
+            drop((reserved, writes));
+            rewrite_if_grown(&aof, dbs);
+            return result;
+        }
+        if &cmd == "INFO" {
+            return info(args, &aof, self.snapshot.as_deref());
+        }
+        if &cmd == "BGREWRITEAOF" {
+            return self.bgrewriteaof(args, &aof, dbs);
+        }
+        if ["SAVE", "BGSAVE", "LASTSAVE"].contains(&cmd.as_str()) {
+            return self.save(&cmd, args, dbs);
+        }
+        // Inside a transaction BLOCK is left to the handlers, which ignore it.
+        if (&cmd == "XREAD" || &cmd == "XREADGROUP") && !self.execution_mode {
+            if let Ok(ReadOptions { block: Some((position, timeout)), .. }) = read_options(args, &cmd == "XREADGROUP") {
+                return self.block(&cmd, arr, position, timeout, aof, dbs);
+            }
         }
 
-        let Value::BulkStr(command) = &arr[0] else {
-            return Value::Error("ERR: The command must be a bulk string");
+        let mut arr = arr.clone();
+        let input = match resolve_timestamps(&cmd, &mut arr) {
+            true => Value::Array(arr.clone()),
+            false => input,
         };
+        let args = &arr[1..];
+
+        if !WRITE_COMMANDS.contains(&cmd.as_str()) {
+            return self.dispatch(&cmd, args.to_vec(), dbs);
+        }
+        if let Some(snapshot) = &self.snapshot {
+            snapshot.changed();
+        }
+        if let Some(&handler) = self.propagated.get(cmd.as_str()) {
+            return self.propagate(handler, args.to_vec(), &aof, dbs);
+        }
+        if self.execution_mode {
+            aof.write().unwrap().enqueue(self.selected, input);
+            let result = self.dispatch(&cmd, args.to_vec(), dbs);
+            self.wake_blocked(&cmd, dbs);
+            return result;
+        }
+
+        // The keys are reserved before the command is logged, so commands
+        // over the same keys are logged in the order they are applied.
+        let writes = aof::lock_writes();
+        let reserved = reserve([(cmd.clone(), args)], self.selected, dbs);
+        if aof.write().unwrap().write(self.selected, input).is_err() {
+            return Value::Error("ERR: Failed to append to AOF");
+        }
+        let result = self.dispatch(&cmd, args.to_vec(), dbs);
+        drop((reserved, writes));
+        self.wake_blocked(&cmd, dbs);
+        rewrite_if_grown(&aof, dbs);
+        result
+    }
+
+    /// Runs a command that tells what the AOF should log for it. Other
+    /// writes wait meanwhile, so the log has the effects in the order they
+    /// happened.
+    fn propagate(&mut self, handler: Propagated, args: Vec<Value>, aof: &Aof, dbs: &Databases) -> Value {
+        let writes = (!self.execution_mode).then(aof::pause_writes);
+        let (result, effects) = handler(args, Arc::clone(&dbs[self.selected]));
+        if effects.is_empty() {
+            return result;
+        }
+
+        let mut log = aof.write().unwrap();
+        for effect in effects {
+            match self.execution_mode {
+                true => log.enqueue(self.selected, effect),
+                false => {
+                    if log.write(self.selected, effect).is_err() {
+                        return Value::Error("ERR: Failed to append to AOF");
+                    }
+                },
+            };
+        }
+        drop(log);
+        drop(writes);
+        dbs[self.selected].wake_waiting();
+        if !self.execution_mode {
+            rewrite_if_grown(aof, dbs);
+        }
+        result
+    }
 
-        let cmd = command.to_uppercase();
-        let handler = match self.get(&cmd) {
-            Some(h) => h,
-            None => return Value::Error("ERR: Command does not exist"),
+    /// Lets the clients blocked on the databases a write may have changed
+    /// check them again.
+    fn wake_blocked(&self, cmd: &str, dbs: &Databases) {
+        match CONNECTION_COMMANDS.contains(&cmd) {
+            true => dbs.iter().for_each(|db| db.wake_waiting()),
+            false => dbs[self.selected].wake_waiting(),
         };
+    }
 
-        if &cmd == "EXEC" || &cmd == "DISCARD" {
-            db.write().unwrap().set_transaction_mode(false);
+    /// Runs a blocking XREAD or XREADGROUP without BLOCK, keeping it to try
+    /// again when there was nothing to read. The connection doesn't get a
+    /// reply until then, and `$` stands for the last ID each stream has
+    /// now, so only what's added from here on counts.
+    fn block(&mut self, cmd: &str, mut arr: Vec<Value>, position: usize, timeout: u64, aof: Aof, dbs: &Databases) -> Value {
+        arr.drain(position + 1..position + 3);
+        if cmd == "XREAD" {
+            let keys: Vec<String> = acl::keys(cmd, &arr[1..]).into_iter().map(String::from).collect();
+            let keyspace = dbs[self.selected].read(&keys);
+            let ids = arr.len() - keys.len();
+            for (key, id) in keys.iter().zip(&mut arr[ids..]) {
+                if matches!(id, Value::BulkStr(id) if id == "$") {
+                    let last = match keyspace.stream_get(key) {
+                        Ok(Some(stream)) => stream.last_id(),
+                        _ => StreamId::MIN,
+                    };
+                    *id = Value::BulkStr(last.to_string());
+                }
+            }
         }
 
-        let args = &arr[1..];
-        if db.read().unwrap().is_transaction_mode() {
-            db.write().unwrap().multi_push(Value::BulkStr(command.into()), args.to_vec());
-            return Value::Str("QUEUED");
+        let command = Value::Array(arr);
+        let result = self.match_handler(command.clone(), aof, dbs);
+        if let Value::Null = result {
+            let deadline = (timeout > 0).then(|| Instant::now() + Duration::from_millis(timeout));
+            self.blocked = Some(Blocked { command, deadline });
+        }
+        result
+    }
+
+    /// Whether the connection waits on a blocked command, which has no
+    /// reply yet.
+    pub fn is_blocked(&self) -> bool {
+        self.blocked.is_some()
+    }
+
+    /// The database whose writes may unblock the connection.
+    pub fn blocked_on(&self) -> Option<usize> {
+        self.blocked.as_ref().map(|_| self.selected)
+    }
+
+    /// When the blocked command times out, if it ever does.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.blocked.as_ref().and_then(|blocked| blocked.deadline)
+    }
+
+    /// Runs the blocked command again, giving its reply once it read
+    /// something or timed out.
+    pub fn retry_blocked(&mut self, aof: Aof, dbs: &Databases) -> Option<Value> {
+        let blocked = self.blocked.take()?;
+        if blocked.deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            return Some(Value::Null);
+        }
+        match self.match_handler(blocked.command.clone(), aof, dbs) {
+            Value::Null => {
+                self.blocked = Some(blocked);
+                None
+            },
+            reply => Some(reply),
+        }
+    }
+
+    fn bgrewriteaof(&mut self, args: &[Value], aof: &Aof, dbs: &Databases) -> Value {
+        if !args.is_empty() {
+            return Value::Error("ERR: Wrong number of arguments provided");
+        }
+        // The rewrite pauses the writes, which waits for the transaction.
+        if self.execution_mode {
+            return Value::Error("ERR: BGREWRITEAOF can't run inside a transaction");
+        }
+        match AOF::rewrite(aof, dbs) {
+            Ok(()) => Value::Str("Background append only file rewriting started"),
+            Err(err) => Value::Error(err),
+        }
+    }
+
+    fn save(&mut self, cmd: &str, args: &[Value], dbs: &Databases) -> Value {
+        if !args.is_empty() {
+            return Value::Error("ERR: Wrong number of arguments provided");
+        }
+        let Some(snapshot) = &self.snapshot else {
+            return Value::Error("ERR: Snapshots are not available");
+        };
+        if cmd == "LASTSAVE" {
+            return Value::Num(snapshot.last_save() as i64);
+        }
+        // The copy pauses the writes, which waits for the transaction.
+        if self.execution_mode {
+            return Value::Error("ERR: SAVE and BGSAVE can't run inside a transaction");
+        }
+        let result = match cmd {
+            "SAVE" => snapshot.save(dbs).map(|_| "OK"),
+            _ => Snapshot::bgsave(snapshot, dbs).map(|_| "Background saving started"),
+        };
+        match result {
+            Ok(reply) => Value::Str(reply),
+            Err(err) => Value::Error(err),
+        }
+    }
+
+    /// Runs a command read back from the AOF in the database it was logged
+    /// for, checked like a client's but never logged again. Only what can
+    /// have been logged is accepted, while a command that failed when it ran
+    /// just fails again.
+    pub fn replay(&mut self, index: usize, input: Value, dbs: &Databases) -> Result<(), &'static str> {
+        let (cmd, arr) = parse_command(&input)?;
+        if index >= dbs.len() {
+            return Err("ERR: DB index is out of range");
+        }
+        if cmd == aof::RESTORE_OPCODE {
+            return restore(&arr[1..], &dbs[index]);
+        }
+        if !WRITE_COMMANDS.contains(&cmd.as_str()) {
+            return Err("ERR: Only write commands can be replayed");
+        }
+        self.selected = index;
+        self.dispatch(&cmd, arr[1..].to_vec(), dbs);
+        Ok(())
+    }
+
+    /// Runs a command against the selected database without logging it.
+    pub fn dispatch(&mut self, cmd: &str, args: Vec<Value>, dbs: &Databases) -> Value {
+        match cmd {
+            "SELECT" => self.select(args, dbs),
+            "MOVE" => self.move_key(args, dbs),
+            "SWAPDB" => swapdb(args, dbs),
+            "FLUSHALL" => flushall(args, dbs),
+            "COPY" => self.copy(args, dbs),
+            "AUTH" => self.auth(args),
+            "ACL" => self.acl(args),
+            _ => match (self.get(cmd), self.propagated.get(cmd), dbs.get(self.selected)) {
+                (Some(handler), _, Some(db)) => handler(args, Arc::clone(db)),
+                (_, Some(handler), Some(db)) => handler(args, Arc::clone(db)).0,
+                (None, None, _) => Value::Error("ERR: Command does not exist"),
+                (_, _, None) => Value::Error("ERR: DB index is out of range"),
+            },
+        }
+    }
+
+    pub fn set_selected(&mut self, index: usize) {
+        self.selected = index;
+    }
+
+    /// Counts write commands for the `save` rules, and is where `SAVE` and
+    /// `BGSAVE` write to.
+    pub fn set_snapshot(&mut self, snapshot: Arc<Snapshot>) {
+        self.snapshot = Some(snapshot);
+    }
+
+    /// Checks every command against the users of `acl`, logging the
+    /// connection in as `default` when that user needs no password.
+    pub fn set_acl(&mut self, acl: Arc<RwLock<Acl>>) {
+        self.user = acl.read().unwrap().initial_user();
+        self.acl = Some(acl);
+    }
+
+    fn check_permissions(&mut self, cmd: &str, args: &[Value]) -> std::result::Result<(), Value> {
+        let (Some(acl), Some(user)) = (&self.acl, &self.user) else {
+            return Ok(());
+        };
+        if cmd == "AUTH" {
+            return Ok(());
+        }
+
+        let denial = match acl.read().unwrap().check(user, cmd, args) {
+            Ok(()) => return Ok(()),
+            Err(denial) => denial,
+        };
+        acl.write().unwrap().log(&denial, user, cmd);
+        match denial {
+            Denial::Command => Err(Value::Error("NOPERM: This user has no permissions to run this command")),
+            Denial::Key(_) => Err(Value::Error("NOPERM: No permissions to access a key")),
+            Denial::Auth => {
+                self.user = None;
+                Err(Value::Error("NOAUTH: Authentication required"))
+            },
+        }
+    }
+
+    fn auth(&mut self, args: Vec<Value>) -> Value {
+        let (user, password) = match args.as_slice() {
+            [Value::BulkStr(password)] => ("default", password),
+            [Value::BulkStr(user), Value::BulkStr(password)] => (user.as_str(), password),
+            _ => return Value::Error("ERR: Wrong number of arguments provided"),
+        };
+        let Some(acl) = &self.acl else {
+            return Value::Error("ERR: AUTH called without any password configured for the default user");
+        };
+        if args.len() == 1 && acl.read().unwrap().initial_user().is_some() {
+            return Value::Error("ERR: AUTH called without any password configured for the default user");
+        }
+
+        if !acl.read().unwrap().authenticate(user, password) {
+            acl.write().unwrap().log(&Denial::Auth, user, "AUTH");
+            return Value::Error("WRONGPASS: Invalid username-password pair or user is disabled");
         }
-            
-        let command_list = vec!["SET", "HSET", "DEL", "HDEL", "INCR", "INCRBY", "DECR", "DECRBY"];
-        if command_list.contains(&cmd.as_str()) {
-            if db.read().unwrap().is_execution_mode() {
-                aof.write().unwrap().enqueue(input);
-            } else {
-                match aof.write().unwrap().write(input) {
-                    Ok(_) => (),
-                    Err(_) => return Value::Error("ERR: Failed to append to AOF"),
+        self.user = Some(user.into());
+        Value::Str("OK")
+    }
+
+    fn acl(&mut self, args: Vec<Value>) -> Value {
+        let Some(acl) = &self.acl else {
+            return Value::Error("ERR: ACL is not enabled for this connection");
+        };
+        let Some(args) = bulk_strings(&args) else {
+            return Value::Error("ERR: Arguments must be bulk strings");
+        };
+        let Some((subcommand, args)) = args.split_first() else {
+            return Value::Error("ERR: Wrong number of arguments provided");
+        };
+
+        match (subcommand.to_uppercase().as_str(), args) {
+            ("SETUSER", [name, rules @ ..]) => match acl.write().unwrap().set_user(name, rules) {
+                Ok(()) => Value::Str("OK"),
+                Err(err) => Value::Error(err),
+            },
+            ("GETUSER", [name]) => acl.read().unwrap().user_info(name),
+            ("DELUSER", names) if !names.is_empty() => {
+                if names.iter().any(|name| name == "default") {
+                    return Value::Error("ERR: The 'default' user cannot be removed");
+                }
+                let mut acl = acl.write().unwrap();
+                Value::Num(names.iter().filter(|name| acl.delete_user(name)).count() as i64)
+            },
+            ("LIST", []) => Value::Array(acl.read().unwrap().list().into_iter().map(Value::BulkStr).collect()),
+            ("USERS", []) => Value::Array(acl.read().unwrap().usernames().into_iter().map(Value::BulkStr).collect()),
+            ("WHOAMI", []) => match &self.user {
+                Some(user) => Value::BulkStr(user.clone()),
+                None => Value::Null,
+            },
+            ("CAT", []) => Value::Array(acl::CATEGORIES.iter().map(|(name, _)| Value::BulkStr(name.to_string())).collect()),
+            ("CAT", [category]) => {
+                let commands: Vec<&str> = match category.to_lowercase().as_str() {
+                    "all" => acl::CATEGORIES.iter().flat_map(|(_, commands)| commands.iter().copied()).collect(),
+                    name => match acl::CATEGORIES.iter().find(|(c, _)| *c == name) {
+                        Some((_, commands)) => commands.to_vec(),
+                        None => return Value::Error("ERR: Unknown category"),
+                    },
                 };
+                let mut commands: Vec<String> = commands.into_iter().map(str::to_lowercase).collect();
+                commands.sort();
+                commands.dedup();
+                Value::Array(commands.into_iter().map(Value::BulkStr).collect())
+            },
+            ("LOG", []) => acl.read().unwrap().log_entries(10),
+            ("LOG", [option]) if option.eq_ignore_ascii_case("RESET") => {
+                acl.write().unwrap().reset_log();
+                Value::Str("OK")
+            },
+            ("LOG", [count]) => match count.parse() {
+                Ok(count) => acl.read().unwrap().log_entries(count),
+                Err(_) => Value::Error("ERR: Value is not an integer or out of range"),
+            },
+            ("SAVE", []) => match acl.read().unwrap().save() {
+                Ok(()) => Value::Str("OK"),
+                Err(_) => Value::Error("ERR: Failed to save the ACL file"),
+            },
+            ("LOAD", []) => match acl.write().unwrap().load() {
+                Ok(()) => Value::Str("OK"),
+                Err(_) => Value::Error("ERR: Failed to load the ACL file, the current users were kept"),
+            },
+            ("SETUSER" | "GETUSER" | "DELUSER" | "LIST" | "USERS" | "WHOAMI" | "CAT" | "LOG" | "SAVE" | "LOAD", _) => {
+                Value::Error("ERR: Wrong number of arguments provided")
+            },
+            _ => Value::Error("ERR: Unknown ACL subcommand"),
+        }
+    }
+
+    fn select(&mut self, args: Vec<Value>, dbs: &Databases) -> Value {
+        if args.len() != 1 {
+            return Value::Error("ERR: Wrong number of arguments provided");
+        }
+
+        match db_index(&args[0], dbs) {
+            Ok(index) => {
+                self.selected = index;
+                Value::Str("OK")
+            },
+            Err(err) => err,
+        }
+    }
+
+    fn move_key(&mut self, args: Vec<Value>, dbs: &Databases) -> Value {
+        if args.len() != 2 {
+            return Value::Error("ERR: Wrong number of arguments provided");
+        }
+
+        let Value::BulkStr(key) = &args[0] else {
+            return Value::Error("ERR: Incorrect definition for key");
+        };
+        let index = match db_index(&args[1], dbs) {
+            Ok(index) => index,
+            Err(err) => return err,
+        };
+        if index == self.selected {
+            return Value::Error("ERR: Source and destination objects are the same");
+        }
+
+        let (mut source, mut destination) = lock_pair(dbs, self.selected, index, |db| db.write([key]));
+        Value::Num(source.move_into(key, &mut destination) as i64)
+    }
+
+    fn copy(&mut self, args: Vec<Value>, dbs: &Databases) -> Value {
+        if args.len() < 2 {
+            return Value::Error("ERR: Wrong number of arguments provided");
+        }
+
+        let Some(args) = bulk_strings(&args) else {
+            return Value::Error("ERR: Arguments must be bulk strings");
+        };
+        let (mut index, mut replace) = (self.selected, false);
+        let mut options = args[2..].iter();
+        while let Some(option) = options.next() {
+            match option.to_uppercase().as_str() {
+                "REPLACE" => replace = true,
+                "DB" => match options.next().map(|i| db_index(&Value::BulkStr(i.clone()), dbs)) {
+                    Some(Ok(i)) => index = i,
+                    Some(Err(err)) => return err,
+                    None => return Value::Error("ERR: Syntax error"),
+                },
+                _ => return Value::Error("ERR: Syntax error"),
+            };
+        }
+
+        if index == self.selected {
+            if args[0] == args[1] {
+                return Value::Error("ERR: Source and destination objects are the same");
+            }
+            let copied = dbs[index].write(&args[..2]).copy(&args[0], args[1].clone(), replace);
+            return Value::Num(copied as i64);
+        }
+        let (source, mut destination) = lock_pair(dbs, self.selected, index, |db| db.write(&args[..2]));
+        Value::Num(source.copy_into(&args[0], &mut destination, args[1].clone(), replace) as i64)
+    }
+
+    /// MULTI starts queueing the commands of this connection and DISCARD
+    /// drops them, so other connections keep running theirs.
+    fn multi(&mut self, cmd: &str, args: &[Value]) -> Value {
+        if !args.is_empty() {
+            return Value::Error("ERR: Wrong number of arguments");
+        }
+
+        match cmd {
+            "MULTI" => self.transaction_mode = true,
+            _ => self.multi.clear(),
+        };
+        Value::Str("OK")
+    }
+
+    fn exec(&mut self, args: Vec<Value>, aof: Aof, dbs: &Databases) -> Value {
+        if !args.is_empty() {
+            return Value::Error("ERR: Wrong number of arguments");
+        }
+
+        let transaction = std::mem::take(&mut self.multi);
+        self.execution_mode = true;
+        let mut undo = Undo::default();
+
+        let mut values: Vec<Value> = Vec::new();
+        for (cmd, args) in transaction.into_iter() {
+            if let Value::BulkStr(name) = &cmd {
+                let name = name.to_uppercase();
+                if WRITE_COMMANDS.contains(&name.as_str()) {
+                    for (index, keys) in self.written_keys(&name, &args, dbs) {
+                        undo.record(dbs, index, keys);
+                    }
+                }
+            }
+
+            let mut input: Vec<Value> = Vec::new();
+            input.push(cmd);
+            input.extend(args);
+            let command = Value::Array(input);
+
+            let value = self.match_handler(command, aof.clone(), dbs);
+            if let Value::Error(_) = value {
+                undo.revert(dbs);
+                aof.write().unwrap().discard_queued();
+                break;
             }
+            values.push(value)
+        }
+
+        self.execution_mode = false;
+
+        match aof.write().unwrap().write_queued() {
+            Ok(_) => Value::Array(values),
+            Err(_) => Value::Error("ERR: Failed to write to AOF"),
+        }
+    }
+
+    /// The keys a write command of a transaction can change, by database.
+    /// Commands over whole databases take every key of them, and SWAPDB
+    /// the keys of both in each, as either may end up in the other.
+    fn written_keys(&self, cmd: &str, args: &[Value], dbs: &Databases) -> Vec<(usize, Vec<String>)> {
+        let every_key = |index: usize| dbs[index].read_all().keys("*");
+        let index_at = |i: usize| args.get(i).and_then(|value| db_index(value, dbs).ok());
+
+        if cmd == "SWAPDB" {
+            return match (index_at(0), index_at(1)) {
+                (Some(a), Some(b)) => {
+                    let keys: Vec<String> = every_key(a).into_iter().chain(every_key(b)).collect();
+                    vec![(a, keys.clone()), (b, keys)]
+                },
+                _ => Vec::new(),
+            };
         }
-        handler(args.to_vec(), db)
+        touched_keys(cmd, args, self.selected, dbs).into_iter()
+            .map(|(index, keys)| (index, keys.unwrap_or_else(|| every_key(index))))
+            .collect()
     }
 
     fn insert(&mut self, key: &'a str, handler: Handler) {
         self.handlers.insert(key, handler);
     }
 
+    fn insert_propagated(&mut self, key: &'a str, handler: Propagated) {
+        self.propagated.insert(key, handler);
+    }
+
     pub fn get(&self, key: &'a str) -> Option<&Handler> {
         self.handlers.get(key)
     }
 
+    fn exists(&self, key: &str) -> bool {
+        self.handlers.contains_key(key) || self.propagated.contains_key(key)
+    }
+
     pub fn init(&mut self) {
         self.insert("COMMAND", command);
 
@@ -80,6 +657,14 @@ impl<'a> Handlers<'a> {
         self.insert("HLEN", hlen);
         self.insert("FLUSHDB", flushdb);
         self.insert("EXISTS", exists);
+        self.insert("TYPE", type_);
+        self.insert("KEYS", keys);
+        self.insert("SCAN", scan);
+        self.insert("RENAME", rename);
+        self.insert("RENAMENX", renamenx);
+        self.insert("RANDOMKEY", randomkey);
+        self.insert("TOUCH", touch);
+        self.insert("UNLINK", del);
         self.insert("HEXISTS", hexists);
         self.insert("SET", set);
         self.insert("HSET", hset);
@@ -91,9 +676,238 @@ impl<'a> Handlers<'a> {
         self.insert("INCRBY", incr_by);
         self.insert("DECR", decr);
         self.insert("DECRBY", decr_by);
-        self.insert("MULTI", multi);
-        self.insert("EXEC", exec);
-        self.insert("DISCARD", discard);
+        self.insert("PFADD", pfadd);
+        self.insert("PFCOUNT", pfcount);
+        self.insert("PFMERGE", pfmerge);
+        self.insert("BF.RESERVE", bf_reserve);
+        self.insert("BF.ADD", bf_add);
+        self.insert("BF.MADD", bf_madd);
+        self.insert("BF.EXISTS", bf_exists);
+        self.insert("BF.MEXISTS", bf_mexists);
+        self.insert("BF.INFO", bf_info);
+        self.insert("CF.RESERVE", cf_reserve);
+        self.insert("CF.ADD", cf_add);
+        self.insert("CF.ADDNX", cf_addnx);
+        self.insert("CF.INSERT", cf_insert);
+        self.insert("CF.INSERTNX", cf_insertnx);
+        self.insert("CF.EXISTS", cf_exists);
+        self.insert("CF.MEXISTS", cf_mexists);
+        self.insert("CF.DEL", cf_del);
+        self.insert("CF.COUNT", cf_count);
+        self.insert("CF.INFO", cf_info);
+        self.insert("JSON.SET", json_set);
+        self.insert("JSON.GET", json_get);
+        self.insert("JSON.DEL", json_del);
+        self.insert("JSON.MGET", json_mget);
+        self.insert("JSON.NUMINCRBY", json_numincrby);
+        self.insert("JSON.ARRAPPEND", json_arrappend);
+        self.insert("JSON.STRAPPEND", json_strappend);
+        self.insert("JSON.TYPE", json_type);
+        self.insert("JSON.OBJKEYS", json_objkeys);
+        self.insert("TS.CREATE", ts_create);
+        self.insert("TS.ADD", ts_add);
+        self.insert("TS.MADD", ts_madd);
+        self.insert("TS.INCRBY", ts_incrby);
+        self.insert("TS.RANGE", ts_range);
+        self.insert("TS.REVRANGE", ts_revrange);
+        self.insert("TS.GET", ts_get);
+        self.insert("TS.INFO", ts_info);
+        self.insert("XLEN", xlen);
+        self.insert("XRANGE", xrange);
+        self.insert("XREVRANGE", xrevrange);
+        self.insert("XDEL", xdel);
+        self.insert("XTRIM", xtrim);
+        self.insert("XREAD", xread);
+        self.insert("XACK", xack);
+        self.insert("XPENDING", xpending);
+        self.insert("XINFO", xinfo);
+        self.insert("ZADD", zadd);
+        self.insert("ZCARD", zcard);
+        self.insert("ZSCORE", zscore);
+        self.insert("ZREM", zrem);
+        self.insert("ZRANGE", zrange);
+        self.insert("GEOADD", geoadd);
+        self.insert("GEOPOS", geopos);
+        self.insert("GEOHASH", geohash);
+        self.insert("GEODIST", geodist);
+        self.insert("GEOSEARCH", geosearch);
+        self.insert("GEOSEARCHSTORE", geosearchstore);
+        self.insert("LPUSH", lpush);
+        self.insert("RPUSH", rpush);
+        self.insert("LPOP", lpop);
+        self.insert("RPOP", rpop);
+        self.insert("LLEN", llen);
+        self.insert("LRANGE", lrange);
+        self.insert("SADD", sadd);
+        self.insert("SREM", srem);
+        self.insert("SCARD", scard);
+        self.insert("SISMEMBER", sismember);
+        self.insert("SMEMBERS", smembers);
+        self.insert_propagated("XADD", xadd);
+        self.insert_propagated("XGROUP", xgroup);
+        self.insert_propagated("XREADGROUP", xreadgroup);
+        self.insert_propagated("XCLAIM", xclaim);
+        self.insert_propagated("XAUTOCLAIM", xautoclaim);
+    }
+}
+
+/// What the keys a transaction writes held before it, put back when one of
+/// its commands fails so only its own writes are undone.
+#[derive(Default)]
+struct Undo {
+    entries: Vec<(usize, String, Option<Data>)>,
+    recorded: HashSet<(usize, String)>,
+}
+
+impl Undo {
+    /// Only the first value of a key counts, the one before the transaction.
+    fn record(&mut self, dbs: &Databases, index: usize, keys: Vec<String>) {
+        let keys: Vec<String> = keys.into_iter().filter(|key| self.recorded.insert((index, key.clone()))).collect();
+        let keyspace = dbs[index].read(&keys);
+        for key in keys {
+            let data = keyspace.cloned(&key);
+            self.entries.push((index, key, data));
+        }
+    }
+
+    fn revert(self, dbs: &Databases) {
+        for (index, key, data) in self.entries {
+            let mut keyspace = dbs[index].write([&key]);
+            match data {
+                Some(data) => keyspace.insert(key, data),
+                None => {
+                    keyspace.remove(&key);
+                },
+            };
+        }
+    }
+}
+
+/// The upper-cased name of a command with the whole array it came in.
+fn parse_command(input: &Value) -> Result<(String, Vec<Value>), &'static str> {
+    let Value::Array(arr) = input else {
+        return Err("ERR: Only arrays should be used");
+    };
+    let Some(first) = arr.first() else {
+        return Err("ERR: An empty array was provided");
+    };
+    let Value::BulkStr(command) = first else {
+        return Err("ERR: The command must be a bulk string");
+    };
+    Ok((command.to_uppercase(), arr.clone()))
+}
+
+fn now_ms() -> u64 {
+    let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH);
+    now.map(|d| d.as_millis() as u64).unwrap_or(0)
+}
+
+/// Replaces `*` timestamps of time series commands with the current time, so
+/// the AOF replays the same samples instead of new ones.
+fn resolve_timestamps(cmd: &str, arr: &mut Vec<Value>) -> bool {
+    let is_star = |value: &Value| matches!(value, Value::BulkStr(s) if s == "*");
+    let now = Value::BulkStr(now_ms().to_string());
+
+    let mut resolved = false;
+    match cmd {
+        "TS.ADD" | "TS.MADD" => {
+            // TS.ADD has a single timestamp, a `*` in its labels is a value.
+            let timestamps = if cmd == "TS.ADD" { 1 } else { arr.len() };
+            for i in (2..arr.len()).step_by(3).take(timestamps) {
+                if is_star(&arr[i]) {
+                    arr[i] = now.clone();
+                    resolved = true;
+                }
+            }
+        },
+        "TS.INCRBY" => {
+            let options = arr.iter().skip(3).map(|v| match v {
+                Value::BulkStr(s) => s.as_str(),
+                _ => "",
+            });
+            match ts_option(options, "TIMESTAMP").map(|p| p + 4) {
+                Some(i) if arr.get(i).is_some_and(is_star) => arr[i] = now,
+                Some(_) => return false,
+                None => {
+                    arr.push(Value::BulkStr("TIMESTAMP".into()));
+                    arr.push(now);
+                },
+            };
+            resolved = true;
+        },
+        _ => (),
+    };
+    resolved
+}
+
+fn db_index(value: &Value, dbs: &Databases) -> Result<usize, Value> {
+    let Value::BulkStr(index) = value else {
+        return Err(Value::Error("ERR: DB index must be a bulk string"));
+    };
+    match index.parse::<usize>() {
+        Ok(index) if index < dbs.len() => Ok(index),
+        Ok(_) => Err(Value::Error("ERR: DB index is out of range")),
+        Err(_) => Err(Value::Error("ERR: Invalid DB index")),
+    }
+}
+
+/// The keys a command can get at, by database, or `None` for every key of
+/// the database.
+fn touched_keys(cmd: &str, args: &[Value], selected: usize, dbs: &Databases) -> Vec<(usize, Option<Vec<String>>)> {
+    let index_at = |i: usize| args.get(i).and_then(|value| db_index(value, dbs).ok());
+    let keys = || Some(acl::keys(cmd, args).into_iter().map(String::from).collect::<Vec<_>>());
+
+    match cmd {
+        "FLUSHDB" | "KEYS" | "SCAN" | "RANDOMKEY" | "DBSIZE" => vec![(selected, None)],
+        "FLUSHALL" => (0..dbs.len()).map(|index| (index, None)).collect(),
+        "SWAPDB" => [index_at(0), index_at(1)].into_iter().flatten().map(|index| (index, None)).collect(),
+        "MOVE" => [Some(selected), index_at(1)].into_iter().flatten().map(|index| (index, keys())).collect(),
+        "COPY" => {
+            let option = args.iter().position(|arg| matches!(arg, Value::BulkStr(s) if s.eq_ignore_ascii_case("DB")));
+            let index = option.and_then(|i| index_at(i + 1)).unwrap_or(selected);
+            vec![(selected, keys()), (index, keys())]
+        },
+        _ => vec![(selected, keys())],
+    }
+}
+
+/// Reserves the shards of every key the commands can get at, following
+/// the SELECTs among them. Databases are taken in index order, as
+/// `Database::reserve` asks.
+fn reserve<'d, 'c>(commands: impl IntoIterator<Item = (String, &'c [Value])>, mut selected: usize, dbs: &'d Databases) -> Vec<Reservation<'d>> {
+    let mut touched: BTreeMap<usize, Option<Vec<String>>> = BTreeMap::new();
+    for (cmd, args) in commands {
+        if cmd == "SELECT" {
+            if let Some(index) = args.first().and_then(|value| db_index(value, dbs).ok()) {
+                selected = index;
+            }
+            continue;
+        }
+        for (index, keys) in touched_keys(&cmd, args, selected, dbs) {
+            match (touched.entry(index).or_insert_with(|| Some(Vec::new())), keys) {
+                (Some(all), Some(keys)) => all.extend(keys),
+                (all, _) => *all = None,
+            }
+        }
+    }
+    touched.into_iter().map(|(index, keys)| match keys {
+        Some(keys) => dbs[index].reserve(keys),
+        None => dbs[index].reserve_all(),
+    }).collect()
+}
+
+/// Locks two distinct databases always in index order, so concurrent
+/// commands over the same pair can't deadlock each other.
+fn lock_pair<'d>(dbs: &'d Databases, a: usize, b: usize, lock: impl Fn(&'d Database) -> Keyspace<'d>) -> (Keyspace<'d>, Keyspace<'d>) {
+    match a < b {
+        true => {
+            let first = lock(&dbs[a]);
+            (first, lock(&dbs[b]))
+        },
+        false => {
+            let second = lock(&dbs[b]);
+            (lock(&dbs[a]), second)
+        },
     }
 }
 
@@ -106,13 +920,13 @@ fn ping(args: Vec<Value>, _db: DB) -> Value {
         return Value::Error("ERR: Wrong number of arguments for command");
     }
 
-    if args.len() == 0 {
+    if args.is_empty() {
         return Value::Str("PONG");
     }
     if let Value::BulkStr(name) = &args[0] {
         return Value::BulkStr(name.into());
     }
-    return Value::Str("PONG");
+    Value::Str("PONG")
 }
 
 fn echo(args: Vec<Value>, _db: DB) -> Value {
@@ -123,18 +937,16 @@ fn echo(args: Vec<Value>, _db: DB) -> Value {
     if let Value::BulkStr(name) = &args[0] {
         return Value::BulkStr(name.into());
     }
-    return Value::Error("ERR: Argument must be a bulk string");
+    Value::Error("ERR: Argument must be a bulk string")
 }
 
 fn dbsize(args: Vec<Value>, db: DB) -> Value {
-    if args.len() != 0 {
+    if !args.is_empty() {
         return Value::Error("ERR: Wrong number of arguments provided");
     }
 
-    let set_len = db.read().unwrap().set_len();
-    let hset_len = db.read().unwrap().hset_total_len();
-    let total_len: i64 = (set_len + hset_len) as i64;
-    Value::Num(total_len)
+    let len = db.read_all().len();
+    Value::Num(len as i64)
 }
 
 fn hlen(args: Vec<Value>, db: DB) -> Value {
@@ -143,37 +955,21 @@ fn hlen(args: Vec<Value>, db: DB) -> Value {
     }
 
     if let Value::BulkStr(hash) = &args[0] {
-        let len = db.read().unwrap().hset_len(hash);
-        return Value::Num(len as i64)
+        return db.read([hash]).hset_len(hash);
     }
     Value::Error("ERR: Argument must be a bulk string")
 }
 
 fn exists(args: Vec<Value>, db: DB) -> Value {
-    if args.len() == 0 {
+    if args.is_empty() {
         return Value::Error("ERR: Wrong number of arguments provided");
     }
 
+    let db = db.read(acl::keys("EXISTS", &args));
     let mut counter = 0i64;
     for val in args {
         if let Value::BulkStr(key) = val {
-            if db.read().unwrap().set_contains(&key) {
-                counter += 1;
-            }
-        }
-    }
-    Value::Num(counter)
-}
-
-fn hexists(args: Vec<Value>, db: DB) -> Value {
-    if args.len() == 0 || args.len() % 2 != 0 {
-        return Value::Error("ERR: Wrong number of arguments provided");
-    }
-
-    let mut counter = 0i64;
-    for arg in args.chunks(2) {
-        if let [Value::BulkStr(hash), Value::BulkStr(key)] = arg {
-            if db.read().unwrap().hset_contains(hash, key) {
+            if db.contains(&key) {
                 counter += 1;
             }
         }
@@ -181,102 +977,233 @@ fn hexists(args: Vec<Value>, db: DB) -> Value {
     Value::Num(counter)
 }
 
-fn flushdb(args: Vec<Value>, db: DB) -> Value {
-    if args.len() != 0 {
-        return Value::Null;
-    }
-
-    db.write().unwrap().set_clear();
-    db.write().unwrap().hset_clear();
-    std::fs::File::create(db.read().unwrap().config().dbname()).unwrap();
-    Value::Null
-}
-
-fn set(args: Vec<Value>, db: DB) -> Value {
-    if args.len() != 2 {
+fn type_(args: Vec<Value>, db: DB) -> Value {
+    if args.len() != 1 {
         return Value::Error("ERR: Wrong number of arguments provided");
     }
 
     let Value::BulkStr(key) = &args[0] else {
-        return Value::Error("ERR: Key must be a bulk string");
-    };
-    let Value::BulkStr(value) = &args[1] else {
-        return Value::Error("ERR: Value must be a bulk string");
+        return Value::Error("ERR: Incorrect definition for key");
     };
-    db.write().unwrap().set_push(key.into(), value.into());
-    Value::Str("OK")
+    Value::Str(db.read([key]).type_name(key))
 }
 
-fn get(args: Vec<Value>, db: DB) -> Value {
+fn keys(args: Vec<Value>, db: DB) -> Value {
     if args.len() != 1 {
         return Value::Error("ERR: Wrong number of arguments provided");
     }
 
-    let Value::BulkStr(key) = &args[0] else {
-        return Value::Error("ERR: Incorrect definition for key");
+    let Value::BulkStr(pattern) = &args[0] else {
+        return Value::Error("ERR: Pattern must be a bulk string");
     };
-    db.read().unwrap().set_get(key)
+    let keys = db.read_all().keys(pattern);
+    Value::Array(keys.into_iter().map(Value::BulkStr).collect())
 }
 
-fn hset(args: Vec<Value>, db: DB) -> Value {
-    if args.len() < 3 {
+fn scan(args: Vec<Value>, db: DB) -> Value {
+    if args.len().is_multiple_of(2) {
         return Value::Error("ERR: Wrong number of arguments provided");
     }
 
-    let Value::BulkStr(hash) = &args[0] else {
-        return Value::Error("ERR: Incorrect definition for hash");
+    let Some(args) = bulk_strings(&args) else {
+        return Value::Error("ERR: Arguments must be bulk strings");
     };
-    let Value::BulkStr(key) = &args[1] else {
-        return Value::Error("ERR: Incorrect definition for key");
-    };
-    let Value::BulkStr(value) = &args[2] else {
-        return Value::Error("ERR: Incorrect definition for value");
+    let Ok(cursor) = args[0].parse::<u64>() else {
+        return Value::Error("ERR: Invalid cursor");
     };
 
-    db.write().unwrap().hset_push(hash.into(), key.into(), value.into());
-    Value::Str("OK")
+    let (mut count, mut pattern, mut kind) = (10, None, None);
+    for option in args[1..].chunks(2) {
+        match option[0].to_uppercase().as_str() {
+            "MATCH" => pattern = Some(option[1].as_str()),
+            "TYPE" => kind = Some(option[1].as_str()),
+            "COUNT" => match option[1].parse::<usize>() {
+                Ok(n) if n > 0 => count = n,
+                _ => return Value::Error("ERR: Value is not an integer or out of range"),
+            },
+            _ => return Value::Error("ERR: Syntax error"),
+        };
+    }
+
+    let (cursor, keys) = db.scan(cursor, count, pattern, kind);
+    Value::Array(vec![
+        Value::BulkStr(cursor.to_string()),
+        Value::Array(keys.into_iter().map(Value::BulkStr).collect()),
+    ])
 }
 
-fn hget(args: Vec<Value>, db: DB) -> Value {
+fn rename_generic(args: Vec<Value>, db: DB, nx: bool) -> Value {
     if args.len() != 2 {
         return Value::Error("ERR: Wrong number of arguments provided");
     }
 
-    let Value::BulkStr(hash) = &args[0] else {
-        return Value::Error("ERR: Incorrect definition for hash");
-    };
-    let Value::BulkStr(key) = &args[1] else {
+    let [Value::BulkStr(key), Value::BulkStr(new_key)] = &args[..] else {
         return Value::Error("ERR: Incorrect definition for key");
     };
+    db.write(acl::keys("RENAME", &args)).rename(key, new_key.clone(), nx)
+}
 
-    db.read().unwrap().hset_get(hash, key)
+fn rename(args: Vec<Value>, db: DB) -> Value {
+    rename_generic(args, db, false)
 }
 
-fn del(args: Vec<Value>, db: DB) -> Value {
-    if args.len() == 0 {
-        return Value::Error("ERR: No arguments were provided");
+fn renamenx(args: Vec<Value>, db: DB) -> Value {
+    rename_generic(args, db, true)
+}
+
+fn randomkey(args: Vec<Value>, db: DB) -> Value {
+    if !args.is_empty() {
+        return Value::Error("ERR: Wrong number of arguments provided");
     }
 
-    let mut counter = 0i64;
-    for arg in args {
-        if let Value::BulkStr(key) = arg {
-            if db.write().unwrap().set_remove(&key) {
-                counter += 1;
-            }
-        }
+    match db.read_all().random_key() {
+        Some(key) => Value::BulkStr(key),
+        None => Value::Null,
     }
-    Value::Num(counter)
 }
 
-fn hdel(args: Vec<Value>, db: DB) -> Value {
-    if args.len() == 0 || args.len() % 2 != 0 {
-        return Value::Error("ERR: Wrong number of arguments provided");
-    }
+fn touch(args: Vec<Value>, db: DB) -> Value {
+    exists(args, db)
+}
 
-    let mut counter = 0i64;
+/// The payload is what `dump::dump` wrote, as the AOF holds it.
+fn restore(args: &[Value], db: &Database) -> Result<(), &'static str> {
+    let [Value::BulkStr(key), payload] = args else {
+        return Err("ERR: Wrong number of arguments provided");
+    };
+    let Some(payload) = payload.bytes() else {
+        return Err("ERR: Invalid serialized value");
+    };
+    match db.write([key]).restore(key.clone(), payload) {
+        Value::Error(err) => Err(err),
+        _ => Ok(()),
+    }
+}
+
+fn hexists(args: Vec<Value>, db: DB) -> Value {
+    if args.is_empty() || !args.len().is_multiple_of(2) {
+        return Value::Error("ERR: Wrong number of arguments provided");
+    }
+
+    let db = db.read(acl::keys("HEXISTS", &args));
+    let mut counter = 0i64;
     for arg in args.chunks(2) {
         if let [Value::BulkStr(hash), Value::BulkStr(key)] = arg {
-            if db.write().unwrap().hset_remove(hash, key) {
+            match db.hset_contains(hash, key) {
+                Ok(true) => counter += 1,
+                Ok(false) => (),
+                Err(err) => return err,
+            };
+        }
+    }
+    Value::Num(counter)
+}
+
+fn flushdb(args: Vec<Value>, db: DB) -> Value {
+    if !args.is_empty() {
+        return Value::Error("ERR: Wrong number of arguments provided");
+    }
+
+    db.write_all().clear();
+    Value::Str("OK")
+}
+
+fn flushall(args: Vec<Value>, dbs: &Databases) -> Value {
+    if !args.is_empty() {
+        return Value::Error("ERR: Wrong number of arguments provided");
+    }
+
+    for db in dbs.iter() {
+        db.write_all().clear();
+    }
+    Value::Str("OK")
+}
+
+fn swapdb(args: Vec<Value>, dbs: &Databases) -> Value {
+    if args.len() != 2 {
+        return Value::Error("ERR: Wrong number of arguments provided");
+    }
+
+    let (a, b) = match (db_index(&args[0], dbs), db_index(&args[1], dbs)) {
+        (Ok(a), Ok(b)) => (a, b),
+        (Err(err), _) | (_, Err(err)) => return err,
+    };
+    if a != b {
+        let (mut first, mut second) = lock_pair(dbs, a, b, |db| db.write_all());
+        first.swap(&mut second);
+    }
+    Value::Str("OK")
+}
+
+fn set(args: Vec<Value>, db: DB) -> Value {
+    if args.len() != 2 {
+        return Value::Error("ERR: Wrong number of arguments provided");
+    }
+
+    let Value::BulkStr(key) = &args[0] else {
+        return Value::Error("ERR: Key must be a bulk string");
+    };
+    let Some(value) = args[1].bytes() else {
+        return Value::Error("ERR: Value must be a bulk string");
+    };
+    db.write([key]).set_push(key.into(), value.to_vec());
+    Value::Str("OK")
+}
+
+fn get(args: Vec<Value>, db: DB) -> Value {
+    if args.len() != 1 {
+        return Value::Error("ERR: Wrong number of arguments provided");
+    }
+
+    let Value::BulkStr(key) = &args[0] else {
+        return Value::Error("ERR: Incorrect definition for key");
+    };
+    db.read([key]).set_get(key)
+}
+
+fn hset(args: Vec<Value>, db: DB) -> Value {
+    if args.len() < 3 {
+        return Value::Error("ERR: Wrong number of arguments provided");
+    }
+
+    let Value::BulkStr(hash) = &args[0] else {
+        return Value::Error("ERR: Incorrect definition for hash");
+    };
+    let Value::BulkStr(key) = &args[1] else {
+        return Value::Error("ERR: Incorrect definition for key");
+    };
+    let Value::BulkStr(value) = &args[2] else {
+        return Value::Error("ERR: Incorrect definition for value");
+    };
+
+    db.write([hash]).hset_push(hash.into(), key.into(), value.into())
+}
+
+fn hget(args: Vec<Value>, db: DB) -> Value {
+    if args.len() != 2 {
+        return Value::Error("ERR: Wrong number of arguments provided");
+    }
+
+    let Value::BulkStr(hash) = &args[0] else {
+        return Value::Error("ERR: Incorrect definition for hash");
+    };
+    let Value::BulkStr(key) = &args[1] else {
+        return Value::Error("ERR: Incorrect definition for key");
+    };
+
+    db.read([hash]).hset_get(hash, key)
+}
+
+fn del(args: Vec<Value>, db: DB) -> Value {
+    if args.is_empty() {
+        return Value::Error("ERR: No arguments were provided");
+    }
+
+    let mut db = db.write(acl::keys("DEL", &args));
+    let mut counter = 0i64;
+    for arg in args {
+        if let Value::BulkStr(key) = arg {
+            if db.remove(&key) {
                 counter += 1;
             }
         }
@@ -284,6 +1211,25 @@ fn hdel(args: Vec<Value>, db: DB) -> Value {
     Value::Num(counter)
 }
 
+fn hdel(args: Vec<Value>, db: DB) -> Value {
+    if args.is_empty() || !args.len().is_multiple_of(2) {
+        return Value::Error("ERR: Wrong number of arguments provided");
+    }
+
+    let mut db = db.write(acl::keys("HDEL", &args));
+    let mut counter = 0i64;
+    for arg in args.chunks(2) {
+        if let [Value::BulkStr(hash), Value::BulkStr(key)] = arg {
+            match db.hset_remove(hash, key) {
+                Ok(true) => counter += 1,
+                Ok(false) => (),
+                Err(err) => return err,
+            };
+        }
+    }
+    Value::Num(counter)
+}
+
 fn incr(args: Vec<Value>, db: DB) -> Value {
     if args.len() != 1 {
         return Value::Error("ERR: Incorrect number of arguments");
@@ -292,7 +1238,7 @@ fn incr(args: Vec<Value>, db: DB) -> Value {
     let Value::BulkStr(key) = &args[0] else {
         return Value::Error("ERR: Incorrect definition for key");
     };
-    db.write().unwrap().set_incr(key.into(), 1)
+    db.write([key]).set_incr(key.into(), 1)
 }
 
 fn incr_by(args: Vec<Value>, db: DB) -> Value {
@@ -311,7 +1257,7 @@ fn incr_by(args: Vec<Value>, db: DB) -> Value {
         Ok(n) => n,
         _ => return Value::Error("ERR: Value is not an integer or out of range"),
     };
-    db.write().unwrap().set_incr(key.into(), incr)
+    db.write([key]).set_incr(key.into(), incr)
 }
 
 fn decr(args: Vec<Value>, db: DB) -> Value {
@@ -322,7 +1268,7 @@ fn decr(args: Vec<Value>, db: DB) -> Value {
     let Value::BulkStr(key) = &args[0] else {
         return Value::Error("ERR: Incorrect definition for key");
     };
-    db.write().unwrap().set_incr(key.into(), -1)
+    db.write([key]).set_incr(key.into(), -1)
 }
 
 fn decr_by(args: Vec<Value>, db: DB) -> Value {
@@ -341,65 +1287,3056 @@ fn decr_by(args: Vec<Value>, db: DB) -> Value {
         Ok(n) => n,
         _ => return Value::Error("ERR: Value is not an integer or out of range"),
     };
-    db.write().unwrap().set_incr(key.into(), -decr)
+    db.write([key]).set_incr(key.into(), -decr)
 }
 
-fn multi(args: Vec<Value>, db: DB) -> Value {
-    if args.len() != 0 {
-        return Value::Error("ERR: Wrong number of arguments");
+fn pfadd(args: Vec<Value>, db: DB) -> Value {
+    if args.is_empty() {
+        return Value::Error("ERR: Wrong number of arguments provided");
     }
 
-    db.write().unwrap().set_transaction_mode(true);
-    Value::Str("OK")
+    let Value::BulkStr(key) = &args[0] else {
+        return Value::Error("ERR: Incorrect definition for key");
+    };
+    let mut elements: Vec<&[u8]> = Vec::new();
+    for arg in &args[1..] {
+        let Some(element) = arg.bytes() else {
+            return Value::Error("ERR: Incorrect definition for element");
+        };
+        elements.push(element);
+    }
+    db.write([key]).pf_add(key.into(), &elements)
 }
 
-fn exec(args: Vec<Value>, db: DB) -> Value {
-    if args.len() != 0 {
-        return Value::Error("ERR: Wrong number of arguments");
+fn pfcount(args: Vec<Value>, db: DB) -> Value {
+    if args.is_empty() {
+        return Value::Error("ERR: Wrong number of arguments provided");
+    }
+
+    let mut keys: Vec<String> = Vec::new();
+    for arg in &args {
+        let Value::BulkStr(key) = arg else {
+            return Value::Error("ERR: Incorrect definition for key");
+        };
+        keys.push(key.into());
+    }
+    db.write(acl::keys("PFCOUNT", &args)).pf_count(&keys)
+}
+
+fn pfmerge(args: Vec<Value>, db: DB) -> Value {
+    if args.is_empty() {
+        return Value::Error("ERR: Wrong number of arguments provided");
     }
 
-    let config = db.read().unwrap().config();
-    let aof = Arc::new(RwLock::new(match AOF::new(config) {
-        Ok(file) => file,
-        Err(_) => return Value::Error("ERR: Failed to access AOF"),
-    }));
+    let Value::BulkStr(dest) = &args[0] else {
+        return Value::Error("ERR: Incorrect definition for destination key");
+    };
+    let mut sources: Vec<String> = Vec::new();
+    for arg in &args[1..] {
+        let Value::BulkStr(key) = arg else {
+            return Value::Error("ERR: Incorrect definition for source key");
+        };
+        sources.push(key.into());
+    }
+    db.write(acl::keys("PFMERGE", &args)).pf_merge(dest.into(), &sources)
+}
 
-    let mut handlers = Handlers::new();
-    handlers.init();
+fn rewrite_if_grown(aof: &Aof, dbs: &Databases) {
+    if aof.read().unwrap().should_rewrite() {
+        let _ = AOF::rewrite(aof, dbs);
+    }
+}
 
-    let transaction = db.read().unwrap().multi_get();
-    db.write().unwrap().set_execution_mode(true);
-    let copy = db.read().unwrap().create_database_copy();
+fn info(args: &[Value], aof: &Aof, snapshot: Option<&Snapshot>) -> Value {
+    let sections = match bulk_strings(args) {
+        Some(sections) => sections,
+        None => return Value::Error("ERR: Arguments must be bulk strings"),
+    };
+    let all = sections.is_empty() || sections.iter()
+        .any(|s| ["ALL", "DEFAULT", "EVERYTHING"].contains(&s.to_uppercase().as_str()));
 
-    let mut values: Vec<Value> = Vec::new();
-    for (cmd, args) in transaction.into_iter() {
-        let mut input: Vec<Value> = Vec::new();
-        input.push(cmd);
-        input.extend(args);
-        let command = Value::Array(input);
-
-        let value = handlers.match_handler(command, aof.clone(), db.clone());
-        if let Value::Error(_) = value {
-            db.write().unwrap().database_revert(copy);
-            break;
+    let mut info = String::new();
+    if all || sections.iter().any(|s| s.eq_ignore_ascii_case("PERSISTENCE")) {
+        match aof.read().unwrap().info() {
+            Ok(lines) => info += &format!("# Persistence\r\n{lines}\r\n"),
+            Err(_) => return Value::Error("ERR: Failed to read the AOF"),
+        };
+        if let Some(snapshot) = snapshot {
+            info += &format!("{}\r\n", snapshot.info());
         }
-        values.push(value)
     }
-    
-    db.write().unwrap().multi_clear();
-    db.write().unwrap().set_execution_mode(false);
+    Value::BulkStr(info)
+}
+
+fn bulk_strings(args: &[Value]) -> Option<Vec<String>> {
+    args.iter()
+        .map(|arg| match arg {
+            Value::BulkStr(s) => Some(s.into()),
+            _ => None,
+        })
+        .collect()
+}
+
+/// The key of a BF.* or CF.* command and its items, which can be any
+/// bytes, such as binary digests.
+fn filter_args(args: &[Value]) -> Option<(String, Vec<&[u8]>)> {
+    let Value::BulkStr(key) = &args[0] else {
+        return None;
+    };
+    let items = args[1..].iter().map(Value::bytes).collect::<Option<_>>()?;
+    Some((key.clone(), items))
+}
+
+fn parse_number<T: std::str::FromStr>(arg: Option<&String>) -> Option<T> {
+    arg?.parse::<T>().ok()
+}
+
+fn info_reply(info: Result<Vec<(&'static str, i64)>, Value>, field: Option<&String>) -> Value {
+    let info = match info {
+        Ok(info) => info,
+        Err(err) => return err,
+    };
+
+    let Some(field) = field else {
+        let mut values: Vec<Value> = Vec::new();
+        for (name, value) in info {
+            values.push(Value::Str(name));
+            values.push(Value::Num(value));
+        }
+        return Value::Array(values);
+    };
 
-    match Arc::clone(&aof).write().unwrap().write_queued() {
-        Ok(_) => Value::Array(values),
-        Err(_) => Value::Error("ERR: Failed to write to AOF"),
+    let field = field.to_uppercase();
+    for (name, value) in info {
+        if name.to_uppercase().split(' ').any(|word| word == field) {
+            return Value::Num(value);
+        }
     }
+    Value::Error("ERR: Invalid information field")
 }
 
-fn discard(args: Vec<Value>, db: DB) -> Value {
-    if args.len() != 0 {
-        return Value::Error("ERR: Wrong number of arguments");
+fn bf_reserve(args: Vec<Value>, db: DB) -> Value {
+    if args.len() < 3 {
+        return Value::Error("ERR: Wrong number of arguments provided");
     }
 
-    db.write().unwrap().multi_clear();
-    Value::Str("OK")
+    let Some(args) = bulk_strings(&args) else {
+        return Value::Error("ERR: Arguments must be bulk strings");
+    };
+    let error_rate = match parse_number::<f64>(args.get(1)) {
+        Some(n) if n > 0.0 && n < 1.0 => n,
+        _ => return Value::Error("ERR: Error rate must be between 0 and 1"),
+    };
+    let capacity = match parse_number::<u64>(args.get(2)) {
+        Some(n) if n > bloom::MAX_CAPACITY => return Value::Error("ERR: Capacity is too large"),
+        Some(n) if n > 0 => n,
+        _ => return Value::Error("ERR: Capacity must be a positive integer"),
+    };
+
+    let mut expansion = bloom::DEFAULT_EXPANSION;
+    let mut options = args[3..].iter();
+    while let Some(option) = options.next() {
+        match option.to_uppercase().as_str() {
+            "NONSCALING" => expansion = 0,
+            "EXPANSION" => match parse_number::<u64>(options.next()) {
+                Some(n) if n > bloom::MAX_EXPANSION => return Value::Error("ERR: Expansion is too large"),
+                Some(n) if n > 0 => expansion = n,
+                _ => return Value::Error("ERR: Expansion must be a positive integer"),
+            },
+            _ => return Value::Error("ERR: Syntax error"),
+        };
+    }
+    db.write([&args[0]]).bf_reserve(args[0].clone(), error_rate, capacity, expansion)
+}
+
+fn bf_add(args: Vec<Value>, db: DB) -> Value {
+    if args.len() != 2 {
+        return Value::Error("ERR: Wrong number of arguments provided");
+    }
+
+    let Some((key, items)) = filter_args(&args) else {
+        return Value::Error("ERR: Arguments must be bulk strings");
+    };
+    db.write([&key]).bf_add(&key, items[0])
+}
+
+fn bf_madd(args: Vec<Value>, db: DB) -> Value {
+    if args.len() < 2 {
+        return Value::Error("ERR: Wrong number of arguments provided");
+    }
+
+    let Some((key, items)) = filter_args(&args) else {
+        return Value::Error("ERR: Arguments must be bulk strings");
+    };
+    let mut db = db.write([&key]);
+    Value::Array(items.into_iter().map(|item| db.bf_add(&key, item)).collect())
+}
+
+fn bf_exists(args: Vec<Value>, db: DB) -> Value {
+    if args.len() != 2 {
+        return Value::Error("ERR: Wrong number of arguments provided");
+    }
+
+    let Some((key, items)) = filter_args(&args) else {
+        return Value::Error("ERR: Arguments must be bulk strings");
+    };
+    db.read([&key]).bf_exists(&key, items[0])
+}
+
+fn bf_mexists(args: Vec<Value>, db: DB) -> Value {
+    if args.len() < 2 {
+        return Value::Error("ERR: Wrong number of arguments provided");
+    }
+
+    let Some((key, items)) = filter_args(&args) else {
+        return Value::Error("ERR: Arguments must be bulk strings");
+    };
+    let db = db.read([&key]);
+    Value::Array(items.into_iter().map(|item| db.bf_exists(&key, item)).collect())
+}
+
+fn bf_info(args: Vec<Value>, db: DB) -> Value {
+    if args.is_empty() || args.len() > 2 {
+        return Value::Error("ERR: Wrong number of arguments provided");
+    }
+
+    let Some(args) = bulk_strings(&args) else {
+        return Value::Error("ERR: Arguments must be bulk strings");
+    };
+    info_reply(db.read([&args[0]]).bf_info(&args[0]), args.get(1))
+}
+
+fn cf_reserve(args: Vec<Value>, db: DB) -> Value {
+    if args.len() < 2 {
+        return Value::Error("ERR: Wrong number of arguments provided");
+    }
+
+    let Some(args) = bulk_strings(&args) else {
+        return Value::Error("ERR: Arguments must be bulk strings");
+    };
+    let capacity = match parse_number::<u64>(args.get(1)) {
+        Some(n) if n > cuckoo::MAX_CAPACITY => return Value::Error("ERR: Capacity is too large"),
+        Some(n) if n > 0 => n,
+        _ => return Value::Error("ERR: Capacity must be a positive integer"),
+    };
+
+    let mut bucket_size = cuckoo::DEFAULT_BUCKET_SIZE;
+    let mut max_iterations = cuckoo::DEFAULT_MAX_ITERATIONS;
+    let mut expansion = cuckoo::DEFAULT_EXPANSION;
+    let mut options = args[2..].iter();
+    while let Some(option) = options.next() {
+        let value = parse_number::<u64>(options.next());
+        match (option.to_uppercase().as_str(), value) {
+            ("BUCKETSIZE", Some(n)) if n > 0 && n <= cuckoo::MAX_BUCKET_SIZE => bucket_size = n,
+            ("MAXITERATIONS", Some(n)) if n > 0 && n <= cuckoo::MAX_ITERATIONS => max_iterations = n,
+            ("EXPANSION", Some(n)) if n <= cuckoo::MAX_EXPANSION => expansion = n,
+            _ => return Value::Error("ERR: Syntax error"),
+        };
+    }
+    db.write([&args[0]]).cf_reserve(args[0].clone(), capacity, bucket_size, max_iterations, expansion)
+}
+
+fn cf_add(args: Vec<Value>, db: DB) -> Value {
+    if args.len() != 2 {
+        return Value::Error("ERR: Wrong number of arguments provided");
+    }
+
+    let Some((key, items)) = filter_args(&args) else {
+        return Value::Error("ERR: Arguments must be bulk strings");
+    };
+    db.write([&key]).cf_insert(&key, items[0], false, Some(cuckoo::DEFAULT_CAPACITY))
+}
+
+fn cf_addnx(args: Vec<Value>, db: DB) -> Value {
+    if args.len() != 2 {
+        return Value::Error("ERR: Wrong number of arguments provided");
+    }
+
+    let Some((key, items)) = filter_args(&args) else {
+        return Value::Error("ERR: Arguments must be bulk strings");
+    };
+    db.write([&key]).cf_insert(&key, items[0], true, Some(cuckoo::DEFAULT_CAPACITY))
+}
+
+fn cf_insert_generic(args: Vec<Value>, db: DB, nx: bool) -> Value {
+    if args.len() < 3 {
+        return Value::Error("ERR: Wrong number of arguments provided");
+    }
+
+    // Only the options before ITEMS have to be text.
+    let is_items = |arg: &Value| matches!(arg, Value::BulkStr(s) if s.eq_ignore_ascii_case("ITEMS"));
+    let Some(end) = args.iter().skip(1).position(is_items).map(|i| i + 1) else {
+        return Value::Error("ERR: Syntax error");
+    };
+    let Some(options) = bulk_strings(&args[..end]) else {
+        return Value::Error("ERR: Arguments must be bulk strings");
+    };
+    let Some(items) = args[end + 1..].iter().map(Value::bytes).collect::<Option<Vec<&[u8]>>>() else {
+        return Value::Error("ERR: Arguments must be bulk strings");
+    };
+    if items.is_empty() {
+        return Value::Error("ERR: Syntax error");
+    }
+
+    let mut capacity = Some(cuckoo::DEFAULT_CAPACITY);
+    let mut nocreate = false;
+    let mut i = 1;
+    while i < options.len() {
+        match options[i].to_uppercase().as_str() {
+            "CAPACITY" => match parse_number::<u64>(options.get(i + 1)) {
+                Some(n) if n > cuckoo::MAX_CAPACITY => return Value::Error("ERR: Capacity is too large"),
+                Some(n) if n > 0 => {
+                    capacity = Some(n);
+                    i += 2;
+                },
+                _ => return Value::Error("ERR: Capacity must be a positive integer"),
+            },
+            "NOCREATE" => {
+                nocreate = true;
+                i += 1;
+            },
+            _ => return Value::Error("ERR: Syntax error"),
+        };
+    }
+    if nocreate {
+        capacity = None;
+    }
+
+    let key = &options[0];
+    let mut db = db.write([key]);
+    let mut values: Vec<Value> = Vec::new();
+    for item in items {
+        let value = match db.cf_insert(key, item, nx, capacity) {
+            Value::Error("ERR: Filter is full") => Value::Num(-1),
+            Value::Error(err) => return Value::Error(err),
+            value => value,
+        };
+        values.push(value);
+    }
+    Value::Array(values)
+}
+
+fn cf_insert(args: Vec<Value>, db: DB) -> Value {
+    cf_insert_generic(args, db, false)
+}
+
+fn cf_insertnx(args: Vec<Value>, db: DB) -> Value {
+    cf_insert_generic(args, db, true)
+}
+
+fn cf_exists(args: Vec<Value>, db: DB) -> Value {
+    if args.len() != 2 {
+        return Value::Error("ERR: Wrong number of arguments provided");
+    }
+
+    let Some((key, items)) = filter_args(&args) else {
+        return Value::Error("ERR: Arguments must be bulk strings");
+    };
+    db.read([&key]).cf_exists(&key, items[0])
+}
+
+fn cf_mexists(args: Vec<Value>, db: DB) -> Value {
+    if args.len() < 2 {
+        return Value::Error("ERR: Wrong number of arguments provided");
+    }
+
+    let Some((key, items)) = filter_args(&args) else {
+        return Value::Error("ERR: Arguments must be bulk strings");
+    };
+    let db = db.read([&key]);
+    Value::Array(items.into_iter().map(|item| db.cf_exists(&key, item)).collect())
+}
+
+fn cf_del(args: Vec<Value>, db: DB) -> Value {
+    if args.len() != 2 {
+        return Value::Error("ERR: Wrong number of arguments provided");
+    }
+
+    let Some((key, items)) = filter_args(&args) else {
+        return Value::Error("ERR: Arguments must be bulk strings");
+    };
+    db.write([&key]).cf_del(&key, items[0])
+}
+
+fn cf_count(args: Vec<Value>, db: DB) -> Value {
+    if args.len() != 2 {
+        return Value::Error("ERR: Wrong number of arguments provided");
+    }
+
+    let Some((key, items)) = filter_args(&args) else {
+        return Value::Error("ERR: Arguments must be bulk strings");
+    };
+    db.read([&key]).cf_count(&key, items[0])
+}
+
+fn cf_info(args: Vec<Value>, db: DB) -> Value {
+    if args.len() != 1 {
+        return Value::Error("ERR: Wrong number of arguments provided");
+    }
+
+    let Some(args) = bulk_strings(&args) else {
+        return Value::Error("ERR: Arguments must be bulk strings");
+    };
+    info_reply(db.read([&args[0]]).cf_info(&args[0]), None)
+}
+
+fn json_path_reply(path: &Path, doc: &Json) -> Value {
+    let values: Vec<Json> = doc.select(path).iter().map(|l| doc.get(l).unwrap().clone()).collect();
+    if !path.is_legacy() {
+        return Value::BulkStr(Json::Array(values).serialize(&json::Format::default()));
+    }
+    match values.first() {
+        Some(value) => Value::BulkStr(value.serialize(&json::Format::default())),
+        None => Value::Null,
+    }
+}
+
+/// Legacy paths answer with the first result only, `$` paths with all of them.
+fn json_reply(path: &Path, results: Vec<Value>) -> Value {
+    if !path.is_legacy() {
+        return Value::Array(results);
+    }
+    match results.into_iter().next() {
+        Some(Value::Null) | None => Value::Error("ERR: Path does not exist or has the wrong type"),
+        Some(value) => value,
+    }
+}
+
+fn json_set(args: Vec<Value>, db: DB) -> Value {
+    if args.len() < 3 || args.len() > 4 {
+        return Value::Error("ERR: Wrong number of arguments provided");
+    }
+
+    let Some(args) = bulk_strings(&args) else {
+        return Value::Error("ERR: Arguments must be bulk strings");
+    };
+    let path = match Path::parse(&args[1]) {
+        Ok(path) => path,
+        Err(err) => return Value::Error(err),
+    };
+    let value = match Json::parse(&args[2]) {
+        Ok(value) => value,
+        Err(err) => return Value::Error(err),
+    };
+    let (nx, xx) = match args.get(3).map(|arg| arg.to_uppercase()).as_deref() {
+        Some("NX") => (true, false),
+        Some("XX") => (false, true),
+        Some(_) => return Value::Error("ERR: Syntax error"),
+        None => (false, false),
+    };
+
+    let mut db = db.write([&args[0]]);
+    match db.json_get(&args[0]) {
+        Ok(Some(_)) => (),
+        Ok(None) => {
+            if !path.is_root() {
+                return Value::Error("ERR: New objects must be created at the root");
+            }
+            if xx {
+                return Value::Null;
+            }
+            db.json_push(args[0].clone(), value);
+            return Value::Str("OK");
+        },
+        Err(err) => return err,
+    };
+    let Ok(Some(doc)) = db.json_get_mut(&args[0]) else {
+        unreachable!();
+    };
+
+    let locations = doc.select(&path);
+    if !locations.is_empty() {
+        if nx {
+            return Value::Null;
+        }
+        // Locations come in document order, so one nested in another
+        // replaced value follows it and is gone by the time it's reached.
+        let mut replaced: Vec<json::Location> = Vec::new();
+        for location in locations {
+            if !replaced.iter().any(|parent| location.starts_with(parent)) {
+                replaced.push(location);
+            }
+        }
+        if !replaced.iter().all(|location| value.fits_at(location)) {
+            return Value::Error(json::TOO_DEEP);
+        }
+        for location in replaced {
+            if let Some(node) = doc.get_mut(&location) {
+                *node = value.clone();
+            }
+        }
+        return Value::Str("OK");
+    }
+
+    let Some((parent, name)) = path.split_field() else {
+        return Value::Null;
+    };
+    if xx {
+        return Value::Null;
+    }
+    let parents: Vec<json::Location> = doc.select(&parent).into_iter()
+        .filter(|location| matches!(doc.get(location), Some(Json::Object(_))))
+        .collect();
+    for location in &parents {
+        let mut child = location.clone();
+        child.push(json::Key::Field(name.clone()));
+        if !value.fits_at(&child) {
+            return Value::Error(json::TOO_DEEP);
+        }
+    }
+    let mut created = false;
+    for location in parents {
+        if let Some(Json::Object(fields)) = doc.get_mut(&location) {
+            fields.push((name.clone(), value.clone()));
+            created = true;
+        }
+    }
+    match created {
+        true => Value::Str("OK"),
+        false => Value::Null,
+    }
+}
+
+fn json_get(args: Vec<Value>, db: DB) -> Value {
+    if args.is_empty() {
+        return Value::Error("ERR: Wrong number of arguments provided");
+    }
+
+    let Some(args) = bulk_strings(&args) else {
+        return Value::Error("ERR: Arguments must be bulk strings");
+    };
+    let mut format = json::Format::default();
+    let mut i = 1;
+    while i < args.len() {
+        let Some(option) = args.get(i + 1) else {
+            break;
+        };
+        match args[i].to_uppercase().as_str() {
+            "INDENT" => format.indent = option,
+            "NEWLINE" => format.newline = option,
+            "SPACE" => format.space = option,
+            _ => break,
+        };
+        i += 2;
+    }
+
+    let mut paths: Vec<Path> = Vec::new();
+    for arg in &args[i..] {
+        match Path::parse(arg) {
+            Ok(path) => paths.push(path),
+            Err(err) => return Value::Error(err),
+        };
+    }
+    if paths.is_empty() {
+        paths.push(Path::parse(".").unwrap());
+    }
+
+    let db = db.read([&args[0]]);
+    let doc = match db.json_get(&args[0]) {
+        Ok(Some(doc)) => doc,
+        Ok(None) => return Value::Null,
+        Err(err) => return err,
+    };
+
+    let mut results: Vec<(String, Json)> = Vec::new();
+    for (path, name) in paths.iter().zip(args[i..].iter().chain(std::iter::once(&".".to_string()))) {
+        let mut values: Vec<Json> = doc.select(path).iter().map(|l| doc.get(l).unwrap().clone()).collect();
+        let value = match path.is_legacy() {
+            true if values.is_empty() => return Value::Error("ERR: Path does not exist"),
+            true => values.swap_remove(0),
+            false => Json::Array(values),
+        };
+        results.push((name.clone(), value));
+    }
+
+    let value = match results.len() {
+        1 => results.swap_remove(0).1,
+        _ => Json::Object(results),
+    };
+    Value::BulkStr(value.serialize(&format))
+}
+
+fn json_del(args: Vec<Value>, db: DB) -> Value {
+    if args.is_empty() || args.len() > 2 {
+        return Value::Error("ERR: Wrong number of arguments provided");
+    }
+
+    let Some(args) = bulk_strings(&args) else {
+        return Value::Error("ERR: Arguments must be bulk strings");
+    };
+    let path = match Path::parse(args.get(1).map_or(".", |p| p.as_str())) {
+        Ok(path) => path,
+        Err(err) => return Value::Error(err),
+    };
+
+    let mut db = db.write([&args[0]]);
+    let doc = match db.json_get_mut(&args[0]) {
+        Ok(Some(doc)) => doc,
+        Ok(None) => return Value::Num(0),
+        Err(err) => return err,
+    };
+    if path.is_root() {
+        return Value::Num(db.remove(&args[0]) as i64);
+    }
+    let locations = doc.select(&path);
+    Value::Num(doc.delete(&locations) as i64)
+}
+
+fn json_mget(args: Vec<Value>, db: DB) -> Value {
+    if args.len() < 2 {
+        return Value::Error("ERR: Wrong number of arguments provided");
+    }
+
+    let Some(args) = bulk_strings(&args) else {
+        return Value::Error("ERR: Arguments must be bulk strings");
+    };
+    let (path, keys) = args.split_last().unwrap();
+    let path = match Path::parse(path) {
+        Ok(path) => path,
+        Err(err) => return Value::Error(err),
+    };
+
+    let db = db.read(keys);
+    let values = keys.iter().map(|key| match db.json_get(key) {
+        Ok(Some(doc)) => json_path_reply(&path, doc),
+        _ => Value::Null,
+    });
+    Value::Array(values.collect())
+}
+
+fn json_numincrby(args: Vec<Value>, db: DB) -> Value {
+    if args.len() != 3 {
+        return Value::Error("ERR: Wrong number of arguments provided");
+    }
+
+    let Some(args) = bulk_strings(&args) else {
+        return Value::Error("ERR: Arguments must be bulk strings");
+    };
+    let path = match Path::parse(&args[1]) {
+        Ok(path) => path,
+        Err(err) => return Value::Error(err),
+    };
+    let increment = match Json::parse(&args[2]) {
+        Ok(value @ (Json::Int(_) | Json::Float(_))) => value,
+        _ => return Value::Error("ERR: Increment must be a number"),
+    };
+
+    let mut db = db.write([&args[0]]);
+    let doc = match db.json_get_mut(&args[0]) {
+        Ok(Some(doc)) => doc,
+        Ok(None) => return Value::Error("ERR: Key does not exist"),
+        Err(err) => return err,
+    };
+
+    let mut updates: Vec<(json::Location, Json)> = Vec::new();
+    let mut results: Vec<Json> = Vec::new();
+    for location in doc.select(&path) {
+        let result = match (doc.get(&location).unwrap(), &increment) {
+            (Json::Int(a), Json::Int(b)) => match a.checked_add(*b) {
+                Some(n) => Json::Int(n),
+                None => Json::Float(*a as f64 + *b as f64),
+            },
+            (Json::Int(a), Json::Float(b)) => Json::Float(*a as f64 + b),
+            (Json::Float(a), Json::Int(b)) => Json::Float(a + *b as f64),
+            (Json::Float(a), Json::Float(b)) => Json::Float(a + b),
+            _ => {
+                results.push(Json::Null);
+                continue;
+            },
+        };
+        if let Json::Float(n) = result {
+            if !n.is_finite() {
+                return Value::Error("ERR: Result is not a finite number");
+            }
+        }
+        results.push(result.clone());
+        updates.push((location, result));
+    }
+
+    if path.is_legacy() && updates.is_empty() {
+        return Value::Error("ERR: Path does not exist or is not a number");
+    }
+    for (location, value) in updates {
+        *doc.get_mut(&location).unwrap() = value;
+    }
+
+    let reply = match path.is_legacy() {
+        true => results.into_iter().find(|r| *r != Json::Null).unwrap(),
+        false => Json::Array(results),
+    };
+    Value::BulkStr(reply.serialize(&json::Format::default()))
+}
+
+fn json_arrappend(args: Vec<Value>, db: DB) -> Value {
+    if args.len() < 2 {
+        return Value::Error("ERR: Wrong number of arguments provided");
+    }
+
+    let Some(args) = bulk_strings(&args) else {
+        return Value::Error("ERR: Arguments must be bulk strings");
+    };
+    let (path, values) = match args.len() {
+        2 => (".", &args[1..]),
+        _ => (args[1].as_str(), &args[2..]),
+    };
+    let path = match Path::parse(path) {
+        Ok(path) => path,
+        Err(err) => return Value::Error(err),
+    };
+    let mut items: Vec<Json> = Vec::new();
+    for value in values {
+        match Json::parse(value) {
+            Ok(value) => items.push(value),
+            Err(err) => return Value::Error(err),
+        };
+    }
+
+    let mut db = db.write([&args[0]]);
+    let doc = match db.json_get_mut(&args[0]) {
+        Ok(Some(doc)) => doc,
+        Ok(None) => return Value::Error("ERR: Key does not exist"),
+        Err(err) => return err,
+    };
+
+    let locations = doc.select(&path);
+    for location in &locations {
+        let mut child = location.clone();
+        child.push(json::Key::Index(0));
+        if matches!(doc.get(location), Some(Json::Array(_))) && !items.iter().all(|item| item.fits_at(&child)) {
+            return Value::Error(json::TOO_DEEP);
+        }
+    }
+
+    let mut results: Vec<Value> = Vec::new();
+    for location in locations {
+        let result = match doc.get_mut(&location).unwrap() {
+            Json::Array(array) => {
+                array.extend(items.iter().cloned());
+                Value::Num(array.len() as i64)
+            },
+            _ => Value::Null,
+        };
+        results.push(result);
+    }
+    json_reply(&path, results)
+}
+
+fn json_strappend(args: Vec<Value>, db: DB) -> Value {
+    if args.len() < 2 || args.len() > 3 {
+        return Value::Error("ERR: Wrong number of arguments provided");
+    }
+
+    let Some(args) = bulk_strings(&args) else {
+        return Value::Error("ERR: Arguments must be bulk strings");
+    };
+    let (path, value) = match args.len() {
+        2 => (".", &args[1]),
+        _ => (args[1].as_str(), &args[2]),
+    };
+    let path = match Path::parse(path) {
+        Ok(path) => path,
+        Err(err) => return Value::Error(err),
+    };
+    let suffix = match Json::parse(value) {
+        Ok(Json::Str(s)) => s,
+        _ => return Value::Error("ERR: Value must be a JSON string"),
+    };
+
+    let mut db = db.write([&args[0]]);
+    let doc = match db.json_get_mut(&args[0]) {
+        Ok(Some(doc)) => doc,
+        Ok(None) => return Value::Error("ERR: Key does not exist"),
+        Err(err) => return err,
+    };
+
+    let mut results: Vec<Value> = Vec::new();
+    for location in doc.select(&path) {
+        let result = match doc.get_mut(&location).unwrap() {
+            Json::Str(s) => {
+                s.push_str(&suffix);
+                Value::Num(s.len() as i64)
+            },
+            _ => Value::Null,
+        };
+        results.push(result);
+    }
+    json_reply(&path, results)
+}
+
+fn json_type(args: Vec<Value>, db: DB) -> Value {
+    if args.is_empty() || args.len() > 2 {
+        return Value::Error("ERR: Wrong number of arguments provided");
+    }
+
+    let Some(args) = bulk_strings(&args) else {
+        return Value::Error("ERR: Arguments must be bulk strings");
+    };
+    let path = match Path::parse(args.get(1).map_or(".", |p| p.as_str())) {
+        Ok(path) => path,
+        Err(err) => return Value::Error(err),
+    };
+
+    let db = db.read([&args[0]]);
+    let doc = match db.json_get(&args[0]) {
+        Ok(Some(doc)) => doc,
+        Ok(None) => return Value::Null,
+        Err(err) => return err,
+    };
+    let types = doc.select(&path).into_iter().map(|l| doc.get(&l).unwrap().type_name());
+    match path.is_legacy() {
+        true => types.map(Value::Str).next().unwrap_or(Value::Null),
+        false => Value::Array(types.map(|t| Value::BulkStr(t.into())).collect()),
+    }
+}
+
+fn json_objkeys(args: Vec<Value>, db: DB) -> Value {
+    if args.is_empty() || args.len() > 2 {
+        return Value::Error("ERR: Wrong number of arguments provided");
+    }
+
+    let Some(args) = bulk_strings(&args) else {
+        return Value::Error("ERR: Arguments must be bulk strings");
+    };
+    let path = match Path::parse(args.get(1).map_or(".", |p| p.as_str())) {
+        Ok(path) => path,
+        Err(err) => return Value::Error(err),
+    };
+
+    let db = db.read([&args[0]]);
+    let doc = match db.json_get(&args[0]) {
+        Ok(Some(doc)) => doc,
+        Ok(None) => return Value::Null,
+        Err(err) => return err,
+    };
+
+    let mut results: Vec<Value> = Vec::new();
+    for location in doc.select(&path) {
+        let result = match doc.get(&location).unwrap() {
+            Json::Object(fields) => {
+                Value::Array(fields.iter().map(|(k, _)| Value::BulkStr(k.clone())).collect())
+            },
+            _ => Value::Null,
+        };
+        results.push(result);
+    }
+    match path.is_legacy() {
+        true => results.into_iter().next().unwrap_or(Value::Null),
+        false => Value::Array(results),
+    }
+}
+
+/// Like RedisTimeSeries, timestamps go up to `i64::MAX`, which keeps the
+/// chunks' signed deltas from overflowing.
+fn parse_timestamp(arg: &str) -> Option<u64> {
+    match arg {
+        "*" => Some(now_ms()),
+        _ => arg.parse::<u64>().ok().filter(|t| *t <= timeseries::MAX_TIMESTAMP),
+    }
+}
+
+fn parse_value(arg: &str) -> Option<f64> {
+    arg.parse::<f64>().ok().filter(|n| n.is_finite())
+}
+
+struct TsOptions {
+    retention: u64,
+    chunk_size: usize,
+    compressed: bool,
+    duplicate_policy: DuplicatePolicy,
+    on_duplicate: Option<DuplicatePolicy>,
+    labels: Vec<(String, String)>,
+}
+
+fn ts_options(args: &[String]) -> Result<TsOptions, &'static str> {
+    let mut options = TsOptions {
+        retention: 0,
+        chunk_size: timeseries::DEFAULT_CHUNK_SIZE,
+        compressed: true,
+        duplicate_policy: DuplicatePolicy::Block,
+        on_duplicate: None,
+        labels: Vec::new(),
+    };
+
+    let mut args = args.iter();
+    while let Some(option) = args.next() {
+        match option.to_uppercase().as_str() {
+            "RETENTION" => {
+                options.retention = parse_number(args.next()).ok_or("ERR: Invalid retention value")?;
+            },
+            "CHUNK_SIZE" => match parse_number::<usize>(args.next()) {
+                Some(n) if (timeseries::MIN_CHUNK_SIZE..=timeseries::MAX_CHUNK_SIZE).contains(&n) => options.chunk_size = n,
+                _ => return Err("ERR: Invalid chunk size"),
+            },
+            "ENCODING" => match args.next().map(|a| a.to_uppercase()).as_deref() {
+                Some("COMPRESSED") => options.compressed = true,
+                Some("UNCOMPRESSED") => options.compressed = false,
+                _ => return Err("ERR: Unknown encoding"),
+            },
+            "UNCOMPRESSED" => options.compressed = false,
+            "DUPLICATE_POLICY" => {
+                let policy = args.next().and_then(|p| DuplicatePolicy::parse(p));
+                options.duplicate_policy = policy.ok_or("ERR: Unknown duplicate policy")?;
+            },
+            "ON_DUPLICATE" => {
+                let policy = args.next().and_then(|p| DuplicatePolicy::parse(p));
+                options.on_duplicate = Some(policy.ok_or("ERR: Unknown duplicate policy")?);
+            },
+            "LABELS" => {
+                let rest: Vec<&String> = args.by_ref().collect();
+                if rest.is_empty() || !rest.len().is_multiple_of(2) {
+                    return Err("ERR: Labels must be label value pairs");
+                }
+                options.labels = rest.chunks(2).map(|p| (p[0].clone(), p[1].clone())).collect();
+            },
+            _ => return Err("ERR: Syntax error"),
+        };
+    }
+    Ok(options)
+}
+
+/// Where `name` is among the options of a time series command, which are
+/// walked along with their arguments up to LABELS, as labels and values
+/// may be named like options.
+fn ts_option<'a>(mut options: impl Iterator<Item = &'a str>, name: &str) -> Option<usize> {
+    let mut position = 0;
+    while let Some(option) = options.next() {
+        let option = option.to_uppercase();
+        match option.as_str() {
+            _ if option == name => return Some(position),
+            "LABELS" => return None,
+            "UNCOMPRESSED" => position += 1,
+            _ => {
+                options.next();
+                position += 2;
+            },
+        };
+    }
+    None
+}
+
+fn new_series(options: &TsOptions) -> TimeSeries {
+    let mut series = TimeSeries::new(options.retention, options.chunk_size, options.compressed, options.duplicate_policy);
+    series.set_labels(options.labels.clone());
+    series
+}
+
+fn sample_reply((timestamp, value): timeseries::Sample) -> Value {
+    Value::Array(vec![Value::Num(timestamp as i64), Value::BulkStr(value.to_string())])
+}
+
+fn ts_create(args: Vec<Value>, db: DB) -> Value {
+    if args.is_empty() {
+        return Value::Error("ERR: Wrong number of arguments provided");
+    }
+
+    let Some(args) = bulk_strings(&args) else {
+        return Value::Error("ERR: Arguments must be bulk strings");
+    };
+    let options = match ts_options(&args[1..]) {
+        Ok(options) => options,
+        Err(err) => return Value::Error(err),
+    };
+
+    let mut db = db.write([&args[0]]);
+    if db.contains(&args[0]) {
+        return Value::Error("ERR: Key already exists");
+    }
+    db.ts_push(args[0].clone(), new_series(&options));
+    Value::Str("OK")
+}
+
+fn ts_add_sample(db: &mut Keyspace, key: &str, timestamp: &str, value: &str, options: &TsOptions) -> Value {
+    let Some(timestamp) = parse_timestamp(timestamp) else {
+        return Value::Error("ERR: Invalid timestamp");
+    };
+    let Some(value) = parse_value(value) else {
+        return Value::Error("ERR: Invalid value");
+    };
+
+    if !db.contains(key) {
+        db.ts_push(key.into(), new_series(options));
+    }
+    let series = match db.ts_get_mut(key) {
+        Ok(Some(series)) => series,
+        Ok(None) => unreachable!(),
+        Err(err) => return err,
+    };
+    match series.add((timestamp, value), options.on_duplicate) {
+        Ok(timestamp) => Value::Num(timestamp as i64),
+        Err(err) => Value::Error(err),
+    }
+}
+
+fn ts_add(args: Vec<Value>, db: DB) -> Value {
+    if args.len() < 3 {
+        return Value::Error("ERR: Wrong number of arguments provided");
+    }
+
+    let Some(args) = bulk_strings(&args) else {
+        return Value::Error("ERR: Arguments must be bulk strings");
+    };
+    let options = match ts_options(&args[3..]) {
+        Ok(options) => options,
+        Err(err) => return Value::Error(err),
+    };
+    ts_add_sample(&mut db.write([&args[0]]), &args[0], &args[1], &args[2], &options)
+}
+
+fn ts_madd(args: Vec<Value>, db: DB) -> Value {
+    if args.is_empty() || !args.len().is_multiple_of(3) {
+        return Value::Error("ERR: Wrong number of arguments provided");
+    }
+
+    let Some(args) = bulk_strings(&args) else {
+        return Value::Error("ERR: Arguments must be bulk strings");
+    };
+    let options = ts_options(&[]).unwrap();
+
+    let mut db = db.write(args.iter().step_by(3));
+    let mut values: Vec<Value> = Vec::new();
+    for triple in args.chunks(3) {
+        if !db.contains(&triple[0]) {
+            values.push(Value::Error("ERR: The key does not exist"));
+            continue;
+        }
+        values.push(ts_add_sample(&mut db, &triple[0], &triple[1], &triple[2], &options));
+    }
+    Value::Array(values)
+}
+
+fn ts_incrby(args: Vec<Value>, db: DB) -> Value {
+    if args.len() < 2 {
+        return Value::Error("ERR: Wrong number of arguments provided");
+    }
+
+    let Some(mut args) = bulk_strings(&args) else {
+        return Value::Error("ERR: Arguments must be bulk strings");
+    };
+    let Some(increment) = parse_value(&args[1]) else {
+        return Value::Error("ERR: Invalid value");
+    };
+
+    let mut timestamp = None;
+    if let Some(i) = ts_option(args[2..].iter().map(String::as_str), "TIMESTAMP").map(|p| p + 2) {
+        let Some(t) = args.get(i + 1).and_then(|t| parse_timestamp(t)) else {
+            return Value::Error("ERR: Invalid timestamp");
+        };
+        timestamp = Some(t);
+        args.drain(i..i + 2);
+    }
+    let options = match ts_options(&args[2..]) {
+        Ok(options) => options,
+        Err(err) => return Value::Error(err),
+    };
+
+    let mut db = db.write([&args[0]]);
+    if !db.contains(&args[0]) {
+        db.ts_push(args[0].clone(), new_series(&options));
+    }
+    let series = match db.ts_get_mut(&args[0]) {
+        Ok(Some(series)) => series,
+        Ok(None) => unreachable!(),
+        Err(err) => return err,
+    };
+    let timestamp = timestamp.unwrap_or_else(now_ms);
+
+    let value = match series.last_sample() {
+        Some((last, _)) if timestamp < last => {
+            return Value::Error("ERR: Timestamp must be equal to or higher than the maximum existing timestamp");
+        },
+        Some((_, value)) => value + increment,
+        None => increment,
+    };
+    match series.add((timestamp, value), Some(DuplicatePolicy::Last)) {
+        Ok(timestamp) => Value::Num(timestamp as i64),
+        Err(err) => Value::Error(err),
+    }
+}
+
+fn ts_range_generic(args: Vec<Value>, db: DB, reverse: bool) -> Value {
+    if args.len() < 3 {
+        return Value::Error("ERR: Wrong number of arguments provided");
+    }
+
+    let Some(args) = bulk_strings(&args) else {
+        return Value::Error("ERR: Arguments must be bulk strings");
+    };
+    let from = match args[1].as_str() {
+        "-" => Some(0),
+        from => from.parse::<u64>().ok(),
+    };
+    let to = match args[2].as_str() {
+        "+" => Some(u64::MAX),
+        to => to.parse::<u64>().ok(),
+    };
+    let (Some(from), Some(to)) = (from, to) else {
+        return Value::Error("ERR: Invalid timestamp");
+    };
+
+    let mut count: Option<usize> = None;
+    let mut aggregation: Option<(Aggregation, u64)> = None;
+    let mut options = args[3..].iter();
+    while let Some(option) = options.next() {
+        match option.to_uppercase().as_str() {
+            "COUNT" => match parse_number::<usize>(options.next()) {
+                Some(n) => count = Some(n),
+                None => return Value::Error("ERR: Invalid count"),
+            },
+            "AGGREGATION" => {
+                let kind = options.next().and_then(|a| Aggregation::parse(a));
+                let duration = parse_number::<u64>(options.next()).filter(|d| *d > 0);
+                match (kind, duration) {
+                    (Some(kind), Some(duration)) => aggregation = Some((kind, duration)),
+                    _ => return Value::Error("ERR: Invalid aggregation"),
+                };
+            },
+            _ => return Value::Error("ERR: Syntax error"),
+        };
+    }
+
+    let db = db.read([&args[0]]);
+    let series = match db.ts_get(&args[0]) {
+        Ok(Some(series)) => series,
+        Ok(None) => return Value::Error("ERR: The key does not exist"),
+        Err(err) => return err,
+    };
+
+    let mut samples = series.range(from, to);
+    if let Some((kind, duration)) = aggregation {
+        samples = timeseries::aggregate(&samples, kind, duration);
+    }
+    if reverse {
+        samples.reverse();
+    }
+    if let Some(count) = count {
+        samples.truncate(count);
+    }
+    Value::Array(samples.into_iter().map(sample_reply).collect())
+}
+
+fn ts_range(args: Vec<Value>, db: DB) -> Value {
+    ts_range_generic(args, db, false)
+}
+
+fn ts_revrange(args: Vec<Value>, db: DB) -> Value {
+    ts_range_generic(args, db, true)
+}
+
+fn ts_get(args: Vec<Value>, db: DB) -> Value {
+    if args.len() != 1 {
+        return Value::Error("ERR: Wrong number of arguments provided");
+    }
+
+    let Value::BulkStr(key) = &args[0] else {
+        return Value::Error("ERR: Incorrect definition for key");
+    };
+    match db.read([key]).ts_get(key) {
+        Ok(Some(series)) => match series.last_sample() {
+            Some(sample) => sample_reply(sample),
+            None => Value::Array(Vec::new()),
+        },
+        Ok(None) => Value::Error("ERR: The key does not exist"),
+        Err(err) => err,
+    }
+}
+
+fn ts_info(args: Vec<Value>, db: DB) -> Value {
+    if args.len() != 1 {
+        return Value::Error("ERR: Wrong number of arguments provided");
+    }
+
+    let Value::BulkStr(key) = &args[0] else {
+        return Value::Error("ERR: Incorrect definition for key");
+    };
+    let db = db.read([key]);
+    let series = match db.ts_get(key) {
+        Ok(Some(series)) => series,
+        Ok(None) => return Value::Error("ERR: The key does not exist"),
+        Err(err) => return err,
+    };
+
+    let labels = series.labels().iter().map(|(label, value)| {
+        Value::Array(vec![Value::BulkStr(label.clone()), Value::BulkStr(value.clone())])
+    });
+    let timestamp = |t: Option<u64>| Value::Num(t.unwrap_or(0) as i64);
+    Value::Array(vec![
+        Value::Str("totalSamples"), Value::Num(series.len() as i64),
+        Value::Str("memoryUsage"), Value::Num(series.memory() as i64),
+        Value::Str("firstTimestamp"), timestamp(series.first_timestamp()),
+        Value::Str("lastTimestamp"), timestamp(series.last_sample().map(|s| s.0)),
+        Value::Str("retentionTime"), Value::Num(series.retention() as i64),
+        Value::Str("chunkCount"), Value::Num(series.chunk_count() as i64),
+        Value::Str("chunkSize"), Value::Num(series.chunk_size() as i64),
+        Value::Str("chunkType"), Value::Str(if series.is_compressed() { "compressed" } else { "uncompressed" }),
+        Value::Str("duplicatePolicy"), Value::Str(series.duplicate_policy().name()),
+        Value::Str("labels"), Value::Array(labels.collect()),
+    ])
+}
+
+const INVALID_STREAM_ID: &str = "ERR: Invalid stream ID specified as stream command argument";
+const NO_STREAM: &str = "ERR: The XGROUP subcommand requires the key to exist. \
+    Note that for CREATE you may want to use the MKSTREAM option to create an empty stream automatically";
+
+fn failed(err: &'static str) -> (Value, Vec<Value>) {
+    (Value::Error(err), Vec::new())
+}
+
+/// A command as the AOF logs it.
+fn command_value<S: Into<String>>(parts: impl IntoIterator<Item = S>) -> Value {
+    Value::Array(parts.into_iter().map(|part| Value::BulkStr(part.into())).collect())
+}
+
+fn stream_entry_reply(id: StreamId, fields: Option<&Fields>) -> Value {
+    let fields = match fields {
+        Some(fields) => Value::Array(fields.iter()
+            .flat_map(|(field, value)| [Value::BulkBytes(field.clone()), Value::BulkBytes(value.clone())])
+            .collect()),
+        None => Value::Null,
+    };
+    Value::Array(vec![Value::BulkStr(id.to_string()), fields])
+}
+
+fn stream_ids_reply(ids: &[StreamId]) -> Value {
+    Value::Array(ids.iter().map(|id| Value::BulkStr(id.to_string())).collect())
+}
+
+/// A bound of XRANGE: `-`, `+`, or an ID whose sequence defaults to the
+/// lowest for the start and the highest for the end, left out after `(`.
+fn parse_range_bound(arg: &str, start: bool) -> Option<StreamId> {
+    let seq = if start { 0 } else { u64::MAX };
+    match (arg, arg.strip_prefix('(')) {
+        ("-", _) => Some(StreamId::MIN),
+        ("+", _) => Some(StreamId::MAX),
+        (_, Some(id)) if start => StreamId::parse(id, seq)?.next(),
+        (_, Some(id)) => StreamId::parse(id, seq)?.prev(),
+        (_, None) => StreamId::parse(arg, seq),
+    }
+}
+
+fn parse_stream_ids(args: &[String]) -> Option<Vec<StreamId>> {
+    args.iter().map(|id| StreamId::parse(id, 0)).collect()
+}
+
+/// Parses `MAXLEN|MINID [=|~] threshold`, returning how many arguments it
+/// took.
+fn parse_trim(args: &[Value]) -> Result<(Trim, usize), &'static str> {
+    let arg = |i: usize| match args.get(i) {
+        Some(Value::BulkStr(arg)) => Some(arg.as_str()),
+        _ => None,
+    };
+    let operator = matches!(arg(1), Some("=" | "~")) as usize;
+    let threshold = arg(1 + operator).ok_or("ERR: Syntax error")?;
+    let trim = match arg(0).map(str::to_uppercase).as_deref() {
+        Some("MAXLEN") => Trim::MaxLen(threshold.parse().map_err(|_| "ERR: The MAXLEN argument must be >= 0")?),
+        Some("MINID") => Trim::MinId(StreamId::parse(threshold, 0).ok_or(INVALID_STREAM_ID)?),
+        _ => return Err("ERR: Syntax error"),
+    };
+    Ok((trim, 2 + operator))
+}
+
+/// Logged with the ID it got, so the entry comes back the same.
+fn xadd(args: Vec<Value>, db: DB) -> (Value, Vec<Value>) {
+    let Some(Value::BulkStr(key)) = args.first() else {
+        return failed("ERR: Wrong number of arguments provided");
+    };
+
+    let (mut position, mut create, mut trim) = (1, true, None);
+    loop {
+        match args.get(position) {
+            Some(Value::BulkStr(option)) if option.eq_ignore_ascii_case("NOMKSTREAM") => {
+                create = false;
+                position += 1;
+            },
+            Some(Value::BulkStr(option)) if option.eq_ignore_ascii_case("MAXLEN") || option.eq_ignore_ascii_case("MINID") => {
+                match parse_trim(&args[position..]) {
+                    Ok((option, len)) => {
+                        trim = Some(option);
+                        position += len;
+                    },
+                    Err(err) => return failed(err),
+                };
+            },
+            _ => break,
+        };
+    }
+    let pairs = args.get(position + 1..).unwrap_or_default();
+    if pairs.is_empty() || !pairs.len().is_multiple_of(2) {
+        return failed("ERR: Wrong number of arguments provided");
+    }
+    let Some(id) = args.get(position).and_then(|id| id.bytes()).and_then(|id| NewId::parse(std::str::from_utf8(id).ok()?)) else {
+        return failed(INVALID_STREAM_ID);
+    };
+    let fields: Option<Fields> = pairs.chunks(2)
+        .map(|pair| Some((pair[0].bytes()?.to_vec(), pair[1].bytes()?.to_vec())))
+        .collect();
+    let Some(fields) = fields else {
+        return failed("ERR: Fields and values must be bulk strings");
+    };
+
+    let mut db = db.write([key]);
+    match db.stream_get(key) {
+        Ok(Some(_)) => (),
+        Ok(None) if !create => return (Value::Null, Vec::new()),
+        Ok(None) => db.stream_push(key.clone(), Stream::default()),
+        Err(err) => return (err, Vec::new()),
+    };
+    let Ok(Some(stream)) = db.stream_get_mut(key) else {
+        unreachable!();
+    };
+    let id = match stream.add(id, fields, now_ms()) {
+        Ok(id) => id,
+        Err(err) => {
+            // Only a stream that was just created can be empty.
+            if stream.is_empty() && stream.groups().is_empty() && stream.last_id() == StreamId::MIN {
+                db.remove(key);
+            }
+            return failed(err);
+        },
+    };
+    if let Some(trim) = trim {
+        stream.trim(trim);
+    }
+
+    let mut effect = vec![Value::BulkStr("XADD".into())];
+    effect.extend(args);
+    effect[position + 1] = Value::BulkStr(id.to_string());
+    (Value::BulkStr(id.to_string()), vec![Value::Array(effect)])
+}
+
+fn xlen(args: Vec<Value>, db: DB) -> Value {
+    if args.len() != 1 {
+        return Value::Error("ERR: Wrong number of arguments provided");
+    }
+
+    let Value::BulkStr(key) = &args[0] else {
+        return Value::Error("ERR: Incorrect definition for key");
+    };
+    match db.read([key]).stream_get(key) {
+        Ok(stream) => Value::Num(stream.map_or(0, Stream::len) as i64),
+        Err(err) => err,
+    }
+}
+
+fn xrange_generic(args: Vec<Value>, db: DB, reverse: bool) -> Value {
+    if args.len() != 3 && args.len() != 5 {
+        return Value::Error("ERR: Wrong number of arguments provided");
+    }
+
+    let Some(args) = bulk_strings(&args) else {
+        return Value::Error("ERR: Arguments must be bulk strings");
+    };
+    let (start, end) = match reverse {
+        false => (&args[1], &args[2]),
+        true => (&args[2], &args[1]),
+    };
+    let (Some(start), Some(end)) = (parse_range_bound(start, true), parse_range_bound(end, false)) else {
+        return Value::Error(INVALID_STREAM_ID);
+    };
+    let count = match args.get(3) {
+        Some(option) if option.eq_ignore_ascii_case("COUNT") => match args[4].parse::<usize>() {
+            Ok(count) => Some(count),
+            Err(_) => return Value::Error("ERR: Value is not an integer or out of range"),
+        },
+        Some(_) => return Value::Error("ERR: Syntax error"),
+        None => None,
+    };
+
+    match db.read([&args[0]]).stream_get(&args[0]) {
+        Ok(Some(stream)) => Value::Array(stream.range(start, end, count, reverse).into_iter()
+            .map(|(id, fields)| stream_entry_reply(id, Some(fields)))
+            .collect()),
+        Ok(None) => Value::Array(Vec::new()),
+        Err(err) => err,
+    }
+}
+
+fn xrange(args: Vec<Value>, db: DB) -> Value {
+    xrange_generic(args, db, false)
+}
+
+fn xrevrange(args: Vec<Value>, db: DB) -> Value {
+    xrange_generic(args, db, true)
+}
+
+fn xdel(args: Vec<Value>, db: DB) -> Value {
+    if args.len() < 2 {
+        return Value::Error("ERR: Wrong number of arguments provided");
+    }
+
+    let Some(args) = bulk_strings(&args) else {
+        return Value::Error("ERR: Arguments must be bulk strings");
+    };
+    let Some(ids) = parse_stream_ids(&args[1..]) else {
+        return Value::Error(INVALID_STREAM_ID);
+    };
+    match db.write([&args[0]]).stream_get_mut(&args[0]) {
+        Ok(Some(stream)) => Value::Num(stream.delete(&ids) as i64),
+        Ok(None) => Value::Num(0),
+        Err(err) => err,
+    }
+}
+
+fn xtrim(args: Vec<Value>, db: DB) -> Value {
+    if args.len() < 3 {
+        return Value::Error("ERR: Wrong number of arguments provided");
+    }
+
+    let Value::BulkStr(key) = &args[0] else {
+        return Value::Error("ERR: Incorrect definition for key");
+    };
+    let trim = match parse_trim(&args[1..]) {
+        Ok((trim, len)) if len == args.len() - 1 => trim,
+        Ok(_) => return Value::Error("ERR: Syntax error"),
+        Err(err) => return Value::Error(err),
+    };
+    match db.write([key]).stream_get_mut(key) {
+        Ok(Some(stream)) => Value::Num(stream.trim(trim) as i64),
+        Ok(None) => Value::Num(0),
+        Err(err) => err,
+    }
+}
+
+/// The options of XREAD and XREADGROUP, with BLOCK's position among the
+/// arguments for the connection to take it out.
+struct ReadOptions {
+    group: Option<(String, String)>,
+    count: Option<usize>,
+    block: Option<(usize, u64)>,
+    no_ack: bool,
+    keys: Vec<String>,
+    ids: Vec<String>,
+}
+
+/// Options are read one after the other up to STREAMS, so a group or
+/// consumer named like one isn't taken for it.
+fn read_options(args: &[Value], group: bool) -> Result<ReadOptions, &'static str> {
+    let Some(args) = bulk_strings(args) else {
+        return Err("ERR: Arguments must be bulk strings");
+    };
+    let mut options = ReadOptions { group: None, count: None, block: None, no_ack: false, keys: Vec::new(), ids: Vec::new() };
+
+    let mut position = 0;
+    loop {
+        let Some(option) = args.get(position) else {
+            return Err("ERR: Syntax error");
+        };
+        position += match option.to_uppercase().as_str() {
+            "GROUP" if group => match args.get(position + 1..position + 3) {
+                Some([name, consumer]) => {
+                    options.group = Some((name.clone(), consumer.clone()));
+                    3
+                },
+                _ => return Err("ERR: Syntax error"),
+            },
+            "COUNT" => {
+                let count = parse_number::<usize>(args.get(position + 1));
+                options.count = count.ok_or("ERR: Value is not an integer or out of range")?.checked_sub(1).map(|n| n + 1);
+                2
+            },
+            "BLOCK" => {
+                let timeout = parse_number(args.get(position + 1)).ok_or("ERR: Timeout is not an integer or out of range")?;
+                options.block = Some((position, timeout));
+                2
+            },
+            "NOACK" if group => {
+                options.no_ack = true;
+                1
+            },
+            "STREAMS" => break,
+            _ => return Err("ERR: Syntax error"),
+        };
+    }
+    if group && options.group.is_none() {
+        return Err("ERR: Missing GROUP option for XREADGROUP");
+    }
+
+    let streams = &args[position + 1..];
+    if streams.is_empty() || !streams.len().is_multiple_of(2) {
+        return Err("ERR: Unbalanced list of streams: for each stream key an ID must be specified");
+    }
+    let (keys, ids) = streams.split_at(streams.len() / 2);
+    (options.keys, options.ids) = (keys.to_vec(), ids.to_vec());
+    Ok(options)
+}
+
+/// Replies nothing when no stream has entries after its ID, which is what
+/// a blocked read waits on. BLOCK is the connection's to handle.
+fn xread(args: Vec<Value>, db: DB) -> Value {
+    let options = match read_options(&args, false) {
+        Ok(options) => options,
+        Err(err) => return Value::Error(err),
+    };
+    let mut starts = Vec::new();
+    for id in &options.ids {
+        match id.as_str() {
+            "$" => starts.push(None),
+            id => match StreamId::parse(id, 0) {
+                Some(id) => starts.push(Some(id)),
+                None => return Value::Error(INVALID_STREAM_ID),
+            },
+        };
+    }
+
+    let db = db.read(&options.keys);
+    let mut streams = Vec::new();
+    for (key, start) in options.keys.iter().zip(starts) {
+        let stream = match db.stream_get(key) {
+            Ok(Some(stream)) => stream,
+            Ok(None) => continue,
+            Err(err) => return err,
+        };
+        let Some(start) = start.unwrap_or(stream.last_id()).next() else {
+            continue;
+        };
+        let entries = stream.range(start, StreamId::MAX, options.count, false);
+        if !entries.is_empty() {
+            let entries = entries.into_iter().map(|(id, fields)| stream_entry_reply(id, Some(fields)));
+            streams.push(Value::Array(vec![Value::BulkStr(key.clone()), Value::Array(entries.collect())]));
+        }
+    }
+    match streams.is_empty() {
+        true => Value::Null,
+        false => Value::Array(streams),
+    }
+}
+
+/// XCLAIM commands that give the consumer the pending entries `ids` just as
+/// it has them now, so the AOF doesn't depend on when it's replayed.
+fn claim_effects(key: &str, group: &str, consumer: &str, stream: &Stream, ids: &[StreamId]) -> Vec<Value> {
+    let Some(pending) = stream.group(group).map(|group| group.pending()) else {
+        return Vec::new();
+    };
+    let mut batches: BTreeMap<(u64, u64), Vec<String>> = BTreeMap::new();
+    for id in ids {
+        if let Some(entry) = pending.get(id).filter(|entry| entry.consumer == consumer) {
+            batches.entry((entry.delivered, entry.count)).or_default().push(id.to_string());
+        }
+    }
+
+    batches.into_iter()
+        .map(|((delivered, count), ids)| {
+            let mut parts = vec!["XCLAIM".to_string(), key.into(), group.into(), consumer.into(), "0".into()];
+            parts.extend(ids);
+            parts.extend(["TIME".into(), delivered.to_string(), "RETRYCOUNT".into(), count.to_string()]);
+            parts.extend(["FORCE".into(), "JUSTID".into()]);
+            command_value(parts)
+        })
+        .collect()
+}
+
+/// Logged as the consumer it created, how far the group read and the
+/// entries each consumer was given, instead of reads that would deliver
+/// them at another time when replayed.
+fn xreadgroup(args: Vec<Value>, db: DB) -> (Value, Vec<Value>) {
+    let options = match read_options(&args, true) {
+        Ok(options) => options,
+        Err(err) => return failed(err),
+    };
+    let Some((group, consumer)) = &options.group else {
+        unreachable!();
+    };
+    let mut starts = Vec::new();
+    for id in &options.ids {
+        match id.as_str() {
+            ">" => starts.push(None),
+            id => match StreamId::parse(id, 0) {
+                Some(id) => starts.push(Some(id)),
+                None => return failed(INVALID_STREAM_ID),
+            },
+        };
+    }
+
+    let mut db = db.write(&options.keys);
+    for key in &options.keys {
+        match db.stream_get(key) {
+            Ok(Some(stream)) if stream.group(group).is_some() => (),
+            Ok(_) => return failed(stream::NOGROUP),
+            Err(err) => return (err, Vec::new()),
+        };
+    }
+
+    let now = now_ms();
+    let (mut streams, mut effects) = (Vec::new(), Vec::new());
+    for (key, start) in options.keys.iter().zip(starts) {
+        let Ok(Some(stream)) = db.stream_get_mut(key) else {
+            unreachable!();
+        };
+        let created = stream.create_consumer(group, consumer, now) == Ok(true);
+        let Ok(read) = stream.read_group(group, consumer, start, options.count, options.no_ack, now) else {
+            unreachable!();
+        };
+
+        if created {
+            effects.push(command_value(["XGROUP", "CREATECONSUMER", key, group, consumer]));
+        }
+        if start.is_none() && !read.is_empty() {
+            let last = stream.group(group).map(|group| group.last_id().to_string()).unwrap_or_default();
+            effects.push(command_value(["XGROUP", "SETID", key, group, &last]));
+        }
+        let delivered: Vec<StreamId> = read.iter().filter(|(_, fields)| fields.is_some()).map(|(id, _)| *id).collect();
+        effects.extend(claim_effects(key, group, consumer, stream, &delivered));
+
+        if start.is_some() || !read.is_empty() {
+            let entries = read.iter().map(|(id, fields)| stream_entry_reply(*id, fields.as_ref()));
+            streams.push(Value::Array(vec![Value::BulkStr(key.clone()), Value::Array(entries.collect())]));
+        }
+    }
+    match streams.is_empty() {
+        true => (Value::Null, effects),
+        false => (Value::Array(streams), effects),
+    }
+}
+
+fn xack(args: Vec<Value>, db: DB) -> Value {
+    if args.len() < 3 {
+        return Value::Error("ERR: Wrong number of arguments provided");
+    }
+
+    let Some(args) = bulk_strings(&args) else {
+        return Value::Error("ERR: Arguments must be bulk strings");
+    };
+    let Some(ids) = parse_stream_ids(&args[2..]) else {
+        return Value::Error(INVALID_STREAM_ID);
+    };
+    match db.write([&args[0]]).stream_get_mut(&args[0]) {
+        Ok(Some(stream)) => Value::Num(stream.ack(&args[1], &ids) as i64),
+        Ok(None) => Value::Num(0),
+        Err(err) => err,
+    }
+}
+
+/// Logged like XREADGROUP, with the entries dropped as removed from the
+/// stream acknowledged.
+fn xclaim(args: Vec<Value>, db: DB) -> (Value, Vec<Value>) {
+    if args.len() < 5 {
+        return failed("ERR: Wrong number of arguments provided");
+    }
+
+    let Some(args) = bulk_strings(&args) else {
+        return failed("ERR: Arguments must be bulk strings");
+    };
+    let (key, group, consumer) = (&args[0], &args[1], &args[2]);
+    let Some(min_idle) = parse_number::<u64>(args.get(3)) else {
+        return failed("ERR: Invalid min-idle-time argument for XCLAIM");
+    };
+    let ids: Vec<StreamId> = args[4..].iter().map_while(|id| StreamId::parse(id, 0)).collect();
+    if ids.is_empty() {
+        return failed(INVALID_STREAM_ID);
+    }
+
+    let now = now_ms();
+    let mut claim = Claim { min_idle, ..Claim::default() };
+    let mut options = args[4 + ids.len()..].iter();
+    while let Some(option) = options.next() {
+        let number = |value: Option<&String>| parse_number::<u64>(value).ok_or("ERR: Value is not an integer or out of range");
+        let result = match option.to_uppercase().as_str() {
+            "IDLE" => number(options.next()).map(|idle| claim.delivered = Some(now.saturating_sub(idle))),
+            "TIME" => number(options.next()).map(|time| claim.delivered = Some(time)),
+            "RETRYCOUNT" => number(options.next()).map(|count| claim.count = Some(count)),
+            "LASTID" => match options.next().and_then(|id| StreamId::parse(id, 0)) {
+                Some(id) => {
+                    claim.last_id = Some(id);
+                    Ok(())
+                },
+                None => Err(INVALID_STREAM_ID),
+            },
+            "FORCE" => {
+                claim.force = true;
+                Ok(())
+            },
+            "JUSTID" => {
+                claim.just_id = true;
+                Ok(())
+            },
+            _ => Err("ERR: Syntax error"),
+        };
+        if let Err(err) = result {
+            return failed(err);
+        }
+    }
+
+    let mut db = db.write([key]);
+    let stream = match db.stream_get_mut(key) {
+        Ok(Some(stream)) => stream,
+        Ok(None) => return failed(stream::NOGROUP),
+        Err(err) => return (err, Vec::new()),
+    };
+    let created = match stream.create_consumer(group, consumer, now) {
+        Ok(created) => created,
+        Err(err) => return failed(err),
+    };
+    let Ok((claimed, deleted)) = stream.claim(group, consumer, &ids, &claim, now) else {
+        unreachable!();
+    };
+
+    let mut effects = Vec::new();
+    if created {
+        effects.push(command_value(["XGROUP", "CREATECONSUMER", key, group, consumer]));
+    }
+    if claim.last_id.is_some() {
+        let last = stream.group(group).map(|group| group.last_id().to_string()).unwrap_or_default();
+        effects.push(command_value(["XGROUP", "SETID", key, group, &last]));
+    }
+    effects.extend(claim_effects(key, group, consumer, stream, &claimed));
+    if !deleted.is_empty() {
+        effects.push(command_value(["XACK".to_string(), key.clone(), group.clone()].into_iter().chain(deleted.iter().map(|id| id.to_string()))));
+    }
+
+    let reply = match claim.just_id {
+        true => stream_ids_reply(&claimed),
+        false => Value::Array(claimed.iter().map(|id| stream_entry_reply(*id, stream.get(*id))).collect()),
+    };
+    (reply, effects)
+}
+
+fn xautoclaim(args: Vec<Value>, db: DB) -> (Value, Vec<Value>) {
+    if args.len() < 5 {
+        return failed("ERR: Wrong number of arguments provided");
+    }
+
+    let Some(args) = bulk_strings(&args) else {
+        return failed("ERR: Arguments must be bulk strings");
+    };
+    let (key, group, consumer) = (&args[0], &args[1], &args[2]);
+    let Some(min_idle) = parse_number::<u64>(args.get(3)) else {
+        return failed("ERR: Invalid min-idle-time argument for XAUTOCLAIM");
+    };
+    let Some(start) = parse_range_bound(&args[4], true) else {
+        return failed(INVALID_STREAM_ID);
+    };
+    let (mut count, mut just_id) = (100, false);
+    let mut options = args[5..].iter();
+    while let Some(option) = options.next() {
+        match option.to_uppercase().as_str() {
+            "COUNT" => match parse_number::<usize>(options.next()) {
+                Some(n) if n > 0 => count = n,
+                _ => return failed("ERR: COUNT must be > 0"),
+            },
+            "JUSTID" => just_id = true,
+            _ => return failed("ERR: Syntax error"),
+        };
+    }
+
+    let now = now_ms();
+    let mut db = db.write([key]);
+    let stream = match db.stream_get_mut(key) {
+        Ok(Some(stream)) => stream,
+        Ok(None) => return failed(stream::NOGROUP),
+        Err(err) => return (err, Vec::new()),
+    };
+    let created = match stream.create_consumer(group, consumer, now) {
+        Ok(created) => created,
+        Err(err) => return failed(err),
+    };
+    let claim = Claim { min_idle, just_id, ..Claim::default() };
+    let Ok((next, claimed, deleted)) = stream.autoclaim(group, consumer, &claim, start, count, now) else {
+        unreachable!();
+    };
+
+    let mut effects = Vec::new();
+    if created {
+        effects.push(command_value(["XGROUP", "CREATECONSUMER", key, group, consumer]));
+    }
+    effects.extend(claim_effects(key, group, consumer, stream, &claimed));
+    if !deleted.is_empty() {
+        effects.push(command_value(["XACK".to_string(), key.clone(), group.clone()].into_iter().chain(deleted.iter().map(|id| id.to_string()))));
+    }
+
+    let claimed = match just_id {
+        true => stream_ids_reply(&claimed),
+        false => Value::Array(claimed.iter().map(|id| stream_entry_reply(*id, stream.get(*id))).collect()),
+    };
+    (Value::Array(vec![Value::BulkStr(next.to_string()), claimed, stream_ids_reply(&deleted)]), effects)
+}
+
+/// Logged as given, except for `$`, which becomes the ID it stood for.
+fn xgroup(args: Vec<Value>, db: DB) -> (Value, Vec<Value>) {
+    let Some(args) = bulk_strings(&args) else {
+        return failed("ERR: Arguments must be bulk strings");
+    };
+    let (subcommand, key, group, rest) = match args.as_slice() {
+        [subcommand, key, group, rest @ ..] => (subcommand.to_uppercase(), key, group, rest),
+        _ => return failed("ERR: Wrong number of arguments provided"),
+    };
+
+    let mut db = db.write([key]);
+    let mkstream = match (subcommand.as_str(), rest) {
+        ("CREATE", [_, option]) if option.eq_ignore_ascii_case("MKSTREAM") => true,
+        ("CREATE", [_, _]) => return failed("ERR: Syntax error"),
+        ("CREATE" | "SETID" | "CREATECONSUMER" | "DELCONSUMER", [_]) | ("DESTROY", []) => false,
+        ("CREATE" | "SETID" | "CREATECONSUMER" | "DELCONSUMER" | "DESTROY", _) => {
+            return failed("ERR: Wrong number of arguments provided");
+        },
+        _ => return failed("ERR: Unknown XGROUP subcommand"),
+    };
+    match db.stream_get(key) {
+        Ok(Some(_)) => (),
+        Ok(None) if mkstream => db.stream_push(key.clone(), Stream::default()),
+        Ok(None) => return failed(NO_STREAM),
+        Err(err) => return (err, Vec::new()),
+    };
+    let Ok(Some(stream)) = db.stream_get_mut(key) else {
+        unreachable!();
+    };
+
+    let mut effect = vec!["XGROUP".to_string(), subcommand.clone(), key.clone(), group.clone()];
+    effect.extend(rest.iter().cloned());
+    let resolve = |id: &str| match id {
+        "$" => Some(stream.last_id()),
+        id => StreamId::parse(id, 0),
+    };
+    let result = match (subcommand.as_str(), rest) {
+        ("CREATE" | "SETID", [id, ..]) => {
+            let Some(id) = resolve(id) else {
+                return failed(INVALID_STREAM_ID);
+            };
+            effect[4] = id.to_string();
+            match subcommand.as_str() {
+                "CREATE" => stream.create_group(group, id),
+                _ => stream.set_group_id(group, id),
+            }.map(|_| Value::Str("OK"))
+        },
+        ("DESTROY", _) => Ok(Value::Num(stream.destroy_group(group) as i64)),
+        ("CREATECONSUMER", [consumer]) => stream.create_consumer(group, consumer, now_ms()).map(|created| Value::Num(created as i64)),
+        (_, [consumer]) => stream.delete_consumer(group, consumer).map(|pending| Value::Num(pending as i64)),
+        _ => unreachable!(),
+    };
+    match result {
+        Ok(reply) => (reply, vec![command_value(effect)]),
+        Err(err) => failed(err),
+    }
+}
+
+fn xpending(args: Vec<Value>, db: DB) -> Value {
+    if args.len() < 2 {
+        return Value::Error("ERR: Wrong number of arguments provided");
+    }
+
+    let Some(args) = bulk_strings(&args) else {
+        return Value::Error("ERR: Arguments must be bulk strings");
+    };
+    let db = db.read([&args[0]]);
+    let group = match db.stream_get(&args[0]) {
+        Ok(Some(stream)) => match stream.group(&args[1]) {
+            Some(group) => group,
+            None => return Value::Error(stream::NOGROUP),
+        },
+        Ok(None) => return Value::Error(stream::NOGROUP),
+        Err(err) => return err,
+    };
+    let pending = group.pending();
+
+    if args.len() == 2 {
+        let (Some((first, _)), Some((last, _))) = (pending.first_key_value(), pending.last_key_value()) else {
+            return Value::Array(vec![Value::Num(0), Value::Null, Value::Null, Value::Null]);
+        };
+        let mut consumers: BTreeMap<&str, usize> = BTreeMap::new();
+        for entry in pending.values() {
+            *consumers.entry(&entry.consumer).or_default() += 1;
+        }
+        let consumers = consumers.into_iter()
+            .map(|(name, count)| Value::Array(vec![Value::BulkStr(name.into()), Value::BulkStr(count.to_string())]));
+        return Value::Array(vec![
+            Value::Num(pending.len() as i64),
+            Value::BulkStr(first.to_string()),
+            Value::BulkStr(last.to_string()),
+            Value::Array(consumers.collect()),
+        ]);
+    }
+
+    let (min_idle, rest) = match &args[2..] {
+        [option, idle, rest @ ..] if option.eq_ignore_ascii_case("IDLE") => match idle.parse::<u64>() {
+            Ok(idle) => (idle, rest),
+            Err(_) => return Value::Error("ERR: Value is not an integer or out of range"),
+        },
+        rest => (0, rest),
+    };
+    let (start, end, count, consumer) = match rest {
+        [start, end, count] => (start, end, count, None),
+        [start, end, count, consumer] => (start, end, count, Some(consumer)),
+        _ => return Value::Error("ERR: Syntax error"),
+    };
+    let (Some(start), Some(end)) = (parse_range_bound(start, true), parse_range_bound(end, false)) else {
+        return Value::Error(INVALID_STREAM_ID);
+    };
+    let Ok(count) = count.parse::<usize>() else {
+        return Value::Error("ERR: Value is not an integer or out of range");
+    };
+    if start > end {
+        return Value::Array(Vec::new());
+    }
+
+    let now = now_ms();
+    let entries = pending.range(start..=end)
+        .filter(|(_, entry)| consumer.is_none_or(|consumer| &entry.consumer == consumer))
+        .filter(|(_, entry)| now.saturating_sub(entry.delivered) >= min_idle)
+        .take(count)
+        .map(|(id, entry)| Value::Array(vec![
+            Value::BulkStr(id.to_string()),
+            Value::BulkStr(entry.consumer.clone()),
+            Value::Num(now.saturating_sub(entry.delivered) as i64),
+            Value::Num(entry.count as i64),
+        ]));
+    Value::Array(entries.collect())
+}
+
+fn xinfo(args: Vec<Value>, db: DB) -> Value {
+    let Some(args) = bulk_strings(&args) else {
+        return Value::Error("ERR: Arguments must be bulk strings");
+    };
+    let Some((subcommand, args)) = args.split_first() else {
+        return Value::Error("ERR: Wrong number of arguments provided");
+    };
+    let subcommand = subcommand.to_uppercase();
+    let key = match (subcommand.as_str(), args) {
+        ("GROUPS", [key]) | ("CONSUMERS", [key, _]) => key,
+        ("GROUPS" | "CONSUMERS", _) => return Value::Error("ERR: Wrong number of arguments provided"),
+        _ => return Value::Error("ERR: Unknown XINFO subcommand"),
+    };
+
+    let db = db.read([key]);
+    let stream = match db.stream_get(key) {
+        Ok(Some(stream)) => stream,
+        Ok(None) => return Value::Error("ERR: No such key"),
+        Err(err) => return err,
+    };
+    let now = now_ms() as i64;
+
+    if subcommand == "GROUPS" {
+        let groups = stream.groups().iter().map(|(name, group)| Value::Array(vec![
+            Value::Str("name"), Value::BulkStr(name.clone()),
+            Value::Str("consumers"), Value::Num(group.consumers().len() as i64),
+            Value::Str("pending"), Value::Num(group.pending().len() as i64),
+            Value::Str("last-delivered-id"), Value::BulkStr(group.last_id().to_string()),
+        ]));
+        return Value::Array(groups.collect());
+    }
+
+    let Some(group) = stream.group(&args[1]) else {
+        return Value::Error(stream::NOGROUP);
+    };
+    let consumers = group.consumers().iter().map(|(name, consumer)| Value::Array(vec![
+        Value::Str("name"), Value::BulkStr(name.clone()),
+        Value::Str("pending"), Value::Num(consumer.pending() as i64),
+        Value::Str("idle"), Value::Num((now - consumer.seen() as i64).max(0)),
+        Value::Str("inactive"), Value::Num(consumer.active().map_or(-1, |active| (now - active as i64).max(0))),
+    ]));
+    Value::Array(consumers.collect())
+}
+
+/// Parses a score as Redis does, where `inf` and `-inf` are valid but NaN
+/// isn't.
+fn parse_score(arg: &str) -> Option<f64> {
+    arg.parse::<f64>().ok().filter(|score| !score.is_nan())
+}
+
+/// A bound of ZRANGE BYSCORE, excluded when it starts with `(`.
+fn parse_score_bound(arg: &str) -> Option<Bound<f64>> {
+    match arg.strip_prefix('(') {
+        Some(score) => parse_score(score).map(Bound::Excluded),
+        None => parse_score(arg).map(Bound::Included),
+    }
+}
+
+struct AddOptions {
+    nx: bool,
+    xx: bool,
+    ch: bool,
+}
+
+/// The `NX`, `XX` and `CH` options in front of what ZADD and GEOADD add,
+/// returning how many arguments they took.
+fn add_options(args: &[String]) -> Result<(AddOptions, usize), &'static str> {
+    let mut options = AddOptions { nx: false, xx: false, ch: false };
+    let mut taken = 0;
+    for arg in args {
+        match arg.to_uppercase().as_str() {
+            "NX" => options.nx = true,
+            "XX" => options.xx = true,
+            "CH" => options.ch = true,
+            _ => break,
+        };
+        taken += 1;
+    }
+    if options.nx && options.xx {
+        return Err("ERR: XX and NX options at the same time are not compatible");
+    }
+    Ok((options, taken))
+}
+
+/// Sets each member's score unless the options rule it out, replying how
+/// many were added, or also changed with `CH`.
+fn zset_add(db: &mut Keyspace, key: &str, options: &AddOptions, members: Vec<(f64, &String)>) -> Value {
+    if let Err(err) = db.zset_get(key) {
+        return err;
+    }
+    if options.xx && !matches!(db.zset_get(key), Ok(Some(_))) {
+        return Value::Num(0);
+    }
+    if !matches!(db.zset_get(key), Ok(Some(_))) {
+        db.zset_push(key.into(), SortedSet::default());
+    }
+    let Ok(Some(set)) = db.zset_get_mut(key) else {
+        unreachable!();
+    };
+
+    let (mut added, mut changed) = (0, 0);
+    for (score, member) in members {
+        match set.score(member) {
+            Some(_) if options.nx => continue,
+            None if options.xx => continue,
+            Some(old) if old == score => continue,
+            Some(_) => changed += 1,
+            None => added += 1,
+        };
+        set.insert(member, score);
+    }
+    if set.is_empty() {
+        db.remove(key);
+    }
+    Value::Num(if options.ch { added + changed } else { added })
+}
+
+fn zadd(args: Vec<Value>, db: DB) -> Value {
+    let Some(args) = bulk_strings(&args) else {
+        return Value::Error("ERR: Arguments must be bulk strings");
+    };
+    let Some((key, args)) = args.split_first() else {
+        return Value::Error("ERR: Wrong number of arguments provided");
+    };
+    let (options, taken) = match add_options(args) {
+        Ok(options) => options,
+        Err(err) => return Value::Error(err),
+    };
+    let pairs = &args[taken..];
+    if pairs.is_empty() || !pairs.len().is_multiple_of(2) {
+        return Value::Error("ERR: Syntax error");
+    }
+    let Some(members) = pairs.chunks(2).map(|pair| Some((parse_score(&pair[0])?, &pair[1]))).collect() else {
+        return Value::Error("ERR: Value is not a valid float");
+    };
+
+    zset_add(&mut db.write([key]), key, &options, members)
+}
+
+fn zcard(args: Vec<Value>, db: DB) -> Value {
+    if args.len() != 1 {
+        return Value::Error("ERR: Wrong number of arguments provided");
+    }
+
+    let Value::BulkStr(key) = &args[0] else {
+        return Value::Error("ERR: Incorrect definition for key");
+    };
+    match db.read([key]).zset_get(key) {
+        Ok(set) => Value::Num(set.map_or(0, SortedSet::len) as i64),
+        Err(err) => err,
+    }
+}
+
+fn zscore(args: Vec<Value>, db: DB) -> Value {
+    if args.len() != 2 {
+        return Value::Error("ERR: Wrong number of arguments provided");
+    }
+
+    let Some(args) = bulk_strings(&args) else {
+        return Value::Error("ERR: Arguments must be bulk strings");
+    };
+    match db.read([&args[0]]).zset_get(&args[0]) {
+        Ok(set) => match set.and_then(|set| set.score(&args[1])) {
+            Some(score) => Value::BulkStr(score.to_string()),
+            None => Value::Null,
+        },
+        Err(err) => err,
+    }
+}
+
+fn zrem(args: Vec<Value>, db: DB) -> Value {
+    if args.len() < 2 {
+        return Value::Error("ERR: Wrong number of arguments provided");
+    }
+
+    let Some(args) = bulk_strings(&args) else {
+        return Value::Error("ERR: Arguments must be bulk strings");
+    };
+    let mut db = db.write([&args[0]]);
+    let removed = match db.zset_get_mut(&args[0]) {
+        Ok(Some(set)) => args[1..].iter().filter(|member| set.remove(member)).count(),
+        Ok(None) => 0,
+        Err(err) => return err,
+    };
+    if matches!(db.zset_get(&args[0]), Ok(Some(set)) if set.is_empty()) {
+        db.remove(&args[0]);
+    }
+    Value::Num(removed as i64)
+}
+
+/// ZRANGE by rank, or by score with BYSCORE, from the highest down with
+/// REV.
+fn zrange(args: Vec<Value>, db: DB) -> Value {
+    if args.len() < 3 {
+        return Value::Error("ERR: Wrong number of arguments provided");
+    }
+
+    let Some(args) = bulk_strings(&args) else {
+        return Value::Error("ERR: Arguments must be bulk strings");
+    };
+    let (mut by_score, mut rev, mut with_scores, mut limit) = (false, false, false, None);
+    let mut options = args[3..].iter();
+    while let Some(option) = options.next() {
+        match option.to_uppercase().as_str() {
+            "BYSCORE" => by_score = true,
+            "REV" => rev = true,
+            "WITHSCORES" => with_scores = true,
+            "LIMIT" => match (parse_number::<usize>(options.next()), parse_number::<i64>(options.next())) {
+                (Some(offset), Some(count)) => limit = Some((offset, usize::try_from(count).ok())),
+                _ => return Value::Error("ERR: Value is not an integer or out of range"),
+            },
+            _ => return Value::Error("ERR: Syntax error"),
+        };
+    }
+    if limit.is_some() && !by_score {
+        return Value::Error("ERR: Syntax error, LIMIT is only supported in combination with BYSCORE");
+    }
+
+    let db = db.read([&args[0]]);
+    let set = match db.zset_get(&args[0]) {
+        Ok(Some(set)) => set,
+        Ok(None) => return Value::Array(Vec::new()),
+        Err(err) => return err,
+    };
+    let members: Vec<(&str, f64)> = match by_score {
+        true => {
+            let (min, max) = match rev {
+                false => (&args[1], &args[2]),
+                true => (&args[2], &args[1]),
+            };
+            let (Some(min), Some(max)) = (parse_score_bound(min), parse_score_bound(max)) else {
+                return Value::Error("ERR: min or max is not a float");
+            };
+            let range = set.range_by_score(min, max);
+            let range: Box<dyn Iterator<Item = (&str, f64)>> = match rev {
+                false => Box::new(range),
+                true => Box::new(range.rev()),
+            };
+            let (offset, count) = limit.unwrap_or((0, None));
+            range.skip(offset).take(count.unwrap_or(usize::MAX)).collect()
+        },
+        false => {
+            let (Some(start), Some(stop)) = (parse_number::<i64>(Some(&args[1])), parse_number::<i64>(Some(&args[2]))) else {
+                return Value::Error("ERR: Value is not an integer or out of range");
+            };
+            let len = set.len() as i64;
+            let start = if start < 0 { (start + len).max(0) } else { start };
+            let stop = if stop < 0 { stop + len } else { stop.min(len - 1) };
+            if start > stop || start >= len {
+                return Value::Array(Vec::new());
+            }
+            let range = (start as usize)..=(stop as usize);
+            match rev {
+                false => set.iter().skip(*range.start()).take(range.count()).collect(),
+                true => set.iter().rev().skip(*range.start()).take(range.count()).collect(),
+            }
+        },
+    };
+
+    let mut reply = Vec::new();
+    for (member, score) in members {
+        reply.push(Value::BulkStr(member.into()));
+        if with_scores {
+            reply.push(Value::BulkStr(score.to_string()));
+        }
+    }
+    Value::Array(reply)
+}
+
+fn geoadd(args: Vec<Value>, db: DB) -> Value {
+    let Some(args) = bulk_strings(&args) else {
+        return Value::Error("ERR: Arguments must be bulk strings");
+    };
+    let Some((key, args)) = args.split_first() else {
+        return Value::Error("ERR: Wrong number of arguments provided");
+    };
+    let (options, taken) = match add_options(args) {
+        Ok(options) => options,
+        Err(err) => return Value::Error(err),
+    };
+    let triples = &args[taken..];
+    if triples.is_empty() || !triples.len().is_multiple_of(3) {
+        return Value::Error("ERR: Wrong number of arguments provided");
+    }
+    let mut members = Vec::new();
+    for triple in triples.chunks(3) {
+        let (Some(lon), Some(lat)) = (parse_value(&triple[0]), parse_value(&triple[1])) else {
+            return Value::Error("ERR: Value is not a valid float");
+        };
+        if !geo::is_valid(lon, lat) {
+            return Value::Error("ERR: Invalid longitude,latitude pair");
+        }
+        members.push((geo::encode(lon, lat), &triple[2]));
+    }
+
+    zset_add(&mut db.write([key]), key, &options, members)
+}
+
+fn coordinates_reply((lon, lat): (f64, f64)) -> Value {
+    Value::Array(vec![Value::BulkStr(lon.to_string()), Value::BulkStr(lat.to_string())])
+}
+
+/// Runs `reply` over the position of each member, replying Null for the
+/// missing ones.
+fn geo_members(args: Vec<Value>, db: DB, reply: fn(f64) -> Value) -> Value {
+    if args.is_empty() {
+        return Value::Error("ERR: Wrong number of arguments provided");
+    }
+
+    let Some(args) = bulk_strings(&args) else {
+        return Value::Error("ERR: Arguments must be bulk strings");
+    };
+    let db = db.read([&args[0]]);
+    let set = match db.zset_get(&args[0]) {
+        Ok(set) => set,
+        Err(err) => return err,
+    };
+    let scores = args[1..].iter().map(|member| set.and_then(|set| set.score(member)));
+    Value::Array(scores.map(|score| score.map_or(Value::Null, reply)).collect())
+}
+
+fn geopos(args: Vec<Value>, db: DB) -> Value {
+    geo_members(args, db, |score| coordinates_reply(geo::decode(score)))
+}
+
+fn geohash(args: Vec<Value>, db: DB) -> Value {
+    geo_members(args, db, |score| Value::BulkStr(geo::geohash(score)))
+}
+
+fn geodist(args: Vec<Value>, db: DB) -> Value {
+    if args.len() != 3 && args.len() != 4 {
+        return Value::Error("ERR: Wrong number of arguments provided");
+    }
+
+    let Some(args) = bulk_strings(&args) else {
+        return Value::Error("ERR: Arguments must be bulk strings");
+    };
+    let Some(unit) = args.get(3).map_or(Some(1.0), |unit| geo::unit(unit)) else {
+        return Value::Error("ERR: Unsupported unit provided. please use M, KM, FT, MI");
+    };
+    let db = db.read([&args[0]]);
+    let set = match db.zset_get(&args[0]) {
+        Ok(set) => set,
+        Err(err) => return err,
+    };
+    let position = |member: &String| set.and_then(|set| set.score(member)).map(geo::decode);
+    match (position(&args[1]), position(&args[2])) {
+        (Some(a), Some(b)) => Value::BulkStr(format!("{:.4}", geo::distance(a, b) / unit)),
+        _ => Value::Null,
+    }
+}
+
+enum GeoOrigin {
+    Member(String),
+    Position(f64, f64),
+}
+
+/// The options of GEOSEARCH and GEOSEARCHSTORE, which take them in any
+/// order.
+struct GeoQuery {
+    origin: GeoOrigin,
+    shape: Shape,
+    unit: f64,
+    descending: Option<bool>,
+    count: Option<(usize, bool)>,
+    with_coord: bool,
+    with_dist: bool,
+    with_hash: bool,
+    store_dist: bool,
+}
+
+fn geo_query(args: &[String], store: bool) -> Result<GeoQuery, &'static str> {
+    let (mut origin, mut shape, mut descending, mut count) = (None, None, None, None);
+    let (mut with_coord, mut with_dist, mut with_hash, mut store_dist) = (false, false, false, false);
+    let distance = |arg: Option<&String>| {
+        arg.and_then(|arg| parse_value(arg)).filter(|n| *n >= 0.0).ok_or("ERR: Need a numeric distance that is not negative")
+    };
+    let unit = |arg: Option<&String>| {
+        arg.and_then(|arg| geo::unit(arg)).ok_or("ERR: Unsupported unit provided. please use M, KM, FT, MI")
+    };
+
+    let mut args = args.iter().peekable();
+    while let Some(option) = args.next() {
+        match option.to_uppercase().as_str() {
+            "FROMMEMBER" if origin.is_none() => {
+                origin = Some(GeoOrigin::Member(args.next().ok_or("ERR: Syntax error")?.clone()));
+            },
+            "FROMLONLAT" if origin.is_none() => {
+                let (lon, lat) = (args.next().and_then(|arg| parse_value(arg)), args.next().and_then(|arg| parse_value(arg)));
+                let (Some(lon), Some(lat)) = (lon, lat) else {
+                    return Err("ERR: Value is not a valid float");
+                };
+                if !geo::is_valid(lon, lat) {
+                    return Err("ERR: Invalid longitude,latitude pair");
+                }
+                origin = Some(GeoOrigin::Position(lon, lat));
+            },
+            "FROMMEMBER" | "FROMLONLAT" => return Err("ERR: Exactly one of FROMMEMBER or FROMLONLAT can be specified"),
+            "BYRADIUS" if shape.is_none() => {
+                let radius = distance(args.next())?;
+                let unit = unit(args.next())?;
+                shape = Some((Shape::Radius(radius * unit), unit));
+            },
+            "BYBOX" if shape.is_none() => {
+                let (width, height) = (distance(args.next())?, distance(args.next())?);
+                let unit = unit(args.next())?;
+                shape = Some((Shape::Box(width * unit, height * unit), unit));
+            },
+            "BYRADIUS" | "BYBOX" => return Err("ERR: Exactly one of BYRADIUS or BYBOX can be specified"),
+            "ASC" => descending = Some(false),
+            "DESC" => descending = Some(true),
+            "COUNT" => {
+                let n = parse_number::<usize>(args.next()).filter(|n| *n > 0).ok_or("ERR: COUNT must be > 0")?;
+                let any = args.next_if(|arg| arg.eq_ignore_ascii_case("ANY")).is_some();
+                count = Some((n, any));
+            },
+            "WITHCOORD" if !store => with_coord = true,
+            "WITHDIST" if !store => with_dist = true,
+            "WITHHASH" if !store => with_hash = true,
+            "STOREDIST" if store => store_dist = true,
+            _ => return Err("ERR: Syntax error"),
+        };
+    }
+
+    let Some(origin) = origin else {
+        return Err("ERR: Exactly one of FROMMEMBER or FROMLONLAT can be specified");
+    };
+    let Some((shape, unit)) = shape else {
+        return Err("ERR: Exactly one of BYRADIUS or BYBOX can be specified");
+    };
+    Ok(GeoQuery { origin, shape, unit, descending, count, with_coord, with_dist, with_hash, store_dist })
+}
+
+/// The members within the query's shape with their distance in meters and
+/// their score, looked up in the cells around its center instead of the
+/// whole set.
+fn geo_search<'s>(set: &'s SortedSet, query: &GeoQuery) -> Result<Vec<(&'s str, f64, f64)>, &'static str> {
+    let center = match &query.origin {
+        GeoOrigin::Member(member) => geo::decode(set.score(member).ok_or("ERR: Could not decode requested zset member")?),
+        GeoOrigin::Position(lon, lat) => (*lon, *lat),
+    };
+
+    let mut found = Vec::new();
+    'cells: for cell in geo::areas(center, &query.shape) {
+        let (min, max) = cell.scores();
+        for (member, score) in set.range_by_score(Bound::Included(min as f64), Bound::Excluded(max as f64)) {
+            if let Some(distance) = query.shape.distance(center, geo::decode(score)) {
+                found.push((member, distance, score));
+                // ANY takes the first ones found, nearest or not.
+                if query.count.is_some_and(|(count, any)| any && found.len() == count) {
+                    break 'cells;
+                }
+            }
+        }
+    }
+
+    // Without ANY, COUNT keeps the nearest ones.
+    let descending = query.descending.or(query.count.filter(|(_, any)| !any).map(|_| false));
+    if let Some(descending) = descending {
+        found.sort_by(|a, b| a.1.total_cmp(&b.1));
+        if descending {
+            found.reverse();
+        }
+    }
+    if let Some((count, _)) = query.count {
+        found.truncate(count);
+    }
+    Ok(found)
+}
+
+fn geosearch(args: Vec<Value>, db: DB) -> Value {
+    let Some(args) = bulk_strings(&args) else {
+        return Value::Error("ERR: Arguments must be bulk strings");
+    };
+    let Some((key, args)) = args.split_first() else {
+        return Value::Error("ERR: Wrong number of arguments provided");
+    };
+    let query = match geo_query(args, false) {
+        Ok(query) => query,
+        Err(err) => return Value::Error(err),
+    };
+
+    let db = db.read([key]);
+    let found = match db.zset_get(key) {
+        Ok(Some(set)) => match geo_search(set, &query) {
+            Ok(found) => found,
+            Err(err) => return Value::Error(err),
+        },
+        Ok(None) => Vec::new(),
+        Err(err) => return err,
+    };
+    let reply = found.into_iter().map(|(member, distance, score)| {
+        if !query.with_dist && !query.with_hash && !query.with_coord {
+            return Value::BulkStr(member.into());
+        }
+        let mut item = vec![Value::BulkStr(member.into())];
+        if query.with_dist {
+            item.push(Value::BulkStr(format!("{:.4}", distance / query.unit)));
+        }
+        if query.with_hash {
+            item.push(Value::Num(score as i64));
+        }
+        if query.with_coord {
+            item.push(coordinates_reply(geo::decode(score)));
+        }
+        Value::Array(item)
+    });
+    Value::Array(reply.collect())
+}
+
+/// Stores what GEOSEARCH would find in a sorted set, scored by position
+/// or with STOREDIST by distance.
+fn geosearchstore(args: Vec<Value>, db: DB) -> Value {
+    let Some(args) = bulk_strings(&args) else {
+        return Value::Error("ERR: Arguments must be bulk strings");
+    };
+    let [destination, source, args @ ..] = args.as_slice() else {
+        return Value::Error("ERR: Wrong number of arguments provided");
+    };
+    let query = match geo_query(args, true) {
+        Ok(query) => query,
+        Err(err) => return Value::Error(err),
+    };
+
+    let mut db = db.write([destination, source]);
+    let mut found = SortedSet::default();
+    match db.zset_get(source) {
+        Ok(Some(set)) => match geo_search(set, &query) {
+            Ok(members) => for (member, distance, score) in members {
+                found.insert(member, if query.store_dist { distance / query.unit } else { score });
+            },
+            Err(err) => return Value::Error(err),
+        },
+        Ok(None) => (),
+        Err(err) => return err,
+    };
+
+    let len = found.len();
+    match found.is_empty() {
+        true => {
+            db.remove(destination);
+        },
+        false => db.zset_push(destination.clone(), found),
+    };
+    Value::Num(len as i64)
+}
+
+fn list_push(args: Vec<Value>, db: DB, front: bool) -> Value {
+    let Some(args) = bulk_strings(&args) else {
+        return Value::Error("ERR: Arguments must be bulk strings");
+    };
+    let [key, elements @ ..] = args.as_slice() else {
+        return Value::Error("ERR: Wrong number of arguments provided");
+    };
+    if elements.is_empty() {
+        return Value::Error("ERR: Wrong number of arguments provided");
+    }
+
+    let mut db = db.write([key]);
+    match db.list_get(key) {
+        Ok(Some(_)) => (),
+        Ok(None) => db.list_push(key.clone(), VecDeque::new()),
+        Err(err) => return err,
+    };
+    let Ok(Some(list)) = db.list_get_mut(key) else {
+        unreachable!();
+    };
+    for element in elements {
+        match front {
+            true => list.push_front(element.clone()),
+            false => list.push_back(element.clone()),
+        };
+    }
+    Value::Num(list.len() as i64)
+}
+
+fn lpush(args: Vec<Value>, db: DB) -> Value {
+    list_push(args, db, true)
+}
+
+fn rpush(args: Vec<Value>, db: DB) -> Value {
+    list_push(args, db, false)
+}
+
+/// A single element without a count, or an array of up to that many.
+fn list_pop(args: Vec<Value>, db: DB, front: bool) -> Value {
+    let Some(args) = bulk_strings(&args) else {
+        return Value::Error("ERR: Arguments must be bulk strings");
+    };
+    let (key, count) = match args.as_slice() {
+        [key] => (key, None),
+        [key, count] => match count.parse::<usize>() {
+            Ok(count) => (key, Some(count)),
+            Err(_) => return Value::Error("ERR: Value is out of range, must be positive"),
+        },
+        _ => return Value::Error("ERR: Wrong number of arguments provided"),
+    };
+
+    let mut db = db.write([key]);
+    let list = match db.list_get_mut(key) {
+        Ok(Some(list)) => list,
+        Ok(None) => return Value::Null,
+        Err(err) => return err,
+    };
+    let mut popped = Vec::new();
+    while popped.len() < count.unwrap_or(1) {
+        let Some(element) = (if front { list.pop_front() } else { list.pop_back() }) else {
+            break;
+        };
+        popped.push(Value::BulkStr(element));
+    }
+    if list.is_empty() {
+        db.remove(key);
+    }
+    match count {
+        Some(_) => Value::Array(popped),
+        None => popped.pop().unwrap_or(Value::Null),
+    }
+}
+
+fn lpop(args: Vec<Value>, db: DB) -> Value {
+    list_pop(args, db, true)
+}
+
+fn rpop(args: Vec<Value>, db: DB) -> Value {
+    list_pop(args, db, false)
+}
+
+fn llen(args: Vec<Value>, db: DB) -> Value {
+    if args.len() != 1 {
+        return Value::Error("ERR: Wrong number of arguments provided");
+    }
+
+    let Value::BulkStr(key) = &args[0] else {
+        return Value::Error("ERR: Incorrect definition for key");
+    };
+    match db.read([key]).list_get(key) {
+        Ok(list) => Value::Num(list.map_or(0, VecDeque::len) as i64),
+        Err(err) => err,
+    }
+}
+
+/// Both ends are included, and negative indexes count from the end.
+fn lrange(args: Vec<Value>, db: DB) -> Value {
+    if args.len() != 3 {
+        return Value::Error("ERR: Wrong number of arguments provided");
+    }
+
+    let Some(args) = bulk_strings(&args) else {
+        return Value::Error("ERR: Arguments must be bulk strings");
+    };
+    let (Some(start), Some(stop)) = (parse_number::<i64>(args.get(1)), parse_number::<i64>(args.get(2))) else {
+        return Value::Error("ERR: Value is not an integer or out of range");
+    };
+
+    let db = db.read([&args[0]]);
+    let list = match db.list_get(&args[0]) {
+        Ok(Some(list)) => list,
+        Ok(None) => return Value::Array(Vec::new()),
+        Err(err) => return err,
+    };
+    let len = list.len() as i64;
+    let index = |i: i64| if i < 0 { len + i } else { i };
+    let (start, stop) = (index(start).max(0), index(stop).min(len - 1));
+    if start > stop {
+        return Value::Array(Vec::new());
+    }
+    Value::Array(list.range(start as usize..=stop as usize).map(|element| Value::BulkStr(element.clone())).collect())
+}
+
+fn sadd(args: Vec<Value>, db: DB) -> Value {
+    let Some(args) = bulk_strings(&args) else {
+        return Value::Error("ERR: Arguments must be bulk strings");
+    };
+    let [key, members @ ..] = args.as_slice() else {
+        return Value::Error("ERR: Wrong number of arguments provided");
+    };
+    if members.is_empty() {
+        return Value::Error("ERR: Wrong number of arguments provided");
+    }
+
+    let mut db = db.write([key]);
+    match db.members_get(key) {
+        Ok(Some(_)) => (),
+        Ok(None) => db.members_push(key.clone(), HashSet::new()),
+        Err(err) => return err,
+    };
+    let Ok(Some(set)) = db.members_get_mut(key) else {
+        unreachable!();
+    };
+    Value::Num(members.iter().filter(|member| set.insert(member.to_string())).count() as i64)
+}
+
+fn srem(args: Vec<Value>, db: DB) -> Value {
+    if args.len() < 2 {
+        return Value::Error("ERR: Wrong number of arguments provided");
+    }
+
+    let Some(args) = bulk_strings(&args) else {
+        return Value::Error("ERR: Arguments must be bulk strings");
+    };
+    let mut db = db.write([&args[0]]);
+    let removed = match db.members_get_mut(&args[0]) {
+        Ok(Some(set)) => args[1..].iter().filter(|member| set.remove(*member)).count(),
+        Ok(None) => 0,
+        Err(err) => return err,
+    };
+    if matches!(db.members_get(&args[0]), Ok(Some(set)) if set.is_empty()) {
+        db.remove(&args[0]);
+    }
+    Value::Num(removed as i64)
+}
+
+fn scard(args: Vec<Value>, db: DB) -> Value {
+    if args.len() != 1 {
+        return Value::Error("ERR: Wrong number of arguments provided");
+    }
+
+    let Value::BulkStr(key) = &args[0] else {
+        return Value::Error("ERR: Incorrect definition for key");
+    };
+    match db.read([key]).members_get(key) {
+        Ok(set) => Value::Num(set.map_or(0, HashSet::len) as i64),
+        Err(err) => err,
+    }
+}
+
+fn sismember(args: Vec<Value>, db: DB) -> Value {
+    if args.len() != 2 {
+        return Value::Error("ERR: Wrong number of arguments provided");
+    }
+
+    let Some(args) = bulk_strings(&args) else {
+        return Value::Error("ERR: Arguments must be bulk strings");
+    };
+    match db.read([&args[0]]).members_get(&args[0]) {
+        Ok(set) => Value::Num(set.is_some_and(|set| set.contains(&args[1])) as i64),
+        Err(err) => err,
+    }
+}
+
+fn smembers(args: Vec<Value>, db: DB) -> Value {
+    if args.len() != 1 {
+        return Value::Error("ERR: Wrong number of arguments provided");
+    }
+
+    let Value::BulkStr(key) = &args[0] else {
+        return Value::Error("ERR: Incorrect definition for key");
+    };
+    match db.read([key]).members_get(key) {
+        Ok(set) => Value::Array(set.into_iter().flatten().map(|member| Value::BulkStr(member.clone())).collect()),
+        Err(err) => err,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<Value> {
+        args.iter().map(|arg| Value::BulkStr(arg.to_string())).collect()
+    }
+
+    fn reply(value: Value) -> String {
+        String::from_utf8(value.marshal()).unwrap()
+    }
+
+    /// An AOF that doesn't write anything, as `appendonly=no` is only read
+    /// from a config file.
+    fn no_aof(name: &str) -> Aof {
+        let path = std::env::temp_dir().join(format!("amandadb-{name}-{}.conf", std::process::id()));
+        std::fs::write(&path, "appendonly=no\n").unwrap();
+        let config = crate::config::Config::read_from_file(path.to_str().unwrap()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        Arc::new(RwLock::new(AOF::new(config).unwrap()))
+    }
+
+    #[test]
+    fn json_set_replaces_nested_matches_once() {
+        let db: DB = Arc::new(Database::new());
+        assert_eq!(reply(json_set(args(&["j", "$", r#"{"a":{"a":1},"b":[{"a":2}]}"#]), db.clone())), "+OK\r\n");
+        assert_eq!(reply(json_set(args(&["j", "$..a", "5"]), db.clone())), "+OK\r\n");
+        assert_eq!(reply(json_get(args(&["j"]), db.clone())), "$21\r\n{\"a\":5,\"b\":[{\"a\":5}]}\r\n");
+
+        assert_eq!(reply(json_set(args(&["j", "$..a", r#"{"a":{"a":0}}"#]), db.clone())), "+OK\r\n");
+        assert_eq!(reply(json_set(args(&["j", "$..a", "1"]), db.clone())), "+OK\r\n");
+        assert_eq!(reply(json_get(args(&["j"]), db)), "$21\r\n{\"a\":1,\"b\":[{\"a\":1}]}\r\n");
+    }
+
+    #[test]
+    fn json_writes_stay_within_the_depth_limit() {
+        let db: DB = Arc::new(Database::new());
+        let doc = format!("{}{}", "[".repeat(128), "]".repeat(128));
+        assert_eq!(reply(json_set(args(&["j", "$", &doc]), db.clone())), "+OK\r\n");
+
+        let path = |depth: usize| format!("${}", "[0]".repeat(depth));
+        assert_eq!(reply(json_set(args(&["j", &path(127), "[[]]"]), db.clone())), "+OK\r\n");
+        assert!(reply(json_set(args(&["j", &path(127), "[[[]]]"]), db.clone())).starts_with('-'));
+        assert_eq!(reply(json_arrappend(args(&["j", &path(127), "1"]), db.clone())), "*1\r\n:2\r\n");
+        assert!(reply(json_arrappend(args(&["j", &path(127), "[[]]"]), db.clone())).starts_with('-'));
+        assert!(reply(json_arrappend(args(&["j", &path(128), "1"]), db.clone())).starts_with('-'));
+
+        assert_eq!(reply(json_set(args(&["o", "$", "{}"]), db.clone())), "+OK\r\n");
+        let deepest = format!("[{doc}]");
+        assert!(reply(json_set(args(&["o", "$.a", &deepest]), db.clone())).starts_with('-'));
+        assert_eq!(reply(json_get(args(&["o"]), db)), "$2\r\n{}\r\n");
+    }
+
+    #[test]
+    fn filters_take_binary_items() {
+        let db: DB = Arc::new(Database::new());
+        let digest = || Value::BulkBytes(vec![0xff, 0x00, 0xfe, 0x80]);
+        let with = |command: &[&str], item: Value| args(command).into_iter().chain([item]).collect::<Vec<Value>>();
+
+        assert_eq!(reply(bf_add(with(&["b"], digest()), db.clone())), ":1\r\n");
+        assert_eq!(reply(bf_exists(with(&["b"], digest()), db.clone())), ":1\r\n");
+        assert_eq!(reply(bf_mexists(with(&["b", "other"], digest()), db.clone())), "*2\r\n:0\r\n:1\r\n");
+
+        assert_eq!(reply(cf_insert(with(&["c", "CAPACITY", "100", "ITEMS"], digest()), db.clone())), "*1\r\n:1\r\n");
+        assert_eq!(reply(cf_count(with(&["c"], digest()), db.clone())), ":1\r\n");
+        assert_eq!(reply(cf_del(with(&["c"], digest()), db.clone())), ":1\r\n");
+        assert_eq!(reply(cf_exists(with(&["c"], digest()), db.clone())), ":0\r\n");
+        assert!(reply(cf_insert(args(&["c", "CAPACITY", "ITEMS", "x"]), db.clone())).starts_with('-'));
+        assert!(reply(cf_insert(args(&["c", "NOCREATE", "ITEMS"]), db)).starts_with('-'));
+    }
+
+    #[test]
+    fn resolves_only_timestamps() {
+        let mut add = args(&["TS.ADD", "t", "1000", "1", "LABELS", "*", "x"]);
+        assert!(!resolve_timestamps("TS.ADD", &mut add));
+        assert_eq!(reply(Value::Array(add)), reply(Value::Array(args(&["TS.ADD", "t", "1000", "1", "LABELS", "*", "x"]))));
+
+        let mut add = args(&["TS.ADD", "t", "*", "1", "LABELS", "*", "x"]);
+        assert!(resolve_timestamps("TS.ADD", &mut add));
+        assert!(matches!(&add[2], Value::BulkStr(s) if s != "*"));
+        assert!(matches!(&add[5], Value::BulkStr(s) if s == "*"));
+
+        let mut madd = args(&["TS.MADD", "a", "*", "1", "b", "*", "2"]);
+        assert!(resolve_timestamps("TS.MADD", &mut madd));
+        assert!(![2, 5].iter().any(|&i| matches!(&madd[i], Value::BulkStr(s) if s == "*")));
+
+        // A label named TIMESTAMP isn't the option, which gets added.
+        let mut incrby = args(&["TS.INCRBY", "t", "1", "RETENTION", "timestamp", "LABELS", "timestamp", "*"]);
+        assert!(resolve_timestamps("TS.INCRBY", &mut incrby));
+        assert_eq!(incrby.len(), 10);
+        assert!(matches!(&incrby[7], Value::BulkStr(s) if s == "*"));
+        assert!(matches!(&incrby[9], Value::BulkStr(s) if s != "*"));
+
+        let mut incrby = args(&["TS.INCRBY", "t", "1", "UNCOMPRESSED", "TIMESTAMP", "*", "LABELS", "a", "b"]);
+        assert!(resolve_timestamps("TS.INCRBY", &mut incrby));
+        assert!(matches!(&incrby[5], Value::BulkStr(s) if s != "*"));
+
+        let mut incrby = args(&["TS.INCRBY", "t", "1", "timestamp", "1000"]);
+        assert!(!resolve_timestamps("TS.INCRBY", &mut incrby));
+
+        let db: DB = Arc::new(Database::new());
+        let added = reply(ts_incrby(args(&["t", "2", "LABELS", "timestamp", "5"]), db.clone()));
+        assert!(added.starts_with(':') && added != ":5\r\n", "{added}");
+        assert_eq!(reply(ts_incrby(args(&["t", "2", "TIMESTAMP", "1", "LABELS", "timestamp", "5"]), db.clone())),
+            "-ERR: Timestamp must be equal to or higher than the maximum existing timestamp\r\n");
+    }
+
+    #[test]
+    fn saves_snapshots_on_demand() {
+        let dir = std::env::temp_dir().join(format!("amandadb-save-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let conf = dir.join("amandadb.conf");
+        std::fs::write(&conf, format!("appendonly=no\ndbfilename={}\n", dir.join("dump.adb").display())).unwrap();
+        let config = crate::config::Config::read_from_file(conf.to_str().unwrap()).unwrap();
+
+        let aof = no_aof("save");
+        let dbs: Databases = Arc::new(vec![Arc::new(Database::new())]);
+        let mut handlers = Handlers::new();
+        handlers.init();
+        let mut run = |command: &[&str]| reply(handlers.match_handler(Value::Array(args(command)), aof.clone(), &dbs));
+        assert_eq!(run(&["SAVE"]), "-ERR: Snapshots are not available\r\n");
+
+        handlers.set_snapshot(Arc::new(Snapshot::new(&config)));
+        let mut run = |command: &[&str]| reply(handlers.match_handler(Value::Array(args(command)), aof.clone(), &dbs));
+        let started = run(&["LASTSAVE"]);
+        run(&["SET", "a", "1"]);
+        assert_eq!(run(&["SAVE"]), "+OK\r\n");
+        assert!(run(&["LASTSAVE"]) >= started);
+        assert!(std::fs::read(dir.join("dump.adb")).unwrap().starts_with(crate::snapshot::MAGIC));
+
+        // Not inside a transaction, whose writes the copy would wait on.
+        std::fs::remove_file(dir.join("dump.adb")).unwrap();
+        run(&["MULTI"]);
+        run(&["BGSAVE"]);
+        run(&["EXEC"]);
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        assert!(!dir.join("dump.adb").exists());
+        assert_eq!(run(&["BGSAVE"]), "+Background saving started\r\n");
+        for _ in 0..100 {
+            if dir.join("dump.adb").exists() {
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(50));
+        }
+        assert!(dir.join("dump.adb").exists());
+        assert!(run(&["SAVE", "now"]).starts_with('-'));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn exec_reverts_only_the_keys_it_wrote() {
+        let aof = no_aof("exec");
+        let dbs: Databases = Arc::new(vec![Arc::new(Database::new()), Arc::new(Database::new())]);
+        let mut handlers = Handlers::new();
+        handlers.init();
+        let mut run = |command: &[&str]| reply(handlers.match_handler(Value::Array(args(command)), aof.clone(), &dbs));
+
+        run(&["SET", "s", "text"]);
+        run(&["SET", "other", "kept"]);
+        run(&["MULTI"]);
+        for command in [&["SET", "a", "1"][..], &["SELECT", "1"], &["SET", "b", "2"], &["SELECT", "0"], &["DEL", "other"],
+            &["INCR", "s"]] {
+            assert_eq!(run(command), "+QUEUED\r\n");
+        }
+        run(&["EXEC"]);
+
+        assert_eq!(run(&["EXISTS", "a"]), ":0\r\n");
+        assert_eq!(run(&["GET", "other"]), "$4\r\nkept\r\n");
+        assert_eq!(run(&["GET", "s"]), "$4\r\ntext\r\n");
+        run(&["SELECT", "1"]);
+        assert_eq!(run(&["EXISTS", "b"]), ":0\r\n");
+    }
+
+    #[test]
+    fn moves_swaps_and_selects_databases() {
+        let aof = no_aof("databases");
+        let dbs: Databases = Arc::new(vec![Arc::new(Database::new()), Arc::new(Database::new())]);
+        let mut handlers = Handlers::new();
+        handlers.init();
+        let mut run = |command: &[&str]| reply(handlers.match_handler(Value::Array(args(command)), aof.clone(), &dbs));
+
+        // A key already in the destination is left alone, and so is the one
+        // to move.
+        run(&["SET", "a", "zero"]);
+        run(&["SET", "moved", "1"]);
+        run(&["SELECT", "1"]);
+        run(&["SET", "a", "one"]);
+        run(&["SELECT", "0"]);
+        assert_eq!(run(&["MOVE", "a", "1"]), ":0\r\n");
+        assert_eq!(run(&["GET", "a"]), "$4\r\nzero\r\n");
+        assert_eq!(run(&["MOVE", "moved", "1"]), ":1\r\n");
+        assert_eq!(run(&["MOVE", "a", "0"]), "-ERR: Source and destination objects are the same\r\n");
+
+        assert_eq!(run(&["SWAPDB", "0", "0"]), "+OK\r\n");
+        assert_eq!(run(&["GET", "a"]), "$4\r\nzero\r\n");
+        assert_eq!(run(&["SWAPDB", "0", "1"]), "+OK\r\n");
+        assert_eq!(run(&["GET", "a"]), "$3\r\none\r\n");
+        assert_eq!(run(&["EXISTS", "moved"]), ":1\r\n");
+
+        let out_of_range = "-ERR: DB index is out of range\r\n";
+        for command in [&["SELECT", "2"][..], &["MOVE", "a", "2"], &["SWAPDB", "0", "2"], &["SWAPDB", "2", "0"],
+            &["COPY", "a", "b", "DB", "2"]] {
+            assert_eq!(run(command), out_of_range, "{command:?}");
+        }
+        assert_eq!(run(&["SELECT", "one"]), "-ERR: Invalid DB index\r\n");
+        assert_eq!(run(&["GET", "a"]), "$3\r\none\r\n");
+
+        // The database selected inside a transaction stays selected after it.
+        run(&["MULTI"]);
+        run(&["SELECT", "1"]);
+        run(&["SET", "b", "2"]);
+        assert_eq!(run(&["EXEC"]), "*2\r\n+OK\r\n+OK\r\n");
+        assert_eq!(run(&["GET", "a"]), "$4\r\nzero\r\n");
+        assert_eq!(run(&["GET", "b"]), "$1\r\n2\r\n");
+    }
+
+    #[test]
+    fn transactions_belong_to_their_connection() {
+        let aof = no_aof("multi");
+        let dbs: Databases = Arc::new(vec![Arc::new(Database::new())]);
+        let (mut first, mut second) = (Handlers::new(), Handlers::new());
+        first.init();
+        second.init();
+        let run = |handlers: &mut Handlers, command: &[&str]| {
+            reply(handlers.match_handler(Value::Array(args(command)), aof.clone(), &dbs))
+        };
+
+        assert_eq!(run(&mut first, &["MULTI"]), "+OK\r\n");
+        assert!(run(&mut first, &["MULTI"]).starts_with('-'));
+        assert_eq!(run(&mut first, &["SET", "a", "1"]), "+QUEUED\r\n");
+        assert_eq!(run(&mut second, &["SET", "b", "2"]), "+OK\r\n");
+        assert_eq!(run(&mut second, &["EXEC"]), "*0\r\n");
+        assert_eq!(run(&mut second, &["DISCARD"]), "+OK\r\n");
+        assert_eq!(run(&mut second, &["EXISTS", "a"]), ":0\r\n");
+
+        assert_eq!(run(&mut first, &["GET", "b"]), "+QUEUED\r\n");
+        assert_eq!(run(&mut first, &["EXEC"]), "*2\r\n+OK\r\n$1\r\n2\r\n");
+        assert_eq!(run(&mut second, &["GET", "a"]), "$1\r\n1\r\n");
+    }
+
+    #[test]
+    fn stream_writes_replay_to_the_same_groups() {
+        let db: DB = Arc::new(Database::new());
+        let (id, effects) = xadd(args(&["s", "MAXLEN", "=", "2", "*", "f", "v"]), db.clone());
+        let Value::BulkStr(id) = id else {
+            panic!("XADD replied {}", reply(id));
+        };
+        assert_eq!(reply(Value::Array(effects.clone())), reply(Value::Array(vec![Value::Array(args(&["XADD", "s",
+            "MAXLEN", "=", "2", &id, "f", "v"]))])));
+
+        let mut log = effects;
+        log.extend(xgroup(args(&["CREATE", "s", "g", "$"]), db.clone()).1);
+        // Reading nothing still creates the consumer.
+        let (_, effects) = xreadgroup(args(&["GROUP", "g", "alice", "STREAMS", "s", ">"]), db.clone());
+        assert_eq!(reply(Value::Array(effects.clone())), reply(Value::Array(vec![Value::Array(args(&["XGROUP",
+            "CREATECONSUMER", "s", "g", "alice"]))])));
+        log.extend(effects);
+        log.extend(xadd(args(&["s", "*", "f", "w"]), db.clone()).1);
+        let (_, effects) = xreadgroup(args(&["GROUP", "g", "alice", "STREAMS", "s", ">"]), db.clone());
+        assert_eq!(effects.len(), 2);
+        log.extend(effects);
+
+        let dbs: Databases = Arc::new(vec![Arc::new(Database::new())]);
+        let mut handlers = Handlers::new();
+        handlers.init();
+        for command in log {
+            handlers.replay(0, command, &dbs).unwrap();
+        }
+        let pending = |db: DB| reply(xpending(args(&["s", "g", "-", "+", "10"]), db))
+            .split("\r\n").filter(|line| !line.starts_with(':')).collect::<Vec<_>>().join("\r\n");
+        assert_eq!(pending(db.clone()), pending(dbs[0].clone()));
+        assert_eq!(reply(xinfo(args(&["GROUPS", "s"]), db)), reply(xinfo(args(&["GROUPS", "s"]), dbs[0].clone())));
+    }
+
+    #[test]
+    fn blocked_reads_reply_once_there_is_something_to_read() {
+        let aof = no_aof("block");
+        let dbs: Databases = Arc::new(vec![Arc::new(Database::new())]);
+        let (mut reader, mut writer) = (Handlers::new(), Handlers::new());
+        reader.init();
+        writer.init();
+        let run = |handlers: &mut Handlers, command: &[&str]| {
+            reply(handlers.match_handler(Value::Array(args(command)), aof.clone(), &dbs))
+        };
+
+        run(&mut writer, &["XADD", "s", "1-0", "f", "old"]);
+        assert_eq!(run(&mut reader, &["XREAD", "BLOCK", "0", "STREAMS", "s", "$"]), "_\r\n");
+        assert!(reader.is_blocked() && reader.deadline().is_none());
+        assert!(reader.retry_blocked(aof.clone(), &dbs).is_none());
+
+        run(&mut writer, &["XADD", "s", "2-0", "f", "new"]);
+        let read = reader.retry_blocked(aof.clone(), &dbs).unwrap();
+        assert_eq!(reply(read), "*1\r\n*2\r\n$1\r\ns\r\n*1\r\n*2\r\n$3\r\n2-0\r\n*2\r\n$1\r\nf\r\n$3\r\nnew\r\n");
+        assert!(!reader.is_blocked());
+
+        run(&mut writer, &["XGROUP", "CREATE", "s", "g", "$"]);
+        assert_eq!(run(&mut reader, &["XREADGROUP", "GROUP", "g", "c", "BLOCK", "1", "STREAMS", "s", ">"]), "_\r\n");
+        assert!(reader.deadline().is_some());
+        std::thread::sleep(Duration::from_millis(5));
+        assert_eq!(reply(reader.retry_blocked(aof.clone(), &dbs).unwrap()), "_\r\n");
+
+        assert_eq!(run(&mut reader, &["MULTI"]), "+OK\r\n");
+        assert_eq!(run(&mut reader, &["XREAD", "BLOCK", "0", "STREAMS", "s", "$"]), "+QUEUED\r\n");
+        assert_eq!(run(&mut reader, &["EXEC"]), "*1\r\n_\r\n");
+        assert!(!reader.is_blocked());
+    }
+
+    #[test]
+    fn geo_searches_around_a_point_or_member() {
+        let db: DB = Arc::new(Database::new());
+        let added = geoadd(args(&["Sicily", "13.361389", "38.115556", "Palermo", "15.087269", "37.502669", "Catania",
+            "12.758489", "38.788135", "edge1", "17.241510", "38.788135", "edge2"]), db.clone());
+        assert_eq!(reply(added), ":4\r\n");
+        assert!(reply(geoadd(args(&["Sicily", "0", "89", "pole"]), db.clone())).starts_with('-'));
+        assert_eq!(reply(geoadd(args(&["Sicily", "XX", "CH", "0", "0", "Rome", "13.4", "38.1", "Palermo"]), db.clone())),
+            ":1\r\n");
+        geoadd(args(&["Sicily", "13.361389", "38.115556", "Palermo"]), db.clone());
+
+        assert_eq!(reply(geodist(args(&["Sicily", "Palermo", "Catania"]), db.clone())), "$11\r\n166274.1516\r\n");
+        assert_eq!(reply(geodist(args(&["Sicily", "Palermo", "Catania", "km"]), db.clone())), "$8\r\n166.2742\r\n");
+        assert_eq!(reply(geodist(args(&["Sicily", "Palermo", "Rome"]), db.clone())), "_\r\n");
+        assert_eq!(reply(geohash(args(&["Sicily", "Palermo", "Rome"]), db.clone())), "*2\r\n$11\r\nsqc8b49rny0\r\n_\r\n");
+
+        let search = |command: &[&str]| reply(geosearch(args(command), db.clone()));
+        assert_eq!(search(&["Sicily", "FROMLONLAT", "15", "37", "BYRADIUS", "200", "km", "ASC", "WITHDIST"]),
+            "*2\r\n*2\r\n$7\r\nCatania\r\n$7\r\n56.4413\r\n*2\r\n$7\r\nPalermo\r\n$8\r\n190.4424\r\n");
+        assert_eq!(search(&["Sicily", "FROMLONLAT", "15", "37", "BYBOX", "400", "400", "km", "DESC"]),
+            "*4\r\n$5\r\nedge1\r\n$5\r\nedge2\r\n$7\r\nPalermo\r\n$7\r\nCatania\r\n");
+        assert_eq!(search(&["Sicily", "FROMMEMBER", "Palermo", "BYRADIUS", "100", "km", "COUNT", "1"]),
+            "*1\r\n$7\r\nPalermo\r\n");
+        assert!(search(&["Sicily", "FROMMEMBER", "Rome", "BYRADIUS", "100", "km"]).starts_with('-'));
+        assert!(search(&["Sicily", "FROMLONLAT", "15", "37", "FROMMEMBER", "Palermo", "BYRADIUS", "1", "m"]).starts_with('-'));
+        assert!(search(&["Sicily", "FROMLONLAT", "15", "37", "BYRADIUS", "1", "m", "STOREDIST"]).starts_with('-'));
+
+        let stored = geosearchstore(args(&["near", "Sicily", "FROMLONLAT", "15", "37", "BYRADIUS", "200", "km",
+            "STOREDIST"]), db.clone());
+        assert_eq!(reply(stored), ":2\r\n");
+        assert_eq!(reply(zrange(args(&["near", "0", "-1", "WITHSCORES"]), db.clone())).lines().nth(2), Some("Catania"));
+        assert_eq!(reply(zrange(args(&["near", "(100", "+inf", "BYSCORE"]), db.clone())), "*1\r\n$7\r\nPalermo\r\n");
+        assert_eq!(reply(geosearchstore(args(&["near", "Sicily", "FROMLONLAT", "0", "0", "BYRADIUS", "1", "m"]), db.clone())),
+            ":0\r\n");
+        assert_eq!(reply(zcard(args(&["near"]), db)), ":0\r\n");
+    }
+
+    #[test]
+    fn reports_the_type_of_every_kind_and_refuses_the_others() {
+        // A key of each kind, the command making it, its TYPE and a command
+        // only that kind takes.
+        let kinds: [(&[&str], &str, &[&str]); 10] = [
+            (&["SET", "string", "a"], "string", &["GET", "string"]),
+            (&["HSET", "hash", "a", "1"], "hash", &["HGET", "hash", "a"]),
+            (&["BF.ADD", "bloom", "a"], "MBbloom--", &["BF.EXISTS", "bloom", "a"]),
+            (&["CF.ADD", "cuckoo", "a"], "MBbloomCF", &["CF.EXISTS", "cuckoo", "a"]),
+            (&["JSON.SET", "json", "$", "1"], "ReJSON-RL", &["JSON.GET", "json"]),
+            (&["TS.CREATE", "series"], "TSDB-TYPE", &["TS.GET", "series"]),
+            (&["XADD", "stream", "1-1", "a", "1"], "stream", &["XLEN", "stream"]),
+            (&["ZADD", "zset", "1", "a"], "zset", &["ZSCORE", "zset", "a"]),
+            (&["RPUSH", "list", "a"], "list", &["LLEN", "list"]),
+            (&["SADD", "set", "a"], "set", &["SCARD", "set"]),
+        ];
+        let aof = no_aof("types");
+        let dbs: Databases = Arc::new(vec![Arc::new(Database::new())]);
+        let mut handlers = Handlers::new();
+        handlers.init();
+        let mut run = |command: &[&str]| reply(handlers.match_handler(Value::Array(args(command)), aof.clone(), &dbs));
+
+        assert_eq!(run(&["TYPE", "missing"]), "+none\r\n");
+        for (create, name, _) in kinds {
+            assert!(!run(create).starts_with('-'), "{create:?}");
+            assert_eq!(run(&["TYPE", create[1]]), format!("+{name}\r\n"));
+        }
+        for (create, _, _) in kinds {
+            for (_, name, command) in kinds.iter().filter(|(other, _, _)| other[1] != create[1]) {
+                let mut command = command.to_vec();
+                command[1] = create[1];
+                assert!(run(&command).starts_with("-WRONGTYPE"), "{name} command on {}", create[1]);
+            }
+        }
+    }
+
+    #[test]
+    fn lists_pop_from_either_end_and_sets_keep_one_of_each() {
+        let db = Arc::new(Database::new());
+        assert_eq!(reply(rpush(args(&["list", "b", "c"]), db.clone())), ":2\r\n");
+        assert_eq!(reply(lpush(args(&["list", "a", "z"]), db.clone())), ":4\r\n");
+        assert_eq!(reply(lrange(args(&["list", "1", "-2"]), db.clone())), "*2\r\n$1\r\na\r\n$1\r\nb\r\n");
+        assert_eq!(reply(lrange(args(&["list", "0", "-5"]), db.clone())), "*0\r\n");
+        assert_eq!(reply(lpop(args(&["list"]), db.clone())), "$1\r\nz\r\n");
+        assert_eq!(reply(rpop(args(&["list", "5"]), db.clone())), "*3\r\n$1\r\nc\r\n$1\r\nb\r\n$1\r\na\r\n");
+        assert_eq!(reply(llen(args(&["list"]), db.clone())), ":0\r\n");
+        assert_eq!(reply(lpop(args(&["list"]), db.clone())), "_\r\n");
+
+        assert_eq!(reply(sadd(args(&["set", "a", "b", "a"]), db.clone())), ":2\r\n");
+        assert_eq!(reply(sismember(args(&["set", "b"]), db.clone())), ":1\r\n");
+        assert_eq!(reply(srem(args(&["set", "b", "c"]), db.clone())), ":1\r\n");
+        assert_eq!(reply(smembers(args(&["set"]), db.clone())), "*1\r\n$1\r\na\r\n");
+        assert_eq!(reply(srem(args(&["set", "a"]), db.clone())), ":1\r\n");
+        assert_eq!(reply(scard(args(&["set"]), db.clone())), ":0\r\n");
+
+        db.write(["string"]).insert("string".into(), Data::Str(b"value".to_vec()));
+        assert!(reply(rpush(args(&["string", "a"]), db.clone())).starts_with("-WRONGTYPE"));
+        assert!(reply(sadd(args(&["string", "a"]), db)).starts_with("-WRONGTYPE"));
+    }
 }
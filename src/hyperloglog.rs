@@ -0,0 +1,338 @@
+// Redis compatible HyperLogLog, so the bytes stored under a key can be moved
+// between AmandaDB and Redis as plain string values.
+
+use crate::hash::murmurhash64a;
+
+const MAGIC: &[u8] = b"HYLL";
+const HEADER_SIZE: usize = 16;
+const P: u32 = 14;
+const Q: u32 = 64 - P;
+const REGISTERS: usize = 1 << P;
+const BITS: usize = 6;
+const DENSE_SIZE: usize = HEADER_SIZE + (REGISTERS * BITS).div_ceil(8);
+const SPARSE_MAX_BYTES: usize = 3000;
+
+const DENSE: u8 = 0;
+const SPARSE: u8 = 1;
+
+const SPARSE_VAL_MAX_VALUE: u8 = 32;
+const SPARSE_VAL_MAX_LEN: usize = 4;
+const SPARSE_ZERO_MAX_LEN: usize = 64;
+const SPARSE_XZERO_MAX_LEN: usize = 16384;
+
+const ALPHA_INF: f64 = 0.721_347_520_444_481_7;
+const SEED: u64 = 0xadc8_3b19;
+
+pub struct HyperLogLog {
+    registers: Vec<u8>,
+    dense: bool,
+    cache: Option<u64>,
+}
+
+impl Default for HyperLogLog {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl HyperLogLog {
+    pub fn new() -> Self {
+        Self {
+            registers: vec![0; REGISTERS],
+            dense: false,
+            cache: Some(0),
+        }
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < HEADER_SIZE || &bytes[0..4] != MAGIC {
+            return None;
+        }
+
+        let card = &bytes[8..16];
+        let cache = match card[7] & 0x80 {
+            0 => Some(u64::from_le_bytes(card.try_into().ok()?)),
+            _ => None,
+        };
+
+        let registers = match bytes[4] {
+            DENSE if bytes.len() == DENSE_SIZE => dense_decode(&bytes[HEADER_SIZE..]),
+            SPARSE => sparse_decode(&bytes[HEADER_SIZE..])?,
+            _ => return None,
+        };
+
+        Some(Self { registers, dense: bytes[4] == DENSE, cache })
+    }
+
+    /// Adds an element, returning whether any register was changed.
+    pub fn add(&mut self, element: &[u8]) -> bool {
+        let (index, count) = pattern_len(element);
+        if self.registers[index] >= count {
+            return false;
+        }
+
+        self.registers[index] = count;
+        self.cache = None;
+        true
+    }
+
+    /// Keeps the highest value of each register between both logs.
+    pub fn merge(&mut self, other: &HyperLogLog) {
+        for (register, value) in self.registers.iter_mut().zip(other.registers.iter()) {
+            if *value > *register {
+                *register = *value;
+                self.cache = None;
+            }
+        }
+        self.dense |= other.dense;
+    }
+
+    pub fn count(&mut self) -> u64 {
+        if let Some(card) = self.cache {
+            return card;
+        }
+
+        let card = estimate(&self.registers);
+        self.cache = Some(card);
+        card
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let sparse = match self.dense {
+            true => None,
+            false => sparse_encode(&self.registers),
+        };
+
+        let mut bytes = Vec::with_capacity(DENSE_SIZE);
+        bytes.extend(MAGIC);
+        bytes.push(if sparse.is_some() { SPARSE } else { DENSE });
+        bytes.extend([0; 3]);
+        match self.cache {
+            Some(card) => bytes.extend(card.to_le_bytes()),
+            None => bytes.extend([0, 0, 0, 0, 0, 0, 0, 0x80]),
+        };
+
+        match sparse {
+            Some(data) => bytes.extend(data),
+            None => bytes.extend(dense_encode(&self.registers)),
+        };
+        bytes
+    }
+}
+
+/// Returns the register index for an element and the length of its run of
+/// zeroes (plus one) in the remaining hash bits.
+fn pattern_len(element: &[u8]) -> (usize, u8) {
+    let mut hash = murmurhash64a(element, SEED);
+    let index = (hash & (REGISTERS as u64 - 1)) as usize;
+
+    hash >>= P;
+    hash |= 1 << Q;
+    (index, hash.trailing_zeros() as u8 + 1)
+}
+
+fn dense_decode(data: &[u8]) -> Vec<u8> {
+    let mut registers = vec![0; REGISTERS];
+    for (index, register) in registers.iter_mut().enumerate() {
+        let byte = index * BITS / 8;
+        let fb = (index * BITS) & 7;
+        let b0 = data[byte] as u16;
+        let b1 = *data.get(byte + 1).unwrap_or(&0) as u16;
+        *register = (((b0 >> fb) | (b1 << (8 - fb))) & 63) as u8;
+    }
+    registers
+}
+
+fn dense_encode(registers: &[u8]) -> Vec<u8> {
+    let mut data = vec![0u8; DENSE_SIZE - HEADER_SIZE];
+    for (index, register) in registers.iter().enumerate() {
+        let byte = index * BITS / 8;
+        let fb = (index * BITS) & 7;
+        let value = *register as u16;
+
+        data[byte] |= (value << fb) as u8;
+        if let Some(next) = data.get_mut(byte + 1) {
+            *next |= (value >> (8 - fb)) as u8;
+        }
+    }
+    data
+}
+
+fn sparse_decode(data: &[u8]) -> Option<Vec<u8>> {
+    let mut registers = Vec::with_capacity(REGISTERS);
+    let mut i = 0;
+
+    while i < data.len() {
+        let op = data[i];
+        if op & 0xc0 == 0 {
+            let len = (op & 0x3f) as usize + 1;
+            registers.resize(registers.len() + len, 0);
+            i += 1;
+        } else if op & 0xc0 == 0x40 {
+            let next = *data.get(i + 1)? as usize;
+            let len = (((op & 0x3f) as usize) << 8 | next) + 1;
+            registers.resize(registers.len() + len, 0);
+            i += 2;
+        } else {
+            let value = ((op >> 2) & 0x1f) + 1;
+            let len = (op & 0x03) as usize + 1;
+            registers.resize(registers.len() + len, value);
+            i += 1;
+        }
+
+        if registers.len() > REGISTERS {
+            return None;
+        }
+    }
+
+    match registers.len() == REGISTERS {
+        true => Some(registers),
+        false => None,
+    }
+}
+
+/// Encodes the registers as opcodes, or returns `None` when the sparse form
+/// can't hold them and the dense one must be used instead.
+fn sparse_encode(registers: &[u8]) -> Option<Vec<u8>> {
+    let mut data = Vec::new();
+    let mut i = 0;
+
+    while i < registers.len() {
+        let value = registers[i];
+        let run = registers[i..].iter().take_while(|r| **r == value).count();
+
+        if value == 0 {
+            let mut left = run;
+            while left > 0 {
+                let len = left.min(SPARSE_XZERO_MAX_LEN);
+                if len > SPARSE_ZERO_MAX_LEN {
+                    data.push(0x40 | ((len - 1) >> 8) as u8);
+                    data.push(((len - 1) & 0xff) as u8);
+                } else {
+                    data.push((len - 1) as u8);
+                }
+                left -= len;
+            }
+        } else {
+            if value > SPARSE_VAL_MAX_VALUE {
+                return None;
+            }
+            let mut left = run;
+            while left > 0 {
+                let len = left.min(SPARSE_VAL_MAX_LEN);
+                data.push(0x80 | ((value - 1) << 2) | (len - 1) as u8);
+                left -= len;
+            }
+        }
+
+        if HEADER_SIZE + data.len() > SPARSE_MAX_BYTES {
+            return None;
+        }
+        i += run;
+    }
+    Some(data)
+}
+
+fn estimate(registers: &[u8]) -> u64 {
+    let m = REGISTERS as f64;
+    let mut histogram = [0u32; 64];
+    for register in registers {
+        histogram[*register as usize] += 1;
+    }
+
+    let q = Q as usize;
+    let mut z = m * tau((m - histogram[q + 1] as f64) / m);
+    for j in (1..=q).rev() {
+        z += histogram[j] as f64;
+        z *= 0.5;
+    }
+    z += m * sigma(histogram[0] as f64 / m);
+
+    (ALPHA_INF * m * m / z).round() as u64
+}
+
+fn sigma(mut x: f64) -> f64 {
+    if x == 1.0 {
+        return f64::INFINITY;
+    }
+
+    let mut y = 1.0;
+    let mut z = x;
+    loop {
+        x *= x;
+        let previous = z;
+        z += x * y;
+        y += y;
+        if previous == z {
+            return z;
+        }
+    }
+}
+
+fn tau(mut x: f64) -> f64 {
+    if x == 0.0 || x == 1.0 {
+        return 0.0;
+    }
+
+    let mut y = 1.0;
+    let mut z = 1.0 - x;
+    loop {
+        x = x.sqrt();
+        let previous = z;
+        y *= 0.5;
+        z -= (1.0 - x).powi(2) * y;
+        if previous == z {
+            return z / 3.0;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Written by tests/fixtures/redis_hll.py, which follows the steps of
+    // Redis' PFADD.
+    const SPARSE_FIXTURE: &[u8] = include_bytes!("../tests/fixtures/hll-sparse.bin");
+    const DENSE_FIXTURE: &[u8] = include_bytes!("../tests/fixtures/hll-dense.bin");
+
+    fn build(elements: impl IntoIterator<Item = Vec<u8>>) -> HyperLogLog {
+        let mut hll = HyperLogLog::new();
+        for element in elements {
+            hll.add(&element);
+        }
+        hll
+    }
+
+    #[test]
+    fn matches_a_sparse_redis_value() {
+        let elements = (0..200).map(|i| format!("element:{i}").into_bytes())
+            .chain((0..20).map(|i| vec![i, 0xff, 0]));
+        let hll = build(elements);
+
+        let mut fixture = HyperLogLog::from_bytes(SPARSE_FIXTURE).unwrap();
+        assert!(!fixture.dense);
+        assert_eq!(fixture.registers, hll.registers);
+        assert_eq!(hll.to_bytes(), SPARSE_FIXTURE);
+        assert_eq!(fixture.count(), 220);
+    }
+
+    #[test]
+    fn matches_a_dense_redis_value() {
+        let mut hll = build((0..5000).map(|i| format!("element:{i}").into_bytes()));
+
+        let mut fixture = HyperLogLog::from_bytes(DENSE_FIXTURE).unwrap();
+        assert!(fixture.dense);
+        assert_eq!(fixture.registers, hll.registers);
+        assert_eq!(hll.to_bytes(), DENSE_FIXTURE);
+        assert_eq!(fixture.count(), 5005);
+        assert_eq!(hll.count(), 5005);
+    }
+
+    #[test]
+    fn rejects_broken_values() {
+        assert!(HyperLogLog::from_bytes(b"HYLL").is_none());
+        assert!(HyperLogLog::from_bytes(&DENSE_FIXTURE[..DENSE_FIXTURE.len() - 1]).is_none());
+        assert!(HyperLogLog::from_bytes(&SPARSE_FIXTURE[..SPARSE_FIXTURE.len() - 1]).is_none());
+    }
+}
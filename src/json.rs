@@ -0,0 +1,833 @@
+use std::fmt::Write;
+
+const MAX_DEPTH: usize = 128;
+
+pub const TOO_DEEP: &str = "ERR: JSON value is nested too deeply";
+
+#[derive(Clone, PartialEq)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    Str(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+/// Step taken from a node to one of its children.
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Key {
+    Field(String),
+    Index(usize),
+}
+
+pub type Location = Vec<Key>;
+
+#[derive(Default)]
+pub struct Format<'a> {
+    pub indent: &'a str,
+    pub newline: &'a str,
+    pub space: &'a str,
+}
+
+impl Json {
+    pub fn parse(input: &str) -> Result<Json, &'static str> {
+        let mut parser = Parser { input: input.as_bytes(), pos: 0 };
+        let value = parser.parse_value(0)?;
+        parser.skip_whitespace();
+        if parser.pos != parser.input.len() {
+            return Err("ERR: Trailing characters after JSON value");
+        }
+        Ok(value)
+    }
+
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Json::Null => "null",
+            Json::Bool(_) => "boolean",
+            Json::Int(_) => "integer",
+            Json::Float(_) => "number",
+            Json::Str(_) => "string",
+            Json::Array(_) => "array",
+            Json::Object(_) => "object",
+        }
+    }
+
+    pub fn field(&self, name: &str) -> Option<&Json> {
+        match self {
+            Json::Object(fields) => fields.iter().find(|(k, _)| k == name).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    fn child(&self, key: &Key) -> Option<&Json> {
+        match (self, key) {
+            (Json::Object(_), Key::Field(name)) => self.field(name),
+            (Json::Array(items), Key::Index(i)) => items.get(*i),
+            _ => None,
+        }
+    }
+
+    fn child_mut(&mut self, key: &Key) -> Option<&mut Json> {
+        match (self, key) {
+            (Json::Object(fields), Key::Field(name)) => {
+                fields.iter_mut().find(|(k, _)| k == name).map(|(_, v)| v)
+            },
+            (Json::Array(items), Key::Index(i)) => items.get_mut(*i),
+            _ => None,
+        }
+    }
+
+    /// How many levels the deepest value inside this one is below it.
+    fn depth(&self) -> usize {
+        match self {
+            Json::Array(items) => items.iter().map(|item| item.depth() + 1).max().unwrap_or(0),
+            Json::Object(fields) => fields.iter().map(|(_, value)| value.depth() + 1).max().unwrap_or(0),
+            _ => 0,
+        }
+    }
+
+    /// Whether this value can be stored at `location` without the document
+    /// getting nested deeper than a parsed one could be.
+    pub fn fits_at(&self, location: &[Key]) -> bool {
+        location.len() + self.depth() <= MAX_DEPTH
+    }
+
+    pub fn get(&self, location: &[Key]) -> Option<&Json> {
+        location.iter().try_fold(self, |node, key| node.child(key))
+    }
+
+    pub fn get_mut(&mut self, location: &[Key]) -> Option<&mut Json> {
+        location.iter().try_fold(self, |node, key| node.child_mut(key))
+    }
+
+    /// Returns every location matched by the path, in document order.
+    pub fn select(&self, path: &Path) -> Vec<Location> {
+        let mut current: Vec<Location> = vec![Vec::new()];
+
+        for step in &path.steps {
+            let mut next: Vec<Location> = Vec::new();
+            for location in current {
+                let Some(node) = self.get(&location) else {
+                    continue;
+                };
+                match step {
+                    Step::Child(selector) => {
+                        selector.apply(node, &location, &mut next);
+                    },
+                    Step::Descendant(selector) => {
+                        let mut nodes: Vec<Location> = Vec::new();
+                        node.descendants(location, &mut nodes);
+                        for location in nodes {
+                            let node = self.get(&location).unwrap();
+                            selector.apply(node, &location, &mut next);
+                        }
+                    },
+                }
+            }
+            current = next;
+        }
+        current
+    }
+
+    fn descendants(&self, location: Location, out: &mut Vec<Location>) {
+        out.push(location.clone());
+        match self {
+            Json::Array(items) => {
+                for (i, item) in items.iter().enumerate() {
+                    let mut child = location.clone();
+                    child.push(Key::Index(i));
+                    item.descendants(child, out);
+                }
+            },
+            Json::Object(fields) => {
+                for (name, value) in fields {
+                    let mut child = location.clone();
+                    child.push(Key::Field(name.clone()));
+                    value.descendants(child, out);
+                }
+            },
+            _ => (),
+        }
+    }
+
+    /// Removes the values at the given locations, returning how many were
+    /// removed. Locations nested inside another removed one are skipped.
+    pub fn delete(&mut self, locations: &[Location]) -> usize {
+        let mut sorted: Vec<&Location> = locations.iter().filter(|l| !l.is_empty()).collect();
+        sorted.sort();
+        sorted.dedup();
+
+        let mut targets: Vec<&Location> = Vec::new();
+        for location in sorted {
+            if targets.last().is_some_and(|parent| location.starts_with(parent)) {
+                continue;
+            }
+            targets.push(location);
+        }
+
+        let mut removed = 0;
+        for location in targets.into_iter().rev() {
+            let (last, parent) = location.split_last().unwrap();
+            let removed_one = match (self.get_mut(parent), last) {
+                (Some(Json::Object(fields)), Key::Field(name)) => {
+                    let len = fields.len();
+                    fields.retain(|(k, _)| k != name);
+                    fields.len() != len
+                },
+                (Some(Json::Array(items)), Key::Index(i)) if *i < items.len() => {
+                    items.remove(*i);
+                    true
+                },
+                _ => false,
+            };
+            removed += removed_one as usize;
+        }
+        removed
+    }
+
+    pub fn serialize(&self, format: &Format) -> String {
+        let mut out = String::new();
+        self.write(&mut out, format, 0);
+        out
+    }
+
+    fn write(&self, out: &mut String, format: &Format, depth: usize) {
+        match self {
+            Json::Null => out.push_str("null"),
+            Json::Bool(b) => out.push_str(if *b { "true" } else { "false" }),
+            Json::Int(n) => out.push_str(&n.to_string()),
+            Json::Float(n) => out.push_str(&format_float(*n)),
+            Json::Str(s) => write_string(out, s),
+            Json::Array(items) => {
+                if items.is_empty() {
+                    out.push_str("[]");
+                    return;
+                }
+                out.push('[');
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        out.push(',');
+                    }
+                    new_line(out, format, depth + 1);
+                    item.write(out, format, depth + 1);
+                }
+                new_line(out, format, depth);
+                out.push(']');
+            },
+            Json::Object(fields) => {
+                if fields.is_empty() {
+                    out.push_str("{}");
+                    return;
+                }
+                out.push('{');
+                for (i, (name, value)) in fields.iter().enumerate() {
+                    if i > 0 {
+                        out.push(',');
+                    }
+                    new_line(out, format, depth + 1);
+                    write_string(out, name);
+                    out.push(':');
+                    out.push_str(format.space);
+                    value.write(out, format, depth + 1);
+                }
+                new_line(out, format, depth);
+                out.push('}');
+            },
+        }
+    }
+}
+
+fn new_line(out: &mut String, format: &Format, depth: usize) {
+    out.push_str(format.newline);
+    for _ in 0..depth {
+        out.push_str(format.indent);
+    }
+}
+
+pub fn format_float(n: f64) -> String {
+    if n.fract() == 0.0 && n.abs() < 1e16 {
+        format!("{n:.1}")
+    } else {
+        n.to_string()
+    }
+}
+
+fn write_string(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            '\u{08}' => out.push_str("\\b"),
+            '\u{0c}' => out.push_str("\\f"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            },
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+struct Parser<'a> {
+    input: &'a [u8],
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn skip_whitespace(&mut self) {
+        while let Some(b' ' | b'\t' | b'\n' | b'\r') = self.input.get(self.pos) {
+            self.pos += 1;
+        }
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.input.get(self.pos).copied()
+    }
+
+    fn expect(&mut self, literal: &str) -> Result<(), &'static str> {
+        match self.input[self.pos..].starts_with(literal.as_bytes()) {
+            true => {
+                self.pos += literal.len();
+                Ok(())
+            },
+            false => Err("ERR: Invalid JSON value"),
+        }
+    }
+
+    fn parse_value(&mut self, depth: usize) -> Result<Json, &'static str> {
+        if depth > MAX_DEPTH {
+            return Err(TOO_DEEP);
+        }
+
+        self.skip_whitespace();
+        match self.peek() {
+            Some(b'n') => self.expect("null").map(|_| Json::Null),
+            Some(b't') => self.expect("true").map(|_| Json::Bool(true)),
+            Some(b'f') => self.expect("false").map(|_| Json::Bool(false)),
+            Some(b'"') => self.parse_string().map(Json::Str),
+            Some(b'[') => self.parse_array(depth),
+            Some(b'{') => self.parse_object(depth),
+            Some(b'-' | b'0'..=b'9') => self.parse_number(),
+            _ => Err("ERR: Invalid JSON value"),
+        }
+    }
+
+    fn parse_array(&mut self, depth: usize) -> Result<Json, &'static str> {
+        self.pos += 1;
+        let mut items: Vec<Json> = Vec::new();
+
+        self.skip_whitespace();
+        if self.peek() == Some(b']') {
+            self.pos += 1;
+            return Ok(Json::Array(items));
+        }
+
+        loop {
+            items.push(self.parse_value(depth + 1)?);
+            self.skip_whitespace();
+            match self.peek() {
+                Some(b',') => self.pos += 1,
+                Some(b']') => {
+                    self.pos += 1;
+                    return Ok(Json::Array(items));
+                },
+                _ => return Err("ERR: Expected ',' or ']' in JSON array"),
+            }
+        }
+    }
+
+    fn parse_object(&mut self, depth: usize) -> Result<Json, &'static str> {
+        self.pos += 1;
+        let mut fields: Vec<(String, Json)> = Vec::new();
+
+        self.skip_whitespace();
+        if self.peek() == Some(b'}') {
+            self.pos += 1;
+            return Ok(Json::Object(fields));
+        }
+
+        loop {
+            self.skip_whitespace();
+            if self.peek() != Some(b'"') {
+                return Err("ERR: Expected string key in JSON object");
+            }
+            let name = self.parse_string()?;
+            self.skip_whitespace();
+            if self.peek() != Some(b':') {
+                return Err("ERR: Expected ':' in JSON object");
+            }
+            self.pos += 1;
+            let value = self.parse_value(depth + 1)?;
+
+            match fields.iter_mut().find(|(k, _)| *k == name) {
+                Some(field) => field.1 = value,
+                None => fields.push((name, value)),
+            };
+
+            self.skip_whitespace();
+            match self.peek() {
+                Some(b',') => self.pos += 1,
+                Some(b'}') => {
+                    self.pos += 1;
+                    return Ok(Json::Object(fields));
+                },
+                _ => return Err("ERR: Expected ',' or '}' in JSON object"),
+            }
+        }
+    }
+
+    fn parse_hex(&mut self) -> Result<u32, &'static str> {
+        let digits = self.input.get(self.pos..self.pos + 4).ok_or("ERR: Invalid JSON escape")?;
+        let digits = std::str::from_utf8(digits).map_err(|_| "ERR: Invalid JSON escape")?;
+        let code = u32::from_str_radix(digits, 16).map_err(|_| "ERR: Invalid JSON escape")?;
+        self.pos += 4;
+        Ok(code)
+    }
+
+    fn parse_string(&mut self) -> Result<String, &'static str> {
+        self.pos += 1;
+        let mut bytes: Vec<u8> = Vec::new();
+
+        loop {
+            let Some(b) = self.peek() else {
+                return Err("ERR: Unterminated JSON string");
+            };
+            self.pos += 1;
+
+            match b {
+                b'"' => break,
+                b'\\' => {
+                    let Some(escape) = self.peek() else {
+                        return Err("ERR: Unterminated JSON string");
+                    };
+                    self.pos += 1;
+                    let c = match escape {
+                        b'"' => '"',
+                        b'\\' => '\\',
+                        b'/' => '/',
+                        b'b' => '\u{08}',
+                        b'f' => '\u{0c}',
+                        b'n' => '\n',
+                        b'r' => '\r',
+                        b't' => '\t',
+                        b'u' => {
+                            let mut code = self.parse_hex()?;
+                            if (0xd800..0xdc00).contains(&code) {
+                                self.expect("\\u").map_err(|_| "ERR: Invalid JSON surrogate pair")?;
+                                let low = self.parse_hex()?;
+                                if !(0xdc00..0xe000).contains(&low) {
+                                    return Err("ERR: Invalid JSON surrogate pair");
+                                }
+                                code = 0x10000 + ((code - 0xd800) << 10) + (low - 0xdc00);
+                            }
+                            char::from_u32(code).ok_or("ERR: Invalid JSON escape")?
+                        },
+                        _ => return Err("ERR: Invalid JSON escape"),
+                    };
+                    let mut buffer = [0; 4];
+                    bytes.extend(c.encode_utf8(&mut buffer).as_bytes());
+                },
+                b if b < 0x20 => return Err("ERR: Control character in JSON string"),
+                b => bytes.push(b),
+            }
+        }
+
+        String::from_utf8(bytes).map_err(|_| "ERR: Invalid UTF-8 in JSON string")
+    }
+
+    fn digits(&mut self) -> usize {
+        let start = self.pos;
+        while let Some(b'0'..=b'9') = self.peek() {
+            self.pos += 1;
+        }
+        self.pos - start
+    }
+
+    /// `-`, then `0` or digits not starting with one, an optional fraction
+    /// and an optional exponent, each with at least one digit.
+    fn parse_number(&mut self) -> Result<Json, &'static str> {
+        const INVALID: &str = "ERR: Invalid JSON number";
+        let start = self.pos;
+        let mut float = false;
+
+        if self.peek() == Some(b'-') {
+            self.pos += 1;
+        }
+        match self.peek() {
+            Some(b'0') => self.pos += 1,
+            Some(b'1'..=b'9') => {
+                self.digits();
+            },
+            _ => return Err(INVALID),
+        };
+        if self.peek() == Some(b'.') {
+            self.pos += 1;
+            float = true;
+            if self.digits() == 0 {
+                return Err(INVALID);
+            }
+        }
+        if let Some(b'e' | b'E') = self.peek() {
+            self.pos += 1;
+            float = true;
+            if let Some(b'+' | b'-') = self.peek() {
+                self.pos += 1;
+            }
+            if self.digits() == 0 {
+                return Err(INVALID);
+            }
+        }
+
+        let text = std::str::from_utf8(&self.input[start..self.pos]).unwrap();
+        if !float {
+            if let Ok(n) = text.parse::<i64>() {
+                return Ok(Json::Int(n));
+            }
+        }
+        match text.parse::<f64>() {
+            Ok(n) if n.is_finite() => Ok(Json::Float(n)),
+            _ => Err(INVALID),
+        }
+    }
+}
+
+#[derive(Clone)]
+enum Selector {
+    Fields(Vec<String>),
+    Indexes(Vec<i64>),
+    Slice(Option<i64>, Option<i64>, i64),
+    Wildcard,
+}
+
+impl Selector {
+    fn apply(&self, node: &Json, location: &Location, out: &mut Vec<Location>) {
+        let mut push = |key: Key| {
+            let mut child = location.clone();
+            child.push(key);
+            out.push(child);
+        };
+
+        match (self, node) {
+            (Selector::Fields(names), Json::Object(_)) => {
+                for name in names {
+                    if node.field(name).is_some() {
+                        push(Key::Field(name.clone()));
+                    }
+                }
+            },
+            (Selector::Indexes(indexes), Json::Array(items)) => {
+                let len = items.len() as i64;
+                for i in indexes {
+                    let i = if *i < 0 { len + i } else { *i };
+                    if i >= 0 && i < len {
+                        push(Key::Index(i as usize));
+                    }
+                }
+            },
+            (Selector::Slice(start, end, step), Json::Array(items)) => {
+                let len = items.len() as i64;
+                let clamp = |i: i64| if i < 0 { (len + i).max(0) } else { i.min(len) };
+                let start = start.map(clamp).unwrap_or(0);
+                let end = end.map(clamp).unwrap_or(len);
+                let mut i = start;
+                while i < end {
+                    push(Key::Index(i as usize));
+                    match i.checked_add(*step) {
+                        Some(next) => i = next,
+                        None => break,
+                    };
+                }
+            },
+            (Selector::Wildcard, Json::Array(items)) => {
+                for i in 0..items.len() {
+                    push(Key::Index(i));
+                }
+            },
+            (Selector::Wildcard, Json::Object(fields)) => {
+                for (name, _) in fields {
+                    push(Key::Field(name.clone()));
+                }
+            },
+            _ => (),
+        }
+    }
+}
+
+enum Step {
+    Child(Selector),
+    Descendant(Selector),
+}
+
+/// JSONPath subset: `$`, `.name`, `['name']`, `[0]`, `[-1]`, `[0,2]`,
+/// `[1:3]`, `*` and `..` for recursive descent. Paths not starting with `$`
+/// use the legacy syntax, which yields a single value instead of an array.
+pub struct Path {
+    steps: Vec<Step>,
+    legacy: bool,
+}
+
+impl Path {
+    pub fn parse(input: &str) -> Result<Path, &'static str> {
+        let legacy = !input.starts_with('$');
+        let bytes = input.as_bytes();
+        let mut pos = if legacy { 0 } else { 1 };
+        let mut steps: Vec<Step> = Vec::new();
+
+        if legacy && !input.is_empty() && !input.starts_with(['.', '[']) {
+            let (name, end) = read_name(bytes, 0);
+            steps.push(Step::Child(name_selector(name)));
+            pos = end;
+        }
+
+        while pos < bytes.len() {
+            match bytes[pos] {
+                b'.' if bytes.get(pos + 1) == Some(&b'.') => {
+                    pos += 2;
+                    let selector = match bytes.get(pos) {
+                        Some(b'[') => {
+                            let (selector, end) = parse_bracket(bytes, pos)?;
+                            pos = end;
+                            selector
+                        },
+                        _ => {
+                            let (name, end) = read_name(bytes, pos);
+                            if name.is_empty() {
+                                return Err("ERR: Invalid JSON path");
+                            }
+                            pos = end;
+                            name_selector(name)
+                        },
+                    };
+                    steps.push(Step::Descendant(selector));
+                },
+                b'.' => {
+                    let (name, end) = read_name(bytes, pos + 1);
+                    pos = end;
+                    if name.is_empty() {
+                        if legacy && pos == bytes.len() && steps.is_empty() {
+                            break;
+                        }
+                        return Err("ERR: Invalid JSON path");
+                    }
+                    steps.push(Step::Child(name_selector(name)));
+                },
+                b'[' => {
+                    let (selector, end) = parse_bracket(bytes, pos)?;
+                    pos = end;
+                    steps.push(Step::Child(selector));
+                },
+                _ => return Err("ERR: Invalid JSON path"),
+            }
+        }
+
+        Ok(Path { steps, legacy })
+    }
+
+    pub fn is_legacy(&self) -> bool {
+        self.legacy
+    }
+
+    pub fn is_root(&self) -> bool {
+        self.steps.is_empty()
+    }
+
+    /// Splits `$.a.b` into `$.a` and the `b` field, used to create missing
+    /// fields on existing objects.
+    pub fn split_field(&self) -> Option<(Path, String)> {
+        let (last, parent) = self.steps.split_last()?;
+        let Step::Child(Selector::Fields(names)) = last else {
+            return None;
+        };
+        let [name] = names.as_slice() else {
+            return None;
+        };
+
+        let steps = parent.iter().map(|step| match step {
+            Step::Child(selector) => Step::Child(selector.clone()),
+            Step::Descendant(selector) => Step::Descendant(selector.clone()),
+        });
+        Some((Path { steps: steps.collect(), legacy: self.legacy }, name.clone()))
+    }
+}
+
+fn name_selector(name: String) -> Selector {
+    match name.as_str() {
+        "*" => Selector::Wildcard,
+        _ => Selector::Fields(vec![name]),
+    }
+}
+
+fn read_name(bytes: &[u8], start: usize) -> (String, usize) {
+    let mut end = start;
+    while end < bytes.len() && bytes[end] != b'.' && bytes[end] != b'[' {
+        end += 1;
+    }
+    (String::from_utf8_lossy(&bytes[start..end]).into(), end)
+}
+
+fn parse_bracket(bytes: &[u8], start: usize) -> Result<(Selector, usize), &'static str> {
+    let mut pos = start + 1;
+    let mut names: Vec<String> = Vec::new();
+
+    if let Some(b'\'' | b'"') = bytes.get(pos) {
+        loop {
+            let quote = bytes[pos];
+            let mut end = pos + 1;
+            while end < bytes.len() && bytes[end] != quote {
+                end += if bytes[end] == b'\\' { 2 } else { 1 };
+            }
+            if end >= bytes.len() {
+                return Err("ERR: Invalid JSON path");
+            }
+            let name = String::from_utf8_lossy(&bytes[pos + 1..end]);
+            names.push(name.replace(&format!("\\{}", quote as char), &(quote as char).to_string()));
+            pos = end + 1;
+
+            match bytes.get(pos) {
+                Some(b',') => pos += 1,
+                Some(b']') => return Ok((Selector::Fields(names), pos + 1)),
+                _ => return Err("ERR: Invalid JSON path"),
+            }
+            while bytes.get(pos) == Some(&b' ') {
+                pos += 1;
+            }
+            if !matches!(bytes.get(pos), Some(b'\'' | b'"')) {
+                return Err("ERR: Invalid JSON path");
+            }
+        }
+    }
+
+    let end = bytes[pos..].iter().position(|b| *b == b']').ok_or("ERR: Invalid JSON path")? + pos;
+    let inner = std::str::from_utf8(&bytes[pos..end]).map_err(|_| "ERR: Invalid JSON path")?.trim();
+
+    let selector = if inner == "*" {
+        Selector::Wildcard
+    } else if inner.contains(':') {
+        let parts: Vec<&str> = inner.split(':').map(str::trim).collect();
+        if parts.len() > 3 {
+            return Err("ERR: Invalid JSON path");
+        }
+        let bound = |part: Option<&&str>| -> Result<Option<i64>, &'static str> {
+            match part {
+                None | Some(&"") => Ok(None),
+                Some(n) => n.parse().map(Some).map_err(|_| "ERR: Invalid JSON path"),
+            }
+        };
+        let step = bound(parts.get(2))?.unwrap_or(1);
+        if step <= 0 {
+            return Err("ERR: Invalid JSON path");
+        }
+        Selector::Slice(bound(parts.first())?, bound(parts.get(1))?, step)
+    } else {
+        let indexes: Result<Vec<i64>, _> = inner.split(',').map(|i| i.trim().parse::<i64>()).collect();
+        Selector::Indexes(indexes.map_err(|_| "ERR: Invalid JSON path")?)
+    };
+    Ok((selector, end + 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn compact(value: &Json) -> String {
+        value.serialize(&Format::default())
+    }
+
+    fn selected(doc: &str, path: &str) -> Vec<String> {
+        let doc = Json::parse(doc).unwrap();
+        let path = Path::parse(path).unwrap();
+        doc.select(&path).iter().map(|location| compact(doc.get(location).unwrap())).collect()
+    }
+
+    #[test]
+    fn parses_escapes_and_surrogate_pairs() {
+        let parsed = Json::parse(r#""a\"\\\/\b\f\n\r\t\u00e9\ud83d\ude00""#).unwrap();
+        assert!(parsed == Json::Str("a\"\\/\u{08}\u{0c}\n\r\t\u{e9}\u{1f600}".into()));
+        assert_eq!(compact(&parsed), "\"a\\\"\\\\/\\b\\f\\n\\r\\t\u{e9}\u{1f600}\"");
+        assert_eq!(compact(&Json::Str("\u{01}".into())), r#""\u0001""#);
+
+        for invalid in [r#""\x""#, r#""\u12""#, r#""\u12g4""#, r#""\ud83d""#, r#""\ud83dx""#, r#""\ud83d\u0041""#,
+            r#""\ude00""#, "\"a\nb\"", r#""abc"#] {
+            assert!(Json::parse(invalid).is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn parses_numbers() {
+        assert!(Json::parse("0").unwrap() == Json::Int(0));
+        assert!(Json::parse("-12").unwrap() == Json::Int(-12));
+        assert!(Json::parse("-0.5e+2").unwrap() == Json::Float(-50.0));
+        assert!(Json::parse("1E3").unwrap() == Json::Float(1000.0));
+        assert!(Json::parse("9223372036854775807").unwrap() == Json::Int(i64::MAX));
+        assert!(Json::parse("-9223372036854775808").unwrap() == Json::Int(i64::MIN));
+        assert!(Json::parse("9223372036854775808").unwrap() == Json::Float(9223372036854775808.0));
+
+        for invalid in ["01", "-01", "1.", ".5", "-", "1e", "1e+", "+1", "1.e5", "1e999", "[01]"] {
+            assert!(Json::parse(invalid).is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn limits_depth() {
+        let nested = |depth: usize| format!("{}{}", "[".repeat(depth), "]".repeat(depth));
+        let deepest = Json::parse(&nested(MAX_DEPTH + 1)).unwrap();
+        assert!(Json::parse(&nested(MAX_DEPTH + 2)).is_err());
+        assert!(Json::parse(&format!("{{\"a\":{}}}", nested(MAX_DEPTH + 1))).is_err());
+
+        assert!(deepest.fits_at(&[]));
+        assert!(!deepest.fits_at(&[Key::Index(0)]));
+        assert!(Json::Null.fits_at(&vec![Key::Index(0); MAX_DEPTH]));
+        assert!(!Json::Array(Vec::new()).fits_at(&vec![Key::Index(0); MAX_DEPTH + 1]));
+    }
+
+    #[test]
+    fn selects_indexes_and_slices() {
+        let doc = "[0,1,2,3,4,5,6]";
+        assert_eq!(selected(doc, "$[-1]"), ["6"]);
+        assert_eq!(selected(doc, "$[0,-2,9,-9]"), ["0", "5"]);
+        assert_eq!(selected(doc, "$[1:6:2]"), ["1", "3", "5"]);
+        assert_eq!(selected(doc, "$[-3:]"), ["4", "5", "6"]);
+        assert_eq!(selected(doc, "$[:2]"), ["0", "1"]);
+        assert_eq!(selected(doc, "$[::3]"), ["0", "3", "6"]);
+        assert_eq!(selected(doc, "$[1:3:9223372036854775807]"), ["1"]);
+        assert_eq!(selected(doc, "$[5:1]"), Vec::<String>::new());
+        assert!(Path::parse("$[1:2:0]").is_err());
+        assert!(Path::parse("$[1:2:3:4]").is_err());
+    }
+
+    #[test]
+    fn selects_names_wildcards_and_descendants() {
+        let doc = r#"{"a":{"b":1,"c":[{"b":2}]},"it's":3,"say \"hi\"":4,"b":5}"#;
+        assert_eq!(selected(doc, "$.a.b"), ["1"]);
+        assert_eq!(selected(doc, "a.c[0].b"), ["2"]);
+        assert_eq!(selected(doc, "$.a.*"), ["1", r#"[{"b":2}]"#]);
+        assert_eq!(selected(doc, "$..b"), ["5", "1", "2"]);
+        assert_eq!(selected(doc, "$..[0]"), [r#"{"b":2}"#]);
+        assert_eq!(selected(doc, r#"$['it\'s']"#), ["3"]);
+        assert_eq!(selected(doc, r#"$["say \"hi\"", 'b']"#), ["4", "5"]);
+        assert_eq!(selected(doc, "$.missing"), Vec::<String>::new());
+        assert!(Path::parse("$['a'").is_err());
+        assert!(Path::parse("$..").is_err());
+    }
+
+    #[test]
+    fn deletes_nested_locations_once() {
+        let mut doc = Json::parse(r#"{"a":{"a":[1,{"a":2}]},"b":[{"a":3},4]}"#).unwrap();
+        let locations = doc.select(&Path::parse("$..a").unwrap());
+        assert_eq!(locations.len(), 4);
+        assert_eq!(doc.delete(&locations), 2);
+        assert_eq!(compact(&doc), r#"{"b":[{},4]}"#);
+
+        let mut doc = Json::parse("[0,1,2,3]").unwrap();
+        let locations = doc.select(&Path::parse("$[0,2,2]").unwrap());
+        assert_eq!(doc.delete(&locations), 2);
+        assert_eq!(compact(&doc), "[1,3]");
+        assert_eq!(doc.delete(&[Vec::new()]), 0);
+    }
+}
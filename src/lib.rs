@@ -0,0 +1,27 @@
+pub mod acl;
+pub mod aof;
+pub mod auth;
+pub mod bloom;
+pub mod config;
+pub mod cuckoo;
+pub mod database;
+pub mod dump;
+pub mod error;
+pub mod geo;
+pub mod glob;
+pub mod handlers;
+pub mod hash;
+pub mod hyperloglog;
+pub mod json;
+pub mod manifest;
+pub mod poll;
+pub mod rdb;
+pub mod resp;
+pub mod server;
+pub mod snapshot;
+pub mod socket;
+pub mod stream;
+pub mod timeseries;
+#[cfg(feature = "tls")]
+pub mod tls;
+pub mod zset;
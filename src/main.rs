@@ -1,41 +1,12 @@
 use std::sync::{Arc, RwLock};
 
-mod aof;
-mod config;
-mod database;
-mod error;
-mod handlers;
-mod resp;
-mod server;
-mod thread;
-
-use aof::AOF;
-use config::Config;
-use error::Result;
-use handlers::Handlers;
-use database::Database;
-use resp::Value;
-use server::Server;
-
-fn handle_read(value: Value, db: Arc<RwLock<Database>>) {
-    let Value::Array(arr) = value else {
-        eprintln!("array only!");
-        return;
-    };
-
-    let Value::BulkStr(command) = &arr[0] else {
-        eprintln!("only array of bulk strings");
-        return;
-    };
-    let args = &arr[1..];
-
-    let mut handlers = Handlers::new();
-    handlers.init();
-
-    let cmd = command.to_uppercase();
-    let handler = handlers.get(cmd.as_str()).unwrap();
-    handler(args.to_vec(), Arc::clone(&db));
-}
+use amandadb::aof::AOF;
+use amandadb::config::Config;
+use amandadb::error::{new_error, Result};
+use amandadb::handlers::{Databases, Handlers};
+use amandadb::database::Database;
+use amandadb::server::Server;
+use amandadb::snapshot::Snapshot;
 
 fn main() -> Result<()> {
     let mut args = std::env::args();
@@ -48,8 +19,26 @@ fn main() -> Result<()> {
 
     let server = Server::new(config.clone())?;
     let aof = Arc::new(RwLock::new(AOF::new(config.clone())?));
-    let db = Arc::new(RwLock::new(Database::new(config.clone())));
-    aof.write().unwrap().read(handle_read, Arc::clone(&db))?;
-
-    server.listen(aof, db)
+    let dbs: Databases = Arc::new((0..config.databases()).map(|_| Arc::new(Database::new())).collect());
+
+    let snapshot = Arc::new(Snapshot::new(&config));
+
+    // The AOF has every write up to the last one, so the snapshot is only
+    // read when there's no AOF. When the AOF was just turned on, what the
+    // snapshot had becomes its base right away, or the next start would
+    // find an AOF without it.
+    if aof.read().unwrap().existed() {
+        let mut handlers = Handlers::new();
+        handlers.init();
+        aof.write().unwrap().replay(&mut handlers, &dbs)?;
+    } else {
+        snapshot.load(&dbs)?;
+        if config.appendonly() && dbs.iter().any(|db| !db.read_all().is_empty()) {
+            AOF::rewrite(&aof, &dbs).map_err(new_error)?;
+        }
+    }
+    AOF::spawn_flusher(Arc::clone(&aof));
+    Snapshot::spawn_saver(Arc::clone(&snapshot), Arc::clone(&dbs));
+
+    server.listen(aof, snapshot, dbs)
 }
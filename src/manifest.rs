@@ -0,0 +1,182 @@
+use std::fs::File;
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+
+use crate::aof;
+use crate::error::{new_error, Result};
+
+/// A file of the AOF directory and its place among the others of its type.
+#[derive(Clone)]
+pub struct Part {
+    pub file: String,
+    pub seq: u64,
+}
+
+/// The files that make up the AOF, in the format of Redis 7: a line of
+/// `file <name> seq <n> type <b|i|h>` for the base written by the last
+/// rewrite, the incremental files appended to since then, and the history
+/// files that are left to be deleted.
+pub struct Manifest {
+    dir: PathBuf,
+    name: String,
+    pub base: Option<Part>,
+    pub incrs: Vec<Part>,
+    history: Vec<Part>,
+}
+
+impl Manifest {
+    pub fn new(dir: &Path, name: &str) -> Self {
+        Self { dir: dir.into(), name: name.into(), base: None, incrs: Vec::new(), history: Vec::new() }
+    }
+
+    fn path(dir: &Path, name: &str) -> PathBuf {
+        dir.join(format!("{name}.manifest"))
+    }
+
+    /// Reads the manifest of `name` in `dir`, if there is one.
+    pub fn load(dir: &Path, name: &str) -> Result<Option<Self>> {
+        let contents = match std::fs::read_to_string(Self::path(dir, name)) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut manifest = Self::new(dir, name);
+        for line in contents.lines().filter(|line| !line.trim().is_empty()) {
+            let words: Vec<&str> = line.split_whitespace().collect();
+            let field = |key: &str| words.chunks(2).find(|pair| pair[0] == key).and_then(|pair| pair.get(1).copied());
+            let (Some(file), Some(seq), Some(kind)) = (field("file"), field("seq"), field("type")) else {
+                return Err(new_error("Invalid line in the AOF manifest"));
+            };
+            if file.contains('/') {
+                return Err(new_error("The AOF manifest can only list files of its directory"));
+            }
+
+            let part = Part { file: file.into(), seq: seq.parse()? };
+            match kind {
+                "b" if manifest.base.is_none() => manifest.base = Some(part),
+                "i" => manifest.incrs.push(part),
+                "h" => manifest.history.push(part),
+                _ => return Err(new_error("Invalid file type in the AOF manifest")),
+            };
+        }
+        manifest.incrs.sort_by_key(|part| part.seq);
+        Ok(Some(manifest))
+    }
+
+    /// Writes the manifest to a temporary file that then replaces it, so a
+    /// crash leaves either the old manifest or the new one.
+    pub fn save(&self) -> Result<()> {
+        let mut contents = String::new();
+        let parts = self.base.iter().map(|part| (part, 'b'))
+            .chain(self.history.iter().map(|part| (part, 'h')))
+            .chain(self.incrs.iter().map(|part| (part, 'i')));
+        for (part, kind) in parts {
+            contents += &format!("file {} seq {} type {kind}\n", part.file, part.seq);
+        }
+
+        let path = Self::path(&self.dir, &self.name);
+        let temp = self.dir.join(format!("temp-{}.manifest", self.name));
+        let mut file = File::create(&temp)?;
+        file.write_all(contents.as_bytes())?;
+        file.sync_all()?;
+        aof::replace_file(&temp, &path)
+    }
+
+    pub fn path_of(&self, part: &Part) -> PathBuf {
+        self.dir.join(&part.file)
+    }
+
+    pub fn temp_path(&self) -> PathBuf {
+        self.dir.join(format!("temp-rewrite-{}.aof", self.name))
+    }
+
+    /// The base first, then the incremental files in the order they were
+    /// written.
+    pub fn parts(&self) -> impl Iterator<Item = &Part> {
+        self.base.iter().chain(&self.incrs)
+    }
+
+    /// Adds an incremental file after the others.
+    pub fn next_incr(&mut self) -> Part {
+        let seq = self.incrs.last().map_or(1, |part| part.seq + 1);
+        let part = Part { file: format!("{}.{seq}.incr.aof", self.name), seq };
+        self.incrs.push(part.clone());
+        part
+    }
+
+    pub fn next_base(&self) -> Part {
+        let seq = self.base.as_ref().map_or(1, |part| part.seq + 1);
+        Part { file: format!("{}.{seq}.base.aof", self.name), seq }
+    }
+
+    /// Makes `base` the start of the AOF, which leaves the old base and every
+    /// incremental file but the last one to history.
+    pub fn set_base(&mut self, base: Part) {
+        let current = self.incrs.pop();
+        self.history.extend(self.base.replace(base));
+        self.history.append(&mut self.incrs);
+        self.incrs.extend(current);
+    }
+
+    /// Deletes the history files, then drops them from the manifest.
+    pub fn remove_history(&mut self) -> Result<()> {
+        if self.history.is_empty() {
+            return Ok(());
+        }
+        for part in &self.history {
+            match std::fs::remove_file(self.path_of(part)) {
+                Err(e) if e.kind() != ErrorKind::NotFound => return Err(e.into()),
+                _ => (),
+            };
+        }
+        self.history.clear();
+        self.save()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("amandadb-manifest-{}-{name}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn files(parts: &[Part]) -> Vec<(&str, u64)> {
+        parts.iter().map(|part| (part.file.as_str(), part.seq)).collect()
+    }
+
+    #[test]
+    fn loads_what_it_saved() {
+        let dir = temp_dir("round-trip");
+        assert!(Manifest::load(&dir, "db.aof").unwrap().is_none());
+
+        let mut manifest = Manifest::new(&dir, "db.aof");
+        manifest.set_base(manifest.next_base());
+        manifest.next_incr();
+        manifest.next_incr();
+        manifest.set_base(manifest.next_base());
+        manifest.next_incr();
+        manifest.save().unwrap();
+
+        let loaded = Manifest::load(&dir, "db.aof").unwrap().unwrap();
+        assert_eq!(loaded.base.as_ref().map(|part| (part.file.as_str(), part.seq)), Some(("db.aof.2.base.aof", 2)));
+        assert_eq!(files(&loaded.incrs), [("db.aof.2.incr.aof", 2), ("db.aof.3.incr.aof", 3)]);
+        assert_eq!(files(&loaded.history), [("db.aof.1.base.aof", 1), ("db.aof.1.incr.aof", 1)]);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn rejects_invalid_manifests() {
+        let dir = temp_dir("invalid");
+        for contents in ["file db.aof.1.base.aof seq 1\n", "file ../db.aof seq 1 type b\n", "file db.aof seq 1 type x\n",
+            "file db.aof seq one type b\n"] {
+            std::fs::write(dir.join("db.aof.manifest"), contents).unwrap();
+            assert!(Manifest::load(&dir, "db.aof").is_err(), "{contents}");
+        }
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,147 @@
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::time::Duration;
+
+const EPOLL_CLOEXEC: i32 = 0o2000000;
+const EPOLL_CTL_ADD: i32 = 1;
+const EPOLL_CTL_DEL: i32 = 2;
+const EPOLL_CTL_MOD: i32 = 3;
+
+const EFD_CLOEXEC: i32 = 0o2000000;
+const EFD_NONBLOCK: i32 = 0o4000;
+
+const EPOLLIN: u32 = 0x001;
+const EPOLLOUT: u32 = 0x004;
+const EPOLLERR: u32 = 0x008;
+const EPOLLHUP: u32 = 0x010;
+const EPOLLRDHUP: u32 = 0x2000;
+const EPOLLEXCLUSIVE: u32 = 1 << 28;
+
+pub const READABLE: u32 = EPOLLIN;
+pub const WRITABLE: u32 = EPOLLOUT;
+/// Wakes up a single poller when many wait on the same file descriptor.
+pub const EXCLUSIVE: u32 = EPOLLEXCLUSIVE;
+
+/// Mirrors `struct epoll_event`, which the kernel packs on x86_64 only.
+#[repr(C)]
+#[cfg_attr(target_arch = "x86_64", repr(packed))]
+#[derive(Clone, Copy)]
+pub struct Event {
+    events: u32,
+    data: u64,
+}
+
+impl Event {
+    pub fn token(&self) -> u64 {
+        self.data
+    }
+
+    pub fn is_readable(&self) -> bool {
+        self.events & (EPOLLIN | EPOLLRDHUP | EPOLLHUP | EPOLLERR) != 0
+    }
+
+    pub fn is_writable(&self) -> bool {
+        self.events & (EPOLLOUT | EPOLLHUP | EPOLLERR) != 0
+    }
+}
+
+extern "C" {
+    fn epoll_create1(flags: i32) -> i32;
+    fn epoll_ctl(epfd: i32, op: i32, fd: i32, event: *mut Event) -> i32;
+    fn epoll_wait(epfd: i32, events: *mut Event, maxevents: i32, timeout: i32) -> i32;
+    fn eventfd(initval: u32, flags: i32) -> i32;
+}
+
+fn check(result: i32) -> io::Result<i32> {
+    match result {
+        -1 => Err(io::Error::last_os_error()),
+        n => Ok(n),
+    }
+}
+
+/// Level triggered epoll instance, using the C library std already links
+/// against instead of depending on the libc crate.
+pub struct Poller {
+    fd: OwnedFd,
+}
+
+impl Poller {
+    pub fn new() -> io::Result<Self> {
+        let fd = check(unsafe { epoll_create1(EPOLL_CLOEXEC) })?;
+        Ok(Self { fd: unsafe { OwnedFd::from_raw_fd(fd) } })
+    }
+
+    fn ctl(&self, op: i32, fd: &impl AsRawFd, token: u64, interest: u32) -> io::Result<()> {
+        let mut event = Event { events: interest, data: token };
+        check(unsafe { epoll_ctl(self.fd.as_raw_fd(), op, fd.as_raw_fd(), &mut event) })?;
+        Ok(())
+    }
+
+    pub fn add(&self, fd: &impl AsRawFd, token: u64, interest: u32) -> io::Result<()> {
+        self.ctl(EPOLL_CTL_ADD, fd, token, interest)
+    }
+
+    pub fn modify(&self, fd: &impl AsRawFd, token: u64, interest: u32) -> io::Result<()> {
+        self.ctl(EPOLL_CTL_MOD, fd, token, interest)
+    }
+
+    pub fn delete(&self, fd: &impl AsRawFd) -> io::Result<()> {
+        self.ctl(EPOLL_CTL_DEL, fd, 0, 0)
+    }
+
+    /// Fills `events` with up to its capacity of ready events, waiting forever
+    /// when no timeout is given.
+    pub fn wait(&self, events: &mut Vec<Event>, timeout: Option<Duration>) -> io::Result<()> {
+        // Rounded up, so a deadline isn't polled for again just before it.
+        let timeout = timeout.map_or(-1, |t| t.as_nanos().div_ceil(1_000_000).min(i32::MAX as u128) as i32);
+        events.clear();
+        loop {
+            let result = unsafe {
+                epoll_wait(self.fd.as_raw_fd(), events.as_mut_ptr(), events.capacity() as i32, timeout)
+            };
+            match check(result) {
+                Ok(n) => {
+                    unsafe { events.set_len(n as usize) };
+                    return Ok(());
+                },
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+/// An eventfd that makes a poller waiting on it readable from any thread.
+pub struct Waker {
+    file: File,
+}
+
+impl Waker {
+    pub fn new() -> io::Result<Self> {
+        let fd = check(unsafe { eventfd(0, EFD_CLOEXEC | EFD_NONBLOCK) })?;
+        Ok(Self { file: unsafe { File::from_raw_fd(fd) } })
+    }
+
+    pub fn wake(&self) -> io::Result<()> {
+        match (&self.file).write(&1u64.to_ne_bytes()) {
+            // The counter is full, so the poller is woken already.
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(()),
+            result => result.map(|_| ()),
+        }
+    }
+
+    /// Clears the wake ups so far, for the poller to wait again.
+    pub fn reset(&self) -> io::Result<()> {
+        match (&self.file).read(&mut [0; 8]) {
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(()),
+            result => result.map(|_| ()),
+        }
+    }
+}
+
+impl AsRawFd for Waker {
+    fn as_raw_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+}
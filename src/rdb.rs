@@ -0,0 +1,1077 @@
+use std::collections::{BTreeMap, HashMap};
+use std::io::Write;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::database::{Data, Database};
+use crate::error::{new_error, Result};
+use crate::zset::SortedSet;
+
+pub const MAGIC: &[u8] = b"REDIS";
+
+/// The version written, which Redis 6 and later load.
+const VERSION: u32 = 9;
+/// The newest version read, from Redis 7.4.
+const MAX_VERSION: u32 = 12;
+
+const OPCODE_SLOT_INFO: u8 = 0xF4;
+const OPCODE_FUNCTION2: u8 = 0xF5;
+const OPCODE_MODULE_AUX: u8 = 0xF7;
+const OPCODE_IDLE: u8 = 0xF8;
+const OPCODE_FREQ: u8 = 0xF9;
+const OPCODE_AUX: u8 = 0xFA;
+const OPCODE_RESIZEDB: u8 = 0xFB;
+const OPCODE_EXPIRETIME_MS: u8 = 0xFC;
+const OPCODE_EXPIRETIME: u8 = 0xFD;
+const OPCODE_SELECTDB: u8 = 0xFE;
+const OPCODE_EOF: u8 = 0xFF;
+
+const TYPE_STRING: u8 = 0;
+const TYPE_LIST: u8 = 1;
+const TYPE_SET: u8 = 2;
+const TYPE_ZSET: u8 = 3;
+const TYPE_HASH: u8 = 4;
+const TYPE_ZSET_2: u8 = 5;
+const TYPE_MODULE_2: u8 = 7;
+const TYPE_HASH_ZIPMAP: u8 = 9;
+const TYPE_LIST_ZIPLIST: u8 = 10;
+const TYPE_SET_INTSET: u8 = 11;
+const TYPE_ZSET_ZIPLIST: u8 = 12;
+const TYPE_HASH_ZIPLIST: u8 = 13;
+const TYPE_LIST_QUICKLIST: u8 = 14;
+const TYPE_STREAM_LISTPACKS: u8 = 15;
+const TYPE_HASH_LISTPACK: u8 = 16;
+const TYPE_ZSET_LISTPACK: u8 = 17;
+const TYPE_LIST_QUICKLIST_2: u8 = 18;
+const TYPE_STREAM_LISTPACKS_2: u8 = 19;
+const TYPE_SET_LISTPACK: u8 = 20;
+const TYPE_STREAM_LISTPACKS_3: u8 = 21;
+const TYPE_HASH_METADATA_PRE_GA: u8 = 22;
+const TYPE_HASH_LISTPACK_EX_PRE_GA: u8 = 23;
+const TYPE_HASH_METADATA: u8 = 24;
+const TYPE_HASH_LISTPACK_EX: u8 = 25;
+
+const QUICKLIST_NODE_PLAIN: u64 = 1;
+const QUICKLIST_NODE_PACKED: u64 = 2;
+
+const ENC_INT8: u8 = 0;
+const ENC_INT16: u8 = 1;
+const ENC_INT32: u8 = 2;
+const ENC_LZF: u8 = 3;
+
+const MODULE_OPCODE_EOF: u64 = 0;
+const MODULE_OPCODE_SINT: u64 = 1;
+const MODULE_OPCODE_UINT: u64 = 2;
+const MODULE_OPCODE_FLOAT: u64 = 3;
+const MODULE_OPCODE_DOUBLE: u64 = 4;
+const MODULE_OPCODE_STRING: u64 = 5;
+
+fn corrupted() -> Box<dyn std::error::Error> {
+    new_error("The RDB file is corrupted")
+}
+
+const fn crc64_table() -> [u64; 256] {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u64;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = match crc & 1 {
+                1 => (crc >> 1) ^ 0x95ac9329ac4bc9b5,
+                _ => crc >> 1,
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+const CRC64_TABLE: [u64; 256] = crc64_table();
+
+/// The CRC-64/Jones checksum Redis ends its files with, continuing from
+/// `crc` so it can be computed over several chunks.
+pub fn crc64(mut crc: u64, bytes: &[u8]) -> u64 {
+    for byte in bytes {
+        crc = CRC64_TABLE[((crc ^ *byte as u64) & 0xff) as usize] ^ (crc >> 8);
+    }
+    crc
+}
+
+/// Strings this short aren't worth compressing, as in Redis.
+const LZF_MIN_LEN: usize = 20;
+/// How far back a reference of LZF can point.
+const LZF_MAX_BACK: usize = 1 << 13;
+/// The longest run a reference of LZF can copy.
+const LZF_MAX_RUN: usize = 7 + 255 + 2;
+const LZF_HASH_BITS: u32 = 14;
+
+/// Compresses with LZF, or `None` when `input` is too short to bother or
+/// doesn't get any smaller.
+pub fn lzf_compress(input: &[u8]) -> Option<Vec<u8>> {
+    if input.len() <= LZF_MIN_LEN {
+        return None;
+    }
+
+    // The last position plus one where each hash of three bytes was seen.
+    let mut table = vec![0; 1 << LZF_HASH_BITS];
+    let mut out = Vec::with_capacity(input.len());
+    let (mut literals, mut i) = (0, 0);
+    while i + 2 < input.len() {
+        let hash = u32::from_be_bytes([0, input[i], input[i + 1], input[i + 2]]).wrapping_mul(2654435761) >> (32 - LZF_HASH_BITS);
+        let candidate = std::mem::replace(&mut table[hash as usize], i + 1);
+        let Some(start) = candidate.checked_sub(1).filter(|start| i - start <= LZF_MAX_BACK) else {
+            i += 1;
+            continue;
+        };
+        if input[start..start + 3] != input[i..i + 3] {
+            i += 1;
+            continue;
+        }
+
+        let max = LZF_MAX_RUN.min(input.len() - i);
+        let mut len = 3;
+        while len < max && input[start + len] == input[i + len] {
+            len += 1;
+        }
+        put_literals(&mut out, &input[literals..i]);
+        let (back, run) = (i - start - 1, len - 2);
+        match run {
+            0..=6 => out.push((run << 5 | back >> 8) as u8),
+            _ => out.extend([(7 << 5 | back >> 8) as u8, (run - 7) as u8]),
+        };
+        out.push(back as u8);
+        i += len;
+        literals = i;
+    }
+    put_literals(&mut out, &input[literals..]);
+    (out.len() < input.len()).then_some(out)
+}
+
+fn put_literals(out: &mut Vec<u8>, literals: &[u8]) {
+    for chunk in literals.chunks(32) {
+        out.push(chunk.len() as u8 - 1);
+        out.extend(chunk);
+    }
+}
+
+/// Expands LZF data, which Redis uses for strings that compress well.
+pub fn lzf_decompress(input: &[u8], len: usize) -> Option<Vec<u8>> {
+    // A reference of two bytes expands to at most `LZF_MAX_RUN`, so a
+    // corrupted length can't ask for more than that.
+    let mut out = Vec::with_capacity(len.min(input.len().saturating_mul(LZF_MAX_RUN / 2)));
+    let mut i = 0;
+    while i < input.len() {
+        let ctrl = input[i] as usize;
+        i += 1;
+        if ctrl < 32 {
+            out.extend(input.get(i..i + ctrl + 1)?);
+            i += ctrl + 1;
+            continue;
+        }
+
+        let mut run = ctrl >> 5;
+        if run == 7 {
+            run += *input.get(i)? as usize;
+            i += 1;
+        }
+        let back = ((ctrl & 0x1f) << 8) + *input.get(i)? as usize + 1;
+        i += 1;
+        let start = out.len().checked_sub(back)?;
+        // The reference may overlap with what it produces.
+        for j in 0..run + 2 {
+            out.push(out[start + j]);
+        }
+    }
+    (out.len() == len).then_some(out)
+}
+
+enum Length {
+    Len(u64),
+    Encoded(u8),
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+    /// Hash fields read with an expiry that hadn't passed yet.
+    expiring_fields: u64,
+}
+
+impl<'a> Reader<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes, pos: 0, expiring_fields: 0 }
+    }
+
+    fn take(&mut self, n: usize) -> Result<&'a [u8]> {
+        let end = self.pos.checked_add(n).filter(|end| *end <= self.bytes.len()).ok_or_else(corrupted)?;
+        let bytes = &self.bytes[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N]> {
+        Ok(self.take(N)?.try_into().unwrap())
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn length_or_encoding(&mut self) -> Result<Length> {
+        let first = self.u8()?;
+        Ok(match first >> 6 {
+            0 => Length::Len((first & 0x3f) as u64),
+            1 => Length::Len(((first & 0x3f) as u64) << 8 | self.u8()? as u64),
+            2 => match first {
+                0x80 => Length::Len(u32::from_be_bytes(self.array()?) as u64),
+                0x81 => Length::Len(u64::from_be_bytes(self.array()?)),
+                _ => return Err(corrupted()),
+            },
+            _ => Length::Encoded(first & 0x3f),
+        })
+    }
+
+    fn length(&mut self) -> Result<u64> {
+        match self.length_or_encoding()? {
+            Length::Len(len) => Ok(len),
+            Length::Encoded(_) => Err(corrupted()),
+        }
+    }
+
+    fn usize(&mut self) -> Result<usize> {
+        usize::try_from(self.length()?).map_err(|_| corrupted())
+    }
+
+    fn string(&mut self) -> Result<Vec<u8>> {
+        Ok(match self.length_or_encoding()? {
+            Length::Len(len) => self.take(usize::try_from(len)?)?.to_vec(),
+            Length::Encoded(ENC_INT8) => (self.u8()? as i8).to_string().into_bytes(),
+            Length::Encoded(ENC_INT16) => i16::from_le_bytes(self.array()?).to_string().into_bytes(),
+            Length::Encoded(ENC_INT32) => i32::from_le_bytes(self.array()?).to_string().into_bytes(),
+            Length::Encoded(ENC_LZF) => {
+                let compressed = self.usize()?;
+                let len = self.usize()?;
+                lzf_decompress(self.take(compressed)?, len).ok_or_else(corrupted)?
+            },
+            Length::Encoded(_) => return Err(corrupted()),
+        })
+    }
+
+    fn millis(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.array()?))
+    }
+
+    /// Scores of the first sorted set type, as text behind a length byte.
+    fn double(&mut self) -> Result<f64> {
+        Ok(match self.u8()? {
+            253 => f64::NAN,
+            254 => f64::INFINITY,
+            255 => f64::NEG_INFINITY,
+            len => score(self.take(len as usize)?)?,
+        })
+    }
+
+    /// Values of module types and module auxiliary data, a list of typed
+    /// fields closed by an EOF opcode.
+    fn skip_module_fields(&mut self) -> Result<()> {
+        loop {
+            match self.length()? {
+                MODULE_OPCODE_EOF => return Ok(()),
+                MODULE_OPCODE_SINT | MODULE_OPCODE_UINT => {
+                    self.length()?;
+                },
+                MODULE_OPCODE_FLOAT => {
+                    self.take(4)?;
+                },
+                MODULE_OPCODE_DOUBLE => {
+                    self.take(8)?;
+                },
+                MODULE_OPCODE_STRING => {
+                    self.string()?;
+                },
+                _ => return Err(corrupted()),
+            };
+        }
+    }
+
+    fn skip_stream(&mut self, kind: u8) -> Result<()> {
+        for _ in 0..self.length()? {
+            self.string()?;
+            self.string()?;
+        }
+        // Length and last id, then the first id, the max deleted id and the
+        // entries added since version 2.
+        let fields = if kind == TYPE_STREAM_LISTPACKS { 3 } else { 8 };
+        for _ in 0..fields {
+            self.length()?;
+        }
+
+        for _ in 0..self.length()? {
+            self.string()?;
+            self.length()?;
+            self.length()?;
+            if kind != TYPE_STREAM_LISTPACKS {
+                self.length()?;
+            }
+            for _ in 0..self.length()? {
+                self.take(16 + 8)?;
+                self.length()?;
+            }
+            for _ in 0..self.length()? {
+                self.string()?;
+                self.take(8)?;
+                if kind == TYPE_STREAM_LISTPACKS_3 {
+                    self.take(8)?;
+                }
+                for _ in 0..self.length()? {
+                    self.take(16)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads a value, or skips it and returns the name of its type when
+    /// AmandaDB has nothing like it. Hash fields that expired by `now` are
+    /// left out.
+    fn value(&mut self, kind: u8, now: u64) -> Result<std::result::Result<Data, &'static str>> {
+        let elements = match kind {
+            TYPE_STRING => return Ok(Ok(Data::Str(self.string()?))),
+            TYPE_LIST | TYPE_SET => {
+                let mut elements = Vec::new();
+                for _ in 0..self.length()? {
+                    elements.push(self.string()?);
+                }
+                elements
+            },
+            TYPE_LIST_ZIPLIST => ziplist(&self.string()?)?,
+            TYPE_LIST_QUICKLIST => {
+                let mut elements = Vec::new();
+                for _ in 0..self.length()? {
+                    elements.extend(ziplist(&self.string()?)?);
+                }
+                elements
+            },
+            TYPE_LIST_QUICKLIST_2 => {
+                let mut elements = Vec::new();
+                for _ in 0..self.length()? {
+                    // Elements too big for a listpack get a node of their own.
+                    match (self.length()?, self.string()?) {
+                        (QUICKLIST_NODE_PLAIN, element) => elements.push(element),
+                        (QUICKLIST_NODE_PACKED, bytes) => elements.extend(listpack(&bytes)?),
+                        _ => return Err(corrupted()),
+                    };
+                }
+                elements
+            },
+            TYPE_SET_INTSET => intset(&self.string()?)?,
+            TYPE_SET_LISTPACK => listpack(&self.string()?)?,
+            TYPE_ZSET | TYPE_ZSET_2 => {
+                let mut members = Vec::new();
+                for _ in 0..self.length()? {
+                    let member = self.string()?;
+                    let score = match kind {
+                        TYPE_ZSET => self.double()?,
+                        _ => f64::from_le_bytes(self.array()?),
+                    };
+                    members.push((member, score));
+                }
+                return sorted_set(members);
+            },
+            TYPE_ZSET_ZIPLIST | TYPE_ZSET_LISTPACK => {
+                let entries = match kind {
+                    TYPE_ZSET_ZIPLIST => ziplist(&self.string()?)?,
+                    _ => listpack(&self.string()?)?,
+                };
+                if !entries.len().is_multiple_of(2) {
+                    return Err(corrupted());
+                }
+                let members = entries.chunks(2).map(|pair| Ok((pair[0].clone(), score(&pair[1])?))).collect::<Result<_>>()?;
+                return sorted_set(members);
+            },
+            TYPE_HASH => {
+                let mut pairs = Vec::new();
+                for _ in 0..self.length()? {
+                    pairs.push(self.string()?);
+                    pairs.push(self.string()?);
+                }
+                return hash(pairs);
+            },
+            TYPE_HASH_ZIPMAP => return hash(zipmap(&self.string()?)?),
+            TYPE_HASH_ZIPLIST => return hash(ziplist(&self.string()?)?),
+            TYPE_HASH_LISTPACK => return hash(listpack(&self.string()?)?),
+            TYPE_HASH_METADATA_PRE_GA | TYPE_HASH_LISTPACK_EX_PRE_GA | TYPE_HASH_METADATA | TYPE_HASH_LISTPACK_EX => {
+                return hash(self.hash_with_expiries(kind, now)?);
+            },
+            _ => return self.skip(kind).map(Err),
+        };
+
+        let Ok(elements) = elements.into_iter().map(String::from_utf8).collect::<std::result::Result<Vec<_>, _>>() else {
+            return Ok(Err(match kind {
+                TYPE_SET | TYPE_SET_INTSET | TYPE_SET_LISTPACK => "sets with binary members",
+                _ => "lists with binary elements",
+            }));
+        };
+        Ok(Ok(match kind {
+            TYPE_SET | TYPE_SET_INTSET | TYPE_SET_LISTPACK => Data::Set(elements.into_iter().collect()),
+            _ => Data::List(elements.into()),
+        }))
+    }
+
+    /// Fields and values of the hashes Redis 7.4 saves with the expiries of
+    /// their fields, in milliseconds and zero for none. Those are absolute
+    /// but for the final format of big hashes, which counts them from the
+    /// earliest one plus one.
+    fn hash_with_expiries(&mut self, kind: u8, now: u64) -> Result<Vec<Vec<u8>>> {
+        let min_expire = match kind {
+            TYPE_HASH_METADATA | TYPE_HASH_LISTPACK_EX => self.millis()?,
+            _ => 0,
+        };
+        let mut fields = Vec::new();
+        match kind {
+            TYPE_HASH_METADATA_PRE_GA | TYPE_HASH_METADATA => {
+                for _ in 0..self.length()? {
+                    let expire = match self.length()? {
+                        ttl if ttl != 0 && kind == TYPE_HASH_METADATA => {
+                            (ttl - 1).checked_add(min_expire).ok_or_else(corrupted)?
+                        },
+                        ttl => ttl,
+                    };
+                    fields.push((self.string()?, self.string()?, expire));
+                }
+            },
+            _ => {
+                let entries = listpack(&self.string()?)?;
+                if !entries.len().is_multiple_of(3) {
+                    return Err(corrupted());
+                }
+                for entry in entries.chunks(3) {
+                    let expire = std::str::from_utf8(&entry[2]).ok().and_then(|n| n.parse().ok()).ok_or_else(corrupted)?;
+                    fields.push((entry[0].clone(), entry[1].clone(), expire));
+                }
+            },
+        };
+
+        let mut pairs = Vec::new();
+        for (field, value, expire) in fields {
+            match expire {
+                0 => (),
+                expire if expire <= now => continue,
+                _ => self.expiring_fields += 1,
+            };
+            pairs.push(field);
+            pairs.push(value);
+        }
+        Ok(pairs)
+    }
+
+    fn skip(&mut self, kind: u8) -> Result<&'static str> {
+        let name = match kind {
+            TYPE_STREAM_LISTPACKS | TYPE_STREAM_LISTPACKS_2 | TYPE_STREAM_LISTPACKS_3 => {
+                self.skip_stream(kind)?;
+                "streams"
+            },
+            TYPE_MODULE_2 => {
+                self.length()?;
+                self.skip_module_fields()?;
+                "module values"
+            },
+            _ => return Err(new_error(&format!("The RDB file has a value of type {kind}, which can't be read"))),
+        };
+        Ok(name)
+    }
+}
+
+fn score(bytes: &[u8]) -> Result<f64> {
+    std::str::from_utf8(bytes).ok().and_then(|score| score.parse().ok()).ok_or_else(corrupted)
+}
+
+fn hash(pairs: Vec<Vec<u8>>) -> Result<std::result::Result<Data, &'static str>> {
+    if !pairs.len().is_multiple_of(2) {
+        return Err(corrupted());
+    }
+    let mut hash = HashMap::new();
+    let mut pairs = pairs.into_iter().map(String::from_utf8);
+    while let (Some(field), Some(value)) = (pairs.next(), pairs.next()) {
+        match (field, value) {
+            (Ok(field), Ok(value)) => hash.insert(field, value),
+            _ => return Ok(Err("hashes with binary fields")),
+        };
+    }
+    Ok(Ok(Data::Hash(hash)))
+}
+
+fn sorted_set(members: Vec<(Vec<u8>, f64)>) -> Result<std::result::Result<Data, &'static str>> {
+    let mut set = SortedSet::default();
+    for (member, score) in members {
+        if score.is_nan() {
+            return Err(corrupted());
+        }
+        match String::from_utf8(member) {
+            Ok(member) => set.insert(&member, score),
+            Err(_) => return Ok(Err("sorted sets with binary members")),
+        };
+    }
+    Ok(Ok(Data::SortedSet(set)))
+}
+
+/// Members of an intset, the sorted integers small sets of them are packed
+/// as, all of the width the biggest one needs.
+fn intset(bytes: &[u8]) -> Result<Vec<Vec<u8>>> {
+    let mut reader = Reader::new(bytes);
+    let width = u32::from_le_bytes(reader.array()?);
+    let len = u32::from_le_bytes(reader.array()?);
+    let mut members = Vec::new();
+    for _ in 0..len {
+        let n = match width {
+            2 => i16::from_le_bytes(reader.array()?) as i64,
+            4 => i32::from_le_bytes(reader.array()?) as i64,
+            8 => i64::from_le_bytes(reader.array()?),
+            _ => return Err(corrupted()),
+        };
+        members.push(n.to_string().into_bytes());
+    }
+    Ok(members)
+}
+
+/// Entries of a ziplist, the packed encoding of small hashes, lists and
+/// sorted sets before Redis 7.
+fn ziplist(bytes: &[u8]) -> Result<Vec<Vec<u8>>> {
+    let mut reader = Reader::new(bytes);
+    reader.take(10)?;
+    let mut entries = Vec::new();
+    loop {
+        match reader.u8()? {
+            0xff => return Ok(entries),
+            0xfe => {
+                reader.take(4)?;
+            },
+            _ => (),
+        };
+
+        let encoding = reader.u8()?;
+        let len = match encoding >> 6 {
+            0 => (encoding & 0x3f) as usize,
+            1 => ((encoding & 0x3f) as usize) << 8 | reader.u8()? as usize,
+            2 => u32::from_be_bytes(reader.array()?) as usize,
+            _ => 0,
+        };
+        let entry = match encoding >> 6 {
+            0..=2 => reader.take(len)?.to_vec(),
+            _ => {
+                let n = match encoding {
+                    0xc0 => i16::from_le_bytes(reader.array()?) as i64,
+                    0xd0 => i32::from_le_bytes(reader.array()?) as i64,
+                    0xe0 => i64::from_le_bytes(reader.array()?),
+                    0xf0 => {
+                        let [a, b, c] = reader.array()?;
+                        i32::from_le_bytes([0, a, b, c]) as i64 >> 8
+                    },
+                    0xfe => reader.u8()? as i8 as i64,
+                    0xf1..=0xfd => (encoding & 0x0f) as i64 - 1,
+                    _ => return Err(corrupted()),
+                };
+                n.to_string().into_bytes()
+            },
+        };
+        entries.push(entry);
+    }
+}
+
+/// Entries of a listpack, which replaced ziplists in Redis 7.
+fn listpack(bytes: &[u8]) -> Result<Vec<Vec<u8>>> {
+    let mut reader = Reader::new(bytes);
+    reader.take(6)?;
+    let mut entries = Vec::new();
+    loop {
+        let start = reader.pos;
+        let encoding = reader.u8()?;
+        let entry = match encoding {
+            0xff => return Ok(entries),
+            0x00..=0x7f => (encoding as i64).to_string().into_bytes(),
+            0x80..=0xbf => reader.take((encoding & 0x3f) as usize)?.to_vec(),
+            0xc0..=0xdf => {
+                let n = ((encoding & 0x1f) as i64) << 8 | reader.u8()? as i64;
+                let n = if n >= 1 << 12 { n - (1 << 13) } else { n };
+                n.to_string().into_bytes()
+            },
+            0xe0..=0xef => {
+                let len = ((encoding & 0x0f) as usize) << 8 | reader.u8()? as usize;
+                reader.take(len)?.to_vec()
+            },
+            0xf0 => {
+                let len = u32::from_le_bytes(reader.array()?) as usize;
+                reader.take(len)?.to_vec()
+            },
+            0xf1 => i16::from_le_bytes(reader.array()?).to_string().into_bytes(),
+            0xf2 => {
+                let [a, b, c] = reader.array()?;
+                (i32::from_le_bytes([0, a, b, c]) >> 8).to_string().into_bytes()
+            },
+            0xf3 => i32::from_le_bytes(reader.array()?).to_string().into_bytes(),
+            0xf4 => i64::from_le_bytes(reader.array()?).to_string().into_bytes(),
+            _ => return Err(corrupted()),
+        };
+        entries.push(entry);
+
+        // Each entry ends with its own length, for walking backwards, in as
+        // many bytes as lpEncodeBacklen picks for it.
+        let len = reader.pos - start;
+        let backlen = match len {
+            0..=127 => 1,
+            128..=16382 => 2,
+            16383..=2097150 => 3,
+            2097151..=268435454 => 4,
+            _ => 5,
+        };
+        reader.take(backlen)?;
+    }
+}
+
+/// Fields and values of a zipmap, how Redis 2.4 and older packed hashes.
+fn zipmap(bytes: &[u8]) -> Result<Vec<Vec<u8>>> {
+    fn len(reader: &mut Reader) -> Result<Option<usize>> {
+        Ok(match reader.u8()? {
+            255 => None,
+            254 => Some(u32::from_le_bytes(reader.array()?) as usize),
+            len => Some(len as usize),
+        })
+    }
+
+    let mut reader = Reader::new(bytes);
+    reader.take(1)?;
+    let mut entries = Vec::new();
+    while let Some(field) = len(&mut reader)? {
+        entries.push(reader.take(field)?.to_vec());
+        let value = len(&mut reader)?.ok_or_else(corrupted)?;
+        let free = reader.u8()? as usize;
+        entries.push(reader.take(value)?.to_vec());
+        reader.take(free)?;
+    }
+    Ok(entries)
+}
+
+/// Loads the strings, hashes, lists, sets and sorted sets of a Redis RDB
+/// file. Anything else fails the load, as saving afterwards would lose it for
+/// good, unless `skip_unsupported` is set to leave it out with a warning.
+///
+/// AmandaDB has no expiries: keys and hash fields that already expired are
+/// left out, and the others are loaded to stay, with a warning.
+pub fn load(bytes: &[u8], dbs: &[Arc<Database>], skip_unsupported: bool) -> Result<()> {
+    let mut reader = Reader::new(bytes);
+    if reader.take(MAGIC.len())? != MAGIC {
+        return Err(new_error("The file is not an RDB file"));
+    }
+    let version: u32 = std::str::from_utf8(reader.take(4)?)?.parse()?;
+    if version > MAX_VERSION {
+        return Err(new_error(&format!("RDB version {version} is not supported")));
+    }
+
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0);
+    let mut db = dbs.first();
+    let mut expire = None;
+    let mut skipped: BTreeMap<&str, u64> = BTreeMap::new();
+    let mut expiries = 0;
+    loop {
+        match reader.u8()? {
+            OPCODE_EOF => break,
+            OPCODE_SELECTDB => {
+                db = dbs.get(reader.usize()?);
+            },
+            OPCODE_RESIZEDB => {
+                reader.length()?;
+                reader.length()?;
+            },
+            OPCODE_AUX => {
+                reader.string()?;
+                reader.string()?;
+            },
+            OPCODE_EXPIRETIME_MS => expire = Some(reader.millis()?),
+            OPCODE_EXPIRETIME => expire = Some(u32::from_le_bytes(reader.array()?) as u64 * 1000),
+            OPCODE_IDLE => {
+                reader.length()?;
+            },
+            OPCODE_FREQ => {
+                reader.u8()?;
+            },
+            OPCODE_SLOT_INFO => {
+                for _ in 0..3 {
+                    reader.length()?;
+                }
+            },
+            OPCODE_FUNCTION2 => {
+                reader.string()?;
+                *skipped.entry("function libraries").or_default() += 1;
+            },
+            OPCODE_MODULE_AUX => {
+                reader.length()?;
+                reader.skip_module_fields()?;
+            },
+            kind => {
+                let key = reader.string()?;
+                let value = reader.value(kind, now)?;
+                match expire.take() {
+                    Some(at) if at <= now => continue,
+                    Some(_) => expiries += 1,
+                    None => (),
+                };
+                let Some(db) = db else {
+                    *skipped.entry("keys in databases past the configured ones").or_default() += 1;
+                    continue;
+                };
+                let Ok(key) = String::from_utf8(key) else {
+                    *skipped.entry("keys with binary names").or_default() += 1;
+                    continue;
+                };
+                match value {
+                    // Every field of it expired.
+                    Ok(Data::Hash(hash)) if hash.is_empty() => (),
+                    Ok(data) => db.write([&key]).insert(key, data),
+                    Err(name) => *skipped.entry(name).or_default() += 1,
+                };
+            },
+        };
+    }
+
+    if version >= 5 {
+        let end = reader.pos;
+        let checksum = u64::from_le_bytes(reader.array()?);
+        if checksum != 0 && checksum != crc64(0, &bytes[..end]) {
+            return Err(new_error("The checksum of the RDB file doesn't match"));
+        }
+    }
+
+    if !skipped.is_empty() && !skip_unsupported {
+        let found: Vec<String> = skipped.iter().map(|(name, count)| format!("{count} {name}")).collect();
+        return Err(new_error(&format!(
+            "The RDB file has {} that AmandaDB can't load, set rdb-skip-unsupported=yes to load it without them",
+            found.join(", "),
+        )));
+    }
+    for (name, count) in skipped {
+        eprintln!("Skipped {count} {name} of the RDB file");
+    }
+    if expiries > 0 {
+        eprintln!("Loaded {expiries} keys of the RDB file without their expiry");
+    }
+    if reader.expiring_fields > 0 {
+        eprintln!("Loaded {} hash fields of the RDB file without their expiry", reader.expiring_fields);
+    }
+    Ok(())
+}
+
+fn put_length(out: &mut Vec<u8>, len: u64) {
+    match len {
+        0..=0x3f => out.push(len as u8),
+        0x40..=0x3fff => out.extend([0x40 | (len >> 8) as u8, len as u8]),
+        0x4000..=0xffff_ffff => {
+            out.push(0x80);
+            out.extend((len as u32).to_be_bytes());
+        },
+        _ => {
+            out.push(0x81);
+            out.extend(len.to_be_bytes());
+        },
+    };
+}
+
+/// Compresses with LZF when `compress` is set and it makes the string
+/// smaller.
+fn put_string(out: &mut Vec<u8>, bytes: &[u8], compress: bool) {
+    if let Some(compressed) = compress.then(|| lzf_compress(bytes)).flatten() {
+        out.push(0xC0 | ENC_LZF);
+        put_length(out, compressed.len() as u64);
+        put_length(out, bytes.len() as u64);
+        out.extend(compressed);
+        return;
+    }
+    put_length(out, bytes.len() as u64);
+    out.extend(bytes);
+}
+
+fn is_written(data: &Data) -> bool {
+    matches!(data, Data::Str(_) | Data::Hash(_) | Data::List(_) | Data::Set(_) | Data::SortedSet(_))
+}
+
+/// Writes the strings, hashes, lists, sets and sorted sets of every database
+/// as an RDB file. Types Redis doesn't have without modules are left out with
+/// a warning.
+pub fn write(writer: &mut impl Write, snapshot: &[Vec<HashMap<String, Data>>], compress: bool) -> Result<()> {
+    let mut out = MAGIC.to_vec();
+    out.extend(format!("{VERSION:04}").as_bytes());
+    let ctime = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    for (field, value) in [("redis-bits", "64".to_string()), ("ctime", ctime.to_string())] {
+        out.push(OPCODE_AUX);
+        put_string(&mut out, field.as_bytes(), false);
+        put_string(&mut out, value.as_bytes(), false);
+    }
+
+    let mut crc = 0;
+    let mut skipped = 0;
+    for (index, shards) in snapshot.iter().enumerate() {
+        let len = shards.iter().flatten().filter(|(_, data)| is_written(data)).count();
+        if len == 0 {
+            continue;
+        }
+        out.push(OPCODE_SELECTDB);
+        put_length(&mut out, index as u64);
+        out.push(OPCODE_RESIZEDB);
+        put_length(&mut out, len as u64);
+        put_length(&mut out, 0);
+
+        for (key, data) in shards.iter().flatten() {
+            match data {
+                Data::Str(value) => {
+                    out.push(TYPE_STRING);
+                    put_string(&mut out, key.as_bytes(), compress);
+                    put_string(&mut out, value, compress);
+                },
+                Data::Hash(hash) => {
+                    out.push(TYPE_HASH);
+                    put_string(&mut out, key.as_bytes(), compress);
+                    put_length(&mut out, hash.len() as u64);
+                    for (field, value) in hash {
+                        put_string(&mut out, field.as_bytes(), compress);
+                        put_string(&mut out, value.as_bytes(), compress);
+                    }
+                },
+                Data::List(list) => {
+                    out.push(TYPE_LIST);
+                    put_string(&mut out, key.as_bytes(), compress);
+                    put_length(&mut out, list.len() as u64);
+                    for element in list {
+                        put_string(&mut out, element.as_bytes(), compress);
+                    }
+                },
+                Data::Set(set) => {
+                    out.push(TYPE_SET);
+                    put_string(&mut out, key.as_bytes(), compress);
+                    put_length(&mut out, set.len() as u64);
+                    for member in set {
+                        put_string(&mut out, member.as_bytes(), compress);
+                    }
+                },
+                Data::SortedSet(set) => {
+                    out.push(TYPE_ZSET_2);
+                    put_string(&mut out, key.as_bytes(), compress);
+                    put_length(&mut out, set.len() as u64);
+                    for (member, score) in set.iter() {
+                        put_string(&mut out, member.as_bytes(), compress);
+                        out.extend(score.to_le_bytes());
+                    }
+                },
+                _ => skipped += 1,
+            };
+            crc = crc64(crc, &out);
+            writer.write_all(&out)?;
+            out.clear();
+        }
+    }
+    out.push(OPCODE_EOF);
+    crc = crc64(crc, &out);
+    out.extend(crc.to_le_bytes());
+    writer.write_all(&out)?;
+
+    if skipped > 0 {
+        eprintln!("Left {skipped} keys out of the RDB file, Redis has no type for them");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::resp::Value;
+
+    fn load_fixture(bytes: &[u8], skip_unsupported: bool) -> Result<Arc<Database>> {
+        let dbs = [Arc::new(Database::new())];
+        load(bytes, &dbs, skip_unsupported)?;
+        let [db] = dbs;
+        Ok(db)
+    }
+
+    fn assert_field(db: &Database, hash: &str, field: &str, value: &str) {
+        let got = db.read([hash]).hset_get(hash, &field.to_string()).marshal();
+        assert_eq!(got, Value::BulkStr(value.to_string()).marshal(), "{hash} {field}");
+    }
+
+    fn assert_string(db: &Database, key: &str, value: &str) {
+        assert_eq!(db.read([key]).set_get(key).marshal(), Value::BulkBytes(value.into()).marshal(), "{key}");
+    }
+
+    fn assert_list(db: &Database, key: &str, elements: &[&str]) {
+        let db = db.read([key]);
+        let Ok(Some(list)) = db.list_get(key) else {
+            panic!("{key} is not a list");
+        };
+        assert!(list.iter().eq(elements), "{key}: {list:?}");
+    }
+
+    fn assert_set(db: &Database, key: &str, members: &[&str]) {
+        let db = db.read([key]);
+        let Ok(Some(set)) = db.members_get(key) else {
+            panic!("{key} is not a set");
+        };
+        assert!(set.len() == members.len() && members.iter().all(|member| set.contains(*member)), "{key}: {set:?}");
+    }
+
+    fn assert_zset(db: &Database, key: &str, members: &[(&str, f64)]) {
+        let db = db.read([key]);
+        let Ok(Some(set)) = db.zset_get(key) else {
+            panic!("{key} is not a sorted set");
+        };
+        assert!(set.iter().eq(members.iter().copied()), "{key}: {set:?}");
+    }
+
+    #[test]
+    fn loads_a_redis_6_file() {
+        let db = load_fixture(include_bytes!("../tests/fixtures/redis6.rdb"), false).unwrap();
+        assert_eq!(db.read_all().len(), 10);
+        assert_string(&db, "plain", "hello");
+        assert_string(&db, "int16", "1234");
+        assert_string(&db, "lzf", "aaaaaaaaaa");
+
+        let long = "x".repeat(300);
+        for (field, value) in [
+            ("name", "amanda"),
+            ("imm", "7"),
+            ("i8", "-100"),
+            ("i16", "30000"),
+            ("i24", "-5000000"),
+            ("i32", "2000000000"),
+            ("i64", "1099511627776"),
+            ("long", &long),
+            ("after", "long"),
+        ] {
+            assert_field(&db, "ziplist", field, value);
+        }
+        assert_field(&db, "zipmap", "k1", "v1");
+        assert_field(&db, "zipmap", "k2", &"z".repeat(300));
+
+        assert_list(&db, "quicklist", &["a", "5", "b"]);
+        assert_set(&db, "intset", &["-3", "70000"]);
+        assert_zset(&db, "zset", &[("m", 1.0), ("n", 2.5)]);
+        assert_zset(&db, "oldzset", &[("low", f64::NEG_INFINITY), ("mid", 1.5)]);
+        assert!(!db.read_all().contains("expired"));
+        assert_string(&db, "expiring", "kept");
+    }
+
+    #[test]
+    fn loads_a_redis_7_file() {
+        let fixture = include_bytes!("../tests/fixtures/redis7.rdb");
+        let e = load_fixture(fixture, false).err().unwrap().to_string();
+        assert!(e.contains("has 1 streams that"), "{e}");
+
+        let db = load_fixture(fixture, true).unwrap();
+        assert_eq!(db.read_all().len(), 5);
+        assert_list(&db, "list", &["a", "b", "c"]);
+        assert_set(&db, "set", &["m"]);
+        assert_zset(&db, "zset", &[("m", 1.0)]);
+        assert_string(&db, "plain", "hello");
+        let long = "y".repeat(200);
+        let edge = "e".repeat(16378);
+        for (field, value) in [
+            ("name", "amanda"),
+            ("u7", "7"),
+            ("i13", "-100"),
+            ("i16", "30000"),
+            ("i24", "-5000000"),
+            ("i32", "-2000000000"),
+            ("i64", "1099511627776"),
+            ("str12", &long),
+            ("after", "str12"),
+            ("edge", &edge),
+            ("past", "edge"),
+        ] {
+            assert_field(&db, "listpack", field, value);
+        }
+    }
+
+    #[test]
+    fn loads_hashes_with_field_expiries() {
+        let db = load_fixture(include_bytes!("../tests/fixtures/redis74.rdb"), false).unwrap();
+        assert_eq!(db.read_all().len(), 4);
+        for hash in ["meta-pre-ga", "meta", "listpack-ex-pre-ga", "listpack-ex"] {
+            assert_field(&db, hash, "a", "1");
+            assert_field(&db, hash, "c", "3");
+            assert_eq!(db.read([hash]).hset_get(hash, &"b".to_string()).marshal(), Value::Null.marshal(), "{hash}");
+        }
+        assert!(!db.read_all().contains("all-expired"));
+    }
+
+    #[test]
+    fn loads_what_it_writes() {
+        let db = Database::new();
+        {
+            let mut keys = db.write_all();
+            keys.insert("string".into(), Data::Str(b"value".to_vec()));
+            keys.insert("hash".into(), Data::Hash(HashMap::from([("field".into(), "value".into())])));
+            keys.insert("list".into(), Data::List(["b", "a", "b"].map(String::from).into()));
+            keys.insert("set".into(), Data::Set(["x", "y"].map(String::from).into()));
+            let mut set = SortedSet::default();
+            set.insert("low", f64::NEG_INFINITY);
+            set.insert("mid", 0.1);
+            keys.insert("zset".into(), Data::SortedSet(set));
+            keys.insert("json".into(), Data::Json(crate::json::Json::parse("[1]").unwrap()));
+        }
+        let mut out = Vec::new();
+        write(&mut out, &[db.create_database_copy()], true).unwrap();
+
+        let db = load_fixture(&out, false).unwrap();
+        assert_eq!(db.read_all().len(), 5);
+        assert_string(&db, "string", "value");
+        assert_field(&db, "hash", "field", "value");
+        assert_list(&db, "list", &["b", "a", "b"]);
+        assert_set(&db, "set", &["x", "y"]);
+        assert_zset(&db, "zset", &[("low", f64::NEG_INFINITY), ("mid", 0.1)]);
+    }
+
+    #[test]
+    fn computes_the_redis_crc64() {
+        assert_eq!(crc64(0, b"123456789"), 0xe9c6d914c4b8d9ca);
+        assert_eq!(crc64(crc64(0, b"1234"), b"56789"), 0xe9c6d914c4b8d9ca);
+    }
+
+    #[test]
+    fn decompresses_what_lzf_compressed() {
+        let mut noise = Vec::new();
+        let mut state: u32 = 1;
+        for _ in 0..20_000 {
+            state = state.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+            noise.push((state >> 16) as u8);
+        }
+        // Repeats further back than a single byte of offset reaches.
+        let mut far = noise[..2000].to_vec();
+        far.extend_from_slice(&noise[..2000]);
+        let inputs = [
+            (b"hello hello hello hello hello".to_vec(), true),
+            (vec![b'a'; 100_000], true),
+            (b"abcdefghij".repeat(1000), true),
+            (far, true),
+            (noise, false),
+            (b"short".to_vec(), false),
+        ];
+
+        for (input, compressible) in &inputs {
+            match lzf_compress(input) {
+                Some(compressed) => {
+                    assert!(compressed.len() < input.len());
+                    assert_eq!(lzf_decompress(&compressed, input.len()).as_deref(), Some(&input[..]));
+                },
+                None => assert!(!compressible, "{} bytes", input.len()),
+            };
+        }
+        assert!(lzf_decompress(&[0x05, b'a'], 6).is_none());
+    }
+
+    #[test]
+    fn rejects_a_bad_checksum() {
+        let mut fixture = include_bytes!("../tests/fixtures/redis6.rdb").to_vec();
+        let last = fixture.len() - 1;
+        fixture[last] ^= 1;
+        assert!(load_fixture(&fixture, false).is_err());
+    }
+}
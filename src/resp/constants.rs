@@ -1,17 +1,17 @@
-pub const STRING: u8 = '+' as u8;
-pub const ERROR: u8 = '-' as u8;
-pub const NUMBER: u8 = ':' as u8;
-pub const BULKSTR: u8 = '$' as u8;
-pub const ARRAY: u8 = '*' as u8;
-pub const BOOLEAN: u8 = '#' as u8;
-pub const DOUBLE: u8 = ',' as u8;
-// pub const BIGNUM: u8 = '(' as u8;
-pub const BULKERR: u8 = '!' as u8;
-// pub const VERBATIM: u8 = '=' as u8;
-pub const ATTRIBUTE: u8 = '|' as u8;
-pub const MAP: u8 = '%' as u8;
-pub const SET: u8 = '~' as u8;
-pub const PUSH: u8 = '>' as u8;
+pub const STRING: u8 = b'+';
+pub const ERROR: u8 = b'-';
+pub const NUMBER: u8 = b':';
+pub const BULKSTR: u8 = b'$';
+pub const ARRAY: u8 = b'*';
+pub const BOOLEAN: u8 = b'#';
+pub const DOUBLE: u8 = b',';
+// pub const BIGNUM: u8 = b'(';
+pub const BULKERR: u8 = b'!';
+// pub const VERBATIM: u8 = b'=';
+pub const ATTRIBUTE: u8 = b'|';
+pub const MAP: u8 = b'%';
+pub const SET: u8 = b'~';
+pub const PUSH: u8 = b'>';
 
-pub const CR: u8 = '\r' as u8;
-pub const LF: u8 = '\n' as u8;
+pub const CR: u8 = b'\r';
+pub const LF: u8 = b'\n';
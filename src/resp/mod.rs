@@ -1,8 +1,6 @@
 mod constants;
 mod reader;
 mod value;
-mod writer;
 
-pub use reader::RESP;
+pub use reader::{frame_len, RESP};
 pub use value::Value;
-pub use writer::Writer;
@@ -1,48 +1,52 @@
-use std::io::{BufReader, Bytes, Read};
+use super::{constants::{ARRAY, BULKSTR, CR, LF}, value::Value};
+use crate::error::{new_error, Result};
 
-use super::{constants::{ARRAY, BULKSTR, CR}, value::Value};
-use crate::error::Result;
+/// Largest bulk string a client may send, as Redis' `proto-max-bulk-len`.
+pub const MAX_BULK_LEN: i64 = 512 * 1024 * 1024;
+/// Largest number of elements in an array, as Redis' multibulk limit.
+pub const MAX_ARRAY_LEN: i64 = 1024 * 1024;
+/// Requests are flat arrays of bulk strings, so anything nested deeper than
+/// this is a broken or hostile client.
+pub const MAX_DEPTH: usize = 8;
+/// Longest line before its CRLF, as Redis' limit on inline requests.
+const MAX_LINE: usize = 64 * 1024;
 
+#[allow(clippy::upper_case_acronyms)]
 pub struct RESP<'a> {
-    reader: Bytes<BufReader<&'a [u8]>>,
+    input: &'a [u8],
+    position: usize,
 }
 
 impl<'a> RESP<'a> {
-    pub fn new(input: &'a str) -> Self {
-        Self {
-            reader: BufReader::new(input.as_bytes()).bytes(),
-        }
+    pub fn new(input: &'a [u8]) -> Self {
+        Self { input, position: 0 }
     }
 
-    fn read_line(&mut self) -> Vec<u8> {
-        let mut line: Vec<u8> = Vec::new();
-        while let Some(Ok(b)) = self.reader.next() {
-            line.push(b);
-            
-            if line.len() >= 2 && line[line.len()-2] == CR {
-                break;
-            }
-        }
-
-        line[0..line.len()-2].to_vec()
+    fn read_line(&mut self) -> Result<&'a [u8]> {
+        let Some(end) = line_end(self.input, self.position) else {
+            return Err(new_error("Unterminated line"));
+        };
+        let line = &self.input[self.position..end - 2];
+        self.position = end;
+        Ok(line)
     }
 
     fn read_integer(&mut self) -> Result<i64> {
-        let line = self.read_line();
-        let string = String::from_utf8_lossy(&line);
-        
-        let int = string.parse::<i64>()?;
-        Ok(int)
+        parse_len(self.read_line()?)
     }
 
     pub fn read(&mut self) -> Result<Value> {
-        let _type = match self.reader.next() {
-            Some(Ok(t)) => t,
-            _ => { return Ok(Value::Null); },
+        self.read_value(0)
+    }
+
+    fn read_value(&mut self, depth: usize) -> Result<Value> {
+        let Some(&_type) = self.input.get(self.position) else {
+            return Ok(Value::Null);
         };
+        self.position += 1;
 
         Ok(match _type {
-            ARRAY => self.read_array()?,
+            ARRAY => self.read_array(depth)?,
             BULKSTR => self.read_bulk()?,
             _ => {
                 println!("Unknwon type: {:?}", _type as char);
@@ -51,12 +55,18 @@ impl<'a> RESP<'a> {
         })
     }
 
-    fn read_array(&mut self) -> Result<Value> {
+    fn read_array(&mut self, depth: usize) -> Result<Value> {
+        if depth >= MAX_DEPTH {
+            return Err(new_error("Protocol error: arrays nested too deep"));
+        }
         let len = self.read_integer()?;
+        if len > MAX_ARRAY_LEN {
+            return Err(new_error("Protocol error: invalid multibulk length"));
+        }
         let mut value: Vec<Value> = Vec::new();
 
         for _ in 0..len {
-            let temp = self.read()?;
+            let temp = self.read_value(depth + 1)?;
             value.push(temp);
         }
 
@@ -64,8 +74,163 @@ impl<'a> RESP<'a> {
     }
 
     fn read_bulk(&mut self) -> Result<Value> {
-        let _len = self.read_integer()?;
-        let value = String::from_utf8_lossy(&self.read_line()).to_string();
-        Ok(Value::BulkStr(value))
+        let len = self.read_integer()?;
+        if len < 0 {
+            return Ok(Value::Null);
+        }
+        if len > MAX_BULK_LEN {
+            return Err(new_error("Protocol error: invalid bulk length"));
+        }
+        let start = self.position;
+        let end = start + len as usize;
+        if self.input.len() < end + 2 || self.input[end..end + 2] != [CR, LF] {
+            return Err(new_error("Protocol error: bulk string doesn't match its length"));
+        }
+        self.position = end + 2;
+        Ok(match String::from_utf8(self.input[start..end].to_vec()) {
+            Ok(s) => Value::BulkStr(s),
+            Err(e) => Value::BulkBytes(e.into_bytes()),
+        })
+    }
+}
+
+fn line_end(input: &[u8], start: usize) -> Option<usize> {
+    let window = &input[start..input.len().min(start + MAX_LINE)];
+    window.windows(2).position(|w| w == [CR, LF]).map(|i| start + i + 2)
+}
+
+fn parse_len(line: &[u8]) -> Result<i64> {
+    Ok(std::str::from_utf8(line)?.parse::<i64>()?)
+}
+
+/// Length of the first whole value at the start of `input`, or `None` when
+/// it hasn't been fully received yet.
+///
+/// This runs on every read of a client, so it walks the value with a stack
+/// of the elements each open array still waits for instead of recursing.
+pub fn frame_len(input: &[u8]) -> Result<Option<usize>> {
+    let mut pending: Vec<i64> = Vec::new();
+    let mut position = 0;
+
+    loop {
+        if position >= input.len() {
+            return Ok(None);
+        }
+        let Some(end) = line_end(input, position) else {
+            if input.len() - position >= MAX_LINE {
+                return Err(new_error("Protocol error: too big inline request"));
+            }
+            return Ok(None);
+        };
+
+        match input[position] {
+            ARRAY => {
+                let len = parse_len(&input[position + 1..end - 2])?;
+                if len > MAX_ARRAY_LEN {
+                    return Err(new_error("Protocol error: invalid multibulk length"));
+                }
+                position = end;
+                if len > 0 {
+                    if pending.len() >= MAX_DEPTH {
+                        return Err(new_error("Protocol error: arrays nested too deep"));
+                    }
+                    pending.push(len);
+                    continue;
+                }
+            },
+            BULKSTR => {
+                let len = parse_len(&input[position + 1..end - 2])?;
+                if len > MAX_BULK_LEN {
+                    return Err(new_error("Protocol error: invalid bulk length"));
+                }
+                position = match len < 0 {
+                    true => end,
+                    false => end + len as usize + 2,
+                };
+                if input.len() < position {
+                    return Ok(None);
+                }
+            },
+            _ => position = end,
+        }
+
+        // A whole value was read: it completes an element of every array
+        // it finishes off.
+        loop {
+            match pending.last_mut() {
+                None => return Ok(Some(position)),
+                Some(left) if *left > 1 => {
+                    *left -= 1;
+                    break;
+                },
+                Some(_) => {
+                    pending.pop();
+                },
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frames_whole_values() {
+        assert_eq!(frame_len(b"").unwrap(), None);
+        assert_eq!(frame_len(b"+OK\r\n").unwrap(), Some(5));
+        assert_eq!(frame_len(b"$3\r\nfoo\r\n$1").unwrap(), Some(9));
+        assert_eq!(frame_len(b"$-1\r\n").unwrap(), Some(5));
+        assert_eq!(frame_len(b"*0\r\n").unwrap(), Some(4));
+        assert_eq!(frame_len(b"*2\r\n$3\r\nGET\r\n$1\r\nk\r\n*1").unwrap(), Some(20));
+        assert_eq!(frame_len(b"*2\r\n*1\r\n$1\r\na\r\n$1\r\nb\r\n").unwrap(), Some(22));
+    }
+
+    #[test]
+    fn waits_for_partial_values() {
+        let frame = b"*2\r\n$3\r\nGET\r\n$1\r\nk\r\n";
+        for len in 0..frame.len() {
+            assert_eq!(frame_len(&frame[..len]).unwrap(), None, "prefix of {len} bytes");
+        }
+    }
+
+    #[test]
+    fn bulk_strings_are_framed_by_their_length() {
+        let frame = b"*2\r\n$3\r\nSET\r\n$4\r\na\r\nb\r\n";
+        assert_eq!(frame_len(frame).unwrap(), Some(frame.len()));
+        let value = RESP::new(frame).read().unwrap();
+        assert_eq!(value.marshal(), frame);
+
+        assert!(RESP::new(b"$4\r\nab\r\n").read().is_err());
+    }
+
+    #[test]
+    fn keeps_binary_bulk_strings() {
+        let frame = b"*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$3\r\n\xff\x00\x80\r\n";
+        let Value::Array(args) = RESP::new(frame).read().unwrap() else {
+            panic!("not an array");
+        };
+        assert!(matches!(&args[2], Value::BulkBytes(bytes) if bytes == b"\xff\x00\x80"));
+        assert_eq!(Value::Array(args).marshal(), frame);
+    }
+
+    #[test]
+    fn rejects_deep_nesting() {
+        let deep = b"*1\r\n".repeat(200_000);
+        assert!(frame_len(&deep).is_err());
+        assert!(RESP::new(&deep).read().is_err());
+
+        let mut nested = b"*1\r\n".repeat(MAX_DEPTH - 1);
+        nested.extend_from_slice(b"$1\r\na\r\n");
+        assert_eq!(frame_len(&nested).unwrap(), Some(nested.len()));
+        assert!(RESP::new(&nested).read().is_ok());
+    }
+
+    #[test]
+    fn rejects_oversized_lengths() {
+        assert!(frame_len(b"$536870913\r\n").is_err());
+        assert!(frame_len(b"*1048577\r\n").is_err());
+        assert!(frame_len(b"$x\r\n").is_err());
+        assert!(frame_len(&[b'+'; MAX_LINE]).is_err());
     }
 }
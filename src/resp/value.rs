@@ -2,11 +2,10 @@ use std::collections::{HashMap, HashSet};
 
 use super::constants::*;
 
-fn usize_to_u8(number: usize) -> u8 {
-    match char::from_digit(number as u32, 10) {
-        Some(c) => c as u8,
-        None => 0,
-    }
+fn length_bytes(number: usize) -> Vec<u8> {
+    let mut bytes = number.to_string().into_bytes();
+    bytes.extend([CR, LF]);
+    bytes
 }
 
 #[allow(dead_code)]
@@ -16,6 +15,8 @@ pub enum Value {
     Error(&'static str),
     Num(i64),
     BulkStr(String),
+    /// A bulk string that isn't valid UTF-8, as the values of `SET` may be.
+    BulkBytes(Vec<u8>),
     Array(Vec<Value>),
     Bool(bool),
     Double(f64),
@@ -30,11 +31,13 @@ pub enum Value {
 }
 
 impl Value {
-    pub fn is_error(&self) -> Option<&str> {
-        if let Value::Error(e) = self {
-            return Some(e);
+    /// The contents of either kind of bulk string.
+    pub fn bytes(&self) -> Option<&[u8]> {
+        match self {
+            Value::BulkStr(s) => Some(s.as_bytes()),
+            Value::BulkBytes(b) => Some(b),
+            _ => None,
         }
-        None
     }
 
     pub fn marshal(self) -> Vec<u8> {
@@ -42,7 +45,7 @@ impl Value {
             Value::Str(_) => self.marshal_string(),
             Value::Error(_) => self.marshal_error(),
             Value::Num(_) => self.marshal_number(),
-            Value::BulkStr(_) => self.marshal_bulkstr(),
+            Value::BulkStr(_) | Value::BulkBytes(_) => self.marshal_bulkstr(),
             Value::Array(_) => self.marshal_array(),
             Value::Bool(_) => self.marshal_bool(),
             Value::Double(_) => self.marshal_double(),
@@ -89,13 +92,15 @@ impl Value {
 
     fn marshal_bulkstr(self) -> Vec<u8> {
         let mut bytes: Vec<u8> = Vec::new();
-        if let Value::BulkStr(blk) = self {
-            bytes.push(BULKSTR);
-            let len = usize_to_u8(blk.chars().count());
-            bytes.extend([len, CR, LF]);
-            bytes.extend(blk.as_bytes());
-            bytes.extend([CR, LF]);
-        }
+        let blk = match self {
+            Value::BulkStr(blk) => blk.into_bytes(),
+            Value::BulkBytes(blk) => blk,
+            _ => return bytes,
+        };
+        bytes.push(BULKSTR);
+        bytes.extend(length_bytes(blk.len()));
+        bytes.extend(blk);
+        bytes.extend([CR, LF]);
         bytes
     }
 
@@ -103,8 +108,7 @@ impl Value {
         let mut bytes: Vec<u8> = Vec::new();
         if let Value::Array(arr) = self {
             bytes.push(ARRAY);
-            let len = usize_to_u8(arr.len());
-            bytes.extend([len, CR, LF]);
+            bytes.extend(length_bytes(arr.len()));
             arr.into_iter().for_each(|value| bytes.extend(value.marshal()));
         }
         bytes
@@ -115,8 +119,8 @@ impl Value {
         if let Value::Bool(bol) = self {
             bytes.push(BOOLEAN);
             match bol {
-                true => bytes.push('t' as u8),
-                false => bytes.push('f' as u8),
+                true => bytes.push(b't'),
+                false => bytes.push(b'f'),
             }
             bytes.extend([CR, LF]);
         }
@@ -141,8 +145,7 @@ impl Value {
         let mut bytes: Vec<u8> = Vec::new();
         if let Value::BulkError(bulk) = self {
             bytes.push(BULKERR);
-            let len = usize_to_u8(bulk.chars().count());
-            bytes.extend([len, CR, LF]);
+            bytes.extend(length_bytes(bulk.len()));
             bytes.extend(bulk.as_bytes());
             bytes.extend([CR, LF]);
         }
@@ -157,8 +160,7 @@ impl Value {
         let mut bytes: Vec<u8> = Vec::new();
         if let Value::Map(map) = self {
             bytes.push(MAP);
-            let len = usize_to_u8(map.len());
-            bytes.extend([len, CR, LF]);
+            bytes.extend(length_bytes(map.len()));
             map.into_iter().for_each(|(key, value)| {
                 bytes.extend(key.marshal());
                 bytes.extend(value.marshal());
@@ -171,8 +173,7 @@ impl Value {
         let mut bytes: Vec<u8> = Vec::new();
         if let Value::Attr(attr) = self {
             bytes.push(ATTRIBUTE);
-            let len = usize_to_u8(attr.len());
-            bytes.extend([len, CR, LF]);
+            bytes.extend(length_bytes(attr.len()));
             attr.into_iter().for_each(|(key, value)| {
                 bytes.extend(key.marshal());
                 bytes.extend(value.marshal());
@@ -185,8 +186,7 @@ impl Value {
         let mut bytes: Vec<u8> = Vec::new();
         if let Value::Set(set) = self {
             bytes.push(SET);
-            let len = usize_to_u8(set.len());
-            bytes.extend([len, CR, LF]);
+            bytes.extend(length_bytes(set.len()));
             set.into_iter().for_each(|value| bytes.extend(value.marshal()));
         }
         bytes
@@ -196,8 +196,7 @@ impl Value {
         let mut bytes: Vec<u8> = Vec::new();
         if let Value::Push(push) = self {
             bytes.push(PUSH);
-            let len = usize_to_u8(push.len());
-            bytes.extend([len, CR, LF]);
+            bytes.extend(length_bytes(push.len()));
             push.into_iter().for_each(|value| bytes.extend(value.marshal()));
         }
         bytes
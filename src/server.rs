@@ -1,68 +1,523 @@
-use std::io::Read;
-use std::net::{TcpListener, TcpStream};
-use std::sync::{Arc, RwLock};
+use std::collections::{HashMap, HashSet};
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{IpAddr, SocketAddrV6, TcpListener, TcpStream};
+use std::os::fd::{AsRawFd, RawFd};
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{mpsc, Arc, RwLock};
+use std::thread;
+use std::time::Instant;
 
+use crate::acl::Acl;
 use crate::aof::AOF;
 use crate::config::Config;
 use crate::error::{new_error, Result};
-use crate::handlers::Handlers;
-use crate::database::Database;
-use crate::resp::{RESP, Writer};
-use crate::thread::ThreadPool;
+use crate::handlers::{Databases, Handlers};
+use crate::poll::{Event, Poller, Waker, EXCLUSIVE, READABLE, WRITABLE};
+use crate::resp::{frame_len, Value, RESP};
+use crate::snapshot::Snapshot;
+use crate::socket;
+#[cfg(feature = "tls")]
+use crate::tls::{self, TlsStream};
+
+const MAX_EVENTS: usize = 1024;
+/// The token of the loop's waker, out of the way of connections.
+const WAKER: u64 = u64::MAX;
+/// Unprocessed input a client may pile up, as Redis' `client-query-buffer-limit`.
+const MAX_QUERY_BUFFER: usize = 1024 * 1024 * 1024;
+const DENIED: Value = Value::Error("DENIED: Running in protected mode, so only loopback clients are accepted. \
+    Set a password for the default user, set protected-mode=no or bind to loopback addresses only");
+
+enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+    #[cfg(feature = "tls")]
+    Tls(TcpListener, Arc<rustls::ServerConfig>),
+}
+
+impl Listener {
+    fn try_clone(&self) -> io::Result<Self> {
+        match self {
+            Listener::Tcp(listener) => listener.try_clone().map(Listener::Tcp),
+            Listener::Unix(listener) => listener.try_clone().map(Listener::Unix),
+            #[cfg(feature = "tls")]
+            Listener::Tls(listener, config) => Ok(Listener::Tls(listener.try_clone()?, Arc::clone(config))),
+        }
+    }
+
+    /// Accepts a connection, telling whether the client is on this host.
+    fn accept(&self) -> io::Result<(Stream, bool)> {
+        match self {
+            Listener::Tcp(listener) => {
+                let (stream, address) = listener.accept()?;
+                stream.set_nodelay(true)?;
+                Ok((Stream::Tcp(stream), address.ip().to_canonical().is_loopback()))
+            },
+            Listener::Unix(listener) => Ok((Stream::Unix(listener.accept()?.0), true)),
+            #[cfg(feature = "tls")]
+            Listener::Tls(listener, config) => {
+                let (stream, address) = listener.accept()?;
+                stream.set_nodelay(true)?;
+                let stream = TlsStream::new(config, stream).map_err(|e| io::Error::other(e.to_string()))?;
+                Ok((Stream::Tls(Box::new(stream)), address.ip().to_canonical().is_loopback()))
+            },
+        }
+    }
+}
+
+impl AsRawFd for Listener {
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            Listener::Tcp(listener) => listener.as_raw_fd(),
+            Listener::Unix(listener) => listener.as_raw_fd(),
+            #[cfg(feature = "tls")]
+            Listener::Tls(listener, _) => listener.as_raw_fd(),
+        }
+    }
+}
+
+enum Stream {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+    #[cfg(feature = "tls")]
+    Tls(Box<TlsStream>),
+}
+
+impl Stream {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.set_nonblocking(nonblocking),
+            Stream::Unix(stream) => stream.set_nonblocking(nonblocking),
+            #[cfg(feature = "tls")]
+            Stream::Tls(stream) => stream.sock().set_nonblocking(nonblocking),
+        }
+    }
+
+    /// Whether encrypted bytes are still waiting for the socket to take them.
+    fn wants_write(&self) -> bool {
+        match self {
+            #[cfg(feature = "tls")]
+            Stream::Tls(stream) => stream.wants_write(),
+            _ => false,
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.read(buf),
+            Stream::Unix(stream) => stream.read(buf),
+            #[cfg(feature = "tls")]
+            Stream::Tls(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.write(buf),
+            Stream::Unix(stream) => stream.write(buf),
+            #[cfg(feature = "tls")]
+            Stream::Tls(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.flush(),
+            Stream::Unix(stream) => stream.flush(),
+            #[cfg(feature = "tls")]
+            Stream::Tls(stream) => stream.flush(),
+        }
+    }
+}
+
+impl AsRawFd for Stream {
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            Stream::Tcp(stream) => stream.as_raw_fd(),
+            Stream::Unix(stream) => stream.as_raw_fd(),
+            #[cfg(feature = "tls")]
+            Stream::Tls(stream) => stream.as_raw_fd(),
+        }
+    }
+}
+
+/// Binds every configured address on `port`, skipping the optional ones
+/// that fail. IPv6 addresses only take IPv6 clients, so `*` and `::*` can
+/// both be bound.
+fn bind_tcp(config: &Config, port: u16) -> Result<Vec<TcpListener>> {
+    let mut listeners = Vec::new();
+    for address in config.bind() {
+        let bound = match address.ip {
+            IpAddr::V4(ip) => TcpListener::bind((ip, port)),
+            IpAddr::V6(ip) => socket::bind_v6_only(SocketAddrV6::new(ip, port, 0, 0)),
+        };
+        let listener = match bound {
+            Ok(listener) => listener,
+            Err(e) if address.optional => {
+                eprintln!("Skipping optional bind address {}: {e}", address.ip);
+                continue;
+            },
+            Err(e) => return Err(e.into()),
+        };
+        listener.set_nonblocking(true)?;
+        listeners.push(listener);
+    }
+    Ok(listeners)
+}
+
+fn bind_unix(path: &str, permissions: Option<u32>) -> Result<UnixListener> {
+    if std::fs::symlink_metadata(path).is_ok_and(|m| m.file_type().is_socket()) {
+        std::fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path)?;
+    if let Some(mode) = permissions {
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+    }
+    Ok(listener)
+}
 
 pub struct Server {
-    listener: TcpListener,
-    pool: ThreadPool,
+    listeners: Vec<Listener>,
+    threads: usize,
+    protected_mode: bool,
+    acl: Arc<RwLock<Acl>>,
 }
 
 impl Server {
+    /// A `port` of zero only listens on the unix socket and the TLS port.
     pub fn new(config: Config) -> Result<Self> {
-        let listener = TcpListener::bind(("127.0.0.1", config.port()))?;
-        let pool = ThreadPool::new(config.threads());
-        
+        let mut listeners = Vec::new();
+        if config.port() != 0 {
+            listeners.extend(bind_tcp(&config, config.port())?.into_iter().map(Listener::Tcp));
+        }
+        if let Some(port) = config.tls_port() {
+            #[cfg(feature = "tls")]
+            {
+                let tls = tls::server_config(&config)?;
+                let bound = bind_tcp(&config, port)?;
+                listeners.extend(bound.into_iter().map(|l| Listener::Tls(l, Arc::clone(&tls))));
+            }
+            #[cfg(not(feature = "tls"))]
+            return Err(new_error(&format!("Can't listen on tls-port {port} without the tls feature")));
+        }
+        if let Some(path) = config.unixsocket() {
+            let listener = bind_unix(path, config.unixsocketperm())?;
+            listener.set_nonblocking(true)?;
+            listeners.push(Listener::Unix(listener));
+        }
+        if listeners.is_empty() {
+            return Err(new_error("Failed to bind to any address"));
+        }
+
         Ok(Self {
-            listener,
-            pool,
+            listeners,
+            threads: config.threads(),
+            protected_mode: config.protected_mode(),
+            acl: Arc::new(RwLock::new(Acl::new(&config)?)),
         })
     }
 
-    pub fn listen(&self, aof: Arc<RwLock<AOF>>, db: Arc<RwLock<Database>>) -> Result<()> {
-        for stream in self.listener.incoming() {
-            let stream = stream?;
-            let aof = Arc::clone(&aof);
-            let db = Arc::clone(&db);
-
-            self.pool.execute(|| {
-                if let Err(e) = handle_request(stream, aof, db) {
-                    eprintln!("{e}");
-                }
+    /// Runs one event loop per thread, all of them accepting connections
+    /// from every listener.
+    pub fn listen(&self, aof: Arc<RwLock<AOF>>, snapshot: Arc<Snapshot>, dbs: Databases) -> Result<()> {
+        let (stopped, loops) = mpsc::channel();
+        for _ in 0..self.threads {
+            let listeners = self.listeners.iter().map(|l| l.try_clone()).collect::<std::io::Result<_>>()?;
+            let mut event_loop = EventLoop::new(listeners, self.protected_mode, Arc::clone(&self.acl),
+                Arc::clone(&aof), Arc::clone(&snapshot), Arc::clone(&dbs))?;
+            let stopped = stopped.clone();
+            thread::spawn(move || {
+                let reason = match panic::catch_unwind(AssertUnwindSafe(|| event_loop.run())) {
+                    Ok(Ok(())) => "stopped".to_string(),
+                    Ok(Err(e)) => format!("failed: {e}"),
+                    Err(_) => "panicked".to_string(),
+                };
+                let _ = stopped.send(reason);
             });
         }
-        println!("shutting down");
+        drop(stopped);
+
+        // The loops only return when something went wrong, and the server
+        // can't go on without one of them, so the first one to stop takes
+        // the whole process down with a non-zero exit.
+        let reason = loops.recv().unwrap_or_else(|_| "stopped".to_string());
+        Err(new_error(&format!("An event loop {reason}, shutting down")))
+    }
+}
+
+struct Connection {
+    stream: Stream,
+    input: Vec<u8>,
+    output: Vec<u8>,
+    handlers: Handlers<'static>,
+    writable: bool,
+}
+
+impl Connection {
+    fn new(stream: Stream, acl: Arc<RwLock<Acl>>, snapshot: Arc<Snapshot>) -> Self {
+        let mut handlers = Handlers::new();
+        handlers.init();
+        handlers.set_acl(acl);
+        handlers.set_snapshot(snapshot);
+
+        Self {
+            stream,
+            input: Vec::new(),
+            output: Vec::new(),
+            handlers,
+            writable: false,
+        }
+    }
+
+    /// Reads everything available, returning false once the client is gone.
+    fn read(&mut self) -> Result<bool> {
+        let mut buffer = [0; 16 * 1024];
+        loop {
+            match self.stream.read(&mut buffer) {
+                Ok(0) => return Ok(false),
+                Ok(n) if self.input.len() + n > MAX_QUERY_BUFFER => {
+                    return Err(new_error("Closing a client whose query buffer is over the limit"));
+                },
+                Ok(n) => self.input.extend_from_slice(&buffer[..n]),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(true),
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e.into()),
+            };
+        }
+    }
+
+    /// Runs every complete command received so far, queueing the replies.
+    /// A blocked command holds back the ones after it until it has its reply.
+    fn process(&mut self, aof: &Arc<RwLock<AOF>>, dbs: &Databases) -> Result<()> {
+        while !self.handlers.is_blocked() {
+            let Some(len) = frame_len(&self.input)? else {
+                break;
+            };
+            let value = RESP::new(&self.input[..len]).read()?;
+            self.input.drain(..len);
+
+            let result = self.handlers.match_handler(value, Arc::clone(aof), dbs);
+            if !self.handlers.is_blocked() {
+                self.output.extend(result.marshal());
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes as much of the pending replies as the socket takes.
+    fn flush(&mut self) -> Result<()> {
+        while !self.output.is_empty() {
+            match self.stream.write(&self.output) {
+                Ok(n) => drop(self.output.drain(..n)),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e.into()),
+            };
+        }
+        self.stream.flush()?;
         Ok(())
     }
 }
 
-fn handle_request(mut stream: TcpStream, aof: Arc<RwLock<AOF>>, db: Arc<RwLock<Database>>) -> Result<()> {
-    let mut buffer = [0; 1024];
+/// Multiplexes many connections over a single thread with epoll.
+/// Listeners use the tokens from zero up to their count, connections the
+/// ones after them.
+///
+/// Connections blocked on a read are tried again whenever a write to the
+/// database they wait on wakes the loop, or once their timeout is up.
+struct EventLoop {
+    poller: Poller,
+    waker: Arc<Waker>,
+    blocked: HashSet<u64>,
+    listeners: Vec<Listener>,
+    protected_mode: bool,
+    acl: Arc<RwLock<Acl>>,
+    connections: HashMap<u64, Connection>,
+    next_token: u64,
+    aof: Arc<RwLock<AOF>>,
+    snapshot: Arc<Snapshot>,
+    dbs: Databases,
+}
+
+impl EventLoop {
+    fn new(listeners: Vec<Listener>, protected_mode: bool, acl: Arc<RwLock<Acl>>,
+        aof: Arc<RwLock<AOF>>, snapshot: Arc<Snapshot>, dbs: Databases) -> Result<Self> {
+        let poller = Poller::new()?;
+        for (token, listener) in listeners.iter().enumerate() {
+            poller.add(listener, token as u64, READABLE | EXCLUSIVE)?;
+        }
+        let waker = Arc::new(Waker::new()?);
+        poller.add(&*waker, WAKER, READABLE)?;
+
+        Ok(Self {
+            poller,
+            waker,
+            blocked: HashSet::new(),
+            next_token: listeners.len() as u64,
+            listeners,
+            protected_mode,
+            acl,
+            connections: HashMap::new(),
+            aof,
+            snapshot,
+            dbs,
+        })
+    }
+
+    fn run(&mut self) -> Result<()> {
+        let mut events: Vec<Event> = Vec::with_capacity(MAX_EVENTS);
+        loop {
+            let deadline = self.deadline();
+            self.poller.wait(&mut events, deadline.map(|d| d.saturating_duration_since(Instant::now())))?;
+            let mut woken = false;
+            for event in &events {
+                match event.token() {
+                    WAKER => {
+                        self.waker.reset()?;
+                        woken = true;
+                    },
+                    token if token < self.listeners.len() as u64 => self.accept(token as usize),
+                    token => {
+                        if let Err(e) = self.ready(token, event) {
+                            eprintln!("{e}");
+                            self.close(token);
+                        }
+                    },
+                };
+            }
+            if woken || deadline.is_some_and(|deadline| deadline <= Instant::now()) {
+                self.retry_blocked();
+            }
+        }
+    }
+
+    /// The nearest timeout of a blocked connection.
+    fn deadline(&self) -> Option<Instant> {
+        self.blocked.iter()
+            .filter_map(|token| self.connections.get(token)?.handlers.deadline())
+            .min()
+    }
+
+    fn accept(&mut self, listener: usize) {
+        loop {
+            let (mut stream, local) = match self.listeners[listener].accept() {
+                Ok(accepted) => accepted,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    eprintln!("{e}");
+                    return;
+                },
+            };
+            // Like Redis, a password on the default user lifts protected mode.
+            if self.protected_mode && !local && self.acl.read().unwrap().initial_user().is_some() {
+                let _ = stream.write_all(&DENIED.marshal());
+                continue;
+            }
+            // A socket the loop can't take is dropped, not the whole loop.
+            if let Err(e) = stream.set_nonblocking(true) {
+                eprintln!("{e}");
+                continue;
+            }
 
-    loop {
-        if stream.read(&mut buffer)? == 0 {
+            let token = self.next_token;
+            self.next_token += 1;
+            if let Err(e) = self.poller.add(&stream, token, READABLE) {
+                eprintln!("{e}");
+                continue;
+            }
+            self.connections.insert(token, Connection::new(stream, Arc::clone(&self.acl), Arc::clone(&self.snapshot)));
+        }
+    }
+
+    fn ready(&mut self, token: u64, event: &Event) -> Result<()> {
+        let Some(connection) = self.connections.get_mut(&token) else {
             return Ok(());
+        };
+
+        let mut open = true;
+        if event.is_readable() {
+            open = connection.read()?;
+            connection.process(&self.aof, &self.dbs)?;
+        }
+        if event.is_writable() || !connection.output.is_empty() || connection.stream.wants_write() {
+            connection.flush()?;
         }
 
-        let request = std::str::from_utf8(&buffer)?;
-        let mut resp = RESP::new(request);
-        let value = resp.read()?;
+        if !open {
+            self.close(token);
+            return Ok(());
+        }
+        self.settle(token)
+    }
 
-        let mut handlers = Handlers::new();
-        handlers.init();
-        let mut writer = Writer::new(Box::new(stream.try_clone()?));
+    fn retry_blocked(&mut self) {
+        let blocked: Vec<u64> = self.blocked.iter().copied().collect();
+        for token in blocked {
+            if let Err(e) = self.retry(token) {
+                eprintln!("{e}");
+                self.close(token);
+            }
+        }
+    }
 
-        let result = handlers.match_handler(value, aof.clone(), db.clone());
-        match result.is_error() {
-            Some(err) => return Err(new_error(err)),
-            _ => writer.write(result)?,
+    /// Runs a blocked command again, going on with the commands after it
+    /// once it has its reply. The loop waits on the database before the
+    /// command runs, so no write in between goes unnoticed.
+    fn retry(&mut self, token: u64) -> Result<()> {
+        let Some(connection) = self.connections.get_mut(&token) else {
+            return Ok(());
         };
+        if let Some(index) = connection.handlers.blocked_on() {
+            self.dbs[index].wait(&self.waker);
+        }
+        let Some(reply) = connection.handlers.retry_blocked(Arc::clone(&self.aof), &self.dbs) else {
+            return Ok(());
+        };
+
+        connection.output.extend(reply.marshal());
+        connection.process(&self.aof, &self.dbs)?;
+        connection.flush()?;
+        self.settle(token)
+    }
+
+    /// Keeps track of whether the connection is blocked, and has it polled
+    /// for writes while replies are left to send.
+    fn settle(&mut self, token: u64) -> Result<()> {
+        let Some(connection) = self.connections.get_mut(&token) else {
+            return Ok(());
+        };
+        match connection.handlers.is_blocked() {
+            // Its first retry waits on the database, so a write since it ran
+            // isn't missed.
+            true if self.blocked.insert(token) => self.waker.wake()?,
+            true => (),
+            false => {
+                self.blocked.remove(&token);
+            },
+        };
+
+        let writable = !connection.output.is_empty() || connection.stream.wants_write();
+        if writable != connection.writable {
+            let interest = match writable {
+                true => READABLE | WRITABLE,
+                false => READABLE,
+            };
+            self.poller.modify(&connection.stream, token, interest)?;
+            connection.writable = writable;
+        }
+        Ok(())
+    }
+
+    fn close(&mut self, token: u64) {
+        self.blocked.remove(&token);
+        if let Some(connection) = self.connections.remove(&token) {
+            let _ = self.poller.delete(&connection.stream);
+        }
     }
 }
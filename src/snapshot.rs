@@ -0,0 +1,540 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufWriter, ErrorKind, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::aof;
+use crate::config::{Config, SnapshotFormat};
+use crate::database::{Data, Database};
+use crate::dump::{self, Reader};
+use crate::error::{new_error, Result};
+use crate::rdb;
+use crate::resp::Value;
+
+pub const MAGIC: &[u8] = b"AMANDADB";
+/// Version 2 added the checksum and the compressed payloads.
+const VERSION: u8 = 2;
+
+const SELECT: u8 = 0xFE;
+const EOF: u8 = 0xFF;
+
+const RAW: u8 = 0;
+const LZF: u8 = 1;
+
+/// Every shard of every database, as copied at the start of a save.
+type DatabaseCopies = Vec<Vec<HashMap<String, Data>>>;
+
+/// How long the `save` rules wait before trying again after a failed save.
+const RETRY_DELAY: u64 = 5;
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+struct State {
+    last_save: u64,
+    last_attempt: u64,
+    in_progress: bool,
+    last_ok: bool,
+}
+
+/// Point-in-time copies of every database, written to `dbfilename` on
+/// `SAVE`, `BGSAVE` and whenever a `save` rule is met.
+pub struct Snapshot {
+    path: String,
+    format: SnapshotFormat,
+    compress: bool,
+    skip_unsupported: bool,
+    rules: Vec<(u64, u64)>,
+    changes: AtomicU64,
+    state: Mutex<State>,
+}
+
+impl Snapshot {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            path: config.dbfilename().into(),
+            format: config.snapshot_format(),
+            compress: config.rdbcompression(),
+            skip_unsupported: config.rdb_skip_unsupported(),
+            rules: config.save().to_vec(),
+            changes: AtomicU64::new(0),
+            state: Mutex::new(State { last_save: now(), last_attempt: 0, in_progress: false, last_ok: true }),
+        }
+    }
+
+    /// Fills the databases from the snapshot file, if there is one, telling
+    /// the formats apart by their header.
+    pub fn load(&self, dbs: &[Arc<Database>]) -> Result<()> {
+        let bytes = match std::fs::read(&self.path) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+
+        if bytes.starts_with(rdb::MAGIC) {
+            return rdb::load(&bytes, dbs, self.skip_unsupported);
+        }
+        read_amandadb(&bytes, dbs)?;
+        Ok(())
+    }
+
+    /// Counts a write command for the `save` rules.
+    pub fn changed(&self) {
+        self.changes.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Saves in the foreground, the calling client waiting until it's done.
+    pub fn save(&self, dbs: &[Arc<Database>]) -> std::result::Result<(), &'static str> {
+        let (copy, changes) = self.start(dbs)?;
+        match self.finish(write_snapshot(&self.path, self.format, self.compress, &copy), changes) {
+            true => Ok(()),
+            false => Err("ERR: Failed to save the snapshot"),
+        }
+    }
+
+    /// Copies the databases and writes them from another thread.
+    pub fn bgsave(snapshot: &Arc<Self>, dbs: &[Arc<Database>]) -> std::result::Result<(), &'static str> {
+        let (copy, changes) = snapshot.start(dbs)?;
+        let snapshot = Arc::clone(snapshot);
+        thread::spawn(move || {
+            let result = write_snapshot(&snapshot.path, snapshot.format, snapshot.compress, &copy);
+            snapshot.finish(result, changes)
+        });
+        Ok(())
+    }
+
+    fn start(&self, dbs: &[Arc<Database>]) -> std::result::Result<(DatabaseCopies, u64), &'static str> {
+        let mut state = self.state.lock().unwrap();
+        if state.in_progress {
+            return Err("ERR: Background save already in progress");
+        }
+        state.in_progress = true;
+        state.last_attempt = now();
+
+        let _writes = aof::pause_writes();
+        Ok((dbs.iter().map(|db| db.create_database_copy()).collect(), self.changes.load(Ordering::Relaxed)))
+    }
+
+    fn finish(&self, result: Result<()>, changes: u64) -> bool {
+        let mut state = self.state.lock().unwrap();
+        state.in_progress = false;
+        state.last_ok = match result {
+            Ok(()) => {
+                self.changes.fetch_sub(changes, Ordering::Relaxed);
+                state.last_save = now();
+                true
+            },
+            Err(e) => {
+                eprintln!("Failed to save the snapshot: {e}");
+                false
+            },
+        };
+        state.last_ok
+    }
+
+    /// Unix time of the last successful save, or of the start of the server.
+    pub fn last_save(&self) -> u64 {
+        self.state.lock().unwrap().last_save
+    }
+
+    /// Starts the thread that saves in the background once any of the
+    /// `save` rules is met.
+    pub fn spawn_saver(snapshot: Arc<Self>, dbs: Arc<Vec<Arc<Database>>>) {
+        if snapshot.rules.is_empty() {
+            return;
+        }
+
+        thread::spawn(move || loop {
+            thread::sleep(Duration::from_secs(1));
+            if snapshot.is_due(now()) {
+                let _ = Snapshot::bgsave(&snapshot, &dbs);
+            }
+        });
+    }
+
+    /// Whether a `save` rule is met at `now`, holding off for a while after
+    /// a save that failed.
+    fn is_due(&self, now: u64) -> bool {
+        let state = self.state.lock().unwrap();
+        let changes = self.changes.load(Ordering::Relaxed);
+        !state.in_progress
+            && (state.last_ok || now.saturating_sub(state.last_attempt) >= RETRY_DELAY)
+            && self.rules.iter().any(|&(seconds, min)| changes >= min && now.saturating_sub(state.last_save) >= seconds)
+    }
+
+    /// The lines of the persistence section of INFO.
+    pub fn info(&self) -> String {
+        let state = self.state.lock().unwrap();
+        [
+            format!("rdb_changes_since_last_save:{}", self.changes.load(Ordering::Relaxed)),
+            format!("rdb_bgsave_in_progress:{}", state.in_progress as u8),
+            format!("rdb_last_save_time:{}", state.last_save),
+            format!("rdb_last_bgsave_status:{}", if state.last_ok { "ok" } else { "err" }),
+        ].join("\r\n")
+    }
+}
+
+/// Loads the snapshot at the start of `bytes`, returning where it ends,
+/// which is where the commands of an AOF with a preamble start.
+pub fn read_amandadb(bytes: &[u8], dbs: &[Arc<Database>]) -> Result<usize> {
+    let mut skipped = None;
+    read_entries(bytes, |index, key, payload| {
+        let Some(db) = dbs.get(index) else {
+            if skipped.replace(index) != Some(index) {
+                eprintln!("Skipping database {index} of the snapshot, only {} are configured", dbs.len());
+            }
+            return Ok(());
+        };
+        match db.write([&key]).restore(key.clone(), payload) {
+            Value::Error(err) => Err(new_error(err)),
+            _ => Ok(()),
+        }
+    })
+}
+
+/// Checks that the snapshot at the start of `bytes` is whole and that every
+/// value in it can be restored, without loading it anywhere.
+pub fn check_amandadb(bytes: &[u8]) -> Result<usize> {
+    read_entries(bytes, |_, _, payload| {
+        let mut reader = Reader::new(payload);
+        match dump::restore(&mut reader) {
+            Ok(_) if reader.is_empty() => Ok(()),
+            Ok(_) => Err(new_error("Invalid serialized value")),
+            Err(err) => Err(new_error(err)),
+        }
+    })
+}
+
+/// Calls `func` with the database index, key and `DUMP` payload of every
+/// key of the snapshot, then checks the checksum it ends with.
+fn read_entries(file: &[u8], mut func: impl FnMut(usize, String, &[u8]) -> Result<()>) -> Result<usize> {
+    let Some(bytes) = file.strip_prefix(MAGIC) else {
+        return Err(new_error("The snapshot file has an unknown format"));
+    };
+    let mut reader = Reader::new(bytes);
+    let version = reader.u8()?;
+    if version == 0 || version > VERSION {
+        return Err(new_error("The snapshot file has an unsupported version"));
+    }
+    if version == 1 {
+        eprintln!("The snapshot file is from before checksums, so damage to it can't be detected until it's saved again");
+    }
+
+    loop {
+        match reader.u8()? {
+            SELECT => (),
+            EOF => break,
+            _ => return Err(new_error("The snapshot file is corrupted")),
+        };
+        let index = reader.u64()? as usize;
+        let len = reader.u64()?;
+        for _ in 0..len {
+            let key = reader.string()?;
+            let payload = match version {
+                1 => Cow::Borrowed(reader.bytes()?),
+                _ => match reader.u8()? {
+                    RAW => Cow::Borrowed(reader.bytes()?),
+                    LZF => {
+                        let len = reader.u64()? as usize;
+                        Cow::Owned(rdb::lzf_decompress(reader.bytes()?, len).ok_or_else(|| new_error("The snapshot file is corrupted"))?)
+                    },
+                    _ => return Err(new_error("The snapshot file is corrupted")),
+                },
+            };
+            func(index, key, &payload)?;
+        }
+    }
+
+    let end = MAGIC.len() + reader.position();
+    if version == 1 {
+        return Ok(end);
+    }
+    if reader.u64()? != rdb::crc64(0, &file[..end]) {
+        return Err(new_error("The snapshot file doesn't match its checksum"));
+    }
+    Ok(end + 8)
+}
+
+/// Writes to a temporary file first, so a failed save leaves the previous
+/// snapshot in place.
+fn write_snapshot(path: &str, format: SnapshotFormat, compress: bool, snapshot: &DatabaseCopies) -> Result<()> {
+    let temp = format!("{path}.tmp");
+    let result = write_file(&temp, format, compress, snapshot).and_then(|_| aof::replace_file(&temp, path));
+    if result.is_err() {
+        let _ = std::fs::remove_file(&temp);
+    }
+    result
+}
+
+fn write_file(path: &str, format: SnapshotFormat, compress: bool, snapshot: &DatabaseCopies) -> Result<()> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    match format {
+        SnapshotFormat::AmandaDb => write_amandadb(&mut writer, snapshot, compress)?,
+        SnapshotFormat::Rdb => rdb::write(&mut writer, snapshot, compress)?,
+    };
+
+    let file = writer.into_inner().map_err(|e| e.into_error())?;
+    file.sync_all()?;
+    Ok(())
+}
+
+/// The header, then every database that has keys with its index and key
+/// count, each key followed by its `DUMP` payload, compressed with LZF when
+/// `compress` is set and that makes it smaller. A CRC64 of everything before
+/// it ends the file.
+pub fn write_amandadb(writer: &mut impl Write, snapshot: &[Vec<HashMap<String, Data>>], compress: bool) -> Result<()> {
+    let mut out = MAGIC.to_vec();
+    out.push(VERSION);
+    let mut crc = 0;
+    for (index, shards) in snapshot.iter().enumerate() {
+        let len: usize = shards.iter().map(HashMap::len).sum();
+        if len == 0 {
+            continue;
+        }
+        out.push(SELECT);
+        dump::put_u64(&mut out, index as u64);
+        dump::put_u64(&mut out, len as u64);
+        for (key, data) in shards.iter().flatten() {
+            let mut payload = Vec::new();
+            dump::dump(data, &mut payload);
+            dump::put_bytes(&mut out, key.as_bytes());
+            match compress.then(|| rdb::lzf_compress(&payload)).flatten() {
+                Some(compressed) => {
+                    out.push(LZF);
+                    dump::put_u64(&mut out, payload.len() as u64);
+                    dump::put_bytes(&mut out, &compressed);
+                },
+                None => {
+                    out.push(RAW);
+                    dump::put_bytes(&mut out, &payload);
+                },
+            };
+            crc = rdb::crc64(crc, &out);
+            writer.write_all(&out)?;
+            out.clear();
+        }
+    }
+    out.push(EOF);
+    crc = rdb::crc64(crc, &out);
+    out.extend(crc.to_le_bytes());
+    writer.write_all(&out)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::zset::SortedSet;
+
+    /// A snapshot saved to `dump.adb` in a fresh directory, with the config
+    /// lines of `config` on top.
+    fn new_snapshot(name: &str, config: &str) -> (Arc<Snapshot>, std::path::PathBuf) {
+        let dir = std::env::temp_dir().join(format!("amandadb-snapshot-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let conf = dir.join("amandadb.conf");
+        std::fs::write(&conf, format!("dbfilename={}\n{config}", dir.join("dump.adb").display())).unwrap();
+        let config = Config::read_from_file(conf.to_str().unwrap()).unwrap();
+        (Arc::new(Snapshot::new(&config)), dir)
+    }
+
+    fn databases(count: usize) -> Vec<Arc<Database>> {
+        (0..count).map(|_| Arc::new(Database::new())).collect()
+    }
+
+    fn dumped(db: &Database) -> Vec<(String, Vec<u8>)> {
+        let mut keys: Vec<(String, Vec<u8>)> = db.create_database_copy().into_iter().flatten()
+            .map(|(key, data)| {
+                let mut payload = Vec::new();
+                dump::dump(&data, &mut payload);
+                (key, payload)
+            })
+            .collect();
+        keys.sort();
+        keys
+    }
+
+    fn wait_for(what: &str, done: impl Fn() -> bool) {
+        for _ in 0..100 {
+            if done() {
+                return;
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+        panic!("Timed out waiting for {what}");
+    }
+
+    #[test]
+    fn reads_back_what_it_wrote() {
+        let dbs = databases(3);
+        dbs[0].write(["s"]).insert("s".into(), Data::Str(b"a".repeat(100)));
+        dbs[0].write(["h"]).insert("h".into(), Data::Hash(HashMap::from([("f".into(), "v".into())])));
+        let mut set = SortedSet::default();
+        set.insert("m", 1.5);
+        dbs[2].write(["z"]).insert("z".into(), Data::SortedSet(set));
+        let copies: DatabaseCopies = dbs.iter().map(|db| db.create_database_copy()).collect();
+
+        for compress in [false, true] {
+            let mut out = Vec::new();
+            write_amandadb(&mut out, &copies, compress).unwrap();
+            out.extend(b"*1\r\n$4\r\nPING\r\n");
+
+            let loaded = databases(3);
+            let end = read_amandadb(&out, &loaded).unwrap();
+            assert_eq!(&out[end..], b"*1\r\n$4\r\nPING\r\n");
+            assert_eq!(check_amandadb(&out).unwrap(), end);
+            for (db, loaded) in dbs.iter().zip(&loaded) {
+                assert_eq!(dumped(db), dumped(loaded), "compress {compress}");
+            }
+
+            // Databases past the configured ones are left out.
+            let fewer = databases(1);
+            read_amandadb(&out, &fewer).unwrap();
+            assert_eq!(dumped(&fewer[0]), dumped(&dbs[0]));
+        }
+    }
+
+    #[test]
+    fn saves_in_the_foreground_and_background() {
+        let (snapshot, dir) = new_snapshot("save", "");
+        let dbs = databases(1);
+        dbs[0].write(["a"]).insert("a".into(), Data::Str(b"1".to_vec()));
+        snapshot.changed();
+        snapshot.state.lock().unwrap().last_save = 0;
+
+        snapshot.save(&dbs).unwrap();
+        assert!(snapshot.last_save() > 0);
+        assert!(snapshot.info().contains("rdb_changes_since_last_save:0"));
+        let loaded = databases(1);
+        snapshot.load(&loaded).unwrap();
+        assert_eq!(dumped(&loaded[0]), dumped(&dbs[0]));
+
+        dbs[0].write(["b"]).insert("b".into(), Data::Str(b"2".to_vec()));
+        Snapshot::bgsave(&snapshot, &dbs).unwrap();
+        wait_for("the background save", || !snapshot.state.lock().unwrap().in_progress);
+        assert!(snapshot.info().contains("rdb_last_bgsave_status:ok"));
+        let loaded = databases(1);
+        snapshot.load(&loaded).unwrap();
+        assert_eq!(dumped(&loaded[0]), dumped(&dbs[0]));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn refuses_to_save_while_a_save_is_in_progress() {
+        let (snapshot, dir) = new_snapshot("busy", "");
+        let dbs = databases(1);
+        snapshot.state.lock().unwrap().in_progress = true;
+        assert_eq!(snapshot.save(&dbs), Err("ERR: Background save already in progress"));
+        assert_eq!(Snapshot::bgsave(&snapshot, &dbs), Err("ERR: Background save already in progress"));
+        assert!(!dir.join("dump.adb").exists());
+
+        snapshot.state.lock().unwrap().in_progress = false;
+        snapshot.save(&dbs).unwrap();
+        assert!(dir.join("dump.adb").exists());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn saves_once_a_rule_is_met() {
+        let (snapshot, dir) = new_snapshot("rules", "save=\"60 1 5 3\"\n");
+        let start = snapshot.last_save();
+        snapshot.changed();
+        assert!(!snapshot.is_due(start + 59));
+        assert!(snapshot.is_due(start + 60));
+        snapshot.changed();
+        snapshot.changed();
+        assert!(!snapshot.is_due(start + 4));
+        assert!(snapshot.is_due(start + 5));
+
+        // A failed save is retried only after a while.
+        {
+            let mut state = snapshot.state.lock().unwrap();
+            (state.last_ok, state.last_attempt) = (false, start + 5);
+        }
+        assert!(!snapshot.is_due(start + 5 + RETRY_DELAY - 1));
+        assert!(snapshot.is_due(start + 5 + RETRY_DELAY));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn the_saver_thread_saves_when_a_rule_is_met() {
+        let (snapshot, dir) = new_snapshot("saver", "save=\"1 1\"\n");
+        Snapshot::spawn_saver(Arc::clone(&snapshot), Arc::new(databases(1)));
+        thread::sleep(Duration::from_millis(1500));
+        assert!(!dir.join("dump.adb").exists());
+
+        snapshot.changed();
+        wait_for("the save of the rule", || dir.join("dump.adb").exists());
+        wait_for("the changes to be reset", || snapshot.info().contains("rdb_changes_since_last_save:0"));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    fn one_value(compress: bool) -> Vec<u8> {
+        let dbs = databases(1);
+        dbs[0].write(["k"]).insert("k".into(), Data::Str(b"abc".repeat(100)));
+        let mut out = Vec::new();
+        write_amandadb(&mut out, &[dbs[0].create_database_copy()], compress).unwrap();
+        out
+    }
+
+    #[test]
+    fn compresses_values_with_lzf() {
+        let (raw, compressed) = (one_value(false), one_value(true));
+        assert!(compressed.len() < raw.len() - 200, "{} of {} bytes", compressed.len(), raw.len());
+        let loaded = databases(1);
+        read_amandadb(&compressed, &loaded).unwrap();
+        assert_eq!(loaded[0].read(["k"]).set_get("k").marshal(), Value::BulkBytes(b"abc".repeat(100)).marshal());
+
+        // The header, the database, then the key and the tag of its payload.
+        let tag = MAGIC.len() + 1 + 1 + 8 + 8 + 8 + 1;
+        assert_eq!((raw[tag], compressed[tag]), (RAW, LZF));
+
+        // A payload that doesn't expand to its length is caught before the
+        // checksum is.
+        let mut bad = compressed.clone();
+        bad[tag + 1] += 1;
+        let e = read_amandadb(&bad, &databases(1)).err().unwrap().to_string();
+        assert!(e.contains("corrupted"), "{e}");
+    }
+
+    #[test]
+    fn rejects_a_file_that_doesnt_match_its_checksum() {
+        for compress in [false, true] {
+            let data = one_value(compress);
+            // The name of the key, which still reads fine, and the checksum.
+            let key = MAGIC.len() + 1 + 1 + 8 + 8 + 8;
+            assert_eq!(data[key], b'k');
+            for at in [key, data.len() - 1] {
+                let mut bad = data.clone();
+                bad[at] ^= 1;
+                for e in [check_amandadb(&bad).err(), read_amandadb(&bad, &databases(1)).err()] {
+                    assert_eq!(e.unwrap().to_string(), "The snapshot file doesn't match its checksum", "byte {at}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn reads_a_version_1_file_without_a_checksum() {
+        let mut data = MAGIC.to_vec();
+        data.push(1);
+        data.push(SELECT);
+        dump::put_u64(&mut data, 0);
+        dump::put_u64(&mut data, 1);
+        dump::put_bytes(&mut data, b"k");
+        let mut payload = Vec::new();
+        dump::dump(&Data::Str(b"v".to_vec()), &mut payload);
+        dump::put_bytes(&mut data, &payload);
+        data.push(EOF);
+
+        let loaded = databases(1);
+        assert_eq!(read_amandadb(&data, &loaded).unwrap(), data.len());
+        assert_eq!(loaded[0].read(["k"]).set_get("k").marshal(), Value::BulkBytes(b"v".to_vec()).marshal());
+    }
+}
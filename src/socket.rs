@@ -0,0 +1,77 @@
+use std::io;
+use std::net::{SocketAddrV6, TcpListener};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+
+const AF_INET6: i32 = 10;
+const SOCK_STREAM: i32 = 1;
+const SOCK_CLOEXEC: i32 = 0o2000000;
+const SOL_SOCKET: i32 = 1;
+const SO_REUSEADDR: i32 = 2;
+const IPPROTO_IPV6: i32 = 41;
+const IPV6_V6ONLY: i32 = 26;
+
+/// Same backlog as std's `TcpListener::bind`.
+const BACKLOG: i32 = 128;
+
+/// Mirrors `struct sockaddr_in6`, with the port and address in network order.
+#[repr(C)]
+struct SockaddrIn6 {
+    family: u16,
+    port: u16,
+    flowinfo: u32,
+    addr: [u8; 16],
+    scope_id: u32,
+}
+
+extern "C" {
+    fn socket(domain: i32, kind: i32, protocol: i32) -> i32;
+    fn setsockopt(fd: i32, level: i32, name: i32, value: *const i32, len: u32) -> i32;
+    fn bind(fd: i32, addr: *const SockaddrIn6, len: u32) -> i32;
+    fn listen(fd: i32, backlog: i32) -> i32;
+}
+
+fn check(result: i32) -> io::Result<i32> {
+    match result {
+        -1 => Err(io::Error::last_os_error()),
+        n => Ok(n),
+    }
+}
+
+/// Binds an IPv6 address that only takes IPv6 clients, as std leaves
+/// `IPV6_V6ONLY` to the system default, under which `::` also takes IPv4
+/// clients and so can't be bound next to `0.0.0.0` on the same port.
+pub fn bind_v6_only(address: SocketAddrV6) -> io::Result<TcpListener> {
+    let fd = check(unsafe { socket(AF_INET6, SOCK_STREAM | SOCK_CLOEXEC, 0) })?;
+    let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+    let raw = fd.as_raw_fd();
+
+    let on: i32 = 1;
+    let len = std::mem::size_of::<i32>() as u32;
+    check(unsafe { setsockopt(raw, SOL_SOCKET, SO_REUSEADDR, &on, len) })?;
+    check(unsafe { setsockopt(raw, IPPROTO_IPV6, IPV6_V6ONLY, &on, len) })?;
+
+    let addr = SockaddrIn6 {
+        family: AF_INET6 as u16,
+        port: address.port().to_be(),
+        flowinfo: address.flowinfo().to_be(),
+        addr: address.ip().octets(),
+        scope_id: address.scope_id(),
+    };
+    check(unsafe { bind(raw, &addr, std::mem::size_of::<SockaddrIn6>() as u32) })?;
+    check(unsafe { listen(raw, BACKLOG) })?;
+    Ok(TcpListener::from(fd))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv6Addr;
+
+    #[test]
+    fn binds_next_to_every_ipv4_address() {
+        let v4 = TcpListener::bind("0.0.0.0:0").unwrap();
+        let port = v4.local_addr().unwrap().port();
+        let v6 = bind_v6_only(SocketAddrV6::new(Ipv6Addr::UNSPECIFIED, port, 0, 0)).unwrap();
+        assert_eq!(v6.local_addr().unwrap().port(), port);
+    }
+}
@@ -0,0 +1,650 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::ops::Bound::{Excluded, Unbounded};
+
+use crate::dump::{put_bytes, put_u64, Reader};
+
+/// The fields of an entry with their values, in the order they were added.
+pub type Fields = Vec<(Vec<u8>, Vec<u8>)>;
+
+pub const NOGROUP: &str = "NOGROUP: No such key or consumer group";
+
+/// XAUTOCLAIM looks at up to this many pending entries per one it may claim,
+/// as Redis does.
+const AUTOCLAIM_ATTEMPTS: usize = 10;
+
+#[derive(Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub struct StreamId {
+    pub ms: u64,
+    pub seq: u64,
+}
+
+impl StreamId {
+    pub const MIN: StreamId = StreamId { ms: 0, seq: 0 };
+    pub const MAX: StreamId = StreamId { ms: u64::MAX, seq: u64::MAX };
+
+    pub fn new(ms: u64, seq: u64) -> Self {
+        Self { ms, seq }
+    }
+
+    /// Parses `<ms>-<seq>`, or a bare `<ms>` with `seq` as its sequence.
+    pub fn parse(arg: &str, seq: u64) -> Option<Self> {
+        let number = |s: &str| s.bytes().all(|b| b.is_ascii_digit()).then(|| s.parse().ok()).flatten();
+        match arg.split_once('-') {
+            Some((ms, s)) => Some(Self { ms: number(ms)?, seq: number(s)? }),
+            None => Some(Self { ms: number(arg)?, seq }),
+        }
+    }
+
+    pub fn next(self) -> Option<Self> {
+        match (self.seq.checked_add(1), self.ms.checked_add(1)) {
+            (Some(seq), _) => Some(Self { ms: self.ms, seq }),
+            (None, Some(ms)) => Some(Self { ms, seq: 0 }),
+            (None, None) => None,
+        }
+    }
+
+    pub fn prev(self) -> Option<Self> {
+        match (self.seq.checked_sub(1), self.ms.checked_sub(1)) {
+            (Some(seq), _) => Some(Self { ms: self.ms, seq }),
+            (None, Some(ms)) => Some(Self { ms, seq: u64::MAX }),
+            (None, None) => None,
+        }
+    }
+}
+
+impl fmt::Display for StreamId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}-{}", self.ms, self.seq)
+    }
+}
+
+/// The ID XADD is given: `*`, `<ms>-*` or a whole one.
+#[derive(Clone, Copy)]
+pub enum NewId {
+    Auto,
+    AutoSeq(u64),
+    Explicit(StreamId),
+}
+
+impl NewId {
+    pub fn parse(arg: &str) -> Option<Self> {
+        match arg.strip_suffix("-*") {
+            _ if arg == "*" => Some(NewId::Auto),
+            Some(ms) => StreamId::parse(ms, 0).filter(|_| !ms.contains('-')).map(|id| NewId::AutoSeq(id.ms)),
+            None => StreamId::parse(arg, 0).map(NewId::Explicit),
+        }
+    }
+}
+
+/// How XADD and XTRIM cut the oldest entries. Like the `=` form of Redis,
+/// `~` trims exactly too.
+#[derive(Clone, Copy)]
+pub enum Trim {
+    MaxLen(usize),
+    MinId(StreamId),
+}
+
+/// An entry delivered to a consumer of a group and not acknowledged yet.
+#[derive(Clone)]
+pub struct Pending {
+    pub consumer: String,
+    pub delivered: u64,
+    pub count: u64,
+}
+
+#[derive(Clone)]
+pub struct Consumer {
+    seen: u64,
+    active: Option<u64>,
+    pending: BTreeSet<StreamId>,
+}
+
+impl Consumer {
+    fn new(now: u64) -> Self {
+        Self { seen: now, active: None, pending: BTreeSet::new() }
+    }
+
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// When it last tried to read or claim entries.
+    pub fn seen(&self) -> u64 {
+        self.seen
+    }
+
+    /// When it last got any, if ever.
+    pub fn active(&self) -> Option<u64> {
+        self.active
+    }
+}
+
+/// A consumer group, whose pending entries list is kept both for the whole
+/// group, ordered by ID, and for each of its consumers.
+#[derive(Clone)]
+pub struct Group {
+    last_id: StreamId,
+    pending: BTreeMap<StreamId, Pending>,
+    consumers: BTreeMap<String, Consumer>,
+}
+
+impl Group {
+    fn new(last_id: StreamId) -> Self {
+        Self { last_id, pending: BTreeMap::new(), consumers: BTreeMap::new() }
+    }
+
+    pub fn last_id(&self) -> StreamId {
+        self.last_id
+    }
+
+    pub fn pending(&self) -> &BTreeMap<StreamId, Pending> {
+        &self.pending
+    }
+
+    pub fn consumers(&self) -> &BTreeMap<String, Consumer> {
+        &self.consumers
+    }
+
+    fn consumer(&mut self, name: &str, now: u64) -> &mut Consumer {
+        let consumer = self.consumers.entry(name.into()).or_insert_with(|| Consumer::new(now));
+        consumer.seen = now;
+        consumer
+    }
+
+    /// Gives the entry to `consumer`, taking it from whoever had it.
+    fn deliver(&mut self, id: StreamId, consumer: &str, delivered: u64, count: u64) {
+        if let Some(previous) = self.pending.insert(id, Pending { consumer: consumer.into(), delivered, count }) {
+            if let Some(owner) = self.consumers.get_mut(&previous.consumer) {
+                owner.pending.remove(&id);
+            }
+        }
+        if let Some(owner) = self.consumers.get_mut(consumer) {
+            owner.pending.insert(id);
+        }
+    }
+
+    fn acknowledge(&mut self, id: StreamId) -> bool {
+        let Some(pending) = self.pending.remove(&id) else {
+            return false;
+        };
+        if let Some(owner) = self.consumers.get_mut(&pending.consumer) {
+            owner.pending.remove(&id);
+        }
+        true
+    }
+}
+
+/// What XCLAIM was asked besides the IDs.
+#[derive(Clone, Copy, Default)]
+pub struct Claim {
+    pub min_idle: u64,
+    pub delivered: Option<u64>,
+    pub count: Option<u64>,
+    pub force: bool,
+    pub just_id: bool,
+    pub last_id: Option<StreamId>,
+}
+
+/// An append-only log of entries, each a list of fields, with the consumer
+/// groups reading it. Entries removed with XDEL or trimming stay in the
+/// pending entries lists until acknowledged, as in Redis.
+#[derive(Clone, Default)]
+pub struct Stream {
+    entries: BTreeMap<StreamId, Fields>,
+    last_id: StreamId,
+    groups: BTreeMap<String, Group>,
+}
+
+impl Stream {
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn last_id(&self) -> StreamId {
+        self.last_id
+    }
+
+    pub fn get(&self, id: StreamId) -> Option<&Fields> {
+        self.entries.get(&id)
+    }
+
+    pub fn add(&mut self, id: NewId, fields: Fields, now: u64) -> Result<StreamId, &'static str> {
+        const SMALLER: &str = "ERR: The ID specified in XADD is equal or smaller than the target stream top item";
+        let last = self.last_id;
+        let id = match id {
+            NewId::Auto if now > last.ms => StreamId::new(now, 0),
+            NewId::Auto => last.next().ok_or("ERR: The stream has exhausted the last possible ID, unable to add more items")?,
+            NewId::AutoSeq(ms) if ms > last.ms => StreamId::new(ms, 0),
+            NewId::AutoSeq(ms) if ms == last.ms => StreamId::new(ms, last.seq.checked_add(1).ok_or(SMALLER)?),
+            NewId::AutoSeq(_) => return Err(SMALLER),
+            NewId::Explicit(StreamId::MIN) => return Err("ERR: The ID specified in XADD must be greater than 0-0"),
+            NewId::Explicit(id) if id <= last => return Err(SMALLER),
+            NewId::Explicit(id) => id,
+        };
+        self.entries.insert(id, fields);
+        self.last_id = id;
+        Ok(id)
+    }
+
+    /// Removes the oldest entries, returning how many went.
+    pub fn trim(&mut self, trim: Trim) -> usize {
+        let len = self.entries.len();
+        match trim {
+            Trim::MaxLen(max) => {
+                while self.entries.len() > max {
+                    self.entries.pop_first();
+                }
+            },
+            Trim::MinId(min) => self.entries = self.entries.split_off(&min),
+        };
+        len - self.entries.len()
+    }
+
+    pub fn delete(&mut self, ids: &[StreamId]) -> usize {
+        ids.iter().filter(|id| self.entries.remove(id).is_some()).count()
+    }
+
+    /// The entries from `start` to `end` included, last first when `rev`.
+    pub fn range(&self, start: StreamId, end: StreamId, count: Option<usize>, rev: bool) -> Vec<(StreamId, &Fields)> {
+        if start > end {
+            return Vec::new();
+        }
+        let range = self.entries.range(start..=end).map(|(id, fields)| (*id, fields));
+        match rev {
+            true => range.rev().take(count.unwrap_or(usize::MAX)).collect(),
+            false => range.take(count.unwrap_or(usize::MAX)).collect(),
+        }
+    }
+
+    pub fn group(&self, name: &str) -> Option<&Group> {
+        self.groups.get(name)
+    }
+
+    pub fn groups(&self) -> &BTreeMap<String, Group> {
+        &self.groups
+    }
+
+    fn group_mut(&mut self, name: &str) -> Result<&mut Group, &'static str> {
+        self.groups.get_mut(name).ok_or(NOGROUP)
+    }
+
+    pub fn create_group(&mut self, name: &str, last_id: StreamId) -> Result<(), &'static str> {
+        if self.groups.contains_key(name) {
+            return Err("BUSYGROUP: Consumer Group name already exists");
+        }
+        self.groups.insert(name.into(), Group::new(last_id));
+        Ok(())
+    }
+
+    pub fn destroy_group(&mut self, name: &str) -> bool {
+        self.groups.remove(name).is_some()
+    }
+
+    pub fn set_group_id(&mut self, name: &str, last_id: StreamId) -> Result<(), &'static str> {
+        self.group_mut(name)?.last_id = last_id;
+        Ok(())
+    }
+
+    /// Tells whether the consumer is new.
+    pub fn create_consumer(&mut self, group: &str, consumer: &str, now: u64) -> Result<bool, &'static str> {
+        let group = self.group_mut(group)?;
+        if group.consumers.contains_key(consumer) {
+            return Ok(false);
+        }
+        group.consumers.insert(consumer.into(), Consumer::new(now));
+        Ok(true)
+    }
+
+    /// Removes the consumer with its pending entries, returning how many
+    /// it had.
+    pub fn delete_consumer(&mut self, group: &str, consumer: &str) -> Result<usize, &'static str> {
+        let group = self.group_mut(group)?;
+        let Some(removed) = group.consumers.remove(consumer) else {
+            return Ok(0);
+        };
+        for id in &removed.pending {
+            group.pending.remove(id);
+        }
+        Ok(removed.pending.len())
+    }
+
+    /// Reads for a consumer of `group`, creating it when missing. Without
+    /// `start`, it gets the entries no consumer of the group got yet, which
+    /// become pending unless `no_ack`; otherwise, its own pending entries
+    /// after `start`, delivered again and with no fields for those that
+    /// were removed from the stream.
+    pub fn read_group(&mut self, group: &str, consumer: &str, start: Option<StreamId>, count: Option<usize>,
+        no_ack: bool, now: u64) -> Result<Vec<(StreamId, Option<Fields>)>, &'static str> {
+        let count = count.unwrap_or(usize::MAX);
+        let entries = &self.entries;
+        let group = self.groups.get_mut(group).ok_or(NOGROUP)?;
+        group.consumer(consumer, now);
+
+        let Some(start) = start else {
+            let read: Vec<(StreamId, Option<Fields>)> = entries.range((Excluded(group.last_id), Unbounded))
+                .take(count)
+                .map(|(id, fields)| (*id, Some(fields.clone())))
+                .collect();
+            let Some((last, _)) = read.last() else {
+                return Ok(read);
+            };
+            group.last_id = *last;
+            group.consumer(consumer, now).active = Some(now);
+            if !no_ack {
+                for (id, _) in &read {
+                    group.deliver(*id, consumer, now, 1);
+                }
+            }
+            return Ok(read);
+        };
+
+        let ids: Vec<StreamId> = group.consumers[consumer].pending.range((Excluded(start), Unbounded))
+            .take(count)
+            .copied()
+            .collect();
+        let mut read = Vec::new();
+        for id in ids {
+            let fields = entries.get(&id).cloned();
+            if fields.is_some() {
+                let pending = group.pending.get_mut(&id).expect("pending entry of the consumer is in its group");
+                pending.delivered = now;
+                pending.count += 1;
+            }
+            read.push((id, fields));
+        }
+        Ok(read)
+    }
+
+    /// Returns how many of the IDs were pending in the group.
+    pub fn ack(&mut self, group: &str, ids: &[StreamId]) -> usize {
+        match self.groups.get_mut(group) {
+            Some(group) => ids.iter().filter(|id| group.acknowledge(**id)).count(),
+            None => 0,
+        }
+    }
+
+    /// Gives the pending entries idle for at least `min_idle` to the
+    /// consumer, creating it when missing. Returns the ones claimed, and
+    /// the ones dropped from the pending entries list as they were removed
+    /// from the stream.
+    pub fn claim(&mut self, group: &str, consumer: &str, ids: &[StreamId], claim: &Claim, now: u64)
+        -> Result<(Vec<StreamId>, Vec<StreamId>), &'static str> {
+        let entries = &self.entries;
+        let group = self.groups.get_mut(group).ok_or(NOGROUP)?;
+        group.consumer(consumer, now);
+        if let Some(last_id) = claim.last_id.filter(|id| *id > group.last_id) {
+            group.last_id = last_id;
+        }
+
+        let (mut claimed, mut deleted) = (Vec::new(), Vec::new());
+        for id in ids {
+            let exists = entries.contains_key(id);
+            let count = match group.pending.get(id) {
+                Some(_) if !exists => {
+                    group.acknowledge(*id);
+                    deleted.push(*id);
+                    continue;
+                },
+                Some(pending) if now.saturating_sub(pending.delivered) < claim.min_idle => continue,
+                Some(pending) => pending.count,
+                // Forced entries are claimed however long they waited.
+                None if claim.force && exists => 0,
+                None => continue,
+            };
+            let count = match (claim.count, claim.just_id) {
+                (Some(count), _) => count,
+                (None, true) => count,
+                (None, false) => count + 1,
+            };
+            group.deliver(*id, consumer, claim.delivered.unwrap_or(now), count);
+            claimed.push(*id);
+        }
+        if !claimed.is_empty() {
+            group.consumer(consumer, now).active = Some(now);
+        }
+        Ok((claimed, deleted))
+    }
+
+    /// Claims up to `count` entries from `start` on as `claim` says, looking
+    /// at no more than ten times that many. Returns where the next call
+    /// should start, 0-0 once the whole list was seen, with what
+    /// [`Stream::claim`] returns.
+    pub fn autoclaim(&mut self, group: &str, consumer: &str, claim: &Claim, start: StreamId, count: usize,
+        now: u64) -> Result<(StreamId, Vec<StreamId>, Vec<StreamId>), &'static str> {
+        let entries = &self.entries;
+        let pending = &self.groups.get(group).ok_or(NOGROUP)?.pending;
+        let mut scan = pending.range(start..).map(|(id, pending)| (*id, pending.delivered));
+
+        // Entries removed from the stream don't count towards `count`.
+        let (mut candidates, mut left, mut attempts) = (Vec::new(), count, count.saturating_mul(AUTOCLAIM_ATTEMPTS));
+        while left > 0 && attempts > 0 {
+            let Some((id, delivered)) = scan.next() else {
+                break;
+            };
+            attempts -= 1;
+            if entries.contains_key(&id) && now.saturating_sub(delivered) >= claim.min_idle {
+                left -= 1;
+            }
+            candidates.push(id);
+        }
+        let cursor = scan.next().map_or(StreamId::MIN, |(id, _)| id);
+
+        let (claimed, deleted) = self.claim(group, consumer, &candidates, claim, now)?;
+        Ok((cursor, claimed, deleted))
+    }
+
+    /// Stores the consumers of each group with their pending entries, which
+    /// are restored in the same lists.
+    pub fn dump(&self, out: &mut Vec<u8>) {
+        let put_id = |out: &mut Vec<u8>, id: &StreamId| {
+            put_u64(out, id.ms);
+            put_u64(out, id.seq);
+        };
+        put_id(out, &self.last_id);
+        put_u64(out, self.entries.len() as u64);
+        for (id, fields) in &self.entries {
+            put_id(out, id);
+            put_u64(out, fields.len() as u64);
+            for (field, value) in fields {
+                put_bytes(out, field);
+                put_bytes(out, value);
+            }
+        }
+
+        put_u64(out, self.groups.len() as u64);
+        for (name, group) in &self.groups {
+            put_bytes(out, name.as_bytes());
+            put_id(out, &group.last_id);
+            put_u64(out, group.consumers.len() as u64);
+            for (name, consumer) in &group.consumers {
+                put_bytes(out, name.as_bytes());
+                put_u64(out, consumer.seen);
+                put_u64(out, consumer.active.map_or(0, |active| active.saturating_add(1)));
+            }
+            put_u64(out, group.pending.len() as u64);
+            for (id, pending) in &group.pending {
+                put_id(out, id);
+                put_bytes(out, pending.consumer.as_bytes());
+                put_u64(out, pending.delivered);
+                put_u64(out, pending.count);
+            }
+        }
+    }
+
+    /// Entries must come in order and up to the last ID, and pending
+    /// entries must belong to a consumer of their group.
+    pub fn restore(reader: &mut Reader) -> Result<Self, &'static str> {
+        const INVALID: &str = "ERR: Invalid serialized stream";
+        let id = |reader: &mut Reader| Ok::<_, &'static str>(StreamId::new(reader.u64()?, reader.u64()?));
+
+        let mut stream = Stream { last_id: id(reader)?, ..Stream::default() };
+        let mut previous = StreamId::MIN;
+        for _ in 0..reader.u64()? {
+            let entry = id(reader)?;
+            if entry <= previous || entry > stream.last_id {
+                return Err(INVALID);
+            }
+            previous = entry;
+            let fields = (0..reader.u64()?)
+                .map(|_| Ok((reader.bytes()?.to_vec(), reader.bytes()?.to_vec())))
+                .collect::<Result<_, &'static str>>()?;
+            stream.entries.insert(entry, fields);
+        }
+
+        for _ in 0..reader.u64()? {
+            let name = reader.string()?;
+            let mut group = Group::new(id(reader)?);
+            for _ in 0..reader.u64()? {
+                let name = reader.string()?;
+                let seen = reader.u64()?;
+                let active = reader.u64()?.checked_sub(1);
+                group.consumers.insert(name, Consumer { seen, active, pending: BTreeSet::new() });
+            }
+            for _ in 0..reader.u64()? {
+                let entry = id(reader)?;
+                let consumer = reader.string()?;
+                let (delivered, count) = (reader.u64()?, reader.u64()?);
+                if !group.consumers.contains_key(&consumer) || group.pending.contains_key(&entry) {
+                    return Err(INVALID);
+                }
+                group.deliver(entry, &consumer, delivered, count);
+            }
+            if stream.groups.insert(name, group).is_some() {
+                return Err(INVALID);
+            }
+        }
+        Ok(stream)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fields(value: &str) -> Fields {
+        vec![(b"f".to_vec(), value.as_bytes().to_vec())]
+    }
+
+    fn ids(ids: &[(u64, u64)]) -> Vec<StreamId> {
+        ids.iter().map(|(ms, seq)| StreamId::new(*ms, *seq)).collect()
+    }
+
+    #[test]
+    fn generates_increasing_ids() {
+        let mut stream = Stream::default();
+        assert_eq!(stream.add(NewId::Auto, fields("a"), 5), Ok(StreamId::new(5, 0)));
+        // A clock going back keeps the IDs going up.
+        assert_eq!(stream.add(NewId::Auto, fields("b"), 3), Ok(StreamId::new(5, 1)));
+        assert_eq!(stream.add(NewId::AutoSeq(5), fields("c"), 0), Ok(StreamId::new(5, 2)));
+        assert_eq!(stream.add(NewId::AutoSeq(7), fields("d"), 0), Ok(StreamId::new(7, 0)));
+        assert!(stream.add(NewId::AutoSeq(6), fields("e"), 0).is_err());
+        assert!(stream.add(NewId::Explicit(StreamId::new(7, 0)), fields("e"), 0).is_err());
+        assert!(Stream::default().add(NewId::Explicit(StreamId::MIN), fields("e"), 0).is_err());
+
+        stream.add(NewId::Explicit(StreamId::new(u64::MAX, u64::MAX)), fields("f"), 0).unwrap();
+        assert!(stream.add(NewId::Auto, fields("g"), 0).is_err());
+        assert_eq!(stream.len(), 5);
+    }
+
+    #[test]
+    fn parses_ids() {
+        assert_eq!(StreamId::parse("5-3", 0), Some(StreamId::new(5, 3)));
+        assert_eq!(StreamId::parse("5", u64::MAX), Some(StreamId::new(5, u64::MAX)));
+        for invalid in ["", "-", "5-", "-3", "+5", "5-3-1", "a-1"] {
+            assert_eq!(StreamId::parse(invalid, 0), None, "{invalid}");
+        }
+        assert!(matches!(NewId::parse("*"), Some(NewId::Auto)));
+        assert!(matches!(NewId::parse("5-*"), Some(NewId::AutoSeq(5))));
+        assert!(NewId::parse("5-1-*").is_none());
+        assert_eq!(StreamId::new(5, u64::MAX).next(), Some(StreamId::new(6, 0)));
+        assert_eq!(StreamId::new(5, 0).prev(), Some(StreamId::new(4, u64::MAX)));
+    }
+
+    #[test]
+    fn keeps_pending_entries_per_consumer() {
+        let mut stream = Stream::default();
+        for i in 1..=4 {
+            stream.add(NewId::Explicit(StreamId::new(i, 0)), fields(&i.to_string()), 0).unwrap();
+        }
+        stream.create_group("g", StreamId::MIN).unwrap();
+        assert!(stream.create_group("g", StreamId::MIN).is_err());
+
+        let read = stream.read_group("g", "alice", None, Some(2), false, 100).unwrap();
+        assert_eq!(read.iter().map(|(id, _)| *id).collect::<Vec<_>>(), ids(&[(1, 0), (2, 0)]));
+        stream.read_group("g", "bob", None, None, false, 100).unwrap();
+        assert!(stream.read_group("g", "bob", None, None, false, 100).unwrap().is_empty());
+
+        // Reading its own history delivers the entries again.
+        stream.delete(&ids(&[(1, 0)]));
+        let history = stream.read_group("g", "alice", Some(StreamId::MIN), None, false, 200).unwrap();
+        assert_eq!(history.iter().map(|(_, fields)| fields.is_some()).collect::<Vec<_>>(), [false, true]);
+        let group = stream.group("g").unwrap();
+        assert_eq!((group.pending[&StreamId::new(2, 0)].count, group.pending[&StreamId::new(2, 0)].delivered), (2, 200));
+        assert_eq!((group.consumers["alice"].pending(), group.consumers["bob"].pending()), (2, 2));
+
+        // Claiming moves entries between the consumers' lists.
+        let claim = Claim { min_idle: 50, ..Claim::default() };
+        let (claimed, deleted) = stream.claim("g", "bob", &ids(&[(1, 0), (2, 0), (3, 0)]), &claim, 200).unwrap();
+        assert_eq!((claimed, deleted), (ids(&[(3, 0)]), ids(&[(1, 0)])));
+        let group = stream.group("g").unwrap();
+        assert_eq!((group.consumers["alice"].pending(), group.consumers["bob"].pending()), (1, 2));
+        assert_eq!(group.pending[&StreamId::new(3, 0)].count, 2);
+
+        assert_eq!(stream.ack("g", &ids(&[(2, 0), (2, 0), (9, 0)])), 1);
+        assert_eq!(stream.delete_consumer("g", "bob"), Ok(2));
+        assert!(stream.group("g").unwrap().pending.is_empty());
+    }
+
+    #[test]
+    fn autoclaims_from_the_cursor() {
+        let mut stream = Stream::default();
+        for i in 1..=5 {
+            stream.add(NewId::Explicit(StreamId::new(i, 0)), fields(&i.to_string()), 0).unwrap();
+        }
+        stream.create_group("g", StreamId::MIN).unwrap();
+        stream.read_group("g", "alice", None, None, false, 0).unwrap();
+        stream.delete(&ids(&[(2, 0)]));
+
+        let claim = Claim { min_idle: 10, ..Claim::default() };
+        let (next, claimed, deleted) = stream.autoclaim("g", "bob", &claim, StreamId::MIN, 2, 100).unwrap();
+        assert_eq!((next, claimed, deleted), (StreamId::new(4, 0), ids(&[(1, 0), (3, 0)]), ids(&[(2, 0)])));
+        let (next, claimed, _) = stream.autoclaim("g", "bob", &Claim { just_id: true, ..claim }, next, 2, 100).unwrap();
+        assert_eq!((next, claimed), (StreamId::MIN, ids(&[(4, 0), (5, 0)])));
+        assert_eq!(stream.group("g").unwrap().pending[&StreamId::new(5, 0)].count, 1);
+    }
+
+    #[test]
+    fn restores_groups_with_their_pending_entries() {
+        let mut stream = Stream::default();
+        for i in 1..=3 {
+            stream.add(NewId::Explicit(StreamId::new(i, 0)), fields(&i.to_string()), 0).unwrap();
+        }
+        stream.create_group("g", StreamId::MIN).unwrap();
+        stream.read_group("g", "alice", None, Some(2), false, 7).unwrap();
+        stream.create_consumer("g", "idle", 8).unwrap();
+        stream.trim(Trim::MaxLen(1));
+
+        let mut payload = Vec::new();
+        stream.dump(&mut payload);
+        let mut reader = Reader::new(&payload);
+        let restored = Stream::restore(&mut reader).unwrap();
+        assert!(reader.is_empty());
+        assert_eq!(restored.range(StreamId::MIN, StreamId::MAX, None, false).len(), 1);
+        assert_eq!(restored.last_id(), StreamId::new(3, 0));
+        let group = restored.group("g").unwrap();
+        assert_eq!(group.last_id(), StreamId::new(2, 0));
+        assert_eq!(group.consumers["alice"].pending, ids(&[(1, 0), (2, 0)]).into_iter().collect());
+        assert_eq!((group.consumers["idle"].active(), group.consumers["alice"].active()), (None, Some(7)));
+
+        // A pending entry of a consumer that isn't there.
+        let mut orphan = payload.clone();
+        let at = orphan.len() - 8 * 2 - 5;
+        orphan[at..at + 5].copy_from_slice(b"bobby");
+        assert!(Stream::restore(&mut Reader::new(&orphan)).is_err());
+    }
+}
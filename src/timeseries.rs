@@ -0,0 +1,649 @@
+use crate::dump::{put_bytes, put_u64, Reader};
+
+pub const DEFAULT_CHUNK_SIZE: usize = 4096;
+pub const MIN_CHUNK_SIZE: usize = 48;
+pub const MAX_CHUNK_SIZE: usize = 1048576;
+pub const MAX_TIMESTAMP: u64 = i64::MAX as u64;
+
+pub type Sample = (u64, f64);
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum DuplicatePolicy {
+    Block,
+    First,
+    Last,
+    Min,
+    Max,
+    Sum,
+}
+
+impl DuplicatePolicy {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.to_uppercase().as_str() {
+            "BLOCK" => Some(Self::Block),
+            "FIRST" => Some(Self::First),
+            "LAST" => Some(Self::Last),
+            "MIN" => Some(Self::Min),
+            "MAX" => Some(Self::Max),
+            "SUM" => Some(Self::Sum),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Block => "block",
+            Self::First => "first",
+            Self::Last => "last",
+            Self::Min => "min",
+            Self::Max => "max",
+            Self::Sum => "sum",
+        }
+    }
+
+    fn resolve(&self, old: f64, new: f64) -> Result<f64, &'static str> {
+        match self {
+            Self::Block => Err("ERR: Update is not supported when DUPLICATE_POLICY is BLOCK"),
+            Self::First => Ok(old),
+            Self::Last => Ok(new),
+            Self::Min => Ok(old.min(new)),
+            Self::Max => Ok(old.max(new)),
+            Self::Sum => Ok(old + new),
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub enum Aggregation {
+    Avg,
+    Sum,
+    Min,
+    Max,
+    Count,
+}
+
+impl Aggregation {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.to_uppercase().as_str() {
+            "AVG" => Some(Self::Avg),
+            "SUM" => Some(Self::Sum),
+            "MIN" => Some(Self::Min),
+            "MAX" => Some(Self::Max),
+            "COUNT" => Some(Self::Count),
+            _ => None,
+        }
+    }
+
+    fn apply(&self, values: &[f64]) -> f64 {
+        match self {
+            Self::Avg => values.iter().sum::<f64>() / values.len() as f64,
+            Self::Sum => values.iter().sum(),
+            Self::Min => values.iter().cloned().fold(f64::INFINITY, f64::min),
+            Self::Max => values.iter().cloned().fold(f64::NEG_INFINITY, f64::max),
+            Self::Count => values.len() as f64,
+        }
+    }
+}
+
+#[derive(Clone, Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    len: usize,
+}
+
+impl BitWriter {
+    fn write(&mut self, value: u64, bits: usize) {
+        for i in (0..bits).rev() {
+            if self.len.is_multiple_of(8) {
+                self.bytes.push(0);
+            }
+            if (value >> i) & 1 == 1 {
+                *self.bytes.last_mut().unwrap() |= 0x80 >> (self.len % 8);
+            }
+            self.len += 1;
+        }
+    }
+}
+
+struct BitReader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl BitReader<'_> {
+    fn read(&mut self, bits: usize) -> u64 {
+        let mut value = 0;
+        for _ in 0..bits {
+            let bit = (self.bytes[self.pos / 8] >> (7 - self.pos % 8)) & 1;
+            value = (value << 1) | bit as u64;
+            self.pos += 1;
+        }
+        value
+    }
+
+    fn read_signed(&mut self, bits: usize) -> i64 {
+        let value = self.read(bits);
+        ((value << (64 - bits)) as i64) >> (64 - bits)
+    }
+}
+
+/// Delta-of-delta timestamps and XOR'd values, as described in Facebook's
+/// Gorilla paper.
+#[derive(Clone, Default)]
+struct GorillaChunk {
+    bits: BitWriter,
+    count: usize,
+    first: u64,
+    last: u64,
+    delta: i64,
+    value: u64,
+    leading: u32,
+    trailing: u32,
+}
+
+const DOD_RANGES: [(usize, u64, usize); 3] = [(7, 0b10, 2), (9, 0b110, 3), (12, 0b1110, 4)];
+
+impl GorillaChunk {
+    fn push(&mut self, (timestamp, value): Sample) {
+        let value = value.to_bits();
+        if self.count == 0 {
+            self.bits.write(timestamp, 64);
+            self.bits.write(value, 64);
+            self.first = timestamp;
+            self.last = timestamp;
+            self.value = value;
+            self.leading = u32::MAX;
+            self.count = 1;
+            return;
+        }
+
+        let delta = (timestamp - self.last) as i64;
+        let dod = delta - self.delta;
+        if dod == 0 {
+            self.bits.write(0, 1);
+        } else {
+            let range = DOD_RANGES.iter().find(|(bits, _, _)| {
+                let limit = 1i64 << (bits - 1);
+                dod >= -limit && dod < limit
+            });
+            match range {
+                Some((bits, prefix, prefix_len)) => {
+                    self.bits.write(*prefix, *prefix_len);
+                    self.bits.write(dod as u64 & ((1 << bits) - 1), *bits);
+                },
+                None => {
+                    self.bits.write(0b1111, 4);
+                    self.bits.write(dod as u64, 64);
+                },
+            };
+        }
+
+        let xor = value ^ self.value;
+        if xor == 0 {
+            self.bits.write(0, 1);
+        } else {
+            let leading = xor.leading_zeros();
+            let trailing = xor.trailing_zeros();
+            if self.leading != u32::MAX && leading >= self.leading && trailing >= self.trailing {
+                self.bits.write(0b10, 2);
+                let meaningful = 64 - self.leading - self.trailing;
+                self.bits.write(xor >> self.trailing, meaningful as usize);
+            } else {
+                let meaningful = 64 - leading - trailing;
+                self.bits.write(0b11, 2);
+                self.bits.write(leading as u64, 6);
+                self.bits.write((meaningful - 1) as u64, 6);
+                self.bits.write(xor >> trailing, meaningful as usize);
+                self.leading = leading;
+                self.trailing = trailing;
+            }
+        }
+
+        self.last = timestamp;
+        self.delta = delta;
+        self.value = value;
+        self.count += 1;
+    }
+
+    fn samples(&self) -> Vec<Sample> {
+        let mut reader = BitReader { bytes: &self.bits.bytes, pos: 0 };
+        let mut samples: Vec<Sample> = Vec::with_capacity(self.count);
+        if self.count == 0 {
+            return samples;
+        }
+
+        let mut timestamp = reader.read(64);
+        let mut value = reader.read(64);
+        let mut delta = 0i64;
+        let (mut leading, mut trailing) = (0, 0);
+        samples.push((timestamp, f64::from_bits(value)));
+
+        for _ in 1..self.count {
+            let mut prefix_len = 0;
+            while prefix_len < 4 && reader.read(1) == 1 {
+                prefix_len += 1;
+            }
+            let dod = match prefix_len {
+                0 => 0,
+                4 => reader.read(64) as i64,
+                n => reader.read_signed(DOD_RANGES[n - 1].0),
+            };
+            delta += dod;
+            timestamp = (timestamp as i64 + delta) as u64;
+
+            if reader.read(1) == 1 {
+                if reader.read(1) == 1 {
+                    leading = reader.read(6) as u32;
+                    let meaningful = reader.read(6) as u32 + 1;
+                    trailing = 64 - leading - meaningful;
+                }
+                let meaningful = 64 - leading - trailing;
+                value ^= reader.read(meaningful as usize) << trailing;
+            }
+            samples.push((timestamp, f64::from_bits(value)));
+        }
+        samples
+    }
+}
+
+#[derive(Clone)]
+enum Chunk {
+    Compressed(GorillaChunk),
+    Uncompressed(Vec<Sample>),
+}
+
+impl Chunk {
+    fn new(compressed: bool) -> Self {
+        match compressed {
+            true => Chunk::Compressed(GorillaChunk::default()),
+            false => Chunk::Uncompressed(Vec::new()),
+        }
+    }
+
+    fn from_samples(samples: Vec<Sample>, compressed: bool) -> Self {
+        match compressed {
+            true => {
+                let mut chunk = GorillaChunk::default();
+                samples.into_iter().for_each(|sample| chunk.push(sample));
+                Chunk::Compressed(chunk)
+            },
+            false => Chunk::Uncompressed(samples),
+        }
+    }
+
+    fn len(&self) -> usize {
+        match self {
+            Chunk::Compressed(chunk) => chunk.count,
+            Chunk::Uncompressed(samples) => samples.len(),
+        }
+    }
+
+    fn size(&self) -> usize {
+        match self {
+            Chunk::Compressed(chunk) => chunk.bits.bytes.len(),
+            Chunk::Uncompressed(samples) => samples.len() * 16,
+        }
+    }
+
+    fn first(&self) -> Option<u64> {
+        match self {
+            Chunk::Compressed(chunk) if chunk.count > 0 => Some(chunk.first),
+            Chunk::Uncompressed(samples) => samples.first().map(|s| s.0),
+            _ => None,
+        }
+    }
+
+    fn last(&self) -> Option<u64> {
+        match self {
+            Chunk::Compressed(chunk) if chunk.count > 0 => Some(chunk.last),
+            Chunk::Uncompressed(samples) => samples.last().map(|s| s.0),
+            _ => None,
+        }
+    }
+
+    fn push(&mut self, sample: Sample) {
+        match self {
+            Chunk::Compressed(chunk) => chunk.push(sample),
+            Chunk::Uncompressed(samples) => samples.push(sample),
+        };
+    }
+
+    fn samples(&self) -> Vec<Sample> {
+        match self {
+            Chunk::Compressed(chunk) => chunk.samples(),
+            Chunk::Uncompressed(samples) => samples.clone(),
+        }
+    }
+}
+
+/// Samples ordered by timestamp, split in chunks of about `chunk_size`
+/// bytes. Chunks entirely outside of the retention window are dropped.
+#[derive(Clone)]
+pub struct TimeSeries {
+    chunks: Vec<Chunk>,
+    retention: u64,
+    chunk_size: usize,
+    compressed: bool,
+    duplicate_policy: DuplicatePolicy,
+    labels: Vec<(String, String)>,
+}
+
+impl TimeSeries {
+    pub fn new(retention: u64, chunk_size: usize, compressed: bool, duplicate_policy: DuplicatePolicy) -> Self {
+        Self {
+            chunks: vec![Chunk::new(compressed)],
+            retention,
+            chunk_size,
+            compressed,
+            duplicate_policy,
+            labels: Vec::new(),
+        }
+    }
+
+    pub fn set_labels(&mut self, labels: Vec<(String, String)>) {
+        self.labels = labels;
+    }
+
+    pub fn labels(&self) -> &[(String, String)] {
+        &self.labels
+    }
+
+    pub fn retention(&self) -> u64 {
+        self.retention
+    }
+
+    pub fn chunk_size(&self) -> usize {
+        self.chunk_size
+    }
+
+    pub fn is_compressed(&self) -> bool {
+        self.compressed
+    }
+
+    pub fn duplicate_policy(&self) -> DuplicatePolicy {
+        self.duplicate_policy
+    }
+
+    pub fn first_timestamp(&self) -> Option<u64> {
+        let oldest = self.oldest_allowed();
+        self.range(oldest, u64::MAX).first().map(|s| s.0)
+    }
+
+    pub fn last_sample(&self) -> Option<Sample> {
+        let chunk = self.chunks.iter().rev().find(|chunk| chunk.len() > 0)?;
+        chunk.samples().last().copied()
+    }
+
+    pub fn len(&self) -> usize {
+        self.chunks.iter().map(Chunk::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.chunks.iter().all(|chunk| chunk.len() == 0)
+    }
+
+    pub fn memory(&self) -> usize {
+        self.chunks.iter().map(Chunk::size).sum()
+    }
+
+    pub fn chunk_count(&self) -> usize {
+        self.chunks.len()
+    }
+
+    fn oldest_allowed(&self) -> u64 {
+        if self.retention == 0 {
+            return 0;
+        }
+        match self.chunks.last().and_then(Chunk::last) {
+            Some(last) => last.saturating_sub(self.retention),
+            None => 0,
+        }
+    }
+
+    /// Inserts a sample, using `policy` (or the series' own policy) when a
+    /// sample with the same timestamp already exists.
+    pub fn add(&mut self, sample: Sample, policy: Option<DuplicatePolicy>) -> Result<u64, &'static str> {
+        let (timestamp, value) = sample;
+        if timestamp < self.oldest_allowed() {
+            return Err("ERR: Timestamp is older than retention");
+        }
+
+        let last = self.chunks.last_mut().unwrap();
+        if last.last().is_none_or(|t| timestamp > t) {
+            if last.size() >= self.chunk_size {
+                self.chunks.push(Chunk::new(self.compressed));
+            }
+            self.chunks.last_mut().unwrap().push(sample);
+            self.trim();
+            return Ok(timestamp);
+        }
+
+        let index = self.chunks.iter()
+            .position(|chunk| chunk.last().is_some_and(|t| timestamp <= t))
+            .unwrap();
+        let mut samples = self.chunks[index].samples();
+        match samples.binary_search_by_key(&timestamp, |s| s.0) {
+            Ok(i) => {
+                let policy = policy.unwrap_or(self.duplicate_policy);
+                samples[i].1 = policy.resolve(samples[i].1, value)?;
+            },
+            Err(i) => samples.insert(i, sample),
+        };
+        self.chunks[index] = Chunk::from_samples(samples, self.compressed);
+        Ok(timestamp)
+    }
+
+    fn trim(&mut self) {
+        let oldest = self.oldest_allowed();
+        while self.chunks.len() > 1 && self.chunks[0].last().is_some_and(|t| t < oldest) {
+            self.chunks.remove(0);
+        }
+    }
+
+    /// Stores the samples rather than the chunks, which get rebuilt on
+    /// restore.
+    pub fn dump(&self, out: &mut Vec<u8>) {
+        put_u64(out, self.retention);
+        put_u64(out, self.chunk_size as u64);
+        out.push(self.compressed as u8);
+        put_bytes(out, self.duplicate_policy.name().as_bytes());
+        put_u64(out, self.labels.len() as u64);
+        for (label, value) in &self.labels {
+            put_bytes(out, label.as_bytes());
+            put_bytes(out, value.as_bytes());
+        }
+        put_u64(out, self.chunks.len() as u64);
+        for chunk in &self.chunks {
+            let samples = chunk.samples();
+            put_u64(out, samples.len() as u64);
+            for (timestamp, value) in samples {
+                put_u64(out, timestamp);
+                out.extend(value.to_bits().to_le_bytes());
+            }
+        }
+    }
+
+    /// Samples must come in the order `add` keeps them, which lookups and
+    /// the compressed chunks rely on.
+    pub fn restore(reader: &mut Reader) -> Result<Self, &'static str> {
+        const INVALID: &str = "ERR: Invalid serialized time series";
+        let retention = reader.u64()?;
+        let chunk_size = usize::try_from(reader.u64()?).map_err(|_| INVALID)?;
+        if !(MIN_CHUNK_SIZE..=MAX_CHUNK_SIZE).contains(&chunk_size) {
+            return Err(INVALID);
+        }
+        let compressed = reader.u8()? != 0;
+        let duplicate_policy = DuplicatePolicy::parse(&reader.string()?).ok_or("ERR: Invalid duplicate policy")?;
+        let labels = (0..reader.u64()?)
+            .map(|_| Ok((reader.string()?, reader.string()?)))
+            .collect::<Result<_, &'static str>>()?;
+
+        let mut chunks = Vec::new();
+        let mut last = None;
+        for _ in 0..reader.u64()? {
+            let samples: Vec<Sample> = (0..reader.u64()?)
+                .map(|_| Ok((reader.u64()?, reader.f64()?)))
+                .collect::<Result<_, &'static str>>()?;
+            for (timestamp, _) in &samples {
+                if *timestamp > MAX_TIMESTAMP || last.is_some_and(|last| last >= *timestamp) {
+                    return Err(INVALID);
+                }
+                last = Some(*timestamp);
+            }
+            chunks.push(Chunk::from_samples(samples, compressed));
+        }
+        if chunks.is_empty() {
+            chunks.push(Chunk::new(compressed));
+        }
+        Ok(Self { chunks, retention, chunk_size, compressed, duplicate_policy, labels })
+    }
+
+    pub fn range(&self, from: u64, to: u64) -> Vec<Sample> {
+        let from = from.max(self.oldest_allowed());
+        let mut samples: Vec<Sample> = Vec::new();
+        for chunk in &self.chunks {
+            let (Some(first), Some(last)) = (chunk.first(), chunk.last()) else {
+                continue;
+            };
+            if last < from || first > to {
+                continue;
+            }
+            samples.extend(chunk.samples().into_iter().filter(|(t, _)| *t >= from && *t <= to));
+        }
+        samples
+    }
+}
+
+/// Groups samples in buckets of `duration` milliseconds aligned to the epoch.
+pub fn aggregate(samples: &[Sample], aggregation: Aggregation, duration: u64) -> Vec<Sample> {
+    let mut buckets: Vec<Sample> = Vec::new();
+    let mut values: Vec<f64> = Vec::new();
+    let mut current: Option<u64> = None;
+
+    for (timestamp, value) in samples {
+        let bucket = timestamp - timestamp % duration;
+        if current.is_some_and(|c| c != bucket) {
+            buckets.push((current.unwrap(), aggregation.apply(&values)));
+            values.clear();
+        }
+        current = Some(bucket);
+        values.push(*value);
+    }
+    if let Some(bucket) = current {
+        buckets.push((bucket, aggregation.apply(&values)));
+    }
+    buckets
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn payload(samples: &[Sample]) -> Vec<u8> {
+        let mut series = TimeSeries::new(0, DEFAULT_CHUNK_SIZE, true, DuplicatePolicy::Block);
+        for sample in samples {
+            series.add(*sample, None).unwrap();
+        }
+        let mut payload = Vec::new();
+        series.dump(&mut payload);
+        payload
+    }
+
+    #[test]
+    fn restores_only_what_it_could_have_built() {
+        let samples = [(1000, 1.0), (2000, 2.0)];
+        let ordered = payload(&samples);
+        let series = TimeSeries::restore(&mut Reader::new(&ordered)).unwrap();
+        assert_eq!(series.range(0, u64::MAX), samples);
+
+        // The first sample starts 32 bytes before the end.
+        let mut unordered = ordered.clone();
+        let at = unordered.len() - 32;
+        unordered[at..at + 8].copy_from_slice(&3000u64.to_le_bytes());
+        assert!(TimeSeries::restore(&mut Reader::new(&unordered)).is_err());
+
+        let mut chunk_size = ordered.clone();
+        chunk_size[8..16].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert!(TimeSeries::restore(&mut Reader::new(&chunk_size)).is_err());
+    }
+
+    /// Pushes every sample, returning the bits each one took, and checks
+    /// they decode back to the same bits.
+    fn round_trip(samples: &[(u64, u64)]) -> Vec<usize> {
+        let mut chunk = GorillaChunk::default();
+        let mut sizes = Vec::new();
+        for (timestamp, value) in samples {
+            let before = chunk.bits.len;
+            chunk.push((*timestamp, f64::from_bits(*value)));
+            sizes.push(chunk.bits.len - before);
+        }
+        let decoded: Vec<(u64, u64)> = chunk.samples().into_iter().map(|(t, v)| (t, v.to_bits())).collect();
+        assert_eq!(decoded, samples);
+        sizes
+    }
+
+    #[test]
+    fn round_trips_delta_of_deltas() {
+        // The edges of each range, then the first values past them.
+        let dods = [-64, 63, -256, 255, -2048, 2047, -65, 64, -257, 256, -2049, 2048, -100_000, 0];
+        let mut timestamps = vec![1_000_000, 1_500_000];
+        let mut delta = 500_000i64;
+        for dod in dods {
+            delta += dod;
+            timestamps.push((*timestamps.last().unwrap() as i64 + delta) as u64);
+        }
+        let samples: Vec<(u64, u64)> = timestamps.into_iter().map(|t| (t, 1f64.to_bits())).collect();
+
+        // One bit for each unchanged value.
+        let sizes = round_trip(&samples);
+        assert_eq!(sizes[2..], [10, 10, 13, 13, 17, 17, 13, 13, 17, 17, 69, 69, 69, 2]);
+    }
+
+    #[test]
+    fn round_trips_xors() {
+        // What each value flips of the one before it.
+        let xors: [u64; 6] = [
+            0xff << 20,
+            // Inside the previous window, which is reused.
+            0xc3 << 20,
+            // Outside of it, so a new one is written.
+            0xff << 40,
+            // Every bit is meaningful.
+            1 << 63 | 1,
+            1 << 10,
+            0,
+        ];
+        let mut values = vec![2f64.to_bits()];
+        for xor in xors {
+            values.push(values.last().unwrap() ^ xor);
+        }
+        let samples: Vec<(u64, u64)> = values.iter().enumerate().map(|(i, v)| (i as u64 * 1000, *v)).collect();
+
+        // The first delta takes 16 bits, the others one.
+        let sizes = round_trip(&samples);
+        assert_eq!(sizes[1..], [16 + 2 + 12 + 8, 1 + 2 + 8, 1 + 2 + 12 + 8, 1 + 2 + 12 + 64, 1 + 2 + 64, 1 + 1]);
+    }
+
+    #[test]
+    fn rebuilds_compressed_chunks_on_out_of_order_inserts() {
+        let mut series = TimeSeries::new(0, MIN_CHUNK_SIZE, true, DuplicatePolicy::Last);
+        let mut expected = Vec::new();
+        for i in 0..20 {
+            let sample = (i * 2000, i as f64 * 1.5);
+            series.add(sample, None).unwrap();
+            expected.push(sample);
+        }
+        assert!(series.chunk_count() > 1);
+
+        for sample in [(1000, -1.0), (3000, 0.25), (21000, 7.0), (0, 9.0)] {
+            series.add(sample, None).unwrap();
+            match expected.binary_search_by_key(&sample.0, |s| s.0) {
+                Ok(i) => expected[i] = sample,
+                Err(i) => expected.insert(i, sample),
+            };
+        }
+        assert_eq!(series.range(0, u64::MAX), expected);
+        assert_eq!(series.len(), expected.len());
+        assert_eq!(series.last_sample(), Some((38000, 28.5)));
+    }
+}
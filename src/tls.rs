@@ -0,0 +1,114 @@
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::TcpStream;
+use std::os::fd::{AsRawFd, RawFd};
+use std::sync::Arc;
+
+use rustls::crypto::ring;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{RootCertStore, ServerConfig, ServerConnection};
+
+use crate::config::{Config, TlsAuthClients};
+use crate::error::Result;
+
+/// Builds the rustls configuration from the files checked when the config
+/// was read.
+pub fn server_config(config: &Config) -> Result<Arc<ServerConfig>> {
+    let certs = CertificateDer::pem_file_iter(config.tls_cert_file().unwrap_or_default())?.collect::<std::result::Result<Vec<_>, _>>()?;
+    let key = PrivateKeyDer::from_pem_file(config.tls_key_file().unwrap_or_default())?;
+
+    let provider = Arc::new(ring::default_provider());
+    let builder = ServerConfig::builder_with_provider(Arc::clone(&provider)).with_safe_default_protocol_versions()?;
+    let builder = match config.tls_auth_clients() {
+        TlsAuthClients::No => builder.with_no_client_auth(),
+        auth => {
+            let mut roots = RootCertStore::empty();
+            for cert in CertificateDer::pem_file_iter(config.tls_ca_cert_file().unwrap_or_default())? {
+                roots.add(cert?)?;
+            }
+
+            let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider);
+            let verifier = match auth {
+                TlsAuthClients::Optional => verifier.allow_unauthenticated().build()?,
+                _ => verifier.build()?,
+            };
+            builder.with_client_cert_verifier(verifier)
+        },
+    };
+    Ok(Arc::new(builder.with_single_cert(certs, key)?))
+}
+
+/// A TLS connection over a non blocking socket, which may hold encrypted
+/// bytes the socket didn't take yet.
+pub struct TlsStream {
+    conn: ServerConnection,
+    sock: TcpStream,
+}
+
+impl TlsStream {
+    pub fn new(config: &Arc<ServerConfig>, sock: TcpStream) -> Result<Self> {
+        Ok(Self { conn: ServerConnection::new(Arc::clone(config))?, sock })
+    }
+
+    pub fn sock(&self) -> &TcpStream {
+        &self.sock
+    }
+
+    pub fn wants_write(&self) -> bool {
+        self.conn.wants_write()
+    }
+
+    fn write_tls(&mut self) -> io::Result<()> {
+        while self.conn.wants_write() {
+            match self.conn.write_tls(&mut self.sock) {
+                Ok(_) => (),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e),
+            };
+        }
+        Ok(())
+    }
+}
+
+impl Read for TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            match self.conn.reader().read(buf) {
+                Err(e) if e.kind() == ErrorKind::WouldBlock => (),
+                result => return result,
+            };
+
+            if self.conn.read_tls(&mut self.sock)? == 0 {
+                return Ok(0);
+            }
+            if let Err(e) = self.conn.process_new_packets() {
+                let _ = self.write_tls();
+                return Err(io::Error::new(ErrorKind::InvalidData, e));
+            }
+            self.write_tls()?;
+        }
+    }
+}
+
+impl Write for TlsStream {
+    /// Fails with `WouldBlock` once rustls buffers as much as it allows.
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.conn.writer().write(buf)?;
+        self.write_tls()?;
+        match n {
+            0 if !buf.is_empty() => Err(ErrorKind::WouldBlock.into()),
+            n => Ok(n),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.write_tls()
+    }
+}
+
+impl AsRawFd for TlsStream {
+    fn as_raw_fd(&self) -> RawFd {
+        self.sock.as_raw_fd()
+    }
+}
@@ -0,0 +1,182 @@
+use std::cmp::Ordering;
+use std::collections::{BTreeSet, HashMap};
+use std::ops::Bound;
+
+use crate::dump::{put_bytes, put_u64, Reader};
+
+const INVALID: &str = "ERR: Invalid serialized value";
+
+/// A score ordered with `total_cmp`, which is a total order once NaN is
+/// kept out and -0 is stored as 0.
+#[derive(Clone, Copy, Debug)]
+struct Score(f64);
+
+impl PartialEq for Score {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Score {}
+
+impl PartialOrd for Score {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Score {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.total_cmp(&other.0)
+    }
+}
+
+/// Members ordered by score, then by name for equal scores, as Redis
+/// orders them.
+#[derive(Clone, Default, Debug)]
+pub struct SortedSet {
+    scores: HashMap<String, f64>,
+    order: BTreeSet<(Score, String)>,
+}
+
+impl SortedSet {
+    pub fn len(&self) -> usize {
+        self.scores.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.scores.is_empty()
+    }
+
+    pub fn score(&self, member: &str) -> Option<f64> {
+        self.scores.get(member).copied()
+    }
+
+    /// Sets the score of `member`, returning its previous one. NaN is for
+    /// the caller to refuse.
+    pub fn insert(&mut self, member: &str, score: f64) -> Option<f64> {
+        let score = score + 0.0;
+        let old = self.scores.insert(member.to_string(), score);
+        if let Some(old) = old {
+            self.order.remove(&(Score(old), member.to_string()));
+        }
+        self.order.insert((Score(score), member.to_string()));
+        old
+    }
+
+    pub fn remove(&mut self, member: &str) -> bool {
+        match self.scores.remove(member) {
+            Some(score) => self.order.remove(&(Score(score), member.to_string())),
+            None => false,
+        }
+    }
+
+    /// Every member from the lowest score up.
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = (&str, f64)> {
+        self.order.iter().map(|(score, member)| (member.as_str(), score.0))
+    }
+
+    /// The members with scores from `min` up to `max`, each bound
+    /// included or not.
+    pub fn range_by_score(&self, min: Bound<f64>, max: Bound<f64>) -> impl DoubleEndedIterator<Item = (&str, f64)> {
+        // The empty name sorts first, so every member of a score comes after
+        // it and before that of the next score up.
+        let lowest = |score: f64| (Score(score + 0.0), String::new());
+        let start = match min {
+            Bound::Included(score) => Some(Some(lowest(score))),
+            Bound::Excluded(score) => next_up(score).map(|score| Some(lowest(score))),
+            Bound::Unbounded => Some(None),
+        };
+        let end = match max {
+            Bound::Included(score) => next_up(score).map(lowest),
+            Bound::Excluded(score) => Some(lowest(score)),
+            Bound::Unbounded => None,
+        };
+        let range = match (start, end) {
+            // Nothing is above the highest score.
+            (None, _) => None,
+            (Some(Some(start)), Some(end)) if start >= end => None,
+            (Some(start), end) => {
+                let bounds = (start.map_or(Bound::Unbounded, Bound::Included), end.map_or(Bound::Unbounded, Bound::Excluded));
+                Some(self.order.range(bounds).map(|(score, member)| (member.as_str(), score.0)))
+            },
+        };
+        range.into_iter().flatten()
+    }
+
+    pub fn dump(&self, out: &mut Vec<u8>) {
+        put_u64(out, self.len() as u64);
+        for (member, score) in self.iter() {
+            put_bytes(out, member.as_bytes());
+            put_u64(out, score.to_bits());
+        }
+    }
+
+    pub fn restore(reader: &mut Reader) -> Result<Self, &'static str> {
+        let mut set = Self::default();
+        for _ in 0..reader.u64()? {
+            let member = reader.string()?;
+            let score = reader.f64()?;
+            if score.is_nan() || set.insert(&member, score).is_some() {
+                return Err(INVALID);
+            }
+        }
+        Ok(set)
+    }
+}
+
+/// The smallest score above `score`, so an included upper bound can be
+/// turned into an excluded one. There's none above infinity.
+fn next_up(score: f64) -> Option<f64> {
+    match score + 0.0 {
+        f64::INFINITY => None,
+        0.0 => Some(f64::from_bits(1)),
+        score if score > 0.0 => Some(f64::from_bits(score.to_bits() + 1)),
+        score => Some(f64::from_bits(score.to_bits() - 1)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn members<'a>(range: impl Iterator<Item = (&'a str, f64)>) -> Vec<&'a str> {
+        range.map(|(member, _)| member).collect()
+    }
+
+    #[test]
+    fn orders_by_score_then_member() {
+        let mut set = SortedSet::default();
+        set.insert("b", 1.0);
+        set.insert("a", 1.0);
+        set.insert("c", -0.0);
+        assert_eq!(set.insert("d", 5.0), None);
+        assert_eq!(set.insert("d", -1.0), Some(5.0));
+        assert_eq!(members(set.iter()), ["d", "c", "a", "b"]);
+        assert!(set.remove("a") && !set.remove("a"));
+        assert_eq!(set.score("c").map(f64::to_bits), Some(0));
+        assert_eq!(set.len(), 3);
+    }
+
+    #[test]
+    fn ranges_by_score_with_either_bound() {
+        let mut set = SortedSet::default();
+        for (member, score) in [("a", 1.0), ("b", 2.0), ("c", 2.0), ("d", 3.0), ("e", f64::INFINITY)] {
+            set.insert(member, score);
+        }
+        let range = |min, max| members(set.range_by_score(min, max));
+        assert_eq!(range(Bound::Included(2.0), Bound::Included(3.0)), ["b", "c", "d"]);
+        assert_eq!(range(Bound::Excluded(2.0), Bound::Excluded(f64::INFINITY)), ["d"]);
+        assert_eq!(range(Bound::Unbounded, Bound::Excluded(2.0)), ["a"]);
+        assert_eq!(range(Bound::Included(3.0), Bound::Unbounded), ["d", "e"]);
+        assert!(range(Bound::Included(3.0), Bound::Excluded(2.0)).is_empty());
+        assert!(range(Bound::Excluded(2.0), Bound::Included(2.0)).is_empty());
+        assert_eq!(range(Bound::Included(f64::NEG_INFINITY), Bound::Included(f64::INFINITY)).len(), 5);
+        assert!(range(Bound::Excluded(f64::INFINITY), Bound::Unbounded).is_empty());
+
+        let mut out = Vec::new();
+        set.dump(&mut out);
+        let restored = SortedSet::restore(&mut Reader::new(&out)).unwrap();
+        assert_eq!(members(restored.iter()), ["a", "b", "c", "d", "e"]);
+    }
+}
@@ -0,0 +1,70 @@
+mod common;
+
+use std::net::TcpStream;
+
+use common::{free_port, send, Server};
+
+fn start(name: &str, config: &str) -> (Server, u16) {
+    let port = free_port();
+    let server = Server::start(name, &format!("port={port}\n{config}"), |_| TcpStream::connect(("127.0.0.1", port)).is_ok());
+    (server, port)
+}
+
+#[test]
+fn asks_each_connection_for_the_password() {
+    let (_server, port) = start("requirepass", "requirepass=secret\n");
+    let mut client = TcpStream::connect(("127.0.0.1", port)).unwrap();
+
+    let noauth = "-NOAUTH: Authentication required\r\n";
+    assert_eq!(send(&mut client, &["SET", "key", "value"]), noauth);
+    assert_eq!(send(&mut client, &["AUTH", "wrong"]), "-WRONGPASS: Invalid username-password pair or user is disabled\r\n");
+    assert_eq!(send(&mut client, &["GET", "key"]), noauth);
+    assert_eq!(send(&mut client, &["AUTH", "secret"]), "+OK\r\n");
+    assert_eq!(send(&mut client, &["SET", "key", "value"]), "+OK\r\n");
+
+    // Another connection logs in on its own, here naming the user.
+    let mut other = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    assert_eq!(send(&mut other, &["GET", "key"]), noauth);
+    assert_eq!(send(&mut other, &["AUTH", "default", "wrong"]), "-WRONGPASS: Invalid username-password pair or user is disabled\r\n");
+    assert_eq!(send(&mut other, &["AUTH", "default", "secret"]), "+OK\r\n");
+    assert_eq!(send(&mut other, &["GET", "key"]), "$5\r\nvalue\r\n");
+}
+
+#[test]
+fn needs_no_password_without_requirepass() {
+    let (_server, port) = start("nopass", "");
+    let mut client = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    assert_eq!(send(&mut client, &["SET", "key", "value"]), "+OK\r\n");
+    assert_eq!(send(&mut client, &["AUTH", "secret"]), "-ERR: AUTH called without any password configured for the default user\r\n");
+}
+
+#[test]
+fn denies_commands_by_category_and_keys_by_pattern() {
+    let aclfile = std::env::temp_dir().join(format!("amandadb-{}-users.acl", std::process::id()));
+    std::fs::write(&aclfile, "user default on >secret +@all ~*\n\
+        user reader on >pw +@read ~cache:*\n\
+        user writer on >pw +@all -@hash -flushdb ~*\n").unwrap();
+    let (_server, port) = start("aclfile", &format!("aclfile={}\n", aclfile.display()));
+    std::fs::remove_file(&aclfile).unwrap();
+
+    let noperm_command = "-NOPERM: This user has no permissions to run this command\r\n";
+    let noperm_key = "-NOPERM: No permissions to access a key\r\n";
+    let mut reader = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    assert_eq!(send(&mut reader, &["AUTH", "reader", "pw"]), "+OK\r\n");
+    assert_eq!(send(&mut reader, &["GET", "cache:a"]), "_\r\n");
+    assert_eq!(send(&mut reader, &["GET", "other"]), noperm_key);
+    assert_eq!(send(&mut reader, &["EXISTS", "cache:a", "other"]), noperm_key);
+    assert_eq!(send(&mut reader, &["SET", "cache:a", "1"]), noperm_command);
+
+    let mut writer = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    assert_eq!(send(&mut writer, &["AUTH", "writer", "pw"]), "+OK\r\n");
+    assert_eq!(send(&mut writer, &["SET", "cache:a", "1"]), "+OK\r\n");
+    assert_eq!(send(&mut writer, &["HSET", "hash", "field", "1"]), noperm_command);
+    assert_eq!(send(&mut writer, &["FLUSHDB"]), noperm_command);
+    assert_eq!(send(&mut reader, &["GET", "cache:a"]), "$1\r\n1\r\n");
+
+    // The default user still runs everything.
+    let mut admin = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    assert_eq!(send(&mut admin, &["AUTH", "secret"]), "+OK\r\n");
+    assert_eq!(send(&mut admin, &["FLUSHDB"]), "+OK\r\n");
+}
@@ -0,0 +1,95 @@
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+const SET: &[u8] = b"*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$1\r\nv\r\n";
+const CUT: &[u8] = b"*3\r\n$3\r\nSET\r\n$1\r\nk";
+
+fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("amandadb-check-aof-{}-{name}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Runs the checker, returning whether it succeeded and what it printed.
+fn check(args: &[&Path]) -> (bool, String) {
+    let output = Command::new(env!("CARGO_BIN_EXE_amandadb-check-aof")).args(args).output().unwrap();
+    (output.status.success(), String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+#[test]
+fn accepts_a_valid_file() {
+    let dir = temp_dir("valid");
+    let file = dir.join("db.aof");
+    std::fs::write(&file, SET.repeat(3)).unwrap();
+
+    let (ok, out) = check(&[&file]);
+    assert!(ok, "{out}");
+    assert!(out.contains("3 commands") && out.contains("The AOF is valid"), "{out}");
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn fixes_a_truncated_file_only_when_asked() {
+    let dir = temp_dir("truncated");
+    let file = dir.join("db.aof");
+    let data = [SET, SET, CUT].concat();
+    std::fs::write(&file, &data).unwrap();
+
+    let (ok, out) = check(&[&file]);
+    assert!(!ok && out.contains("Run with --fix"), "{out}");
+    assert_eq!(std::fs::read(&file).unwrap(), data);
+
+    let (ok, out) = check(&[Path::new("--fix"), &file]);
+    assert!(ok, "{out}");
+    assert_eq!(std::fs::read(&file).unwrap(), SET.repeat(2));
+
+    let (ok, out) = check(&[&file]);
+    assert!(ok && out.contains("The AOF is valid"), "{out}");
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn cuts_a_corrupted_file_at_the_damage() {
+    let dir = temp_dir("corrupted");
+    let file = dir.join("db.aof");
+    std::fs::write(&file, [SET, b"garbage\r\n", SET].concat()).unwrap();
+
+    let (ok, out) = check(&[&file, Path::new("--fix")]);
+    assert!(ok, "{out}");
+    assert_eq!(std::fs::read(&file).unwrap(), SET);
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn fixes_only_the_last_file_of_a_manifest() {
+    let dir = temp_dir("manifest");
+    let manifest = dir.join("db.aof.manifest");
+    std::fs::write(&manifest, "file db.aof.1.base.aof seq 1 type b\nfile db.aof.1.incr.aof seq 1 type i\n").unwrap();
+    let (base, incr) = (dir.join("db.aof.1.base.aof"), dir.join("db.aof.1.incr.aof"));
+
+    // Damage in the base can't be cut off, the incremental file carries on
+    // from it.
+    std::fs::write(&base, [SET, CUT].concat()).unwrap();
+    std::fs::write(&incr, SET).unwrap();
+    let (ok, out) = check(&[Path::new("--fix"), &manifest]);
+    assert!(!ok && out.contains("Only the last file of the AOF can be fixed"), "{out}");
+    assert_eq!(std::fs::read(&base).unwrap(), [SET, CUT].concat());
+
+    std::fs::write(&base, SET).unwrap();
+    std::fs::write(&incr, [SET, CUT].concat()).unwrap();
+    let (ok, out) = check(&[Path::new("--fix"), &manifest]);
+    assert!(ok, "{out}");
+    assert_eq!(std::fs::read(&incr).unwrap(), SET);
+
+    let (ok, out) = check(&[&manifest]);
+    assert!(ok && out.contains("The AOF is valid"), "{out}");
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn rejects_bad_arguments() {
+    assert!(!check(&[]).0);
+    assert!(!check(&[Path::new("--fix")]).0);
+    assert!(!check(&[Path::new("/nonexistent/db.aof")]).0);
+}
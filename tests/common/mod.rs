@@ -0,0 +1,86 @@
+use std::io::{Read, Write};
+use std::net::TcpListener;
+use std::path::PathBuf;
+use std::process::{Child, Command};
+use std::thread;
+use std::time::Duration;
+
+/// The server binary running on a config of its own, killed when dropped.
+pub struct Server {
+    pub dir: PathBuf,
+    process: Child,
+}
+
+impl Server {
+    /// Starts the server in a directory of its own, which relative paths of
+    /// `config` are in, waiting until `ready` says it listens.
+    pub fn start(name: &str, config: &str, ready: impl Fn(&Server) -> bool) -> Server {
+        let dir = std::env::temp_dir().join(format!("amandadb-{}-{name}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let path = dir.join("amandadb.conf");
+        std::fs::write(&path, format!("appendonly=no\n{config}")).unwrap();
+
+        let process = Command::new(env!("CARGO_BIN_EXE_amandadb")).arg(&path).current_dir(&dir).spawn().unwrap();
+        let mut server = Server { dir, process };
+        for _ in 0..100 {
+            if ready(&server) {
+                return server;
+            }
+            if let Some(status) = server.process.try_wait().unwrap() {
+                panic!("server exited with {status}");
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+        server.process.kill().unwrap();
+        panic!("server did not start");
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.process.kill();
+        let _ = self.process.wait();
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+/// A port nothing listens on, as far as can be told before the server binds
+/// it.
+pub fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port()
+}
+
+/// Sends a command and reads its reply, which must fit on one line or in a
+/// bulk string.
+pub fn send(stream: &mut (impl Read + Write), args: &[&str]) -> String {
+    let mut request = format!("*{}\r\n", args.len());
+    for arg in args {
+        request.push_str(&format!("${}\r\n{arg}\r\n", arg.len()));
+    }
+    stream.write_all(request.as_bytes()).unwrap();
+
+    let mut reply = Vec::new();
+    let mut buffer = [0; 1024];
+    while !is_complete(&reply) {
+        match stream.read(&mut buffer).unwrap() {
+            0 => panic!("connection closed by the server"),
+            read => reply.extend_from_slice(&buffer[..read]),
+        }
+    }
+    String::from_utf8(reply).unwrap()
+}
+
+fn is_complete(reply: &[u8]) -> bool {
+    let Some(end) = reply.windows(2).position(|w| w == b"\r\n") else {
+        return false;
+    };
+    match reply[0] {
+        b'$' => match std::str::from_utf8(&reply[1..end]).unwrap().parse::<i64>().unwrap() {
+            -1 => true,
+            len => reply.len() >= end + 2 + len as usize + 2,
+        },
+        _ => true,
+    }
+}
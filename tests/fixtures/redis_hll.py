@@ -0,0 +1,200 @@
+# Port of the PFADD path of Redis' hyperloglog.c: the in-place edits of the
+# sparse encoding and the promotion to dense. It writes the reference HLL
+# strings the HyperLogLog tests compare against, following Redis' own steps
+# rather than re-encoding the registers the way src/hyperloglog.rs does.
+#
+#     python3 tests/fixtures/redis_hll.py tests/fixtures
+import math, struct, sys
+M64 = (1 << 64) - 1
+P, Q = 14, 50
+REGS = 1 << P
+HDR = 16
+SPARSE_MAX = 3000
+
+def murmur64a(key, seed=0xadc83b19):
+    m, r = 0xc6a4a7935bd1e995, 47
+    h = (seed ^ (len(key) * m)) & M64
+    n = len(key) - (len(key) & 7)
+    for i in range(0, n, 8):
+        k = struct.unpack('<Q', key[i:i+8])[0]
+        k = (k * m) & M64; k ^= k >> r; k = (k * m) & M64
+        h ^= k; h = (h * m) & M64
+    rest = key[n:]
+    if rest:
+        for i in range(len(rest) - 1, -1, -1):
+            h ^= rest[i] << (8 * i)
+        h = (h * m) & M64
+    h ^= h >> r; h = (h * m) & M64; h ^= h >> r
+    return h
+
+def patlen(ele):
+    h = murmur64a(ele)
+    index = h & (REGS - 1)
+    h >>= P
+    h |= 1 << Q
+    bit, count = 1, 1
+    while h & bit == 0:
+        count += 1; bit <<= 1
+    return index, count
+
+is_zero = lambda b: b & 0xc0 == 0
+is_xzero = lambda b: b & 0xc0 == 0x40
+is_val = lambda b: b & 0x80
+zero_len = lambda b: (b & 0x3f) + 1
+xzero_len = lambda b0, b1: (((b0 & 0x3f) << 8) | b1) + 1
+val_value = lambda b: ((b >> 2) & 0x1f) + 1
+val_len = lambda b: (b & 0x3) + 1
+def val_op(v, l): return 0x80 | ((v - 1) << 2) | (l - 1)
+def zero_ops(l):
+    if l > 64:
+        return [0x40 | ((l - 1) >> 8), (l - 1) & 0xff]
+    return [l - 1]
+
+class HLL:
+    def __init__(self):
+        self.hdr = bytearray(b'HYLL' + bytes([1, 0, 0, 0]) + bytes(8))
+        self.sparse = bytearray([0x7f, 0xff])
+        self.dense = None
+
+    def sparse_registers(self):
+        regs, i, s = [], 0, self.sparse
+        while i < len(s):
+            b = s[i]
+            if is_zero(b): regs += [0] * zero_len(b); i += 1
+            elif is_xzero(b): regs += [0] * xzero_len(b, s[i+1]); i += 2
+            else: regs += [val_value(b)] * val_len(b); i += 1
+        return regs
+
+    def dense_set(self, index, count):
+        d = self.dense
+        byte, fb = index * 6 // 8, (index * 6) & 7
+        old = ((d[byte] >> fb) | ((d[byte+1] if byte+1 < len(d) else 0) << (8 - fb))) & 63
+        if count <= old:
+            return 0
+        d[byte] = (d[byte] & ~((63 << fb) & 0xff)) | ((count << fb) & 0xff)
+        if byte + 1 < len(d):
+            d[byte+1] = (d[byte+1] & ~(63 >> (8 - fb))) | (count >> (8 - fb))
+        return 1
+
+    def promote(self):
+        regs = self.sparse_registers()
+        self.dense = bytearray((REGS * 6 + 7) // 8)
+        for i, v in enumerate(regs):
+            if v: self.dense_set(i, v)
+        self.hdr[4] = 0
+        self.sparse = None
+
+    def sparse_set(self, index, count):
+        if count > 32:
+            self.promote(); return self.dense_set(index, count)
+        s = self.sparse
+        p, first, prev, span = 0, 0, None, 0
+        while p < len(s):
+            oplen = 1
+            if is_zero(s[p]): span = zero_len(s[p])
+            elif is_val(s[p]): span = val_len(s[p])
+            else: span = xzero_len(s[p], s[p+1]); oplen = 2
+            if index <= first + span - 1: break
+            prev = p; p += oplen; first += span
+        assert span and p < len(s)
+        b = s[p]
+        if is_val(b):
+            if val_value(b) >= count: return 0
+            if val_len(b) == 1:
+                s[p] = val_op(count, 1); return self.merge(prev)
+        if is_zero(b) and zero_len(b) == 1:
+            s[p] = val_op(count, 1); return self.merge(prev)
+        last = first + span - 1
+        seq = []
+        if not is_val(b):
+            if index != first: seq += zero_ops(index - first)
+            seq.append(val_op(count, 1))
+            if index != last: seq += zero_ops(last - index)
+        else:
+            cur = val_value(b)
+            if index != first: seq.append(val_op(cur, index - first))
+            seq.append(val_op(count, 1))
+            if index != last: seq.append(val_op(cur, last - index))
+        oldlen = 2 if is_xzero(b) else 1
+        delta = len(seq) - oldlen
+        if delta > 0 and HDR + len(s) + delta > SPARSE_MAX:
+            self.promote(); return self.dense_set(index, count)
+        s[p:p+oldlen] = bytes(seq)
+        return self.merge(prev)
+
+    def merge(self, prev):
+        s = self.sparse
+        p = prev if prev is not None else 0
+        scan = 5
+        while p < len(s) and scan > 0:
+            scan -= 1
+            if is_xzero(s[p]): p += 2; continue
+            if is_zero(s[p]): p += 1; continue
+            if p + 1 < len(s) and is_val(s[p+1]):
+                v1, v2 = val_value(s[p]), val_value(s[p+1])
+                if v1 == v2:
+                    l = val_len(s[p]) + val_len(s[p+1])
+                    if l <= 4:
+                        s[p+1] = val_op(v1, l)
+                        del s[p]
+                        continue
+            p += 1
+        return 1
+
+    def add(self, ele):
+        index, count = patlen(ele)
+        if self.sparse is not None:
+            return self.sparse_set(index, count)
+        return self.dense_set(index, count)
+
+    def pfadd(self, elements):
+        updated = 0
+        for e in elements:
+            updated += self.add(e)
+        if updated:
+            self.hdr[15] |= 0x80
+        return updated
+
+    def registers(self):
+        if self.sparse is not None:
+            return self.sparse_registers()
+        d = self.dense + b'\0'
+        return [((d[i*6//8] >> ((i*6) & 7)) | (d[i*6//8+1] << (8 - ((i*6) & 7)))) & 63 for i in range(REGS)]
+
+    def count(self):
+        m = float(REGS)
+        histo = [0] * 64
+        for r in self.registers(): histo[r] += 1
+        z = m * tau((m - histo[Q+1]) / m)
+        for j in range(Q, 0, -1):
+            z += histo[j]; z *= 0.5
+        z += m * sigma(histo[0] / m)
+        return round(0.721347520444481703680 * m * m / z)
+
+    def bytes(self):
+        return bytes(self.hdr) + bytes(self.sparse if self.sparse is not None else self.dense)
+
+def sigma(x):
+    if x == 1.0: return math.inf
+    y, z = 1.0, x
+    while True:
+        x *= x; zp = z; z += x * y; y += y
+        if zp == z: return z
+
+def tau(x):
+    if x == 0.0 or x == 1.0: return 0.0
+    y, z = 1.0, 1 - x
+    while True:
+        x = math.sqrt(x); zp = z; y *= 0.5; z -= (1 - x) ** 2 * y
+        if zp == z: return z / 3
+
+
+if __name__ == '__main__':
+    out = sys.argv[1]
+    sparse = HLL()
+    sparse.pfadd([b'element:%d' % i for i in range(200)] + [bytes([i, 0xff, 0]) for i in range(20)])
+    open(out + '/hll-sparse.bin', 'wb').write(sparse.bytes())
+    dense = HLL()
+    dense.pfadd([b'element:%d' % i for i in range(5000)])
+    open(out + '/hll-dense.bin', 'wb').write(dense.bytes())
+    print(sparse.count(), dense.count())
@@ -0,0 +1,207 @@
+# Writes the RDB files the rdb.rs tests load, laid out the way Redis 6.2
+# (RDB 9, ziplists), Redis 7.2 (RDB 11, listpacks and v3 streams) and Redis
+# 7.4 (RDB 12, hash fields with expiries) save them. The packed encodings
+# follow ziplist.c, listpack.c, intset.c and zipmap.c.
+#
+#     python3 tests/fixtures/redis_rdb.py tests/fixtures
+
+import struct
+import sys
+
+POLY = 0x95ac9329ac4bc9b5
+TABLE = []
+for i in range(256):
+    c = i
+    for _ in range(8):
+        c = (c >> 1) ^ POLY if c & 1 else c >> 1
+    TABLE.append(c)
+
+
+def crc64(data, crc=0):
+    for b in data:
+        crc = TABLE[(crc ^ b) & 0xff] ^ (crc >> 8)
+    return crc
+
+
+assert crc64(b'123456789') == 0xe9c6d914c4b8d9ca
+
+
+def length(n):
+    if n < 64:
+        return bytes([n])
+    if n < 16384:
+        return bytes([0x40 | n >> 8, n & 0xff])
+    if n < 1 << 32:
+        return b'\x80' + struct.pack('>I', n)
+    return b'\x81' + struct.pack('>Q', n)
+
+
+def string(s):
+    s = s if isinstance(s, bytes) else str(s).encode()
+    return length(len(s)) + s
+
+
+def ziplist(entries):
+    body, prev = b'', 0
+    for e in entries:
+        if isinstance(e, int):
+            if 0 <= e <= 12:
+                enc = bytes([0xf1 + e])
+            elif -128 <= e < 128:
+                enc = b'\xfe' + struct.pack('<b', e)
+            elif -32768 <= e < 32768:
+                enc = b'\xc0' + struct.pack('<h', e)
+            elif -(1 << 23) <= e < (1 << 23):
+                enc = b'\xf0' + (e & 0xffffff).to_bytes(3, 'little')
+            elif -(1 << 31) <= e < (1 << 31):
+                enc = b'\xd0' + struct.pack('<i', e)
+            else:
+                enc = b'\xe0' + struct.pack('<q', e)
+        else:
+            e = e.encode()
+            if len(e) < 64:
+                enc = bytes([len(e)]) + e
+            elif len(e) < 16384:
+                enc = bytes([0x40 | len(e) >> 8, len(e) & 0xff]) + e
+            else:
+                enc = b'\x80' + struct.pack('>I', len(e)) + e
+        prevlen = bytes([prev]) if prev < 254 else b'\xfe' + struct.pack('<I', prev)
+        entry = prevlen + enc
+        tail = 10 + len(body)
+        body += entry
+        prev = len(entry)
+    return struct.pack('<IIH', 11 + len(body), tail, len(entries)) + body + b'\xff'
+
+
+def backlen(n):
+    # lpEncodeBacklen, whose limits past one byte are one short of what the
+    # bytes could hold.
+    size = 1 if n <= 127 else 2 if n < 16383 else 3 if n < 2097151 else 4 if n < 268435455 else 5
+    return bytes([(n >> 7 * (size - 1 - i)) & 127 | (128 if i else 0) for i in range(size)])
+
+
+def listpack(entries):
+    body = b''
+    for e in entries:
+        if isinstance(e, int):
+            if 0 <= e < 128:
+                enc = bytes([e])
+            elif -4096 <= e < 4096:
+                v = e & 0x1fff
+                enc = bytes([0xc0 | v >> 8, v & 0xff])
+            elif -32768 <= e < 32768:
+                enc = b'\xf1' + struct.pack('<h', e)
+            elif -(1 << 23) <= e < (1 << 23):
+                enc = b'\xf2' + (e & 0xffffff).to_bytes(3, 'little')
+            elif -(1 << 31) <= e < (1 << 31):
+                enc = b'\xf3' + struct.pack('<i', e)
+            else:
+                enc = b'\xf4' + struct.pack('<q', e)
+        else:
+            e = e.encode()
+            if len(e) < 64:
+                enc = bytes([0x80 | len(e)]) + e
+            elif len(e) < 4096:
+                enc = bytes([0xe0 | len(e) >> 8, len(e) & 0xff]) + e
+            else:
+                enc = b'\xf0' + struct.pack('<I', len(e)) + e
+        body += enc + backlen(len(enc))
+    return struct.pack('<IH', 7 + len(body), len(entries)) + body + b'\xff'
+
+
+def zipmap(pairs):
+    def zlen(n):
+        return bytes([n]) if n < 254 else b'\xfe' + struct.pack('<I', n)
+
+    out = bytes([len(pairs)])
+    for k, v in pairs:
+        k, v = k.encode(), v.encode()
+        out += zlen(len(k)) + k + zlen(len(v)) + b'\x02' + v + b'zz'
+    return out + b'\xff'
+
+
+def intset(members, width):
+    code = {2: '<h', 4: '<i', 8: '<q'}[width]
+    return struct.pack('<II', width, len(members)) + b''.join(struct.pack(code, m) for m in sorted(members))
+
+
+def finish(out):
+    out += b'\xff'
+    return out + struct.pack('<Q', crc64(out))
+
+
+# Far enough ahead not to have passed when the tests run.
+LATER = 4102444800000
+
+
+def redis6():
+    out = b'REDIS0009' + b'\xfa' + string('redis-ver') + string('6.2.14')
+    out += b'\xfa' + string('redis-bits') + b'\xc0\x40'
+    out += b'\xfe\x00\xfb' + length(5) + length(0)
+    out += b'\x00' + string('plain') + string('hello')
+    out += b'\x00' + string('int16') + b'\xc1' + struct.pack('<h', 1234)
+    out += b'\x00' + string('lzf') + b'\xc3' + length(5) + length(10) + b'\x00\x61\xe0\x00\x00'
+    out += b'\x0d' + string('ziplist') + string(ziplist([
+        'name', 'amanda', 'imm', 7, 'i8', -100, 'i16', 30000, 'i24', -5000000, 'i32', 2000000000,
+        'i64', 1 << 40, 'long', 'x' * 300, 'after', 'long',
+    ]))
+    out += b'\x09' + string('zipmap') + string(zipmap([('k1', 'v1'), ('k2', 'z' * 300)]))
+    out += b'\x0e' + string('quicklist') + length(2) + string(ziplist(['a', 5])) + string(ziplist(['b']))
+    out += b'\x0b' + string('intset') + string(intset([70000, -3], 4))
+    out += b'\x0c' + string('zset') + string(ziplist(['m', 1, 'n', '2.5']))
+    # Scores as text, which Redis 2.4 and older saved.
+    out += b'\x03' + string('oldzset') + length(2) + string('low') + b'\xff' + string('mid') + b'\x031.5'
+    out += b'\xfc' + struct.pack('<Q', 1000) + b'\x00' + string('expired') + string('gone')
+    out += b'\xfc' + struct.pack('<Q', LATER) + b'\x00' + string('expiring') + string('kept')
+    return finish(out)
+
+
+def redis7():
+    out = b'REDIS0011' + b'\xfa' + string('redis-ver') + string('7.2.4')
+    out += b'\xfa' + string('redis-bits') + b'\xc0\x40'
+    out += b'\xfe\x00\xfb' + length(5) + length(0)
+    out += b'\x00' + string('plain') + string('hello')
+    out += b'\x10' + string('listpack') + string(listpack([
+        'name', 'amanda', 'u7', 7, 'i13', -100, 'i16', 30000, 'i24', -5000000, 'i32', -2000000000,
+        'i64', 1 << 40, 'str12', 'y' * 200, 'after', 'str12',
+        # Five bytes of header make this entry 16383 bytes, a backlen limit.
+        'edge', 'e' * 16378, 'past', 'edge',
+    ]))
+    # A packed node, then a plain one holding a single element.
+    out += b'\x12' + string('list') + length(2) + length(2) + string(listpack(['a', 'b'])) + length(1) + string('c')
+    out += b'\x14' + string('set') + string(listpack(['m']))
+    out += b'\x11' + string('zset') + string(listpack(['m', 1]))
+    # One listpack node, then length, last id, first id, max deleted id and
+    # entries added, one group with one pending entry and one consumer.
+    stream = length(1) + string(b'\x00' * 16) + string(listpack([1, 0, 1, 'f', 0, 0, 0, 1, 'v', 4, 1]))
+    stream += length(1) + length(5) + length(0) + length(5) + length(0) + length(0) + length(0) + length(1)
+    stream += length(1) + string('group') + length(5) + length(0) + length(1)
+    stream += length(1) + b'\x00' * 16 + struct.pack('<Q', 1700000000000) + length(1)
+    stream += length(1) + string('consumer') + struct.pack('<QQ', 1700000000000, 1700000000000)
+    stream += length(1) + b'\x00' * 16
+    out += b'\x15' + string('stream') + stream
+    return finish(out)
+
+
+def redis74():
+    out = b'REDIS0012' + b'\xfa' + string('redis-ver') + string('7.4.0')
+    out += b'\xfe\x00\xfb' + length(5) + length(0)
+    # Each hash has a field without expiry, one that expired and one that
+    # hasn't yet.
+    fields = [('a', '1', 0), ('b', '2', 1000), ('c', '3', LATER)]
+    out += b'\x16' + string('meta-pre-ga') + length(3)
+    out += b''.join(length(ttl) + string(f) + string(v) for f, v, ttl in fields)
+    out += b'\x18' + string('meta') + struct.pack('<Q', 1000) + length(3)
+    out += b''.join(length(ttl and ttl - 1000 + 1) + string(f) + string(v) for f, v, ttl in fields)
+    triples = [e for f, v, ttl in fields for e in (f, v, ttl)]
+    out += b'\x17' + string('listpack-ex-pre-ga') + string(listpack(triples))
+    out += b'\x19' + string('listpack-ex') + struct.pack('<Q', 1000) + string(listpack(triples))
+    out += b'\x19' + string('all-expired') + struct.pack('<Q', 1000) + string(listpack(['f', 'v', 1000]))
+    return finish(out)
+
+
+if __name__ == '__main__':
+    out = sys.argv[1]
+    open(out + '/redis6.rdb', 'wb').write(redis6())
+    open(out + '/redis7.rdb', 'wb').write(redis7())
+    open(out + '/redis74.rdb', 'wb').write(redis74())
@@ -0,0 +1,8 @@
+# Writes the self-signed certificate and key the TLS tests start the server
+# with, good for localhost and 127.0.0.1 for a hundred years.
+#
+#     sh tests/fixtures/tls.sh tests/fixtures
+
+openssl req -x509 -newkey ec -pkeyopt ec_paramgen_curve:prime256v1 -nodes -days 36500 -subj /CN=localhost \
+    -addext "subjectAltName=DNS:localhost,IP:127.0.0.1" -addext "basicConstraints=critical,CA:FALSE" \
+    -keyout "$1/tls-key.pem" -out "$1/tls-cert.pem"
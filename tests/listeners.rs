@@ -0,0 +1,31 @@
+mod common;
+
+use std::net::TcpStream;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::UnixStream;
+
+use common::{free_port, send, Server};
+
+#[test]
+fn serves_the_unix_socket_alongside_the_port() {
+    let port = free_port();
+    let config = format!("port={port}\nunixsocket=amandadb.sock\nunixsocketperm=700\n");
+    let server = Server::start("unixsocket", &config, |server| UnixStream::connect(server.dir.join("amandadb.sock")).is_ok());
+    let path = server.dir.join("amandadb.sock");
+    assert_eq!(std::fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o700);
+
+    let mut unix = UnixStream::connect(&path).unwrap();
+    let mut tcp = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    assert_eq!(send(&mut unix, &["SET", "key", "value"]), "+OK\r\n");
+    assert_eq!(send(&mut tcp, &["GET", "key"]), "$5\r\nvalue\r\n");
+    assert_eq!(send(&mut unix, &["GET", "key"]), "$5\r\nvalue\r\n");
+}
+
+#[test]
+fn listens_only_on_the_unix_socket_without_a_port() {
+    let server = Server::start("unixsocket-only", "port=0\nunixsocket=amandadb.sock\n", |server| {
+        UnixStream::connect(server.dir.join("amandadb.sock")).is_ok()
+    });
+    let mut unix = UnixStream::connect(server.dir.join("amandadb.sock")).unwrap();
+    assert_eq!(send(&mut unix, &["PING"]), "+PONG\r\n");
+}
@@ -0,0 +1,59 @@
+#![cfg(feature = "tls")]
+
+mod common;
+
+use std::net::TcpStream;
+use std::sync::Arc;
+
+use rustls::crypto::ring;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, ServerName};
+use rustls::{ClientConfig, ClientConnection, RootCertStore, StreamOwned};
+
+use common::{free_port, send, Server};
+
+const CERT: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/tls-cert.pem");
+const KEY: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/tls-key.pem");
+
+fn start(name: &str, config: &str, port: u16) -> Server {
+    let config = format!("port=0\ntls-port={port}\ntls-cert-file={CERT}\ntls-key-file={KEY}\n{config}");
+    Server::start(name, &config, |_| TcpStream::connect(("127.0.0.1", port)).is_ok())
+}
+
+/// A client trusting the self-signed certificate of the fixture.
+fn connect(port: u16) -> StreamOwned<ClientConnection, TcpStream> {
+    let mut roots = RootCertStore::empty();
+    roots.add(CertificateDer::from_pem_file(CERT).unwrap()).unwrap();
+    let config = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions().unwrap()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    let conn = ClientConnection::new(Arc::new(config), ServerName::try_from("localhost").unwrap()).unwrap();
+    StreamOwned::new(conn, TcpStream::connect(("127.0.0.1", port)).unwrap())
+}
+
+#[test]
+fn serves_clients_over_tls() {
+    let port = free_port();
+    let _server = start("tls", "tls-auth-clients=no\n", port);
+
+    let (mut first, mut second) = (connect(port), connect(port));
+    assert_eq!(send(&mut first, &["SET", "key", "value"]), "+OK\r\n");
+    assert_eq!(send(&mut second, &["GET", "key"]), "$5\r\nvalue\r\n");
+    // A reply bigger than a TLS record comes through whole.
+    let value = "x".repeat(40_000);
+    assert_eq!(send(&mut first, &["SET", "big", &value]), "+OK\r\n");
+    assert_eq!(send(&mut second, &["GET", "big"]), format!("$40000\r\n{value}\r\n"));
+}
+
+#[test]
+fn refuses_clients_without_a_certificate_when_asked_to() {
+    let port = free_port();
+    let _server = start("tls-auth", &format!("tls-auth-clients=yes\ntls-ca-cert-file={CERT}\n"), port);
+
+    let mut client = connect(port);
+    let mut request = b"*1\r\n$4\r\nPING\r\n".as_slice();
+    let refused = std::io::copy(&mut request, &mut client)
+        .and_then(|_| std::io::Read::read(&mut client, &mut [0; 64]));
+    assert!(!matches!(refused, Ok(read) if read > 0), "{refused:?}");
+}